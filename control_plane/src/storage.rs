@@ -410,6 +410,10 @@ impl PageServerNode {
                     .map(|x| x.parse::<usize>())
                     .transpose()?,
                 pitr_interval: settings.get("pitr_interval").map(|x| x.to_string()),
+                min_versions_per_block: settings
+                    .get("min_versions_per_block")
+                    .map(|x| x.parse::<usize>())
+                    .transpose()?,
             })
             .send()?
             .error_from_body()?
@@ -449,6 +453,9 @@ impl PageServerNode {
                     .get("image_creation_threshold")
                     .map(|x| x.parse::<usize>().unwrap()),
                 pitr_interval: settings.get("pitr_interval").map(|x| x.to_string()),
+                min_versions_per_block: settings
+                    .get("min_versions_per_block")
+                    .map(|x| x.parse::<usize>().unwrap()),
             })
             .send()?
             .error_from_body()?;
@@ -12,6 +12,7 @@ use utils::lsn::Lsn;
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
+pub mod checksum_utils;
 pub mod controlfile_utils;
 pub mod nonrelfile_utils;
 pub mod pg_constants;
@@ -40,6 +40,13 @@ pub struct WalDecodeError {
     lsn: Lsn,
 }
 
+impl WalDecodeError {
+    /// Position in the WAL stream where decoding failed.
+    pub fn lsn(&self) -> Lsn {
+        self.lsn
+    }
+}
+
 //
 // WalRecordStream is a Stream that returns a stream of WAL records
 // FIXME: This isn't a proper rust stream
@@ -27,7 +27,7 @@ use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use utils::bin_ser::DeserializeError;
 use utils::bin_ser::SerializeError;
 use utils::const_assert;
@@ -124,11 +124,12 @@ pub fn get_current_timestamp() -> TimestampTz {
     to_pg_timestamp(SystemTime::now())
 }
 
+const UNIX_EPOCH_JDATE: u64 = 2440588; /* == date2j(1970, 1, 1) */
+const POSTGRES_EPOCH_JDATE: u64 = 2451545; /* == date2j(2000, 1, 1) */
+const SECS_PER_DAY: u64 = 86400;
+const USECS_PER_SEC: u64 = 1000000;
+
 pub fn to_pg_timestamp(time: SystemTime) -> TimestampTz {
-    const UNIX_EPOCH_JDATE: u64 = 2440588; /* == date2j(1970, 1, 1) */
-    const POSTGRES_EPOCH_JDATE: u64 = 2451545; /* == date2j(2000, 1, 1) */
-    const SECS_PER_DAY: u64 = 86400;
-    const USECS_PER_SEC: u64 = 1000000;
     match time.duration_since(SystemTime::UNIX_EPOCH) {
         Ok(n) => {
             ((n.as_secs() - ((POSTGRES_EPOCH_JDATE - UNIX_EPOCH_JDATE) * SECS_PER_DAY))
@@ -139,6 +140,16 @@ pub fn to_pg_timestamp(time: SystemTime) -> TimestampTz {
     }
 }
 
+pub fn from_pg_timestamp(time: TimestampTz) -> SystemTime {
+    let pg_epoch = SystemTime::UNIX_EPOCH
+        + Duration::from_secs((POSTGRES_EPOCH_JDATE - UNIX_EPOCH_JDATE) * SECS_PER_DAY);
+    if time >= 0 {
+        pg_epoch + Duration::from_micros(time as u64)
+    } else {
+        pg_epoch - Duration::from_micros(time.unsigned_abs())
+    }
+}
+
 /// Return offset of the last valid record in the segment segno, starting
 /// looking at start_offset. Returns start_offset if no records found.
 fn find_end_of_wal_segment(
@@ -741,4 +752,15 @@ mod tests {
         checkpoint.update_next_xid(1024);
         assert_eq!(checkpoint.nextXid.value, 2048);
     }
+
+    #[test]
+    pub fn test_pg_timestamp_roundtrip() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_650_000_000_123_456);
+        assert_eq!(from_pg_timestamp(to_pg_timestamp(time)), time);
+
+        // Before the Postgres epoch
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        assert!(to_pg_timestamp(time) < 0);
+        assert_eq!(from_pg_timestamp(to_pg_timestamp(time)), time);
+    }
 }
@@ -456,6 +456,11 @@ pub enum BeAuthenticationSaslMessage<'a> {
 pub enum BeParameterStatusMessage<'a> {
     Encoding(&'a str),
     ServerVersion(&'a str),
+    /// Any other parameter, e.g. one relayed from another server.
+    Other {
+        name: &'a str,
+        value: &'a str,
+    },
 }
 
 impl BeParameterStatusMessage<'static> {
@@ -828,25 +833,20 @@ impl<'a> BeMessage<'a> {
             }
 
             BeMessage::ParameterStatus(param) => {
-                use std::io::{IoSlice, Write};
                 use BeParameterStatusMessage::*;
 
-                let [name, value] = match param {
-                    Encoding(name) => [b"client_encoding", name.as_bytes()],
-                    ServerVersion(version) => [b"server_version", version.as_bytes()],
+                let (name, value) = match param {
+                    Encoding(name) => ("client_encoding", *name),
+                    ServerVersion(version) => ("server_version", *version),
+                    Other { name, value } => (*name, *value),
                 };
 
                 // Parameter names and values are passed as null-terminated strings
-                let iov = &mut [name, b"\0", value, b"\0"].map(IoSlice::new);
-                let mut buffer = [0u8; 64]; // this should be enough
-                let cnt = buffer.as_mut().write_vectored(iov).unwrap();
-
                 buf.put_u8(b'S');
                 write_body(buf, |buf| {
-                    buf.put_slice(&buffer[..cnt]);
-                    Ok::<_, io::Error>(())
-                })
-                .unwrap();
+                    write_cstr(name.as_bytes(), buf)?;
+                    write_cstr(value.as_bytes(), buf)
+                })?;
             }
 
             BeMessage::ParameterDescription => {
@@ -930,11 +930,18 @@ pub struct ZenithFeedback {
     pub ps_applylsn: u64,
     pub ps_flushlsn: u64,
     pub ps_replytime: SystemTime,
+    // Amount of WAL, in bytes, safekeeper holds only because pageserver hasn't
+    // persisted it remotely yet. Only meaningful from safekeeper to proposer,
+    // where it carries AppendResponse::backpressure_lag for the proposer to
+    // throttle on. Pageservers must send 0; safekeepers overwrite the value
+    // with their own when they pass the feedback on.
+    #[serde(default)]
+    pub backpressure_lag: u64,
 }
 
 // NOTE: Do not forget to increment this number when adding new fields to ZenithFeedback.
 // Do not remove previously available fields because this might be backwards incompatible.
-pub const ZENITH_FEEDBACK_FIELDS_NUMBER: u8 = 5;
+pub const ZENITH_FEEDBACK_FIELDS_NUMBER: u8 = 6;
 
 impl ZenithFeedback {
     pub fn empty() -> ZenithFeedback {
@@ -944,6 +951,7 @@ impl ZenithFeedback {
             ps_applylsn: 0,
             ps_flushlsn: 0,
             ps_replytime: SystemTime::now(),
+            backpressure_lag: 0,
         }
     }
 
@@ -982,6 +990,10 @@ impl ZenithFeedback {
         write_cstr(&Bytes::from("ps_replytime"), buf)?;
         buf.put_i32(8);
         buf.put_i64(timestamp);
+
+        write_cstr(&Bytes::from("backpressure_lag"), buf)?;
+        buf.put_i32(8);
+        buf.put_u64(self.backpressure_lag);
         Ok(())
     }
 
@@ -1025,6 +1037,11 @@ impl ZenithFeedback {
                         zf.ps_replytime = *PG_EPOCH - Duration::from_micros(-raw_time as u64);
                     }
                 }
+                "backpressure_lag" => {
+                    let len = buf.get_i32();
+                    assert_eq!(len, 8);
+                    zf.backpressure_lag = buf.get_u64();
+                }
                 _ => {
                     let len = buf.get_i32();
                     warn!(
@@ -1049,6 +1066,7 @@ mod tests {
         let mut zf = ZenithFeedback::empty();
         // Fill zf with some values
         zf.current_timeline_size = 12345678;
+        zf.backpressure_lag = 4242;
         // Set rounded time to be able to compare it with deserialized value,
         // because it is rounded up to microseconds during serialization.
         zf.ps_replytime = *PG_EPOCH + Duration::from_secs(100_000_000);
@@ -1084,6 +1102,35 @@ mod tests {
         assert_eq!(zf, zf_parsed);
     }
 
+    #[test]
+    fn test_zenithfeedback_old_layout() {
+        // Layout before backpressure_lag was added
+        let mut data = BytesMut::new();
+        data.put_u8(5);
+        for (key, value) in [
+            ("current_timeline_size", 12345678),
+            ("ps_writelsn", 1),
+            ("ps_flushlsn", 2),
+            ("ps_applylsn", 3),
+            ("ps_replytime", 100_000_000_000_000),
+        ] {
+            write_cstr(&Bytes::from(key), &mut data).unwrap();
+            data.put_i32(8);
+            data.put_u64(value);
+        }
+
+        let zf_parsed = ZenithFeedback::parse(data.freeze());
+        assert_eq!(zf_parsed.current_timeline_size, 12345678);
+        assert_eq!(zf_parsed.ps_writelsn, 1);
+        assert_eq!(zf_parsed.ps_flushlsn, 2);
+        assert_eq!(zf_parsed.ps_applylsn, 3);
+        assert_eq!(
+            zf_parsed.ps_replytime,
+            *PG_EPOCH + Duration::from_secs(100_000_000)
+        );
+        assert_eq!(zf_parsed.backpressure_lag, 0);
+    }
+
     // Make sure that `read` is sync/async callable
     async fn _assert(stream: &mut (impl tokio::io::AsyncRead + Unpin)) {
         let _ = FeMessage::read(&mut [].as_ref());
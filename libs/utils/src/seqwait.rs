@@ -14,7 +14,7 @@ use std::time::Duration;
 pub enum SeqWaitError {
     /// The wait timeout was reached
     Timeout,
-    /// [`SeqWait::shutdown`] was called
+    /// [`SeqWait::shutdown`] or [`SeqWait::cancel_waiters`] was called
     Shutdown,
 }
 
@@ -137,6 +137,15 @@ where
         drop(waiters);
     }
 
+    /// Wake all the current waiters with an error, like `shutdown`, but
+    /// keep accepting new waiters.
+    pub fn cancel_waiters(&self) {
+        let waiters = mem::take(&mut self.internal.lock().unwrap().waiters);
+
+        // Dropping the senders wakes the receivers with an error
+        drop(waiters);
+    }
+
     /// Wait for a number to arrive
     ///
     /// This call won't complete until someone has called `advance`
@@ -224,6 +233,16 @@ where
         old_value
     }
 
+    /// Move the current value backwards, e.g. when a timeline is rewound.
+    ///
+    /// Nobody is woken up: waiters for a number above the new value keep
+    /// waiting, and those at or below it have already been woken.
+    pub fn reset(&self, num: S) {
+        let mut internal = self.internal.lock().unwrap();
+        assert!(num.cnt_value() <= internal.current.cnt_value());
+        internal.current = num;
+    }
+
     /// Read the current value, without waiting.
     pub fn load(&self) -> S {
         self.internal.lock().unwrap().current
@@ -275,6 +294,23 @@ mod tests {
         seq.shutdown();
     }
 
+    #[test]
+    fn seqwait_cancel_waiters() {
+        let seq = Arc::new(SeqWait::new(0));
+        let seq2 = Arc::clone(&seq);
+        let waiter = spawn(move || seq2.wait_for_timeout(42, Duration::from_secs(60)));
+        sleep(Duration::from_millis(100));
+        seq.cancel_waiters();
+        assert_eq!(waiter.join().unwrap(), Err(SeqWaitError::Shutdown));
+
+        // New waiters are still accepted
+        let seq3 = Arc::clone(&seq);
+        let waiter = spawn(move || seq3.wait_for(42));
+        sleep(Duration::from_millis(100));
+        seq.advance(42);
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+    }
+
     #[test]
     fn seqwait_timeout() {
         let seq = Arc::new(SeqWait::new(0));
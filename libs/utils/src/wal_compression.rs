@@ -0,0 +1,125 @@
+//! Compression of the WAL streamed from safekeepers to pageservers.
+//!
+//! The pageserver asks for it with a `COMPRESSION <method>` option of the
+//! `START_REPLICATION` command, if the safekeeper advertised support for the
+//! method in its `IDENTIFY_SYSTEM` response. Then the payload of each
+//! XLogData message is compressed separately, so that the receiver can
+//! decompress every message on its own. `wal_start` and `wal_end` in the
+//! messages are still in terms of uncompressed WAL.
+
+use anyhow::{bail, Context, Result};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Upper bound on the size of decompressed XLogData payload. Senders never
+/// put more WAL than this in a single message, and it protects the receiver
+/// from blowing up its memory on a bogus message.
+pub const MAX_DECOMPRESSED_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalCompression {
+    Deflate,
+}
+
+impl WalCompression {
+    /// Methods supported by this build, in the order of preference.
+    pub const SUPPORTED: &'static [WalCompression] = &[WalCompression::Deflate];
+
+    /// Pick the first of our methods that is in the comma-separated list
+    /// advertised by the other side.
+    pub fn negotiate(advertised: &str) -> Option<WalCompression> {
+        let advertised: Vec<_> = advertised
+            .split(',')
+            .filter_map(|name| name.trim().parse::<WalCompression>().ok())
+            .collect();
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .find(|method| advertised.contains(method))
+    }
+
+    /// Comma-separated list of the supported methods, to advertise.
+    pub fn advertise() -> String {
+        Self::SUPPORTED
+            .iter()
+            .map(|method| method.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            WalCompression::Deflate => {
+                let mut encoder =
+                    DeflateEncoder::new(Vec::with_capacity(data.len() / 2), Compression::fast());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            WalCompression::Deflate => {
+                let mut buf = Vec::new();
+                DeflateDecoder::new(data)
+                    .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                    .read_to_end(&mut buf)
+                    .context("failed to decompress WAL")?;
+                if buf.len() > MAX_DECOMPRESSED_SIZE {
+                    bail!("decompressed WAL exceeds {} bytes", MAX_DECOMPRESSED_SIZE);
+                }
+                Ok(buf)
+            }
+        }
+    }
+}
+
+impl FromStr for WalCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "deflate" => Ok(WalCompression::Deflate),
+            _ => bail!("unknown WAL compression method '{}'", s),
+        }
+    }
+}
+
+impl fmt::Display for WalCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalCompression::Deflate => write!(f, "deflate"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            WalCompression::negotiate(&WalCompression::advertise()),
+            Some(WalCompression::Deflate)
+        );
+        assert_eq!(
+            WalCompression::negotiate("zstd, deflate"),
+            Some(WalCompression::Deflate)
+        );
+        assert_eq!(WalCompression::negotiate("zstd"), None);
+        assert_eq!(WalCompression::negotiate(""), None);
+    }
+
+    #[test]
+    fn test_decompress_limit() -> Result<()> {
+        let compressed = WalCompression::Deflate.compress(&vec![0; MAX_DECOMPRESSED_SIZE + 1])?;
+        assert!(WalCompression::Deflate.decompress(&compressed).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+//! Repeated reads of one page, served from the global page cache, which
+//! copies the page out for every read, and from the per-timeline recent
+//! pages cache, which hands out the same buffer.
+//!
+//! The number of allocations per read is printed before the timings.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion};
+use pageserver::layered_repository::recent_pages::RecentPages;
+use pageserver::page_cache;
+use pageserver::repository::Key;
+use utils::{
+    lsn::Lsn,
+    zid::{ZTenantId, ZTimelineId},
+};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const READS: usize = 1000;
+
+fn count_allocations(mut f: impl FnMut()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..READS {
+        f();
+    }
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+pub fn bench_repeated_reads(c: &mut Criterion) {
+    page_cache::init(100);
+    let cache = page_cache::get();
+    let tenant_id = ZTenantId::generate();
+    let timeline_id = ZTimelineId::generate();
+    let key = Key::from_hex("000000067F000032BE0000400000000070B6").unwrap();
+    let img = vec![0x55u8; page_cache::PAGE_SZ];
+    cache.memorize_materialized_page(tenant_id, timeline_id, key, Lsn(0x10), &img);
+
+    let recent_pages = RecentPages::new(128);
+    recent_pages.memorize(key, Lsn(0x10), Bytes::from(img));
+
+    let read_from_page_cache = || {
+        let (_, guard) = cache
+            .lookup_materialized_page(tenant_id, timeline_id, &key, Lsn(0x20))
+            .unwrap();
+        Bytes::from(guard.to_vec())
+    };
+    let read_from_recent_pages = || recent_pages.lookup(&key, Lsn(0x20)).unwrap().1;
+
+    println!(
+        "allocations per {READS} reads: page cache {}, recent pages {}",
+        count_allocations(|| drop(read_from_page_cache())),
+        count_allocations(|| drop(read_from_recent_pages())),
+    );
+
+    c.bench_function("read page from page cache", |b| {
+        b.iter(read_from_page_cache)
+    });
+    c.bench_function("read page from recent pages", |b| {
+        b.iter(read_from_recent_pages)
+    });
+}
+
+criterion_group!(benches, bench_repeated_reads);
+criterion_main!(benches);
@@ -26,7 +26,7 @@ fn main() -> Result<()> {
     let path = PathBuf::from(arg_matches.value_of("path").unwrap());
 
     // Basic initialization of things that don't change after startup
-    virtual_file::init(10);
+    virtual_file::init(10, Box::new(virtual_file::ClockPolicy::default()));
     page_cache::init(100);
 
     dump_layerfile_from_path(&path, true)?;
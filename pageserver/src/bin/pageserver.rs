@@ -11,9 +11,9 @@ use daemonize::Daemonize;
 use fail::FailScenario;
 use pageserver::{
     config::{defaults::*, PageServerConf},
-    http, page_cache, page_service, profiling, tenant_mgr, thread_mgr,
+    http, page_cache, page_service, profiling, rate_limiter, tenant_mgr, thread_mgr,
     thread_mgr::ThreadKind,
-    timelines, virtual_file, LOG_FILE_NAME,
+    timelines, virtual_file, walredo, LOG_FILE_NAME,
 };
 use utils::{
     auth::JwtAuth,
@@ -188,8 +188,13 @@ fn main() -> anyhow::Result<()> {
     let scenario = FailScenario::setup();
 
     // Basic initialization of things that don't change after startup
-    virtual_file::init(conf.max_file_descriptors);
+    virtual_file::init(
+        conf.max_file_descriptors,
+        Box::new(virtual_file::ClockPolicy::default()),
+    );
     page_cache::init(conf.page_cache_size);
+    rate_limiter::init(conf.checkpoint_io_rate_limit);
+    walredo::init_redo_limiter(conf.max_concurrent_redo);
 
     // Create repo and exit if init was requested
     if init {
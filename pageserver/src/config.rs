@@ -20,6 +20,7 @@ use utils::{
 };
 
 use crate::layered_repository::TIMELINES_SEGMENT_NAME;
+use crate::pgdatadir_mapping::MAX_PREFETCH_DEPTH;
 use crate::tenant_config::{TenantConf, TenantConfOpt};
 
 pub mod defaults {
@@ -33,11 +34,27 @@ pub mod defaults {
 
     pub const DEFAULT_WAIT_LSN_TIMEOUT: &str = "60 s";
     pub const DEFAULT_WAL_REDO_TIMEOUT: &str = "60 s";
+    pub const DEFAULT_WAL_CONNECT_TIMEOUT: &str = "10 s";
 
     pub const DEFAULT_SUPERUSER: &str = "zenith_admin";
 
     pub const DEFAULT_PAGE_CACHE_SIZE: usize = 8192;
     pub const DEFAULT_MAX_FILE_DESCRIPTORS: usize = 100;
+    pub const DEFAULT_PREFETCH_DEPTH: u32 = 0;
+    pub const DEFAULT_REL_ACCESS_STATS: bool = false;
+    pub const DEFAULT_MAX_CONCURRENT_REDO: usize = 0;
+    pub const DEFAULT_MAX_ANCESTOR_DEPTH: usize = 1000;
+    pub const DEFAULT_RECENT_PAGE_CACHE_SIZE: usize = 32;
+    pub const DEFAULT_SLOW_GETPAGE_THRESHOLD: &str = "1 s";
+    pub const DEFAULT_PRELOAD_METADATA: bool = false;
+    pub const DEFAULT_MATERIALIZE_ON_READ_THRESHOLD: usize = 0;
+    pub const DEFAULT_MAX_TIMELINES_PER_TENANT: usize = 0;
+    pub const DEFAULT_WAL_RECEIVER_APPLICATION_NAME: &str = "pageserver";
+    pub const DEFAULT_GC_PARALLELISM: usize = 1;
+    pub const DEFAULT_VERIFY_PAGE_CHECKSUMS: bool = false;
+    pub const DEFAULT_MAX_REDO_RECORD_BYTES: usize = 0;
+    pub const DEFAULT_MAX_WAL_RECEIVERS: usize = 0;
+    pub const DEFAULT_STRICT_LSN_ORDER: bool = false;
 
     ///
     /// Default built-in configuration file.
@@ -51,9 +68,26 @@ pub mod defaults {
 
 #wait_lsn_timeout = '{DEFAULT_WAIT_LSN_TIMEOUT}'
 #wal_redo_timeout = '{DEFAULT_WAL_REDO_TIMEOUT}'
+#wal_connect_timeout = '{DEFAULT_WAL_CONNECT_TIMEOUT}'
 
 #max_file_descriptors = {DEFAULT_MAX_FILE_DESCRIPTORS}
 
+#prefetch_depth = {DEFAULT_PREFETCH_DEPTH}
+#rel_access_stats = {DEFAULT_REL_ACCESS_STATS}
+#max_concurrent_redo = {DEFAULT_MAX_CONCURRENT_REDO}
+#max_ancestor_depth = {DEFAULT_MAX_ANCESTOR_DEPTH}
+#recent_page_cache_size = {DEFAULT_RECENT_PAGE_CACHE_SIZE}
+#slow_getpage_threshold = '{DEFAULT_SLOW_GETPAGE_THRESHOLD}'
+#preload_metadata = {DEFAULT_PRELOAD_METADATA}
+#materialize_on_read_threshold = {DEFAULT_MATERIALIZE_ON_READ_THRESHOLD}
+#max_timelines_per_tenant = {DEFAULT_MAX_TIMELINES_PER_TENANT}
+#wal_receiver_application_name = '{DEFAULT_WAL_RECEIVER_APPLICATION_NAME}'
+#gc_parallelism = {DEFAULT_GC_PARALLELISM}
+#verify_page_checksums = {DEFAULT_VERIFY_PAGE_CHECKSUMS}
+#max_redo_record_bytes = {DEFAULT_MAX_REDO_RECORD_BYTES}
+#max_wal_receivers = {DEFAULT_MAX_WAL_RECEIVERS}
+#strict_lsn_order = {DEFAULT_STRICT_LSN_ORDER}
+
 # initial superuser role name to use when creating a new tenant
 #initial_superuser_name = '{DEFAULT_SUPERUSER}'
 
@@ -67,6 +101,7 @@ pub mod defaults {
 #gc_horizon = {DEFAULT_GC_HORIZON}
 #image_creation_threshold = {DEFAULT_IMAGE_CREATION_THRESHOLD}
 #pitr_interval = '{DEFAULT_PITR_INTERVAL}'
+#min_versions_per_block = {DEFAULT_MIN_VERSIONS_PER_BLOCK}
 
 # [remote_storage]
 
@@ -89,6 +124,8 @@ pub struct PageServerConf {
     pub wait_lsn_timeout: Duration,
     // How long to wait for WAL redo to complete.
     pub wal_redo_timeout: Duration,
+    // How long the WAL receiver waits for the connection to the WAL producer to be established.
+    pub wal_connect_timeout: Duration,
 
     pub superuser: String,
 
@@ -119,6 +156,88 @@ pub struct PageServerConf {
 
     /// Etcd broker endpoints to connect to.
     pub broker_endpoints: Vec<Url>,
+
+    /// Max rate, in bytes per second, at which checkpointing writes out layer files.
+    /// Unset or 0 means no limit.
+    pub checkpoint_io_rate_limit: Option<u64>,
+
+    /// Max rate, in bytes per second, at which a single tenant writes out
+    /// layer files. Every tenant gets a limit of its own. Unset or 0 means
+    /// no limit.
+    pub tenant_io_rate_limit: Option<u64>,
+
+    /// Number of blocks to read ahead when a relation is scanned sequentially.
+    /// 0 disables read-ahead.
+    pub prefetch_depth: u32,
+
+    /// Count page reads and WAL redo per relation, see
+    /// LayeredTimeline::rel_access_stats(). Off by default, to avoid the overhead.
+    pub rel_access_stats: bool,
+
+    /// Max number of WAL redo requests in flight at the same time, across all
+    /// tenants. Excess page reconstructions wait for their turn. 0 means no limit.
+    pub max_concurrent_redo: usize,
+
+    /// Max number of ancestors a timeline can have. Loading a timeline with a
+    /// longer chain of ancestors fails.
+    pub max_ancestor_depth: usize,
+
+    /// Number of recently read pages to keep per timeline, to serve repeated
+    /// reads of the same page without copying it. 0 disables it.
+    pub recent_page_cache_size: usize,
+
+    /// Page reads that take longer than this are logged, with details on
+    /// what it took to reconstruct the page. Zero disables the logging.
+    pub slow_getpage_threshold: Duration,
+
+    /// If true, the relation directories of a timeline are read into the
+    /// cache when the timeline is loaded, instead of on first use.
+    pub preload_metadata: bool,
+
+    /// If a read of the latest version of a page needs to replay at least
+    /// this many WAL records, the reconstructed page is stored back into the
+    /// timeline, so that the next reads don't replay them again. 0 disables it.
+    pub materialize_on_read_threshold: usize,
+
+    /// If set, the timeline metadata files are kept under this directory,
+    /// in the same 'tenants/<tenant>/timelines/<timeline>' layout as under
+    /// 'workdir', instead of next to the layer files.
+    pub metadata_dir: Option<PathBuf>,
+
+    /// Max number of timelines a tenant can have. Creating or branching
+    /// more fails with `TooManyTimelines`. 0 means no limit.
+    pub max_timelines_per_tenant: usize,
+
+    /// Prefix of the 'application_name' of the WAL receiver connections to
+    /// the safekeepers. The node id and tenant id are appended to it, so that
+    /// the connections of different pageservers can be told apart.
+    pub wal_receiver_application_name: String,
+
+    /// Number of timelines of a tenant that are garbage collected at the
+    /// same time.
+    pub gc_parallelism: usize,
+
+    /// Verify the checksums of the relation pages materialized into image
+    /// layers, and fail the layer creation on a mismatch. Only useful if the
+    /// computes have data checksums enabled.
+    pub verify_page_checksums: bool,
+
+    /// Maximum total size of the WAL records held in memory for one page read,
+    /// and passed to the WAL redo manager at once, unless a single record is
+    /// larger. A page with more WAL is reconstructed in several steps, and
+    /// if it's the latest version, stored back like with
+    /// 'materialize_on_read_threshold'. 0 means no limit.
+    pub max_redo_record_bytes: usize,
+
+    /// Maximum number of WAL receivers running at the same time. Timelines
+    /// launched beyond that wait in a queue until a running receiver exits.
+    /// 0 means no limit.
+    pub max_wal_receivers: usize,
+
+    /// Writes of a key at an LSN older than the key's latest stored version
+    /// always fail. If this is set, so do writes at the same LSN, which
+    /// otherwise replace the latest version.
+    pub strict_lsn_order: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -164,6 +283,7 @@ struct PageServerConfigBuilder {
 
     wait_lsn_timeout: BuilderValue<Duration>,
     wal_redo_timeout: BuilderValue<Duration>,
+    wal_connect_timeout: BuilderValue<Duration>,
 
     superuser: BuilderValue<String>,
 
@@ -185,6 +305,24 @@ struct PageServerConfigBuilder {
     profiling: BuilderValue<ProfilingConfig>,
     broker_etcd_prefix: BuilderValue<String>,
     broker_endpoints: BuilderValue<Vec<Url>>,
+    checkpoint_io_rate_limit: BuilderValue<Option<u64>>,
+    tenant_io_rate_limit: BuilderValue<Option<u64>>,
+    prefetch_depth: BuilderValue<u32>,
+    rel_access_stats: BuilderValue<bool>,
+    max_concurrent_redo: BuilderValue<usize>,
+    max_ancestor_depth: BuilderValue<usize>,
+    recent_page_cache_size: BuilderValue<usize>,
+    slow_getpage_threshold: BuilderValue<Duration>,
+    preload_metadata: BuilderValue<bool>,
+    materialize_on_read_threshold: BuilderValue<usize>,
+    metadata_dir: BuilderValue<Option<PathBuf>>,
+    max_timelines_per_tenant: BuilderValue<usize>,
+    wal_receiver_application_name: BuilderValue<String>,
+    gc_parallelism: BuilderValue<usize>,
+    verify_page_checksums: BuilderValue<bool>,
+    max_redo_record_bytes: BuilderValue<usize>,
+    max_wal_receivers: BuilderValue<usize>,
+    strict_lsn_order: BuilderValue<bool>,
 }
 
 impl Default for PageServerConfigBuilder {
@@ -198,6 +336,8 @@ impl Default for PageServerConfigBuilder {
                 .expect("cannot parse default wait lsn timeout")),
             wal_redo_timeout: Set(humantime::parse_duration(DEFAULT_WAL_REDO_TIMEOUT)
                 .expect("cannot parse default wal redo timeout")),
+            wal_connect_timeout: Set(humantime::parse_duration(DEFAULT_WAL_CONNECT_TIMEOUT)
+                .expect("cannot parse default wal connect timeout")),
             superuser: Set(DEFAULT_SUPERUSER.to_string()),
             page_cache_size: Set(DEFAULT_PAGE_CACHE_SIZE),
             max_file_descriptors: Set(DEFAULT_MAX_FILE_DESCRIPTORS),
@@ -212,6 +352,25 @@ impl Default for PageServerConfigBuilder {
             profiling: Set(ProfilingConfig::Disabled),
             broker_etcd_prefix: Set(etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string()),
             broker_endpoints: Set(Vec::new()),
+            checkpoint_io_rate_limit: Set(None),
+            tenant_io_rate_limit: Set(None),
+            prefetch_depth: Set(DEFAULT_PREFETCH_DEPTH),
+            rel_access_stats: Set(DEFAULT_REL_ACCESS_STATS),
+            max_concurrent_redo: Set(DEFAULT_MAX_CONCURRENT_REDO),
+            max_ancestor_depth: Set(DEFAULT_MAX_ANCESTOR_DEPTH),
+            recent_page_cache_size: Set(DEFAULT_RECENT_PAGE_CACHE_SIZE),
+            slow_getpage_threshold: Set(humantime::parse_duration(DEFAULT_SLOW_GETPAGE_THRESHOLD)
+                .expect("cannot parse default slow getpage threshold")),
+            preload_metadata: Set(DEFAULT_PRELOAD_METADATA),
+            materialize_on_read_threshold: Set(DEFAULT_MATERIALIZE_ON_READ_THRESHOLD),
+            metadata_dir: Set(None),
+            max_timelines_per_tenant: Set(DEFAULT_MAX_TIMELINES_PER_TENANT),
+            wal_receiver_application_name: Set(DEFAULT_WAL_RECEIVER_APPLICATION_NAME.to_string()),
+            gc_parallelism: Set(DEFAULT_GC_PARALLELISM),
+            verify_page_checksums: Set(DEFAULT_VERIFY_PAGE_CHECKSUMS),
+            max_redo_record_bytes: Set(DEFAULT_MAX_REDO_RECORD_BYTES),
+            max_wal_receivers: Set(DEFAULT_MAX_WAL_RECEIVERS),
+            strict_lsn_order: Set(DEFAULT_STRICT_LSN_ORDER),
         }
     }
 }
@@ -233,6 +392,10 @@ impl PageServerConfigBuilder {
         self.wal_redo_timeout = BuilderValue::Set(wal_redo_timeout)
     }
 
+    pub fn wal_connect_timeout(&mut self, wal_connect_timeout: Duration) {
+        self.wal_connect_timeout = BuilderValue::Set(wal_connect_timeout)
+    }
+
     pub fn superuser(&mut self, superuser: String) {
         self.superuser = BuilderValue::Set(superuser)
     }
@@ -284,6 +447,78 @@ impl PageServerConfigBuilder {
         self.profiling = BuilderValue::Set(profiling)
     }
 
+    pub fn checkpoint_io_rate_limit(&mut self, checkpoint_io_rate_limit: Option<u64>) {
+        self.checkpoint_io_rate_limit = BuilderValue::Set(checkpoint_io_rate_limit)
+    }
+
+    pub fn tenant_io_rate_limit(&mut self, tenant_io_rate_limit: Option<u64>) {
+        self.tenant_io_rate_limit = BuilderValue::Set(tenant_io_rate_limit)
+    }
+
+    pub fn prefetch_depth(&mut self, prefetch_depth: u32) {
+        self.prefetch_depth = BuilderValue::Set(prefetch_depth)
+    }
+
+    pub fn rel_access_stats(&mut self, rel_access_stats: bool) {
+        self.rel_access_stats = BuilderValue::Set(rel_access_stats)
+    }
+
+    pub fn max_concurrent_redo(&mut self, max_concurrent_redo: usize) {
+        self.max_concurrent_redo = BuilderValue::Set(max_concurrent_redo)
+    }
+
+    pub fn max_ancestor_depth(&mut self, max_ancestor_depth: usize) {
+        self.max_ancestor_depth = BuilderValue::Set(max_ancestor_depth)
+    }
+
+    pub fn recent_page_cache_size(&mut self, recent_page_cache_size: usize) {
+        self.recent_page_cache_size = BuilderValue::Set(recent_page_cache_size)
+    }
+
+    pub fn slow_getpage_threshold(&mut self, slow_getpage_threshold: Duration) {
+        self.slow_getpage_threshold = BuilderValue::Set(slow_getpage_threshold)
+    }
+
+    pub fn preload_metadata(&mut self, preload_metadata: bool) {
+        self.preload_metadata = BuilderValue::Set(preload_metadata)
+    }
+
+    pub fn materialize_on_read_threshold(&mut self, materialize_on_read_threshold: usize) {
+        self.materialize_on_read_threshold = BuilderValue::Set(materialize_on_read_threshold)
+    }
+
+    pub fn metadata_dir(&mut self, metadata_dir: Option<PathBuf>) {
+        self.metadata_dir = BuilderValue::Set(metadata_dir)
+    }
+
+    pub fn max_timelines_per_tenant(&mut self, max_timelines_per_tenant: usize) {
+        self.max_timelines_per_tenant = BuilderValue::Set(max_timelines_per_tenant)
+    }
+
+    pub fn wal_receiver_application_name(&mut self, wal_receiver_application_name: String) {
+        self.wal_receiver_application_name = BuilderValue::Set(wal_receiver_application_name)
+    }
+
+    pub fn gc_parallelism(&mut self, gc_parallelism: usize) {
+        self.gc_parallelism = BuilderValue::Set(gc_parallelism)
+    }
+
+    pub fn verify_page_checksums(&mut self, verify_page_checksums: bool) {
+        self.verify_page_checksums = BuilderValue::Set(verify_page_checksums)
+    }
+
+    pub fn max_redo_record_bytes(&mut self, max_redo_record_bytes: usize) {
+        self.max_redo_record_bytes = BuilderValue::Set(max_redo_record_bytes)
+    }
+
+    pub fn max_wal_receivers(&mut self, max_wal_receivers: usize) {
+        self.max_wal_receivers = BuilderValue::Set(max_wal_receivers)
+    }
+
+    pub fn strict_lsn_order(&mut self, strict_lsn_order: bool) {
+        self.strict_lsn_order = BuilderValue::Set(strict_lsn_order)
+    }
+
     pub fn build(self) -> anyhow::Result<PageServerConf> {
         let broker_endpoints = self
             .broker_endpoints
@@ -302,6 +537,9 @@ impl PageServerConfigBuilder {
             wal_redo_timeout: self
                 .wal_redo_timeout
                 .ok_or(anyhow!("missing wal_redo_timeout"))?,
+            wal_connect_timeout: self
+                .wal_connect_timeout
+                .ok_or(anyhow!("missing wal_connect_timeout"))?,
             superuser: self.superuser.ok_or(anyhow!("missing superuser"))?,
             page_cache_size: self
                 .page_cache_size
@@ -328,6 +566,58 @@ impl PageServerConfigBuilder {
             broker_etcd_prefix: self
                 .broker_etcd_prefix
                 .ok_or(anyhow!("missing broker_etcd_prefix"))?,
+            checkpoint_io_rate_limit: self
+                .checkpoint_io_rate_limit
+                .ok_or(anyhow!("missing checkpoint_io_rate_limit"))?,
+            tenant_io_rate_limit: self
+                .tenant_io_rate_limit
+                .ok_or(anyhow!("missing tenant_io_rate_limit"))?,
+            prefetch_depth: self
+                .prefetch_depth
+                .ok_or(anyhow!("missing prefetch_depth"))?,
+            rel_access_stats: self
+                .rel_access_stats
+                .ok_or(anyhow!("missing rel_access_stats"))?,
+            max_concurrent_redo: self
+                .max_concurrent_redo
+                .ok_or(anyhow!("missing max_concurrent_redo"))?,
+            max_ancestor_depth: self
+                .max_ancestor_depth
+                .ok_or(anyhow!("missing max_ancestor_depth"))?,
+            recent_page_cache_size: self
+                .recent_page_cache_size
+                .ok_or(anyhow!("missing recent_page_cache_size"))?,
+            slow_getpage_threshold: self
+                .slow_getpage_threshold
+                .ok_or(anyhow!("missing slow_getpage_threshold"))?,
+            preload_metadata: self
+                .preload_metadata
+                .ok_or(anyhow!("missing preload_metadata"))?,
+            materialize_on_read_threshold: self
+                .materialize_on_read_threshold
+                .ok_or(anyhow!("missing materialize_on_read_threshold"))?,
+            metadata_dir: self.metadata_dir.ok_or(anyhow!("missing metadata_dir"))?,
+            max_timelines_per_tenant: self
+                .max_timelines_per_tenant
+                .ok_or(anyhow!("missing max_timelines_per_tenant"))?,
+            wal_receiver_application_name: self
+                .wal_receiver_application_name
+                .ok_or(anyhow!("missing wal_receiver_application_name"))?,
+            gc_parallelism: self
+                .gc_parallelism
+                .ok_or(anyhow!("missing gc_parallelism"))?,
+            verify_page_checksums: self
+                .verify_page_checksums
+                .ok_or(anyhow!("missing verify_page_checksums"))?,
+            max_redo_record_bytes: self
+                .max_redo_record_bytes
+                .ok_or(anyhow!("missing max_redo_record_bytes"))?,
+            max_wal_receivers: self
+                .max_wal_receivers
+                .ok_or(anyhow!("missing max_wal_receivers"))?,
+            strict_lsn_order: self
+                .strict_lsn_order
+                .ok_or(anyhow!("missing strict_lsn_order"))?,
         })
     }
 }
@@ -353,6 +643,26 @@ impl PageServerConf {
         self.timelines_path(tenantid).join(timelineid.to_string())
     }
 
+    /// Directory holding the metadata files of all tenants, if
+    /// 'metadata_dir' is set.
+    pub fn tenants_metadata_dir(&self) -> Option<PathBuf> {
+        self.metadata_dir
+            .as_ref()
+            .map(|metadata_dir| metadata_dir.join("tenants"))
+    }
+
+    /// Directory holding the timeline's metadata file. Same as
+    /// timeline_path(), unless 'metadata_dir' is set.
+    pub fn timeline_metadata_dir(&self, timelineid: &ZTimelineId, tenantid: &ZTenantId) -> PathBuf {
+        match self.tenants_metadata_dir() {
+            Some(tenants_metadata_dir) => tenants_metadata_dir
+                .join(tenantid.to_string())
+                .join(TIMELINES_SEGMENT_NAME)
+                .join(timelineid.to_string()),
+            None => self.timeline_path(timelineid, tenantid),
+        }
+    }
+
     //
     // Postgres distribution paths
     //
@@ -381,6 +691,9 @@ impl PageServerConf {
                 "listen_http_addr" => builder.listen_http_addr(parse_toml_string(key, item)?),
                 "wait_lsn_timeout" => builder.wait_lsn_timeout(parse_toml_duration(key, item)?),
                 "wal_redo_timeout" => builder.wal_redo_timeout(parse_toml_duration(key, item)?),
+                "wal_connect_timeout" => {
+                    builder.wal_connect_timeout(parse_toml_duration(key, item)?)
+                }
                 "initial_superuser_name" => builder.superuser(parse_toml_string(key, item)?),
                 "page_cache_size" => builder.page_cache_size(parse_toml_u64(key, item)? as usize),
                 "max_file_descriptors" => {
@@ -412,6 +725,65 @@ impl PageServerConf {
                         })
                         .collect::<anyhow::Result<_>>()?,
                 ),
+                "checkpoint_io_rate_limit" => builder.checkpoint_io_rate_limit(Some(parse_toml_u64(key, item)?)),
+                "tenant_io_rate_limit" => builder.tenant_io_rate_limit(Some(parse_toml_u64(key, item)?)),
+                "prefetch_depth" => {
+                    let depth = parse_toml_u64(key, item)?;
+                    ensure!(
+                        depth <= MAX_PREFETCH_DEPTH as u64,
+                        "prefetch_depth cannot exceed {MAX_PREFETCH_DEPTH}"
+                    );
+                    builder.prefetch_depth(depth as u32)
+                }
+                "rel_access_stats" => builder.rel_access_stats(parse_toml_bool(key, item)?),
+                "max_concurrent_redo" => {
+                    builder.max_concurrent_redo(parse_toml_u64(key, item)? as usize)
+                }
+                "max_ancestor_depth" => {
+                    builder.max_ancestor_depth(parse_toml_u64(key, item)? as usize)
+                }
+                "recent_page_cache_size" => {
+                    builder.recent_page_cache_size(parse_toml_u64(key, item)? as usize)
+                }
+                "slow_getpage_threshold" => {
+                    builder.slow_getpage_threshold(parse_toml_duration(key, item)?)
+                }
+                "preload_metadata" => builder.preload_metadata(parse_toml_bool(key, item)?),
+                "materialize_on_read_threshold" => {
+                    builder.materialize_on_read_threshold(parse_toml_u64(key, item)? as usize)
+                }
+                "metadata_dir" => {
+                    builder.metadata_dir(Some(PathBuf::from(parse_toml_string(key, item)?)))
+                }
+                "max_timelines_per_tenant" => {
+                    builder.max_timelines_per_tenant(parse_toml_u64(key, item)? as usize)
+                }
+                "wal_receiver_application_name" => {
+                    let name = parse_toml_string(key, item)?;
+                    ensure!(
+                        !name.is_empty()
+                            && name
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+                        "wal_receiver_application_name may only contain ASCII letters, digits, '_' and '-'"
+                    );
+                    builder.wal_receiver_application_name(name)
+                }
+                "gc_parallelism" => {
+                    let gc_parallelism = parse_toml_u64(key, item)? as usize;
+                    ensure!(gc_parallelism > 0, "gc_parallelism must be positive");
+                    builder.gc_parallelism(gc_parallelism)
+                }
+                "verify_page_checksums" => {
+                    builder.verify_page_checksums(parse_toml_bool(key, item)?)
+                }
+                "max_redo_record_bytes" => {
+                    builder.max_redo_record_bytes(parse_toml_u64(key, item)? as usize)
+                }
+                "max_wal_receivers" => {
+                    builder.max_wal_receivers(parse_toml_u64(key, item)? as usize)
+                }
+                "strict_lsn_order" => builder.strict_lsn_order(parse_toml_bool(key, item)?),
                 _ => bail!("unrecognized pageserver option '{key}'"),
             }
         }
@@ -481,6 +853,11 @@ impl PageServerConf {
             t_conf.pitr_interval = Some(parse_toml_duration("pitr_interval", pitr_interval)?);
         }
 
+        if let Some(min_versions_per_block) = item.get("min_versions_per_block") {
+            t_conf.min_versions_per_block =
+                Some(parse_toml_u64("min_versions_per_block", min_versions_per_block)?.try_into()?);
+        }
+
         Ok(t_conf)
     }
 
@@ -495,6 +872,7 @@ impl PageServerConf {
             id: NodeId(0),
             wait_lsn_timeout: Duration::from_secs(60),
             wal_redo_timeout: Duration::from_secs(60),
+            wal_connect_timeout: Duration::from_secs(10),
             page_cache_size: defaults::DEFAULT_PAGE_CACHE_SIZE,
             max_file_descriptors: defaults::DEFAULT_MAX_FILE_DESCRIPTORS,
             listen_pg_addr: defaults::DEFAULT_PG_LISTEN_ADDR.to_string(),
@@ -509,6 +887,25 @@ impl PageServerConf {
             default_tenant_conf: TenantConf::dummy_conf(),
             broker_endpoints: Vec::new(),
             broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
+            checkpoint_io_rate_limit: None,
+            tenant_io_rate_limit: None,
+            prefetch_depth: defaults::DEFAULT_PREFETCH_DEPTH,
+            rel_access_stats: false,
+            max_concurrent_redo: defaults::DEFAULT_MAX_CONCURRENT_REDO,
+            max_ancestor_depth: defaults::DEFAULT_MAX_ANCESTOR_DEPTH,
+            recent_page_cache_size: defaults::DEFAULT_RECENT_PAGE_CACHE_SIZE,
+            slow_getpage_threshold: Duration::from_secs(1),
+            preload_metadata: false,
+            materialize_on_read_threshold: 0,
+            metadata_dir: None,
+            max_timelines_per_tenant: 0,
+            wal_receiver_application_name: defaults::DEFAULT_WAL_RECEIVER_APPLICATION_NAME
+                .to_string(),
+            gc_parallelism: defaults::DEFAULT_GC_PARALLELISM,
+            verify_page_checksums: defaults::DEFAULT_VERIFY_PAGE_CHECKSUMS,
+            max_redo_record_bytes: defaults::DEFAULT_MAX_REDO_RECORD_BYTES,
+            max_wal_receivers: defaults::DEFAULT_MAX_WAL_RECEIVERS,
+            strict_lsn_order: defaults::DEFAULT_STRICT_LSN_ORDER,
         }
     }
 }
@@ -534,6 +931,11 @@ fn parse_toml_u64(name: &str, item: &Item) -> Result<u64> {
     Ok(i as u64)
 }
 
+fn parse_toml_bool(name: &str, item: &Item) -> Result<bool> {
+    item.as_bool()
+        .with_context(|| format!("configure option {name} is not a boolean"))
+}
+
 fn parse_toml_duration(name: &str, item: &Item) -> Result<Duration> {
     let s = item
         .as_str()
@@ -594,6 +996,7 @@ listen_http_addr = '127.0.0.1:9898'
 
 wait_lsn_timeout = '111 s'
 wal_redo_timeout = '111 s'
+wal_connect_timeout = '111 s'
 
 page_cache_size = 444
 max_file_descriptors = 333
@@ -627,6 +1030,9 @@ id = 10
                 listen_http_addr: defaults::DEFAULT_HTTP_LISTEN_ADDR.to_string(),
                 wait_lsn_timeout: humantime::parse_duration(defaults::DEFAULT_WAIT_LSN_TIMEOUT)?,
                 wal_redo_timeout: humantime::parse_duration(defaults::DEFAULT_WAL_REDO_TIMEOUT)?,
+                wal_connect_timeout: humantime::parse_duration(
+                    defaults::DEFAULT_WAL_CONNECT_TIMEOUT
+                )?,
                 superuser: defaults::DEFAULT_SUPERUSER.to_string(),
                 page_cache_size: defaults::DEFAULT_PAGE_CACHE_SIZE,
                 max_file_descriptors: defaults::DEFAULT_MAX_FILE_DESCRIPTORS,
@@ -641,6 +1047,27 @@ id = 10
                     .parse()
                     .expect("Failed to parse a valid broker endpoint URL")],
                 broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
+                checkpoint_io_rate_limit: None,
+                tenant_io_rate_limit: None,
+                prefetch_depth: defaults::DEFAULT_PREFETCH_DEPTH,
+                rel_access_stats: defaults::DEFAULT_REL_ACCESS_STATS,
+                max_concurrent_redo: defaults::DEFAULT_MAX_CONCURRENT_REDO,
+                max_ancestor_depth: defaults::DEFAULT_MAX_ANCESTOR_DEPTH,
+                recent_page_cache_size: defaults::DEFAULT_RECENT_PAGE_CACHE_SIZE,
+                slow_getpage_threshold: humantime::parse_duration(
+                    defaults::DEFAULT_SLOW_GETPAGE_THRESHOLD
+                )?,
+                preload_metadata: defaults::DEFAULT_PRELOAD_METADATA,
+                materialize_on_read_threshold: defaults::DEFAULT_MATERIALIZE_ON_READ_THRESHOLD,
+                metadata_dir: None,
+                max_timelines_per_tenant: defaults::DEFAULT_MAX_TIMELINES_PER_TENANT,
+                wal_receiver_application_name: defaults::DEFAULT_WAL_RECEIVER_APPLICATION_NAME
+                    .to_string(),
+                gc_parallelism: defaults::DEFAULT_GC_PARALLELISM,
+                verify_page_checksums: defaults::DEFAULT_VERIFY_PAGE_CHECKSUMS,
+                max_redo_record_bytes: defaults::DEFAULT_MAX_REDO_RECORD_BYTES,
+                max_wal_receivers: defaults::DEFAULT_MAX_WAL_RECEIVERS,
+                strict_lsn_order: defaults::DEFAULT_STRICT_LSN_ORDER,
             },
             "Correct defaults should be used when no config values are provided"
         );
@@ -671,6 +1098,7 @@ id = 10
                 listen_http_addr: "127.0.0.1:9898".to_string(),
                 wait_lsn_timeout: Duration::from_secs(111),
                 wal_redo_timeout: Duration::from_secs(111),
+                wal_connect_timeout: Duration::from_secs(111),
                 superuser: "zzzz".to_string(),
                 page_cache_size: 444,
                 max_file_descriptors: 333,
@@ -685,6 +1113,27 @@ id = 10
                     .parse()
                     .expect("Failed to parse a valid broker endpoint URL")],
                 broker_etcd_prefix: etcd_broker::DEFAULT_NEON_BROKER_ETCD_PREFIX.to_string(),
+                checkpoint_io_rate_limit: None,
+                tenant_io_rate_limit: None,
+                prefetch_depth: defaults::DEFAULT_PREFETCH_DEPTH,
+                rel_access_stats: defaults::DEFAULT_REL_ACCESS_STATS,
+                max_concurrent_redo: defaults::DEFAULT_MAX_CONCURRENT_REDO,
+                max_ancestor_depth: defaults::DEFAULT_MAX_ANCESTOR_DEPTH,
+                recent_page_cache_size: defaults::DEFAULT_RECENT_PAGE_CACHE_SIZE,
+                slow_getpage_threshold: humantime::parse_duration(
+                    defaults::DEFAULT_SLOW_GETPAGE_THRESHOLD
+                )?,
+                preload_metadata: defaults::DEFAULT_PRELOAD_METADATA,
+                materialize_on_read_threshold: defaults::DEFAULT_MATERIALIZE_ON_READ_THRESHOLD,
+                metadata_dir: None,
+                max_timelines_per_tenant: defaults::DEFAULT_MAX_TIMELINES_PER_TENANT,
+                wal_receiver_application_name: defaults::DEFAULT_WAL_RECEIVER_APPLICATION_NAME
+                    .to_string(),
+                gc_parallelism: defaults::DEFAULT_GC_PARALLELISM,
+                verify_page_checksums: defaults::DEFAULT_VERIFY_PAGE_CHECKSUMS,
+                max_redo_record_bytes: defaults::DEFAULT_MAX_REDO_RECORD_BYTES,
+                max_wal_receivers: defaults::DEFAULT_MAX_WAL_RECEIVERS,
+                strict_lsn_order: defaults::DEFAULT_STRICT_LSN_ORDER,
             },
             "Should be able to parse all basic config values correctly"
         );
@@ -33,6 +33,7 @@ pub struct TenantCreateRequest {
     pub gc_period: Option<String>,
     pub image_creation_threshold: Option<usize>,
     pub pitr_interval: Option<String>,
+    pub min_versions_per_block: Option<usize>,
 }
 
 #[serde_as]
@@ -68,6 +69,7 @@ pub struct TenantConfigRequest {
     pub gc_period: Option<String>,
     pub image_creation_threshold: Option<usize>,
     pub pitr_interval: Option<String>,
+    pub min_versions_per_block: Option<usize>,
 }
 
 impl TenantConfigRequest {
@@ -82,6 +84,7 @@ impl TenantConfigRequest {
             gc_period: None,
             image_creation_threshold: None,
             pitr_interval: None,
+            min_versions_per_block: None,
         }
     }
 }
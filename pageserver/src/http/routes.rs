@@ -405,6 +405,7 @@ async fn tenant_create_handler(mut request: Request<Body>) -> Result<Response<Bo
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
     tenant_conf.compaction_threshold = request_data.compaction_threshold;
+    tenant_conf.min_versions_per_block = request_data.min_versions_per_block;
 
     if let Some(compaction_period) = request_data.compaction_period {
         tenant_conf.compaction_period =
@@ -453,6 +454,7 @@ async fn tenant_config_handler(mut request: Request<Body>) -> Result<Response<Bo
     tenant_conf.checkpoint_distance = request_data.checkpoint_distance;
     tenant_conf.compaction_target_size = request_data.compaction_target_size;
     tenant_conf.compaction_threshold = request_data.compaction_threshold;
+    tenant_conf.min_versions_per_block = request_data.min_versions_per_block;
 
     if let Some(compaction_period) = request_data.compaction_period {
         tenant_conf.compaction_period =
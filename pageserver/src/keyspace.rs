@@ -61,6 +61,14 @@ impl KeySpace {
 
         KeyPartitioning { parts }
     }
+
+    ///
+    /// Check if the key space contains the given key.
+    ///
+    pub fn contains(&self, key: &Key) -> bool {
+        let idx = self.ranges.partition_point(|range| range.end <= *key);
+        idx < self.ranges.len() && self.ranges[idx].start <= *key
+    }
 }
 
 ///
@@ -16,38 +16,43 @@ use bytes::Bytes;
 use fail::fail_point;
 use itertools::Itertools;
 use lazy_static::lazy_static;
+use tokio::sync::watch;
 use tracing::*;
 
 use std::cmp::{max, min, Ordering};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashSet};
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::ops::{Bound::Included, Deref, Range};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{self, AtomicBool};
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
 use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, TryLockError};
 use std::time::{Duration, Instant, SystemTime};
 
-use self::metadata::{metadata_path, TimelineMetadata, METADATA_FILE_NAME};
+use self::metadata::{
+    metadata_path, TimelineMetadata, LSN_TIMESTAMPS_FILE_NAME, METADATA_FILE_NAME,
+};
 use crate::config::PageServerConf;
-use crate::keyspace::KeySpace;
+use crate::keyspace::{KeySpace, KeySpaceAccum};
 use crate::storage_sync::index::RemoteIndex;
 use crate::tenant_config::{TenantConf, TenantConfOpt};
 
 use crate::repository::{
     GcResult, Repository, RepositoryTimeline, Timeline, TimelineSyncStatusUpdate, TimelineWriter,
 };
-use crate::repository::{Key, Value};
+use crate::repository::{Key, KeyNotFound, PageVersionKind, ReconstructPlan, Value};
 use crate::tenant_mgr;
 use crate::thread_mgr;
 use crate::virtual_file::VirtualFile;
 use crate::walreceiver::IS_WAL_RECEIVER;
-use crate::walredo::WalRedoManager;
+use crate::walrecord::ZenithWalRecord;
+use crate::walredo::{self, WalRedoError, WalRedoManager};
 use crate::CheckpointConfig;
-use crate::{page_cache, storage_sync};
+use crate::DatadirTimelineImpl;
+use crate::{page_cache, rate_limiter, storage_sync};
 
 use metrics::{
     register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
@@ -57,7 +62,7 @@ use toml_edit;
 use utils::{
     crashsafe_dir,
     lsn::{AtomicLsn, Lsn, RecordLsn},
-    seqwait::SeqWait,
+    seqwait::{SeqWait, SeqWaitError},
     zid::{ZTenantId, ZTimelineId},
 };
 
@@ -70,11 +75,14 @@ mod filename;
 mod image_layer;
 mod inmemory_layer;
 mod layer_map;
+mod lock_order;
 pub mod metadata;
 mod par_fsync;
+pub mod recent_pages;
 mod storage_layer;
 
-use crate::pgdatadir_mapping::LsnForTimestamp;
+use crate::pgdatadir_mapping::{is_rel_block_key, key_to_rel_block, LsnForTimestamp};
+use crate::reltag::RelTag;
 use delta_layer::{DeltaLayer, DeltaLayerWriter};
 use ephemeral_file::is_ephemeral_file;
 use filename::{DeltaFileName, ImageFileName};
@@ -82,8 +90,11 @@ use image_layer::{ImageLayer, ImageLayerWriter};
 use inmemory_layer::InMemoryLayer;
 use layer_map::LayerMap;
 use layer_map::SearchResult;
+use lock_order::{LockRank, OrderedGuard, OrderedMutex, OrderedRwLock};
+use postgres_ffi::checksum_utils::{page_get_checksum, page_verify_checksum, pg_checksum_page};
 use postgres_ffi::xlog_utils::to_pg_timestamp;
-use storage_layer::{Layer, ValueReconstructResult, ValueReconstructState};
+use recent_pages::RecentPages;
+use storage_layer::{range_overlaps, Layer, ValueReconstructResult, ValueReconstructState};
 
 // re-export this function so that page_cache.rs can use it.
 pub use crate::layered_repository::ephemeral_file::writeback as writeback_ephemeral_file;
@@ -115,6 +126,12 @@ lazy_static! {
         &["tenant_id", "timeline_id"]
     )
     .expect("failed to define a metric");
+    static ref SLOW_GETPAGE: IntCounterVec = register_int_counter_vec!(
+        "pageserver_slow_getpage_requests_total",
+        "Number of page reads that took longer than slow_getpage_threshold",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
     static ref WAIT_LSN_TIME: HistogramVec = register_histogram_vec!(
         "pageserver_wait_lsn_seconds",
         "Time spent waiting for WAL to arrive",
@@ -132,6 +149,29 @@ lazy_static! {
     .expect("failed to define a metric");
 }
 
+// Metrics on the work done by the checkpointer (layer flush and compaction),
+// to be able to tell a stuck checkpointer apart from an idle one.
+lazy_static! {
+    static ref LAST_CHECKPOINT_COMPLETED: IntGaugeVec = register_int_gauge_vec!(
+        "pageserver_last_checkpoint_completed_timestamp",
+        "Unix timestamp of the last completed layer flush or compaction pass",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+    static ref CHECKPOINT_PAGES_MATERIALIZED: IntCounterVec = register_int_counter_vec!(
+        "pageserver_checkpoint_pages_materialized_total",
+        "Number of page images materialized into new image layers",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+    static ref CHECKPOINT_BYTES_WRITTEN: IntCounterVec = register_int_counter_vec!(
+        "pageserver_checkpoint_bytes_written_total",
+        "Bytes of layer files written by layer flushes and compaction",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+}
+
 // Metrics for cloud upload. These metrics reflect data uploaded to cloud storage,
 // or in testing they estimate how much we would upload if we did.
 lazy_static! {
@@ -147,9 +187,31 @@ lazy_static! {
     .expect("failed to define a metric");
 }
 
+/// Minimum interval between two slow GetPage warnings on the same timeline.
+/// The slow requests in between are only counted.
+const SLOW_GETPAGE_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
 /// Parts of the `.zenith/tenants/<tenantid>/timelines/<timelineid>` directory prefix.
 pub const TIMELINES_SEGMENT_NAME: &str = "timelines";
 
+/// Problems found by [`LayeredRepository::check_tenant_integrity`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TenantIntegrityReport {
+    /// Number of timeline directories with a loadable metadata file.
+    pub timelines_checked: usize,
+    /// Timelines whose metadata file exists, but cannot be loaded, with the error.
+    pub broken_metadata: Vec<(ZTimelineId, String)>,
+    /// (timeline, ancestor) pairs, where the ancestor timeline directory is
+    /// missing or has no loadable metadata.
+    pub dangling_ancestors: Vec<(ZTimelineId, ZTimelineId)>,
+}
+
+impl TenantIntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.broken_metadata.is_empty() && self.dangling_ancestors.is_empty()
+    }
+}
+
 ///
 /// Repository consists of multiple timelines. Keep them in a hash table.
 ///
@@ -180,6 +242,10 @@ pub struct LayeredRepository {
 
     /// Makes every timeline to backup their files to remote storage.
     upload_layers: bool,
+
+    /// Caps the rate of this tenant's background I/O: layer flushes, image
+    /// layer creation and GC.
+    io_limiter: Option<Arc<rate_limiter::RateLimiter>>,
 }
 
 /// Public interface
@@ -223,6 +289,7 @@ impl Repository for LayeredRepository {
         initdb_lsn: Lsn,
     ) -> Result<Arc<LayeredTimeline>> {
         let mut timelines = self.timelines.lock().unwrap();
+        self.check_timelines_limit()?;
 
         // Create the timeline directory, and write initial metadata to file.
         crashsafe_dir::create_dir_all(self.conf.timeline_path(&timelineid, &self.tenant_id))?;
@@ -239,8 +306,9 @@ impl Repository for LayeredRepository {
             self.tenant_id,
             Arc::clone(&self.walredo_mgr),
             self.upload_layers,
+            self.io_limiter.clone(),
         );
-        timeline.layers.write().unwrap().next_open_layer_at = Some(initdb_lsn);
+        timeline.layers.write().next_open_layer_at = Some(initdb_lsn);
 
         let timeline = Arc::new(timeline);
         let r = timelines.insert(
@@ -262,6 +330,7 @@ impl Repository for LayeredRepository {
         let _gc_cs = self.gc_cs.lock().unwrap();
 
         let mut timelines = self.timelines.lock().unwrap();
+        self.check_timelines_limit()?;
         let src_timeline = self
             .get_timeline_load_internal(src, &mut timelines)
             // message about timeline being remote is one .context up in the stack
@@ -309,6 +378,228 @@ impl Repository for LayeredRepository {
         Ok(())
     }
 
+    fn rebase_branch(&self, timelineid: ZTimelineId, new_ancestor_lsn: Lsn) -> Result<()> {
+        // Like in branch_timeline, prevent GC from removing the data the
+        // timeline is about to start depending on.
+        let _gc_cs = self.gc_cs.lock().unwrap();
+
+        let mut timelines = self.timelines.lock().unwrap();
+        let entry = timelines
+            .get(&timelineid)
+            .ok_or_else(|| anyhow::anyhow!("unknown timeline id: {}", timelineid))?;
+        if let LayeredTimelineEntry::Loaded(timeline) = entry {
+            // The repository holds the only reference, otherwise someone
+            // would keep using the timeline with the old branch point. See
+            // tenant_mgr::rebase_timeline() for unloading it.
+            ensure!(
+                Arc::strong_count(timeline) == 1,
+                "timeline {} is in use, cannot rebase it",
+                timelineid
+            );
+            ensure!(
+                timeline.get_last_record_lsn() == timeline.ancestor_lsn,
+                "timeline {} has local writes up to {}, cannot rebase it",
+                timelineid,
+                timeline.get_last_record_lsn()
+            );
+        }
+
+        let metadata = try_recover_metadata(self.conf, timelineid, self.tenant_id)
+            .context("failed to load metadata")?;
+        let ancestor_id = metadata
+            .ancestor_timeline()
+            .ok_or_else(|| anyhow::anyhow!("timeline {} has no ancestor", timelineid))?;
+        let old_ancestor_lsn = metadata.ancestor_lsn();
+        // WAL generated on top of the old branch point would not apply to
+        // the newer state of the ancestor, so the branch must be empty.
+        ensure!(
+            metadata.disk_consistent_lsn() == old_ancestor_lsn,
+            "timeline {} has local writes up to {}, cannot rebase it",
+            timelineid,
+            metadata.disk_consistent_lsn()
+        );
+        ensure!(
+            new_ancestor_lsn >= old_ancestor_lsn,
+            "new ancestor lsn {} is before the current one {}",
+            new_ancestor_lsn,
+            old_ancestor_lsn
+        );
+
+        let ancestor = self
+            .get_timeline_load_internal(ancestor_id, &mut timelines)
+            .context("failed to load ancestor timeline")?
+            .ok_or_else(|| anyhow::anyhow!("unknown ancestor timeline id: {}", ancestor_id))?;
+        let RecordLsn {
+            last: ancestor_last,
+            prev: ancestor_prev,
+        } = ancestor.get_last_record_rlsn();
+        ensure!(
+            new_ancestor_lsn <= ancestor_last,
+            "new ancestor lsn {} is beyond the last record lsn {} of the ancestor",
+            new_ancestor_lsn,
+            ancestor_last
+        );
+        let latest_gc_cutoff_lsn = ancestor.get_latest_gc_cutoff_lsn();
+        ancestor
+            .check_lsn_is_in_scope(new_ancestor_lsn, &latest_gc_cutoff_lsn)
+            .context("invalid new ancestor lsn")?;
+
+        let prev_record_lsn = if ancestor_last == new_ancestor_lsn {
+            Some(ancestor_prev)
+        } else {
+            None
+        };
+        let new_metadata = TimelineMetadata::new(
+            new_ancestor_lsn,
+            prev_record_lsn,
+            Some(ancestor_id),
+            new_ancestor_lsn,
+            *latest_gc_cutoff_lsn,
+            metadata.initdb_lsn(),
+        );
+        drop(latest_gc_cutoff_lsn);
+        Self::save_metadata(self.conf, timelineid, self.tenant_id, &new_metadata, false)?;
+
+        if self.upload_layers {
+            storage_sync::schedule_layer_upload(
+                self.tenant_id,
+                timelineid,
+                HashSet::new(),
+                Some(new_metadata.clone()),
+            );
+        }
+
+        // Make the next access load the timeline with the new branch point.
+        timelines.insert(
+            timelineid,
+            LayeredTimelineEntry::Unloaded {
+                id: timelineid,
+                metadata: new_metadata,
+            },
+        );
+
+        info!(
+            "rebased timeline {} on {} from {} to {}",
+            timelineid, ancestor_id, old_ancestor_lsn, new_ancestor_lsn
+        );
+
+        Ok(())
+    }
+
+    fn detach_from_ancestor(&self, timelineid: ZTimelineId) -> Result<()> {
+        let timeline = {
+            let mut timelines = self.timelines.lock().unwrap();
+            let entry = timelines
+                .get(&timelineid)
+                .ok_or_else(|| anyhow::anyhow!("unknown timeline id: {}", timelineid))?;
+            if let LayeredTimelineEntry::Loaded(timeline) = entry {
+                // The repository holds the only reference, otherwise someone
+                // would keep reading through the old ancestor. See
+                // tenant_mgr::detach_timeline_from_ancestor() for unloading it.
+                ensure!(
+                    Arc::strong_count(timeline) == 1,
+                    "timeline {} is in use, cannot detach it",
+                    timelineid
+                );
+            }
+            ensure!(
+                entry.ancestor_timeline_id().is_some(),
+                "timeline {} has no ancestor",
+                timelineid
+            );
+
+            self.get_timeline_load_internal(timelineid, &mut timelines)
+                .context("failed to load timeline")?
+                .ok_or_else(|| anyhow::anyhow!("unknown timeline id: {}", timelineid))?
+        };
+        let ancestor_id = timeline.get_ancestor_timeline_id().unwrap();
+        let ancestor_lsn = timeline.ancestor_lsn;
+
+        // Copy everything the timeline can see at the branch point into image
+        // layers of its own. The timeline's own changes all come after the
+        // branch point, so the image layers are below all of them. This is
+        // done without holding any locks: as long as the timeline is a child
+        // of the ancestor, GC on the ancestor keeps the data at the branch
+        // point.
+        let keys = timeline
+            .get_ancestor_timeline()?
+            .collect_keys_at(ancestor_lsn)?;
+        let num_keys = keys.len();
+        let mut layer_paths_to_upload = HashSet::new();
+        if !keys.is_empty() {
+            let mut keyspace = KeySpaceAccum::new();
+            for key in keys {
+                keyspace.add_key(key);
+            }
+            let partitioning = keyspace
+                .to_keyspace()
+                .partition(timeline.get_compaction_target_size());
+            for partition in partitioning.parts.iter() {
+                let new_path = timeline.create_image_layer(partition, ancestor_lsn)?;
+                layer_paths_to_upload.insert(new_path);
+            }
+        }
+
+        // Prevent GC from running on the timeline while it's switched over.
+        let _gc_cs = self.gc_cs.lock().unwrap();
+        let mut timelines = self.timelines.lock().unwrap();
+        match timelines.get(&timelineid) {
+            Some(LayeredTimelineEntry::Loaded(entry)) if Arc::ptr_eq(entry, &timeline) => {}
+            _ => bail!("timeline {} was changed while detaching it", timelineid),
+        }
+        // Nobody else may have started using it during the copy, apart from
+        // the repository and us.
+        ensure!(
+            Arc::strong_count(&timeline) == 2,
+            "timeline {} is in use, cannot detach it",
+            timelineid
+        );
+
+        // Everything in memory must be on disk before the timeline is
+        // reloaded without its ancestor.
+        timeline.checkpoint(CheckpointConfig::Flush)?;
+
+        // The history before the branch point is not copied, so nothing
+        // older than it can be read anymore.
+        let metadata = try_recover_metadata(self.conf, timelineid, self.tenant_id)
+            .context("failed to load metadata")?;
+        let new_metadata = TimelineMetadata::new(
+            metadata.disk_consistent_lsn(),
+            metadata.prev_record_lsn(),
+            None,
+            Lsn(0),
+            max(metadata.latest_gc_cutoff_lsn(), ancestor_lsn),
+            metadata.initdb_lsn(),
+        );
+        Self::save_metadata(self.conf, timelineid, self.tenant_id, &new_metadata, false)?;
+
+        if timeline.upload_layers.load(atomic::Ordering::Relaxed) {
+            storage_sync::schedule_layer_upload(
+                self.tenant_id,
+                timelineid,
+                layer_paths_to_upload,
+                Some(new_metadata.clone()),
+            );
+        }
+        drop(timeline);
+
+        // Make the next access load the timeline without the ancestor.
+        timelines.insert(
+            timelineid,
+            LayeredTimelineEntry::Unloaded {
+                id: timelineid,
+                metadata: new_metadata,
+            },
+        );
+
+        info!(
+            "detached timeline {} from {} at {}, copied {} pages",
+            timelineid, ancestor_id, ancestor_lsn, num_keys
+        );
+
+        Ok(())
+    }
+
     /// Public entry point to GC. All the logic is in the private
     /// gc_iteration_internal function, this public facade just wraps it for
     /// metrics collection.
@@ -348,6 +639,7 @@ impl Repository for LayeredRepository {
             match timeline {
                 LayeredTimelineEntry::Loaded(timeline) => {
                     timeline.compact()?;
+                    timeline.refresh_metadata_file()?;
                 }
                 LayeredTimelineEntry::Unloaded { .. } => {
                     debug!("Cannot compact remote timeline {}", timelineid)
@@ -430,7 +722,7 @@ impl Repository for LayeredRepository {
                     Entry::Occupied(_) => bail!("We completed a download for a timeline that already exists in repository. This is a bug."),
                     Entry::Vacant(entry) => {
                         // we need to get metadata of a timeline, another option is to pass it along with Downloaded status
-                        let metadata = load_metadata(self.conf, timeline_id, self.tenant_id).context("failed to load local metadata")?;
+                        let metadata = try_recover_metadata(self.conf, timeline_id, self.tenant_id).context("failed to load local metadata")?;
                         // finally we make newly downloaded timeline visible to repository
                         entry.insert(LayeredTimelineEntry::Unloaded { id: timeline_id, metadata, })
                     },
@@ -536,6 +828,18 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.gc_horizon)
     }
 
+    /// Change the GC horizon of this tenant. The GC thread picks up the new
+    /// value on its next iteration. A horizon of 0 disables GC.
+    ///
+    /// Like other tenant config changes, this is persisted in the tenant's
+    /// config file.
+    pub fn set_gc_horizon(&self, gc_horizon: u64) -> Result<()> {
+        self.update_tenant_config(TenantConfOpt {
+            gc_horizon: Some(gc_horizon),
+            ..TenantConfOpt::default()
+        })
+    }
+
     pub fn get_gc_period(&self) -> Duration {
         let tenant_conf = self.tenant_conf.read().unwrap();
         tenant_conf
@@ -557,6 +861,14 @@ impl LayeredRepository {
             .unwrap_or(self.conf.default_tenant_conf.pitr_interval)
     }
 
+    pub fn get_prefetch_depth(&self) -> u32 {
+        self.conf.prefetch_depth
+    }
+
+    pub fn get_preload_metadata(&self) -> bool {
+        self.conf.preload_metadata
+    }
+
     pub fn update_tenant_config(&self, new_tenant_conf: TenantConfOpt) -> Result<()> {
         let mut tenant_conf = self.tenant_conf.write().unwrap();
 
@@ -602,38 +914,79 @@ impl LayeredRepository {
             "timeline {} found on a local disk, but not loaded into the memory, loading",
             &timelineid
         );
-        let timeline = self.load_local_timeline(timelineid, timelines)?;
-        let was_loaded = timelines.insert(
-            timelineid,
-            LayeredTimelineEntry::Loaded(Arc::clone(&timeline)),
-        );
-        ensure!(
-            was_loaded.is_none()
-                || matches!(was_loaded, Some(LayeredTimelineEntry::Unloaded { .. })),
-            "assertion failure, inserted wrong timeline in an incorrect state"
-        );
-        Ok(Some(timeline))
+
+        // Find the ancestors that need to be loaded first. They are loaded in
+        // a loop, starting from the root, rather than recursively, so that a
+        // deep branch hierarchy cannot overflow the stack.
+        let max_depth = self.conf.max_ancestor_depth;
+        let mut to_load = vec![timelineid];
+        let mut seen = HashSet::from([timelineid]);
+        let mut depth = 0;
+        let mut next = timelines
+            .get(&timelineid)
+            .and_then(LayeredTimelineEntry::ancestor_timeline_id);
+        while let Some(id) = next {
+            let entry = match timelines.get(&id) {
+                Some(entry) => entry,
+                None => break,
+            };
+            depth += 1;
+            if depth > max_depth {
+                return Err(AncestorChainTooDeep {
+                    timeline_id: timelineid,
+                    max_depth,
+                }
+                .into());
+            }
+            ensure!(
+                seen.insert(id),
+                "ancestors of timeline {} form a cycle at timeline {}",
+                timelineid,
+                id
+            );
+            if let LayeredTimelineEntry::Unloaded { .. } = entry {
+                to_load.push(id);
+            }
+            next = entry.ancestor_timeline_id();
+        }
+
+        let mut timeline = None;
+        for id in to_load.into_iter().rev() {
+            let loaded = self.load_local_timeline(id, timelines)?;
+            let was_loaded =
+                timelines.insert(id, LayeredTimelineEntry::Loaded(Arc::clone(&loaded)));
+            ensure!(
+                was_loaded.is_none()
+                    || matches!(was_loaded, Some(LayeredTimelineEntry::Unloaded { .. })),
+                "assertion failure, inserted wrong timeline in an incorrect state"
+            );
+            timeline = Some(loaded);
+        }
+        Ok(timeline)
     }
 
     fn load_local_timeline(
         &self,
         timeline_id: ZTimelineId,
-        timelines: &mut HashMap<ZTimelineId, LayeredTimelineEntry>,
+        timelines: &HashMap<ZTimelineId, LayeredTimelineEntry>,
     ) -> anyhow::Result<Arc<LayeredTimeline>> {
-        let metadata = load_metadata(self.conf, timeline_id, self.tenant_id)
+        let metadata = try_recover_metadata(self.conf, timeline_id, self.tenant_id)
             .context("failed to load metadata")?;
         let disk_consistent_lsn = metadata.disk_consistent_lsn();
 
-        let ancestor = metadata
-            .ancestor_timeline()
-            .map(|ancestor_timeline_id| {
-                trace!("loading {timeline_id}'s ancestor {}", &ancestor_timeline_id);
-                self.get_timeline_load_internal(ancestor_timeline_id, timelines)
-            })
-            .transpose()
-            .context("cannot load ancestor timeline")?
-            .flatten()
-            .map(LayeredTimelineEntry::Loaded);
+        // The ancestor has been loaded already, see get_timeline_load_internal
+        let ancestor = match metadata.ancestor_timeline() {
+            Some(ancestor_timeline_id) => match timelines.get(&ancestor_timeline_id) {
+                Some(LayeredTimelineEntry::Loaded(ancestor)) => {
+                    Some(LayeredTimelineEntry::Loaded(Arc::clone(ancestor)))
+                }
+                Some(LayeredTimelineEntry::Unloaded { .. }) => bail!(
+                    "cannot load ancestor timeline: {timeline_id}'s ancestor {ancestor_timeline_id} is not loaded"
+                ),
+                None => None,
+            },
+            None => None,
+        };
         let _enter = info_span!("loading local timeline").entered();
 
         let timeline = LayeredTimeline::new(
@@ -645,6 +998,7 @@ impl LayeredRepository {
             self.tenant_id,
             Arc::clone(&self.walredo_mgr),
             self.upload_layers,
+            self.io_limiter.clone(),
         );
         timeline
             .load_layer_map(disk_consistent_lsn)
@@ -660,6 +1014,7 @@ impl LayeredRepository {
         tenant_id: ZTenantId,
         remote_index: RemoteIndex,
         upload_layers: bool,
+        io_limiter: Option<Arc<rate_limiter::RateLimiter>>,
     ) -> LayeredRepository {
         LayeredRepository {
             tenant_id,
@@ -670,7 +1025,65 @@ impl LayeredRepository {
             walredo_mgr,
             remote_index,
             upload_layers,
+            io_limiter,
+        }
+    }
+
+    /// Load a timeline and all of its ancestors, and return them in order from
+    /// the timeline itself up to the root of its branch hierarchy.
+    ///
+    /// Operations that walk the whole chain, like GC or consistency checks,
+    /// can call this up front, instead of loading ancestors in the middle of
+    /// the operation while holding other locks. The ancestor links are checked
+    /// for cycles before anything is loaded, as loading would otherwise recurse
+    /// forever.
+    pub fn load_ancestor_chain(
+        &self,
+        timeline_id: ZTimelineId,
+    ) -> Result<Vec<Arc<LayeredTimeline>>> {
+        let mut timelines = self.timelines.lock().unwrap();
+
+        let mut seen = HashSet::new();
+        let mut next = Some(timeline_id);
+        while let Some(id) = next {
+            ensure!(
+                seen.insert(id),
+                "ancestors of timeline {} form a cycle at timeline {}",
+                timeline_id,
+                id
+            );
+            next = timelines
+                .get(&id)
+                .with_context(|| {
+                    format!(
+                        "timeline {} in the ancestors of timeline {} not found",
+                        id, timeline_id
+                    )
+                })?
+                .ancestor_timeline_id();
+        }
+
+        let timeline = self
+            .get_timeline_load_internal(timeline_id, &mut timelines)?
+            .with_context(|| format!("timeline {} not found", timeline_id))?;
+        drop(timelines);
+
+        let mut chain = vec![timeline];
+        loop {
+            let last = chain.last().unwrap();
+            if last.ancestor_timeline.is_none() {
+                break;
+            }
+            let ancestor = last.get_ancestor_timeline()?;
+            chain.push(ancestor);
         }
+        Ok(chain)
+    }
+
+    /// The tenant's I/O limiter, if it has one. Use
+    /// [`rate_limiter::RateLimiter::set_rate`] to adjust the limit at runtime.
+    pub fn io_limiter(&self) -> Option<&Arc<rate_limiter::RateLimiter>> {
+        self.io_limiter.as_ref()
     }
 
     /// Locate and load config
@@ -739,41 +1152,289 @@ impl LayeredRepository {
     }
 
     /// Save timeline metadata to file
+    ///
+    /// The new contents are written to a temporary file first, which is then
+    /// renamed over the old file, so that a crash in the middle leaves either
+    /// the old or the new metadata in place, never a torn file.
     pub fn save_metadata(
         conf: &'static PageServerConf,
         timelineid: ZTimelineId,
         tenantid: ZTenantId,
         data: &TimelineMetadata,
         first_save: bool,
+    ) -> Result<()> {
+        Self::save_metadata_via(
+            conf,
+            timelineid,
+            tenantid,
+            data,
+            first_save,
+            write_metadata_bytes,
+        )
+    }
+
+    /// save_metadata(), with the bytes written to the temporary file by
+    /// 'write'. Tests use it to make the writes fail.
+    fn save_metadata_via(
+        conf: &'static PageServerConf,
+        timelineid: ZTimelineId,
+        tenantid: ZTenantId,
+        data: &TimelineMetadata,
+        first_save: bool,
+        write: impl FnOnce(&mut VirtualFile, &[u8]) -> std::io::Result<()>,
     ) -> Result<()> {
         let _enter = info_span!("saving metadata").entered();
         let path = metadata_path(conf, timelineid, tenantid);
-        // use OpenOptions to ensure file presence is consistent with first_save
+        let temp_path = metadata_temp_path(&path);
+        // ensure file presence is consistent with first_save
+        if first_save {
+            // With a separate 'metadata_dir', the metadata file doesn't live in
+            // the timeline directory that the caller created.
+            crashsafe_dir::create_dir_all(
+                path.parent()
+                    .expect("Metadata should always have a parent dir"),
+            )?;
+            ensure!(
+                !path.exists(),
+                "Metadata file {} already exists",
+                path.display()
+            );
+        } else {
+            ensure!(
+                path.exists(),
+                "Metadata file {} does not exist",
+                path.display()
+            );
+
+            // Keep the previous version around, to recover from if the new
+            // file gets corrupted. See try_recover_metadata(). Don't replace a
+            // good backup with a corrupt file, though. The old file is durable
+            // already, so a hard link is enough, and the directory fsync below
+            // makes the link durable.
+            let old_metadata_bytes = fs::read(&path)?;
+            if TimelineMetadata::from_bytes(&old_metadata_bytes).is_ok() {
+                let backup_path = metadata_backup_path(&path);
+                if let Err(e) = fs::remove_file(&backup_path) {
+                    ensure!(e.kind() == std::io::ErrorKind::NotFound, e);
+                }
+                fs::hard_link(&path, &backup_path)?;
+            }
+        }
+
         let mut file = VirtualFile::open_with_options(
-            &path,
-            OpenOptions::new().write(true).create_new(first_save),
+            &temp_path,
+            OpenOptions::new().write(true).create(true).truncate(true),
         )?;
 
         let metadata_bytes = data.to_bytes().context("Failed to get metadata bytes")?;
 
-        if file.write(&metadata_bytes)? != metadata_bytes.len() {
-            bail!("Could not write all the metadata bytes in a single call");
-        }
+        write(&mut file, &metadata_bytes)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        fail_point!("save-metadata-after-write", |_| bail!(
+            "failpoint save-metadata-after-write"
+        ));
         file.sync_all()?;
+        drop(file);
 
-        // fsync the parent directory to ensure the directory entry is durable
-        if first_save {
-            let timeline_dir = File::open(
-                &path
-                    .parent()
-                    .expect("Metadata should always have a parent dir"),
-            )?;
-            timeline_dir.sync_all()?;
+        fail_point!("save-metadata-before-rename", |_| bail!(
+            "failpoint save-metadata-before-rename"
+        ));
+        fs::rename(&temp_path, &path).with_context(|| {
+            format!(
+                "Failed to rename {} to {}",
+                temp_path.display(),
+                path.display()
+            )
+        })?;
+
+        // fsync the parent directory to ensure the directory entry is durable.
+        // This is the metadata directory, which may be on a different
+        // filesystem than the layer files.
+        let timeline_dir = File::open(
+            &path
+                .parent()
+                .expect("Metadata should always have a parent dir"),
+        )?;
+        timeline_dir.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Load the metadata of a timeline from disk, and return it as JSON, for
+    /// debugging tools. LSNs are rendered in the usual `X/X` hex form.
+    pub fn metadata_json(&self, timeline_id: ZTimelineId) -> Result<serde_json::Value> {
+        let metadata = try_recover_metadata(self.conf, timeline_id, self.tenant_id)?;
+        Ok(serde_json::json!({
+            "disk_consistent_lsn": metadata.disk_consistent_lsn().to_string(),
+            "prev_record_lsn": metadata.prev_record_lsn().map(|lsn| lsn.to_string()),
+            "ancestor_timeline": metadata.ancestor_timeline().map(|id| id.to_string()),
+            "ancestor_lsn": metadata.ancestor_lsn().to_string(),
+            "latest_gc_cutoff_lsn": metadata.latest_gc_cutoff_lsn().to_string(),
+            "initdb_lsn": metadata.initdb_lsn().to_string(),
+        }))
+    }
+
+    /// Returns the oldest LSN that some child branch of a timeline in this
+    /// repository still depends on, i.e. the minimum branch point LSN.
+    /// Returns `Lsn::MAX` if there are no branches.
+    ///
+    /// This reads the metadata files from disk, so it also covers timelines
+    /// that are not loaded.
+    pub fn min_retained_lsn(&self) -> Result<Lsn> {
+        let timelines_path = self.conf.timelines_path(&self.tenant_id);
+        let mut min_lsn = Lsn::MAX;
+        for entry in fs::read_dir(&timelines_path).with_context(|| {
+            format!(
+                "Failed to list timelines directory {}",
+                timelines_path.display()
+            )
+        })? {
+            let entry = entry?;
+            let timeline_id = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<ZTimelineId>().ok())
+            {
+                Some(timeline_id) => timeline_id,
+                None => continue,
+            };
+            // A timeline that is still being created may not have its metadata yet
+            if !metadata_path(self.conf, timeline_id, self.tenant_id).exists() {
+                continue;
+            }
+            let metadata = try_recover_metadata(self.conf, timeline_id, self.tenant_id)?;
+            if metadata.ancestor_timeline().is_some() {
+                min_lsn = min(min_lsn, metadata.ancestor_lsn());
+            }
         }
+        Ok(min_lsn)
+    }
 
+    /// Check that a new timeline can be created without exceeding
+    /// 'max_timelines_per_tenant'. The caller must hold the 'timelines' lock,
+    /// so that the count doesn't change before the timeline is created.
+    fn check_timelines_limit(&self) -> Result<()> {
+        let limit = self.conf.max_timelines_per_tenant;
+        if limit == 0 {
+            return Ok(());
+        }
+        let timelines_path = self.conf.timelines_path(&self.tenant_id);
+        let mut num_timelines = 0;
+        for entry in fs::read_dir(&timelines_path).with_context(|| {
+            format!(
+                "Failed to list timelines directory {}",
+                timelines_path.display()
+            )
+        })? {
+            if entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<ZTimelineId>().ok())
+                .is_some()
+            {
+                num_timelines += 1;
+            }
+        }
+        if num_timelines >= limit {
+            return Err(TooManyTimelines {
+                tenant_id: self.tenant_id,
+                limit,
+            }
+            .into());
+        }
         Ok(())
     }
 
+    /// Returns the direct children of a timeline, with the LSNs they were
+    /// branched at, ordered by the branch LSN.
+    ///
+    /// Like min_retained_lsn(), this reads the metadata files from disk, so
+    /// it also covers timelines that are not loaded.
+    pub fn list_children(&self, timelineid: ZTimelineId) -> Result<Vec<(ZTimelineId, Lsn)>> {
+        let timelines_path = self.conf.timelines_path(&self.tenant_id);
+        let mut children = Vec::new();
+        for entry in fs::read_dir(&timelines_path).with_context(|| {
+            format!(
+                "Failed to list timelines directory {}",
+                timelines_path.display()
+            )
+        })? {
+            let entry = entry?;
+            let timeline_id = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<ZTimelineId>().ok())
+            {
+                Some(timeline_id) => timeline_id,
+                None => continue,
+            };
+            // A timeline that is still being created may not have its metadata yet
+            if !metadata_path(self.conf, timeline_id, self.tenant_id).exists() {
+                continue;
+            }
+            let metadata = try_recover_metadata(self.conf, timeline_id, self.tenant_id)?;
+            if metadata.ancestor_timeline() == Some(timelineid) {
+                children.push((timeline_id, metadata.ancestor_lsn()));
+            }
+        }
+        children.sort_by_key(|(timeline_id, lsn)| (*lsn, *timeline_id));
+        Ok(children)
+    }
+
+    /// Scan all the timeline directories of the tenant, and check that their
+    /// metadata can be loaded, and that every ancestor timeline referenced
+    /// from it exists on disk. Without this, a missing ancestor only shows
+    /// up as a file-not-found error when the child timeline is loaded.
+    pub fn check_tenant_integrity(&self) -> Result<TenantIntegrityReport> {
+        let timelines_path = self.conf.timelines_path(&self.tenant_id);
+        let mut report = TenantIntegrityReport::default();
+        let mut loaded = HashMap::new();
+        for entry in fs::read_dir(&timelines_path).with_context(|| {
+            format!(
+                "Failed to list timelines directory {}",
+                timelines_path.display()
+            )
+        })? {
+            let entry = entry?;
+            let timeline_id = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<ZTimelineId>().ok())
+            {
+                Some(timeline_id) => timeline_id,
+                None => continue,
+            };
+            // A timeline that is still being created may not have its metadata yet
+            if !metadata_path(self.conf, timeline_id, self.tenant_id).exists() {
+                continue;
+            }
+            // Report a corrupt metadata file as such, even if there's a backup
+            // to recover from
+            match load_metadata(self.conf, timeline_id, self.tenant_id) {
+                Ok(metadata) => {
+                    report.timelines_checked += 1;
+                    loaded.insert(timeline_id, metadata);
+                }
+                Err(e) => report
+                    .broken_metadata
+                    .push((timeline_id, format!("{:#}", e))),
+            }
+        }
+
+        for (timeline_id, metadata) in loaded.iter() {
+            if let Some(ancestor_id) = metadata.ancestor_timeline() {
+                if !loaded.contains_key(&ancestor_id) {
+                    warn!(
+                        "timeline {} refers to ancestor {}, which is missing or has no valid metadata",
+                        timeline_id, ancestor_id
+                    );
+                    report.dangling_ancestors.push((*timeline_id, ancestor_id));
+                }
+            }
+        }
+        Ok(report)
+    }
+
     //
     // How garbage collection works:
     //
@@ -841,56 +1502,118 @@ impl LayeredRepository {
             }
         }
 
-        // Ok, we now know all the branch points.
-        // Perform GC for each timeline.
+        // Ok, we now know all the branch points. Load the timelines to GC,
+        // and release the lock, so that it doesn't block access to the
+        // timelines while we GC them. 'gc_cs' still prevents branching.
+        let mut gc_timelines = Vec::new();
         for timelineid in timeline_ids.into_iter() {
-            if thread_mgr::is_shutdown_requested() {
-                // We were requested to shut down. Stop and return with the progress we
-                // made.
-                break;
+            // If target_timeline is specified, only GC it
+            if let Some(target_timelineid) = target_timelineid {
+                if timelineid != target_timelineid {
+                    continue;
+                }
             }
 
             // Timeline is known to be local and loaded.
             let timeline = self
                 .get_timeline_load_internal(timelineid, &mut *timelines)?
                 .expect("checked above that timeline is local and loaded");
+            gc_timelines.push(timeline);
+        }
+        drop(timelines);
 
-            // If target_timeline is specified, only GC it
-            if let Some(target_timelineid) = target_timelineid {
-                if timelineid != target_timelineid {
-                    continue;
+        // Perform GC for each timeline, on up to 'gc_parallelism' threads.
+        // The current thread is also one of the workers. All of them check
+        // for shutdown requests to the current thread.
+        let num_threads = gc_timelines.len().min(self.conf.gc_parallelism).max(1);
+        let next_timeline_idx = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let results = Mutex::new(Vec::new());
+        let span = Span::current();
+        let shutdown = thread_mgr::shutdown_watch();
+        let gc_worker = || -> Result<()> {
+            let _enter = span.enter();
+            while let Some(timeline) =
+                gc_timelines.get(next_timeline_idx.fetch_add(1, atomic::Ordering::Relaxed))
+            {
+                if shutdown.is_shutdown_requested() {
+                    // We were requested to shut down. Stop and return with the
+                    // progress we made.
+                    stop.store(true, atomic::Ordering::Relaxed);
                 }
-            }
-
-            if let Some(cutoff) = timeline.get_last_record_lsn().checked_sub(horizon) {
-                drop(timelines);
-                let branchpoints: Vec<Lsn> = all_branchpoints
-                    .range((
-                        Included((timelineid, Lsn(0))),
-                        Included((timelineid, Lsn(u64::MAX))),
-                    ))
-                    .map(|&x| x.1)
-                    .collect();
-
-                // If requested, force flush all in-memory layers to disk first,
-                // so that they too can be garbage collected. That's
-                // used in tests, so we want as deterministic results as possible.
-                if checkpoint_before_gc {
-                    timeline.checkpoint(CheckpointConfig::Forced)?;
-                    info!("timeline {} checkpoint_before_gc done", timelineid);
+                if stop.load(atomic::Ordering::Relaxed) {
+                    break;
                 }
-                timeline.update_gc_info(branchpoints, cutoff, pitr);
-                let result = timeline.gc()?;
-
-                totals += result;
-                timelines = self.timelines.lock().unwrap();
+                let result = Self::gc_timeline(
+                    timeline,
+                    &all_branchpoints,
+                    horizon,
+                    pitr,
+                    checkpoint_before_gc,
+                );
+                if result.is_err() {
+                    stop.store(true, atomic::Ordering::Relaxed);
+                }
+                results.lock().unwrap().push(result);
             }
-        }
+            Ok(())
+        };
+        crossbeam_utils::thread::scope(|s| -> Result<()> {
+            let mut handles = Vec::new();
+            // Spawn `num_threads - 1`, as the current thread is also a worker.
+            for _ in 1..num_threads {
+                handles.push(s.spawn(|_| gc_worker()));
+            }
+            gc_worker()?;
+            for handle in handles {
+                handle.join().unwrap()?;
+            }
+            Ok(())
+        })
+        .unwrap()?;
 
+        for result in results.into_inner().unwrap() {
+            totals += result?;
+        }
         totals.elapsed = now.elapsed();
         Ok(totals)
     }
 
+    /// GC one timeline, with the branch points collected in
+    /// gc_iteration_internal().
+    fn gc_timeline(
+        timeline: &LayeredTimeline,
+        all_branchpoints: &BTreeSet<(ZTimelineId, Lsn)>,
+        horizon: u64,
+        pitr: Duration,
+        checkpoint_before_gc: bool,
+    ) -> Result<GcResult> {
+        fail_point!("gc-before-timeline");
+
+        let timelineid = timeline.timeline_id;
+        let cutoff = match timeline.get_last_record_lsn().checked_sub(horizon) {
+            Some(cutoff) => cutoff,
+            None => return Ok(GcResult::default()),
+        };
+        let branchpoints: Vec<Lsn> = all_branchpoints
+            .range((
+                Included((timelineid, Lsn(0))),
+                Included((timelineid, Lsn(u64::MAX))),
+            ))
+            .map(|&x| x.1)
+            .collect();
+
+        // If requested, force flush all in-memory layers to disk first,
+        // so that they too can be garbage collected. That's
+        // used in tests, so we want as deterministic results as possible.
+        if checkpoint_before_gc {
+            timeline.checkpoint(CheckpointConfig::Forced)?;
+            info!("timeline {} checkpoint_before_gc done", timelineid);
+        }
+        timeline.update_gc_info(branchpoints, cutoff, pitr);
+        timeline.gc()
+    }
+
     pub fn tenant_id(&self) -> ZTenantId {
         self.tenant_id
     }
@@ -903,7 +1626,7 @@ pub struct LayeredTimeline {
     tenant_id: ZTenantId,
     timeline_id: ZTimelineId,
 
-    layers: RwLock<LayerMap>,
+    layers: OrderedRwLock<LayerMap>,
 
     last_freeze_at: AtomicLsn,
 
@@ -925,6 +1648,12 @@ pub struct LayeredTimeline {
     // keep track of it.
     last_record_lsn: SeqWait<RecordLsn, Lsn>,
 
+    // Publishes the changes of 'last_record_lsn.load().last', for
+    // subscribe_last_record_lsn(). The receiver is kept around so that
+    // sending never fails for lack of subscribers.
+    last_record_lsn_tx: watch::Sender<Lsn>,
+    last_record_lsn_rx: watch::Receiver<Lsn>,
+
     // All WAL records have been processed and stored durably on files on
     // local disk, up to this LSN. On crash and restart, we need to re-process
     // the WAL starting from this point.
@@ -942,21 +1671,29 @@ pub struct LayeredTimeline {
     // Metrics
     reconstruct_time_histo: Histogram,
     materialized_page_cache_hit_counter: IntCounter,
+    slow_getpage_counter: IntCounter,
     flush_time_histo: Histogram,
     compact_time_histo: Histogram,
     create_images_time_histo: Histogram,
     last_record_gauge: IntGauge,
     wait_lsn_time_histo: Histogram,
+    last_checkpoint_completed_gauge: IntGauge,
+    checkpoint_pages_materialized_counter: IntCounter,
+    checkpoint_bytes_written_counter: IntCounter,
 
     /// If `true`, will backup its files that appear after each checkpointing to the remote storage.
     upload_layers: AtomicBool,
 
+    /// The tenant's background I/O limiter, shared by all its timelines.
+    io_limiter: Option<Arc<rate_limiter::RateLimiter>>,
+
     /// Ensures layers aren't frozen by checkpointer between
     /// [`LayeredTimeline::get_layer_for_write`] and layer reads.
     /// Locked automatically by [`LayeredTimelineWriter`] and checkpointer.
     /// Must always be acquired before the layer map/individual layer lock
-    /// to avoid deadlock.
-    write_lock: Mutex<()>,
+    /// to avoid deadlock. The order with respect to the layer map is checked
+    /// in debug builds, see [`lock_order`].
+    write_lock: OrderedMutex<()>,
 
     /// Used to ensure that there is only one thread
     layer_flush_lock: Mutex<()>,
@@ -974,6 +1711,23 @@ pub struct LayeredTimeline {
     // garbage collecting data that is still needed by the child timelines.
     gc_info: RwLock<GcInfo>,
 
+    // LSNs pinned with pin_lsn(), with the number of pins on each. GC doesn't
+    // advance the cutoff past the oldest one.
+    lsn_pins: Mutex<BTreeMap<Lsn, usize>>,
+
+    // Per-relation access counters, if enabled with the 'rel_access_stats'
+    // option. The map is only locked in write mode to add a new relation,
+    // the counters themselves are atomic.
+    rel_access_stats: Option<RwLock<HashMap<RelTag, AtomicAccessStats>>>,
+
+    // Recently read pages, handed out without copying on repeated reads.
+    // Consulted before the global page cache.
+    recent_pages: RecentPages,
+
+    // When the last slow GetPage warning was logged, and the number of slow
+    // requests that weren't logged since then.
+    last_slow_getpage_warning: Mutex<(Option<Instant>, u64)>,
+
     // It may change across major versions so for simplicity
     // keep it after running initdb for a timeline.
     // It is needed in checks when we want to error on some operations
@@ -983,6 +1737,43 @@ pub struct LayeredTimeline {
     initdb_lsn: Lsn,
 }
 
+/// Page access counters of one relation, see [`LayeredTimeline::rel_access_stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AccessStats {
+    /// Number of page reads
+    pub reads: u64,
+    /// Number of page reads that needed WAL redo
+    pub reconstructs: u64,
+    /// Total number of WAL records replayed for those reads
+    pub reconstruct_records: u64,
+}
+
+#[derive(Default)]
+struct AtomicAccessStats {
+    reads: AtomicU64,
+    reconstructs: AtomicU64,
+    reconstruct_records: AtomicU64,
+}
+
+impl AtomicAccessStats {
+    fn inc(&self, redo_records: usize) {
+        self.reads.fetch_add(1, atomic::Ordering::Relaxed);
+        if redo_records > 0 {
+            self.reconstructs.fetch_add(1, atomic::Ordering::Relaxed);
+            self.reconstruct_records
+                .fetch_add(redo_records as u64, atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn load(&self) -> AccessStats {
+        AccessStats {
+            reads: self.reads.load(atomic::Ordering::Relaxed),
+            reconstructs: self.reconstructs.load(atomic::Ordering::Relaxed),
+            reconstruct_records: self.reconstruct_records.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
 ///
 /// Information about how much history needs to be retained, needed by
 /// Garbage Collection.
@@ -1010,12 +1801,61 @@ struct GcInfo {
     pitr: Duration,
 }
 
+/// Keeps an LSN pinned on a timeline, see [`LayeredTimeline::pin_lsn`].
+/// Dropping the guard releases the pin.
+pub struct PinGuard {
+    timeline: Arc<LayeredTimeline>,
+    lsn: Lsn,
+}
+
+impl PinGuard {
+    pub fn lsn(&self) -> Lsn {
+        self.lsn
+    }
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        let mut lsn_pins = self.timeline.lsn_pins.lock().unwrap();
+        if let btree_map::Entry::Occupied(mut entry) = lsn_pins.entry(self.lsn) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
 /// Public interface functions
 impl Timeline for LayeredTimeline {
+    fn get_tenant_id(&self) -> ZTenantId {
+        self.tenant_id
+    }
+
+    fn get_timeline_id(&self) -> ZTimelineId {
+        self.timeline_id
+    }
+
     fn get_ancestor_lsn(&self) -> Lsn {
         self.ancestor_lsn
     }
 
+    fn get_oldest_readable_lsn(&self) -> Lsn {
+        let oldest_lsn = max(*self.get_latest_gc_cutoff_lsn(), self.initdb_lsn);
+
+        // The versions before the branch point are read from the ancestor,
+        // which may have garbage collected more of them. The branch point
+        // itself is always retained in the ancestor.
+        let ancestor_oldest_lsn = match &self.ancestor_timeline {
+            None => return oldest_lsn,
+            Some(LayeredTimelineEntry::Loaded(ancestor)) => ancestor.get_oldest_readable_lsn(),
+            Some(LayeredTimelineEntry::Unloaded { metadata, .. }) => {
+                max(metadata.latest_gc_cutoff_lsn(), metadata.initdb_lsn())
+            }
+        };
+        max(oldest_lsn, min(ancestor_oldest_lsn, self.ancestor_lsn))
+    }
+
     fn get_ancestor_timeline_id(&self) -> Option<ZTimelineId> {
         self.ancestor_timeline
             .as_ref()
@@ -1034,16 +1874,28 @@ impl Timeline for LayeredTimeline {
         self.wait_lsn_time_histo.observe_closure_duration(
             || self.last_record_lsn
                 .wait_for_timeout(lsn, self.conf.wait_lsn_timeout)
-                .with_context(|| {
-                    format!(
-                        "Timed out while waiting for WAL record at LSN {} to arrive, last_record_lsn {} disk consistent LSN={}",
-                        lsn, self.get_last_record_lsn(), self.get_disk_consistent_lsn()
-                    )
+                .map_err(|e| {
+                    let what = match e {
+                        SeqWaitError::Timeout => "Timed out while waiting",
+                        SeqWaitError::Shutdown => "WAL receiver stopped while waiting",
+                    };
+                    anyhow::Error::new(e).context(format!(
+                        "{} for WAL record at LSN {} to arrive, last_record_lsn {} disk consistent LSN={}",
+                        what, lsn, self.get_last_record_lsn(), self.get_disk_consistent_lsn()
+                    ))
                 }))?;
 
         Ok(())
     }
 
+    fn cancel_lsn_waits(&self) {
+        self.last_record_lsn.cancel_waiters();
+    }
+
+    fn subscribe_last_record_lsn(&self) -> watch::Receiver<Lsn> {
+        self.last_record_lsn_rx.clone()
+    }
+
     fn get_latest_gc_cutoff_lsn(&self) -> RwLockReadGuard<Lsn> {
         self.latest_gc_cutoff_lsn.read().unwrap()
     }
@@ -1051,6 +1903,7 @@ impl Timeline for LayeredTimeline {
     /// Look up the value with the given a key
     fn get(&self, key: Key, lsn: Lsn) -> Result<Bytes> {
         debug_assert!(lsn <= self.get_last_record_lsn());
+        let start = Instant::now();
 
         // Check the page cache. We will get back the most recent page with lsn <= `lsn`.
         // The cached image can be returned directly if there is no WAL between the cached image
@@ -1060,7 +1913,11 @@ impl Timeline for LayeredTimeline {
             Some((cached_lsn, cached_img)) => {
                 match cached_lsn.cmp(&lsn) {
                     Ordering::Less => {} // there might be WAL between cached_lsn and lsn, we need to check
-                    Ordering::Equal => return Ok(cached_img), // exact LSN match, return the image
+                    Ordering::Equal => {
+                        // exact LSN match, return the image
+                        self.count_rel_access(key, 0);
+                        return Ok(cached_img);
+                    }
                     Ordering::Greater => panic!(), // the returned lsn should never be after the requested lsn
                 }
                 Some((cached_lsn, cached_img))
@@ -1068,15 +1925,190 @@ impl Timeline for LayeredTimeline {
             None => None,
         };
 
-        let mut reconstruct_state = ValueReconstructState {
-            records: Vec::new(),
-            img: cached_page_img,
+        // With 'max_redo_record_bytes' set, collecting the records stops at
+        // that many bytes. The version just before the oldest collected record
+        // is then reconstructed first, the same way, and the collection starts
+        // over with that intermediate image as the base. 'pending' holds the
+        // LSNs of the versions still to reconstruct, the requested one first,
+        // so only one batch of records is in memory at a time.
+        let max_bytes = self.conf.max_redo_record_bytes;
+        let mut pending = vec![lsn];
+        let mut base_img = cached_page_img;
+        let mut num_records = 0;
+        let mut record_bytes = 0;
+        let (result, has_base_image) = loop {
+            let target_lsn = *pending.last().unwrap();
+            let mut reconstruct_state = ValueReconstructState::new(base_img.take(), max_bytes);
+            let crossed_timeline =
+                self.get_reconstruct_data(key, target_lsn, &mut reconstruct_state)?;
+            if reconstruct_state.capped {
+                let (oldest_lsn, _) = reconstruct_state.records.last().unwrap();
+                pending.push(Lsn(oldest_lsn.0 - 1));
+                base_img = reconstruct_state.img;
+                continue;
+            }
+
+            // The intermediate images are also memorized in the page cache by
+            // reconstruct_value(), so that the next reads can start from them.
+            num_records += reconstruct_state.records.len();
+            record_bytes += reconstruct_state.record_bytes;
+            let has_base_image = reconstruct_state.img.is_some();
+            let result = self.reconstruct_time_histo.observe_closure_duration(|| {
+                self.reconstruct_value(key, target_lsn, reconstruct_state, crossed_timeline)
+            });
+            pending.pop();
+            match result {
+                Ok(img) if !pending.is_empty() => base_img = Some((target_lsn, img)),
+                result => break (result, has_base_image),
+            }
         };
+        self.count_rel_access(key, num_records);
+        if let Ok(img) = &result {
+            self.materialize_on_read(key, lsn, num_records, record_bytes, img);
+        }
+
+        self.check_slow_get(key, lsn, start.elapsed(), num_records, has_base_image);
+        result
+    }
+
+    fn get_changed_keys(&self, key_range: Range<Key>, lsn_range: Range<Lsn>) -> Result<Vec<Key>> {
+        {
+            let latest_gc_cutoff_lsn = self.get_latest_gc_cutoff_lsn();
+            ensure!(
+                lsn_range.start >= *latest_gc_cutoff_lsn,
+                "LSN {} is earlier than latest GC horizon {}",
+                lsn_range.start,
+                *latest_gc_cutoff_lsn
+            );
+        }
+
+        let mut keys = Vec::new();
+        let mut timeline_owned;
+        let mut timeline = self;
+        let mut lsn_range = lsn_range;
+        while lsn_range.start < lsn_range.end {
+            let layers = timeline.layers.read();
+            let in_memory_layers = layers
+                .open_layer
+                .iter()
+                .chain(layers.frozen_layers.iter())
+                .map(|l| Arc::clone(l) as Arc<dyn Layer>);
+            for layer in layers
+                .iter_historic_layers()
+                .cloned()
+                .chain(in_memory_layers)
+            {
+                let layer_key_range = layer.get_key_range();
+                let layer_lsn_range = layer.get_lsn_range();
+                if layer_key_range.start < key_range.end
+                    && key_range.start < layer_key_range.end
+                    && layer_lsn_range.start < lsn_range.end
+                    && lsn_range.start < layer_lsn_range.end
+                {
+                    keys.extend(layer.get_changed_keys(&key_range, &lsn_range)?);
+                }
+            }
+            drop(layers);
+
+            // The versions up to and including the branch point are stored
+            // in the ancestor.
+            if timeline.ancestor_timeline.is_none() || lsn_range.start > timeline.ancestor_lsn {
+                break;
+            }
+            lsn_range.end = min(lsn_range.end, timeline.ancestor_lsn + 1);
+            timeline_owned = timeline.get_ancestor_timeline()?;
+            timeline = &*timeline_owned;
+        }
+
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    fn explain_get(&self, key: Key, lsn: Lsn) -> Result<ReconstructPlan> {
+        debug_assert!(lsn <= self.get_last_record_lsn());
+
+        let mut reconstruct_state = ValueReconstructState::new(None, 0);
+        let crosses_timeline = self.get_reconstruct_data(key, lsn, &mut reconstruct_state)?;
+
+        // The records are collected from newest to oldest
+        Ok(ReconstructPlan {
+            base_image_lsn: reconstruct_state.img.map(|(img_lsn, _)| img_lsn),
+            record_lsns: reconstruct_state
+                .records
+                .iter()
+                .rev()
+                .map(|(rec_lsn, _)| *rec_lsn)
+                .collect(),
+            crosses_timeline,
+        })
+    }
+
+    fn get_key_history(&self, key: Key) -> Result<Vec<(Lsn, PageVersionKind)>> {
+        let mut history = Vec::new();
+        let mut timeline_owned;
+        let mut timeline = self;
+        let mut end_lsn = Lsn::MAX;
+        loop {
+            let layers = timeline.layers.read();
+            let in_memory_layers = layers
+                .open_layer
+                .iter()
+                .chain(layers.frozen_layers.iter())
+                .map(|l| Arc::clone(l) as Arc<dyn Layer>);
+            for layer in layers
+                .iter_historic_layers()
+                .cloned()
+                .chain(in_memory_layers)
+            {
+                if layer.get_key_range().contains(&key) && layer.get_lsn_range().start <= end_lsn {
+                    history.extend(
+                        layer
+                            .get_key_history(key)?
+                            .into_iter()
+                            .filter(|(lsn, _)| *lsn <= end_lsn),
+                    );
+                }
+            }
+            drop(layers);
+
+            // The versions up to and including the branch point are stored
+            // in the ancestor.
+            if timeline.ancestor_timeline.is_none() {
+                break;
+            }
+            end_lsn = min(end_lsn, timeline.ancestor_lsn);
+            timeline_owned = timeline.get_ancestor_timeline()?;
+            timeline = &*timeline_owned;
+        }
+
+        history.sort_by_key(|(lsn, _)| *lsn);
+        Ok(history)
+    }
+
+    fn flush_to_lsn(&self, lsn: Lsn) -> Result<()> {
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            lsn <= last_record_lsn,
+            "cannot flush up to {}, the last record LSN is {}",
+            lsn,
+            last_record_lsn
+        );
+        if self.get_disk_consistent_lsn() >= lsn {
+            return Ok(());
+        }
 
-        self.get_reconstruct_data(key, lsn, &mut reconstruct_state)?;
+        self.freeze_inmem_layer(false);
+        self.flush_frozen_layers(true)?;
 
-        self.reconstruct_time_histo
-            .observe_closure_duration(|| self.reconstruct_value(key, lsn, reconstruct_state))
+        let disk_consistent_lsn = self.get_disk_consistent_lsn();
+        ensure!(
+            disk_consistent_lsn >= lsn,
+            "flushed up to {}, not {}",
+            disk_consistent_lsn,
+            lsn
+        );
+        Ok(())
     }
 
     /// Public entry point for checkpoint(). All the logic is in the private
@@ -1096,6 +2128,171 @@ impl Timeline for LayeredTimeline {
         }
     }
 
+    fn reset_to_lsn(&self, lsn: Lsn) -> Result<()> {
+        ensure!(lsn.is_aligned(), "reset LSN {} is not aligned", lsn);
+        ensure!(
+            lsn >= self.ancestor_lsn,
+            "cannot reset timeline {} to {}, before its branch point {}",
+            self.timeline_id,
+            lsn,
+            self.ancestor_lsn
+        );
+
+        // The WAL receiver would carry on ingesting from where it was. Stop it,
+        // the caller launches it again once the timeline is rewound.
+        thread_mgr::shutdown_threads(
+            Some(thread_mgr::ThreadKind::WalReceiver),
+            Some(self.tenant_id),
+            Some(self.timeline_id),
+        );
+
+        // Hold the write lock throughout, so that nothing is ingested while we
+        // rewind.
+        let _write_guard = self.write_lock.lock();
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
+            lsn <= last_record_lsn,
+            "cannot reset timeline {} to {}, beyond its last record LSN {}",
+            self.timeline_id,
+            lsn,
+            last_record_lsn
+        );
+        let latest_gc_cutoff_lsn = *self.get_latest_gc_cutoff_lsn();
+        ensure!(
+            lsn >= latest_gc_cutoff_lsn,
+            "cannot reset timeline {} to {}, before latest GC horizon {}",
+            self.timeline_id,
+            lsn,
+            latest_gc_cutoff_lsn
+        );
+
+        info!(
+            "resetting timeline {} from {} to {}",
+            self.timeline_id, last_record_lsn, lsn
+        );
+
+        // Flush everything first, so that we only need to deal with the
+        // historic layers below.
+        self.freeze_inmem_layer(true);
+        self.flush_frozen_layers(true)?;
+
+        let _compaction_cs = self.compaction_cs.lock().unwrap();
+        let mut layers = self.layers.write();
+
+        // Layers that start after 'lsn' are dropped altogether. Delta layers
+        // that span it are replaced with a copy that only has the page
+        // versions up to 'lsn'.
+        let mut layers_to_remove = Vec::new();
+        let mut new_layers = Vec::new();
+        for l in layers.iter_historic_layers() {
+            let lsn_range = l.get_lsn_range();
+            if lsn_range.start > lsn {
+                layers_to_remove.push(Arc::clone(l));
+            } else if l.is_incremental() && lsn_range.end > lsn + 1 {
+                let key_range = l.get_key_range();
+                let mut writer: Option<DeltaLayerWriter> = None;
+                for x in l.iter() {
+                    let (key, value_lsn, value) = x?;
+                    if value_lsn > lsn {
+                        continue;
+                    }
+                    if writer.is_none() {
+                        writer = Some(DeltaLayerWriter::new(
+                            self.conf,
+                            self.timeline_id,
+                            self.tenant_id,
+                            key_range.start,
+                            lsn_range.start..lsn + 1,
+                        )?);
+                    }
+                    writer.as_mut().unwrap().put_value(key, value_lsn, value)?;
+                }
+                if let Some(writer) = writer {
+                    new_layers.push(writer.finish(key_range.end)?);
+                }
+                layers_to_remove.push(Arc::clone(l));
+            }
+        }
+
+        let mut new_layer_paths = HashSet::with_capacity(new_layers.len());
+        if !new_layers.is_empty() {
+            let mut layer_paths: Vec<PathBuf> = new_layers.iter().map(|l| l.path()).collect();
+            layer_paths.push(self.conf.timeline_path(&self.timeline_id, &self.tenant_id));
+            par_fsync::par_fsync(&layer_paths)?;
+        }
+        for l in new_layers {
+            new_layer_paths.insert(l.path());
+            layers.insert_historic(Arc::new(l));
+        }
+
+        for doomed_layer in &layers_to_remove {
+            layers.remove_historic(Arc::clone(doomed_layer));
+        }
+        layers.next_open_layer_at = Some(lsn + 1);
+        drop(layers);
+
+        // Save the rewound metadata before deleting any layers. If we crash
+        // in between, the layers above 'lsn' are left behind, but the loader
+        // sets aside layers beyond disk_consistent_lsn anyway. The other way
+        // round, the metadata would point to deleted layers.
+        let metadata = TimelineMetadata::new(
+            lsn,
+            None,
+            self.get_ancestor_timeline_id(),
+            self.ancestor_lsn,
+            latest_gc_cutoff_lsn,
+            self.initdb_lsn,
+        );
+        LayeredRepository::save_metadata(
+            self.conf,
+            self.timeline_id,
+            self.tenant_id,
+            &metadata,
+            false,
+        )?;
+
+        // The layers are out of the layer map, so the files can be deleted
+        // without blocking readers.
+        let mut layer_paths_to_delete = HashSet::with_capacity(layers_to_remove.len());
+        for doomed_layer in layers_to_remove {
+            doomed_layer.delete()?;
+            if let Some(path) = doomed_layer.local_path() {
+                layer_paths_to_delete.insert(path);
+            }
+        }
+
+        // The previous record LSN at 'lsn' is not known. It's left unset
+        // until more WAL is ingested.
+        self.last_record_lsn.reset(RecordLsn {
+            last: lsn,
+            prev: Lsn(0),
+        });
+        self.publish_last_record_lsn();
+        self.last_record_gauge.set(lsn.0 as i64);
+        self.last_freeze_at.store(lsn);
+        self.disk_consistent_lsn.store(lsn);
+
+        // Materialized page versions after 'lsn' would shadow the re-ingested ones
+        page_cache::get().drop_materialized_pages_after(self.tenant_id, self.timeline_id, lsn);
+        self.recent_pages.drop_after(lsn);
+
+        if self.upload_layers.load(atomic::Ordering::Relaxed) {
+            storage_sync::schedule_layer_upload(
+                self.tenant_id,
+                self.timeline_id,
+                new_layer_paths,
+                Some(metadata),
+            );
+            storage_sync::schedule_layer_delete(
+                self.tenant_id,
+                self.timeline_id,
+                layer_paths_to_delete,
+            );
+        }
+
+        Ok(())
+    }
+
     ///
     /// Validate lsn against initdb_lsn and latest_gc_cutoff_lsn.
     ///
@@ -1113,6 +2310,46 @@ impl Timeline for LayeredTimeline {
         Ok(())
     }
 
+    fn check_key_lsn_is_in_scope(
+        &self,
+        key: Key,
+        lsn: Lsn,
+        latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
+    ) -> Result<()> {
+        let min_versions = self.get_min_versions_per_block();
+        if lsn >= **latest_gc_cutoff_lsn || min_versions <= 1 {
+            return self.check_lsn_is_in_scope(lsn, latest_gc_cutoff_lsn);
+        }
+
+        // GC keeps the delta layers with the 'min_versions' newest changes
+        // of the key, see retain_newest_versions(). Those are enough to
+        // reconstruct it at any LSN starting from the oldest of them.
+        let mut versions = Vec::new();
+        {
+            let layers = self.layers.read();
+            let in_memory_layers = layers
+                .open_layer
+                .iter()
+                .chain(layers.frozen_layers.iter())
+                .map(|l| Arc::clone(l) as Arc<dyn Layer>);
+            for layer in layers
+                .iter_historic_layers()
+                .filter(|l| l.is_incremental() && !l.is_in_memory())
+                .cloned()
+                .chain(in_memory_layers)
+            {
+                if layer.get_key_range().contains(&key) {
+                    versions.extend(layer.get_key_history(key)?.into_iter().map(|(lsn, _)| lsn));
+                }
+            }
+        }
+        versions.sort_unstable();
+        match versions.get(versions.len().saturating_sub(min_versions)) {
+            Some(oldest_kept) if lsn >= *oldest_kept => Ok(()),
+            _ => self.check_lsn_is_in_scope(lsn, latest_gc_cutoff_lsn),
+        }
+    }
+
     fn get_last_record_lsn(&self) -> Lsn {
         self.last_record_lsn.load().last
     }
@@ -1132,7 +2369,7 @@ impl Timeline for LayeredTimeline {
     fn writer<'a>(&'a self) -> Box<dyn TimelineWriter + 'a> {
         Box::new(LayeredTimelineWriter {
             tl: self,
-            _write_guard: self.write_lock.lock().unwrap(),
+            _write_guard: self.write_lock.lock(),
         })
     }
 }
@@ -1166,6 +2403,13 @@ impl LayeredTimeline {
             .unwrap_or(self.conf.default_tenant_conf.image_creation_threshold)
     }
 
+    fn get_min_versions_per_block(&self) -> usize {
+        let tenant_conf = self.tenant_conf.read().unwrap();
+        tenant_conf
+            .min_versions_per_block
+            .unwrap_or(self.conf.default_tenant_conf.min_versions_per_block)
+    }
+
     /// Open a Timeline handle.
     ///
     /// Loads the metadata for the timeline into memory, but not the layer map.
@@ -1179,6 +2423,7 @@ impl LayeredTimeline {
         tenant_id: ZTenantId,
         walredo_mgr: Arc<dyn WalRedoManager + Send + Sync>,
         upload_layers: bool,
+        io_limiter: Option<Arc<rate_limiter::RateLimiter>>,
     ) -> LayeredTimeline {
         let reconstruct_time_histo = RECONSTRUCT_TIME
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
@@ -1186,6 +2431,9 @@ impl LayeredTimeline {
         let materialized_page_cache_hit_counter = MATERIALIZED_PAGE_CACHE_HIT
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
             .unwrap();
+        let slow_getpage_counter = SLOW_GETPAGE
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
         let flush_time_histo = STORAGE_TIME
             .get_metric_with_label_values(&[
                 "layer flush",
@@ -1213,13 +2461,24 @@ impl LayeredTimeline {
         let wait_lsn_time_histo = WAIT_LSN_TIME
             .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
             .unwrap();
+        let last_checkpoint_completed_gauge = LAST_CHECKPOINT_COMPLETED
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let checkpoint_pages_materialized_counter = CHECKPOINT_PAGES_MATERIALIZED
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let checkpoint_bytes_written_counter = CHECKPOINT_BYTES_WRITTEN
+            .get_metric_with_label_values(&[&tenant_id.to_string(), &timeline_id.to_string()])
+            .unwrap();
+        let (last_record_lsn_tx, last_record_lsn_rx) =
+            watch::channel(metadata.disk_consistent_lsn());
 
         LayeredTimeline {
             conf,
             tenant_conf,
             timeline_id,
             tenant_id,
-            layers: RwLock::new(LayerMap::default()),
+            layers: OrderedRwLock::new(LockRank::LayerMap, LayerMap::default()),
 
             walredo_mgr,
 
@@ -1228,6 +2487,8 @@ impl LayeredTimeline {
                 last: metadata.disk_consistent_lsn(),
                 prev: metadata.prev_record_lsn().unwrap_or(Lsn(0)),
             }),
+            last_record_lsn_tx,
+            last_record_lsn_rx,
             disk_consistent_lsn: AtomicLsn::new(metadata.disk_consistent_lsn().0),
 
             last_freeze_at: AtomicLsn::new(metadata.disk_consistent_lsn().0),
@@ -1237,15 +2498,20 @@ impl LayeredTimeline {
 
             reconstruct_time_histo,
             materialized_page_cache_hit_counter,
+            slow_getpage_counter,
             flush_time_histo,
             compact_time_histo,
             create_images_time_histo,
             last_record_gauge,
             wait_lsn_time_histo,
+            last_checkpoint_completed_gauge,
+            checkpoint_pages_materialized_counter,
+            checkpoint_bytes_written_counter,
 
             upload_layers: AtomicBool::new(upload_layers),
+            io_limiter,
 
-            write_lock: Mutex::new(()),
+            write_lock: OrderedMutex::new(LockRank::TimelineWrite, ()),
             layer_flush_lock: Mutex::new(()),
             compaction_cs: Mutex::new(()),
 
@@ -1254,6 +2520,14 @@ impl LayeredTimeline {
                 cutoff: Lsn(0),
                 pitr: Duration::ZERO,
             }),
+            lsn_pins: Mutex::new(BTreeMap::new()),
+            rel_access_stats: if conf.rel_access_stats {
+                Some(RwLock::new(HashMap::new()))
+            } else {
+                None
+            },
+            recent_pages: RecentPages::new(conf.recent_page_cache_size),
+            last_slow_getpage_warning: Mutex::new((None, 0)),
 
             latest_gc_cutoff_lsn: RwLock::new(metadata.latest_gc_cutoff_lsn()),
             initdb_lsn: metadata.initdb_lsn(),
@@ -1265,7 +2539,7 @@ impl LayeredTimeline {
     /// Returns all timeline-related files that were found and loaded.
     ///
     fn load_layer_map(&self, disk_consistent_lsn: Lsn) -> anyhow::Result<()> {
-        let mut layers = self.layers.write().unwrap();
+        let mut layers = self.layers.write();
         let mut num_layers = 0;
 
         // Scan timeline directory and create ImageFileName and DeltaFilename
@@ -1318,8 +2592,22 @@ impl LayeredTimeline {
                 trace!("found layer {}", layer.filename().display());
                 layers.insert_historic(Arc::new(layer));
                 num_layers += 1;
-            } else if fname == METADATA_FILE_NAME || fname.ends_with(".old") {
+            } else if fname == METADATA_FILE_NAME
+                || fname.strip_suffix(".bak") == Some(METADATA_FILE_NAME)
+                || fname == LSN_TIMESTAMPS_FILE_NAME
+                || fname.ends_with(".old")
+            {
                 // ignore these
+            } else if fname.strip_suffix(".temp") == Some(METADATA_FILE_NAME)
+                || fname.strip_suffix(".temp") == Some(LSN_TIMESTAMPS_FILE_NAME)
+            {
+                // Leftover from a save_metadata() call that didn't finish.
+                // The metadata file itself is intact.
+                trace!(
+                    "deleting incomplete metadata file in timeline dir: {}",
+                    fname
+                );
+                fs::remove_file(direntry.path())?;
             } else if is_ephemeral_file(&fname) {
                 // Delete any old ephemeral files
                 trace!("deleting old ephemeral file in timeline dir: {}", fname);
@@ -1347,15 +2635,18 @@ impl LayeredTimeline {
     ///
     /// This function takes the current timeline's locked LayerMap as an argument,
     /// so callers can avoid potential race conditions.
+    ///
+    /// Returns true if some of the data came from ancestor timelines.
     fn get_reconstruct_data(
         &self,
         key: Key,
         request_lsn: Lsn,
         reconstruct_state: &mut ValueReconstructState,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<bool> {
         // Start from the current timeline.
         let mut timeline_owned;
         let mut timeline = self;
+        let mut crossed_timeline = false;
 
         // For debugging purposes, collect the path of layers that we traversed
         // through. It's included in the error message if we fail to find the key.
@@ -1379,12 +2670,14 @@ impl LayeredTimeline {
             // The function should have updated 'state'
             //info!("CALLED for {} at {}: {:?} with {} records, cached {}", key, cont_lsn, result, reconstruct_state.records.len(), cached_lsn);
             match result {
-                ValueReconstructResult::Complete => return Ok(()),
+                ValueReconstructResult::Complete | ValueReconstructResult::Capped => {
+                    return Ok(crossed_timeline)
+                }
                 ValueReconstructResult::Continue => {
                     // If we reached an earlier cached page image, we're done.
                     if cont_lsn == cached_lsn + 1 {
                         self.materialized_page_cache_hit_counter.inc_by(1);
-                        return Ok(());
+                        return Ok(crossed_timeline);
                     }
                     if prev_lsn <= cont_lsn {
                         // Didn't make any progress in last iteration. Error out to avoid
@@ -1400,13 +2693,26 @@ impl LayeredTimeline {
                     prev_lsn = cont_lsn;
                 }
                 ValueReconstructResult::Missing => {
-                    return layer_traversal_error(
+                    let result = layer_traversal_error(
                         format!(
-                            "could not find data for key {} at LSN {}, for request at LSN {}",
-                            key, cont_lsn, request_lsn
+                            "could not find data for key {} at LSN {}, for request at LSN {}, {}, ancestor {}",
+                            key,
+                            cont_lsn,
+                            request_lsn,
+                            describe_wal_records(&reconstruct_state.records),
+                            if crossed_timeline { "consulted" } else { "not consulted" }
                         ),
                         traversal_path,
                     );
+                    // Nothing at all found for the key, as opposed to a broken
+                    // chain of versions
+                    if reconstruct_state.records.is_empty() && reconstruct_state.img.is_none() {
+                        return result.context(KeyNotFound {
+                            key,
+                            lsn: request_lsn,
+                        });
+                    }
+                    return result;
                 }
             }
 
@@ -1420,11 +2726,12 @@ impl LayeredTimeline {
                 let ancestor = timeline.get_ancestor_timeline()?;
                 timeline_owned = ancestor;
                 timeline = &*timeline_owned;
+                crossed_timeline = true;
                 prev_lsn = Lsn(u64::MAX);
                 continue;
             }
 
-            let layers = timeline.layers.read().unwrap();
+            let layers = timeline.layers.read();
 
             // Check the open and frozen in-memory layers first, in order from newest
             // to oldest.
@@ -1484,6 +2791,10 @@ impl LayeredTimeline {
     }
 
     fn lookup_cached_page(&self, key: &Key, lsn: Lsn) -> Option<(Lsn, Bytes)> {
+        if let Some(recent) = self.recent_pages.lookup(key, lsn) {
+            return Some(recent);
+        }
+
         let cache = page_cache::get();
 
         // FIXME: It's pointless to check the cache for things that are not 8kB pages.
@@ -1491,9 +2802,21 @@ impl LayeredTimeline {
         let (lsn, read_guard) =
             cache.lookup_materialized_page(self.tenant_id, self.timeline_id, key, lsn)?;
         let img = Bytes::from(read_guard.to_vec());
+        drop(read_guard);
+        // Copy it only once, if it's read again
+        self.memorize_recent_page(*key, lsn, &img);
         Some((lsn, img))
     }
 
+    /// Remember a page image in the recent pages cache. Only values up to a
+    /// page in size are cached, larger ones are things like relation
+    /// directories that are not read over and over.
+    fn memorize_recent_page(&self, key: Key, lsn: Lsn, img: &Bytes) {
+        if img.len() <= page_cache::PAGE_SZ {
+            self.recent_pages.memorize(key, lsn, img.clone());
+        }
+    }
+
     fn get_ancestor_timeline(&self) -> Result<Arc<LayeredTimeline>> {
         let ancestor = self
             .ancestor_timeline
@@ -1517,15 +2840,96 @@ impl LayeredTimeline {
     }
 
     ///
-    /// Get a handle to the latest layer for appending.
+    /// Collect all the keys that a read at 'lsn' can find on this timeline,
+    /// including the ones stored in the ancestors, in ascending order.
     ///
-    fn get_layer_for_write(&self, lsn: Lsn) -> anyhow::Result<Arc<InMemoryLayer>> {
-        let mut layers = self.layers.write().unwrap();
+    /// A key exists if there is a version of it newer than the latest image
+    /// layer that covers it, or if that image layer contains it. Key ranges
+    /// with no image layer at all are looked up in the ancestor as well.
+    ///
+    fn collect_keys_at(&self, lsn: Lsn) -> Result<Vec<Key>> {
+        let mut keys = Vec::new();
+        let mut timeline_owned;
+        let mut timeline = self;
+        let mut lsn = lsn;
+        let mut remaining = vec![Key::MIN..Key::MAX];
+        loop {
+            let layers = timeline.layers.read();
+            let delta_layers: Vec<Arc<dyn Layer>> = layers
+                .iter_historic_layers()
+                .filter(|l| l.is_incremental())
+                .cloned()
+                .chain(
+                    layers
+                        .open_layer
+                        .iter()
+                        .chain(layers.frozen_layers.iter())
+                        .map(|l| Arc::clone(l) as Arc<dyn Layer>),
+                )
+                .filter(|l| l.get_lsn_range().start <= lsn)
+                .collect();
+
+            // An image layer can cover several of the ranges, read each one once
+            let mut image_keys: HashMap<PathBuf, Vec<Key>> = HashMap::new();
+            let mut uncovered = Vec::new();
+            for range in remaining {
+                for (subrange, image) in layers.image_coverage(&range, lsn)? {
+                    let lsn_floor = if let Some(image) = image {
+                        let all_keys = match image_keys.entry(image.filename()) {
+                            Entry::Occupied(e) => e.into_mut(),
+                            Entry::Vacant(e) => e.insert(
+                                image
+                                    .iter()
+                                    .map_ok(|(key, _, _)| key)
+                                    .collect::<Result<_>>()?,
+                            ),
+                        };
+                        keys.extend(all_keys.iter().filter(|key| subrange.contains(*key)));
+                        image.get_lsn_range().end
+                    } else {
+                        uncovered.push(subrange.clone());
+                        Lsn(0)
+                    };
+
+                    let lsn_range = lsn_floor..lsn + 1;
+                    for layer in delta_layers.iter() {
+                        let layer_key_range = layer.get_key_range();
+                        let layer_lsn_range = layer.get_lsn_range();
+                        if layer_key_range.start < subrange.end
+                            && subrange.start < layer_key_range.end
+                            && lsn_range.start < layer_lsn_range.end
+                        {
+                            keys.extend(layer.get_changed_keys(&subrange, &lsn_range)?);
+                        }
+                    }
+                }
+            }
+            drop(layers);
 
-        ensure!(lsn.is_aligned());
+            remaining = uncovered;
+            if remaining.is_empty() || timeline.ancestor_timeline.is_none() {
+                break;
+            }
+            lsn = min(lsn, timeline.ancestor_lsn);
+            timeline_owned = timeline.get_ancestor_timeline()?;
+            timeline = &*timeline_owned;
+        }
 
-        let last_record_lsn = self.get_last_record_lsn();
-        ensure!(
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    ///
+    /// Get a handle to the latest layer for appending.
+    ///
+    fn get_layer_for_write(&self, lsn: Lsn) -> anyhow::Result<Arc<InMemoryLayer>> {
+        let mut layers = self.layers.write();
+
+        ensure!(lsn.is_aligned());
+
+        let last_record_lsn = self.get_last_record_lsn();
+        ensure!(
             lsn > last_record_lsn,
             "cannot modify relation after advancing last_record_lsn (incoming_lsn={}, last_record_lsn={})",
             lsn,
@@ -1569,6 +2973,12 @@ impl LayeredTimeline {
         Ok(())
     }
 
+    fn put_values(&self, lsn: Lsn, batch: Vec<(Key, Value)>) -> Result<()> {
+        let layer = self.get_layer_for_write(lsn)?;
+        layer.put_values(lsn, batch)?;
+        Ok(())
+    }
+
     fn put_tombstone(&self, key_range: Range<Key>, lsn: Lsn) -> Result<()> {
         let layer = self.get_layer_for_write(lsn)?;
         layer.put_tombstone(key_range, lsn)?;
@@ -1581,6 +2991,17 @@ impl LayeredTimeline {
 
         self.last_record_gauge.set(new_lsn.0 as i64);
         self.last_record_lsn.advance(new_lsn);
+        self.publish_last_record_lsn();
+    }
+
+    /// Notify the subscribe_last_record_lsn() subscribers, if the last record
+    /// LSN has changed.
+    fn publish_last_record_lsn(&self) {
+        let last_record_lsn = self.get_last_record_lsn();
+        if *self.last_record_lsn_rx.borrow() != last_record_lsn {
+            // Can't fail, we hold a receiver
+            let _ = self.last_record_lsn_tx.send(last_record_lsn);
+        }
     }
 
     fn freeze_inmem_layer(&self, write_lock_held: bool) {
@@ -1589,9 +3010,9 @@ impl LayeredTimeline {
         let _write_guard = if write_lock_held {
             None
         } else {
-            Some(self.write_lock.lock().unwrap())
+            Some(self.write_lock.lock())
         };
-        let mut layers = self.layers.write().unwrap();
+        let mut layers = self.layers.write();
         if let Some(open_layer) = &layers.open_layer {
             let open_layer_rc = Arc::clone(open_layer);
             // Does this layer need freezing?
@@ -1664,7 +3085,7 @@ impl LayeredTimeline {
         let timer = self.flush_time_histo.start_timer();
 
         loop {
-            let layers = self.layers.read().unwrap();
+            let layers = self.layers.read();
             if let Some(frozen_layer) = layers.frozen_layers.front() {
                 let frozen_layer = Arc::clone(frozen_layer);
                 drop(layers); // to allow concurrent reads and writes
@@ -1684,14 +3105,103 @@ impl LayeredTimeline {
         }
 
         timer.stop_and_record();
+        self.checkpoint_pass_completed();
+
+        Ok(())
+    }
+
+    /// The metadata to save for this timeline, once everything up to
+    /// 'disk_consistent_lsn' is on disk.
+    fn metadata_at(&self, disk_consistent_lsn: Lsn) -> TimelineMetadata {
+        // We can only save a valid 'prev_record_lsn' value on disk if we
+        // flushed *all* in-memory changes to disk. We only track
+        // 'prev_record_lsn' in memory for the latest processed record, so we
+        // don't remember what the correct value that corresponds to some old
+        // LSN is. But if we flush everything, then the value corresponding
+        // current 'last_record_lsn' is correct and we can store it on disk.
+        let RecordLsn {
+            last: last_record_lsn,
+            prev: prev_record_lsn,
+        } = self.last_record_lsn.load();
+        let ondisk_prev_record_lsn = if disk_consistent_lsn == last_record_lsn {
+            Some(prev_record_lsn)
+        } else {
+            None
+        };
+
+        let ancestor_timelineid = self
+            .ancestor_timeline
+            .as_ref()
+            .map(LayeredTimelineEntry::timeline_id);
+
+        TimelineMetadata::new(
+            disk_consistent_lsn,
+            ondisk_prev_record_lsn,
+            ancestor_timelineid,
+            self.ancestor_lsn,
+            *self.latest_gc_cutoff_lsn.read().unwrap(),
+            self.initdb_lsn,
+        )
+    }
 
+    /// Save the current 'disk_consistent_lsn' in the metadata file, if the
+    /// file has a different one. Normally, the file is updated after each
+    /// layer flush, but this catches it up if one of those saves failed.
+    /// Called by the checkpointer after each compaction pass.
+    pub fn refresh_metadata_file(&self) -> Result<()> {
+        // Don't race with the metadata saves of the layer flushes
+        let _flush_guard = self.layer_flush_lock.lock().unwrap();
+
+        // At the same 'disk_consistent_lsn', the file is up to date. It may
+        // even have the 'prev_record_lsn' that we can't tell anymore, once
+        // more WAL has arrived.
+        let disk_consistent_lsn = self.disk_consistent_lsn.load();
+        let ondisk_metadata = try_recover_metadata(self.conf, self.timeline_id, self.tenant_id)?;
+        if ondisk_metadata.disk_consistent_lsn() == disk_consistent_lsn {
+            return Ok(());
+        }
+        let metadata = self.metadata_at(disk_consistent_lsn);
+
+        info!(
+            "updating metadata file: disk_consistent_lsn {} -> {}, prev_record_lsn {:?} -> {:?}",
+            ondisk_metadata.disk_consistent_lsn(),
+            metadata.disk_consistent_lsn(),
+            ondisk_metadata.prev_record_lsn(),
+            metadata.prev_record_lsn(),
+        );
+        LayeredRepository::save_metadata(
+            self.conf,
+            self.timeline_id,
+            self.tenant_id,
+            &metadata,
+            false,
+        )?;
+        if self.upload_layers.load(atomic::Ordering::Relaxed) {
+            storage_sync::schedule_layer_upload(
+                self.tenant_id,
+                self.timeline_id,
+                HashSet::new(),
+                Some(metadata),
+            );
+        }
         Ok(())
     }
 
+    /// Record the completion of a layer flush or compaction pass.
+    fn checkpoint_pass_completed(&self) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.last_checkpoint_completed_gauge.set(now as i64);
+    }
+
     /// Flush one frozen in-memory layer to disk, as a new delta layer.
     fn flush_frozen_layer(&self, frozen_layer: Arc<InMemoryLayer>) -> Result<()> {
-        let new_delta = frozen_layer.write_to_disk()?;
+        let new_delta = frozen_layer.write_to_disk(self.io_limiter.as_deref())?;
         let new_delta_path = new_delta.path();
+        let new_delta_size = new_delta_path.metadata()?.len();
+        self.checkpoint_bytes_written_counter.inc_by(new_delta_size);
 
         // Sync the new layer to disk.
         //
@@ -1711,7 +3221,7 @@ impl LayeredTimeline {
 
         // Finally, replace the frozen in-memory layer with the new on-disk layer
         {
-            let mut layers = self.layers.write().unwrap();
+            let mut layers = self.layers.write();
             let l = layers.frozen_layers.pop_front();
 
             // Only one thread may call this function at a time (for this
@@ -1738,35 +3248,7 @@ impl LayeredTimeline {
         if disk_consistent_lsn != old_disk_consistent_lsn {
             assert!(disk_consistent_lsn > old_disk_consistent_lsn);
 
-            // We can only save a valid 'prev_record_lsn' value on disk if we
-            // flushed *all* in-memory changes to disk. We only track
-            // 'prev_record_lsn' in memory for the latest processed record, so we
-            // don't remember what the correct value that corresponds to some old
-            // LSN is. But if we flush everything, then the value corresponding
-            // current 'last_record_lsn' is correct and we can store it on disk.
-            let RecordLsn {
-                last: last_record_lsn,
-                prev: prev_record_lsn,
-            } = self.last_record_lsn.load();
-            let ondisk_prev_record_lsn = if disk_consistent_lsn == last_record_lsn {
-                Some(prev_record_lsn)
-            } else {
-                None
-            };
-
-            let ancestor_timelineid = self
-                .ancestor_timeline
-                .as_ref()
-                .map(LayeredTimelineEntry::timeline_id);
-
-            let metadata = TimelineMetadata::new(
-                disk_consistent_lsn,
-                ondisk_prev_record_lsn,
-                ancestor_timelineid,
-                self.ancestor_lsn,
-                *self.latest_gc_cutoff_lsn.read().unwrap(),
-                self.initdb_lsn,
-            );
+            let metadata = self.metadata_at(disk_consistent_lsn);
 
             fail_point!("checkpoint-before-saving-metadata", |x| bail!(
                 "{}",
@@ -1782,7 +3264,7 @@ impl LayeredTimeline {
             )?;
 
             NUM_PERSISTENT_FILES_CREATED.inc_by(1);
-            PERSISTENT_BYTES_WRITTEN.inc_by(new_delta_path.metadata()?.len());
+            PERSISTENT_BYTES_WRITTEN.inc_by(new_delta_size);
 
             if self.upload_layers.load(atomic::Ordering::Relaxed) {
                 storage_sync::schedule_layer_upload(
@@ -1869,18 +3351,19 @@ impl LayeredTimeline {
 
             // 3. Compact
             let timer = self.compact_time_histo.start_timer();
-            self.compact_level0(target_file_size)?;
+            self.compact_level0(target_file_size, &pgdir)?;
             timer.stop_and_record();
         } else {
             debug!("Could not compact because no partitioning specified yet");
         }
+        self.checkpoint_pass_completed();
 
         Ok(())
     }
 
     // Is it time to create a new image layer for the given partition?
     fn time_for_new_image_layer(&self, partition: &KeySpace, lsn: Lsn) -> Result<bool> {
-        let layers = self.layers.read().unwrap();
+        let layers = self.layers.read();
 
         for part_range in &partition.ranges {
             let image_coverage = layers.image_coverage(part_range, lsn)?;
@@ -1912,15 +3395,29 @@ impl LayeredTimeline {
         let mut image_layer_writer =
             ImageLayerWriter::new(self.conf, self.timeline_id, self.tenant_id, &img_range, lsn)?;
 
+        let mut num_pages = 0;
         for range in &partition.ranges {
             let mut key = range.start;
             while key < range.end {
                 let img = self.get(key, lsn)?;
+                if self.conf.verify_page_checksums && is_rel_block_key(key) {
+                    Self::verify_page_checksum(key, lsn, &img)?;
+                }
+                if let Some(limiter) = rate_limiter::checkpoint_io_limiter() {
+                    limiter.acquire(img.len() as u64);
+                }
+                if let Some(limiter) = &self.io_limiter {
+                    limiter.acquire(img.len() as u64);
+                }
                 image_layer_writer.put_image(key, &img)?;
+                num_pages += 1;
                 key = key.next();
             }
         }
         let image_layer = image_layer_writer.finish()?;
+        self.checkpoint_pages_materialized_counter.inc_by(num_pages);
+        self.checkpoint_bytes_written_counter
+            .inc_by(image_layer.path().metadata()?.len());
 
         // Sync the new layer to disk before adding it to the layer map, to make sure
         // we don't garbage collect something based on the new layer, before it has
@@ -1938,7 +3435,7 @@ impl LayeredTimeline {
 
         // FIXME: Do we need to do something to upload it to remote storage here?
 
-        let mut layers = self.layers.write().unwrap();
+        let mut layers = self.layers.write();
         let new_path = image_layer.path();
         layers.insert_historic(Arc::new(image_layer));
         drop(layers);
@@ -1946,12 +3443,31 @@ impl LayeredTimeline {
         Ok(new_path)
     }
 
+    /// Check the checksum in the header of a materialized relation page, before
+    /// it's written to an image layer. Catches WAL redo bugs and corruption.
+    fn verify_page_checksum(key: Key, lsn: Lsn, img: &[u8]) -> Result<()> {
+        let (rel, blknum) = key_to_rel_block(key)?;
+        if !page_verify_checksum(img, blknum)? {
+            let stored = page_get_checksum(img);
+            let computed = pg_checksum_page(img, blknum)?;
+            bail!(
+                "page checksum mismatch for rel {} blk {} at {}: stored {:04x}, computed {:04x}",
+                rel,
+                blknum,
+                lsn,
+                stored,
+                computed
+            );
+        }
+        Ok(())
+    }
+
     ///
     /// Collect a bunch of Level 0 layer files, and compact and reshuffle them as
     /// as Level 1 files.
     ///
-    fn compact_level0(&self, target_file_size: u64) -> Result<()> {
-        let layers = self.layers.read().unwrap();
+    fn compact_level0(&self, target_file_size: u64, pgdir: &DatadirTimelineImpl) -> Result<()> {
+        let layers = self.layers.read();
         let mut level0_deltas = layers.get_level0_deltas()?;
         drop(layers);
 
@@ -2007,6 +3523,21 @@ impl LayeredTimeline {
         // we don't accidentally use it later in the function.
         drop(level0_deltas);
 
+        // Page versions of truncated relation blocks that are older than the
+        // GC cutoff cannot be read anymore. Leave them out of the new layers.
+        let gc_cutoff = *self.get_latest_gc_cutoff_lsn();
+        let truncated_blocks = if gc_cutoff == Lsn(0) {
+            KeySpace { ranges: Vec::new() }
+        } else {
+            let retain_lsns = self.gc_info.read().unwrap().retain_lsns.clone();
+            pgdir
+                .collect_truncated_blocks(gc_cutoff, &retain_lsns)
+                .unwrap_or_else(|e| {
+                    warn!("could not collect truncated relation blocks: {:#}", e);
+                    KeySpace { ranges: Vec::new() }
+                })
+        };
+
         // This iterator walks through all key-value pairs from all the layers
         // we're compacting, in key, LSN order.
         let all_values_iter = deltas_to_compact
@@ -2042,6 +3573,10 @@ impl LayeredTimeline {
         for x in all_values_iter {
             let (key, lsn, value) = x?;
 
+            if lsn < gc_cutoff && truncated_blocks.contains(&key) {
+                continue;
+            }
+
             if let Some(prev_key) = prev_key {
                 if key != prev_key && writer.is_some() {
                     let size = writer.as_mut().unwrap().size();
@@ -2081,9 +3616,14 @@ impl LayeredTimeline {
             par_fsync::par_fsync(&layer_paths)?;
 
             layer_paths.pop().unwrap();
+
+            for path in &layer_paths {
+                self.checkpoint_bytes_written_counter
+                    .inc_by(path.metadata()?.len());
+            }
         }
 
-        let mut layers = self.layers.write().unwrap();
+        let mut layers = self.layers.write();
         let mut new_layer_paths = HashSet::with_capacity(new_layers.len());
         for l in new_layers {
             new_layer_paths.insert(l.path());
@@ -2119,6 +3659,244 @@ impl LayeredTimeline {
         Ok(())
     }
 
+    /// Pin an LSN, so that GC keeps all the data needed to read the timeline
+    /// at it, until the returned guard is dropped. This lets a long-running
+    /// read see a consistent snapshot, even if it falls behind the GC horizon.
+    ///
+    /// Fails if the LSN has already been garbage collected.
+    pub fn pin_lsn(self: &Arc<Self>, lsn: Lsn) -> Result<PinGuard> {
+        // Hold the lock on the cutoff while adding the pin, so that GC cannot
+        // move the cutoff past it concurrently. See gc().
+        let latest_gc_cutoff_lsn = self.latest_gc_cutoff_lsn.read().unwrap();
+        self.check_lsn_is_in_scope(lsn, &latest_gc_cutoff_lsn)
+            .context("cannot pin LSN")?;
+        *self.lsn_pins.lock().unwrap().entry(lsn).or_insert(0) += 1;
+
+        Ok(PinGuard {
+            timeline: Arc::clone(self),
+            lsn,
+        })
+    }
+
+    fn min_pinned_lsn(&self) -> Option<Lsn> {
+        self.lsn_pins.lock().unwrap().keys().next().copied()
+    }
+
+    /// Page access counters of each relation that has been read since the
+    /// timeline was loaded, to find the hottest relations. Returns an empty
+    /// list unless enabled with the 'rel_access_stats' option.
+    pub fn rel_access_stats(&self) -> Vec<(RelTag, AccessStats)> {
+        match &self.rel_access_stats {
+            Some(stats) => stats
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(rel, stats)| (*rel, stats.load()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Count a read of 'key' in the access stats of its relation, if enabled.
+    /// 'redo_records' is the number of WAL records that need to be replayed
+    /// to reconstruct the page.
+    fn count_rel_access(&self, key: Key, redo_records: usize) {
+        let stats = match &self.rel_access_stats {
+            Some(stats) if is_rel_block_key(key) => stats,
+            _ => return,
+        };
+        let (rel, _) = key_to_rel_block(key).unwrap();
+
+        if let Some(rel_stats) = stats.read().unwrap().get(&rel) {
+            rel_stats.inc(redo_records);
+            return;
+        }
+        stats
+            .write()
+            .unwrap()
+            .entry(rel)
+            .or_default()
+            .inc(redo_records);
+    }
+
+    /// Write out every version of every key stored in the timeline, as
+    /// newline-delimited JSON, for offline inspection. Relation block keys
+    /// are decoded to the relation and block number. With 'include_values',
+    /// each entry also tells whether the value is an image or a WAL record,
+    /// and the length and CRC of the serialized value.
+    ///
+    /// Versions inherited from the ancestor timelines are not included. The
+    /// layers are read one value at a time, so this works on timelines of
+    /// any size.
+    pub fn dump_store(&self, w: &mut impl Write, include_values: bool) -> Result<()> {
+        let (historic_layers, in_memory_layers) = {
+            let layers = self.layers.read();
+            let historic_layers: Vec<Arc<dyn Layer>> =
+                layers.iter_historic_layers().cloned().collect();
+            let in_memory_layers: Vec<Arc<InMemoryLayer>> = layers
+                .frozen_layers
+                .iter()
+                .chain(layers.open_layer.iter())
+                .cloned()
+                .collect();
+            (historic_layers, in_memory_layers)
+        };
+
+        let mut write_entry = |layer: &PathBuf, key: Key, lsn: Lsn, value: Value| -> Result<()> {
+            let mut entry = serde_json::json!({
+                "layer": layer.display().to_string(),
+                "key": key.to_string(),
+                "lsn": lsn.to_string(),
+            });
+            if is_rel_block_key(key) {
+                let (rel, blknum) = key_to_rel_block(key)?;
+                entry["rel"] = rel.to_string().into();
+                entry["blknum"] = blknum.into();
+            }
+            if include_values {
+                let buf = Value::ser(&value)?;
+                entry["kind"] = match value {
+                    Value::Image(_) => "image",
+                    Value::WalRecord(_) => "wal_record",
+                }
+                .into();
+                entry["len"] = buf.len().into();
+                entry["crc32c"] = crc32c::crc32c(&buf).into();
+            }
+            serde_json::to_writer(&mut *w, &entry)?;
+            writeln!(w)?;
+            Ok(())
+        };
+
+        for layer in historic_layers {
+            let filename = layer.filename();
+            for item in layer.iter() {
+                let (key, lsn, value) = item?;
+                write_entry(&filename, key, lsn, value)?;
+            }
+        }
+        for layer in in_memory_layers {
+            let filename = layer.filename();
+            layer.for_each_value(|key, lsn, value| write_entry(&filename, key, lsn, value))?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Combine runs of WAL records of 'key' in the open layer, up to
+    /// 'up_to_lsn', into single records with the same effect, so that
+    /// reconstructing the value needs fewer records, without storing a full
+    /// image. Returns the number of records removed.
+    ///
+    /// The value can no longer be read in the middle of a combined run, so
+    /// only records below the GC cutoff are combined, and runs are not
+    /// combined across the branch points.
+    pub fn coalesce_deltas(&self, key: Key, up_to_lsn: Lsn) -> Result<usize> {
+        // Branches can only be created above the cutoff, so reading the
+        // cutoff first ensures that we see all the branch points below it.
+        let up_to_lsn = min(up_to_lsn, *self.latest_gc_cutoff_lsn.read().unwrap());
+        let retain_lsns = self.gc_info.read().unwrap().retain_lsns.clone();
+
+        let layers = self.layers.read();
+        let collapsed = match &layers.open_layer {
+            Some(open_layer) => open_layer.coalesce_records(key, up_to_lsn, &retain_lsns)?,
+            None => 0,
+        };
+        if collapsed > 0 {
+            debug!(
+                "coalesced {} WAL records of key {} up to {}",
+                collapsed, key, up_to_lsn
+            );
+        }
+        Ok(collapsed)
+    }
+
+    /// Store a page reconstructed from at least 'materialize_on_read_threshold'
+    /// WAL records, or from more than 'max_redo_record_bytes' of them, back
+    /// into the open layer, so that the next reads of the page don't have to
+    /// replay them again.
+    ///
+    /// Only reads of the latest version qualify: an image in the middle of the
+    /// history can't be added after the newer versions. To keep the read fast,
+    /// this is skipped if a write is in progress, and failures are only logged.
+    fn materialize_on_read(
+        &self,
+        key: Key,
+        lsn: Lsn,
+        num_records: usize,
+        record_bytes: usize,
+        img: &Bytes,
+    ) {
+        let threshold = self.conf.materialize_on_read_threshold;
+        let max_bytes = self.conf.max_redo_record_bytes;
+        let too_many_records = threshold != 0 && num_records >= threshold;
+        let too_many_bytes = max_bytes != 0 && record_bytes > max_bytes;
+        if !(too_many_records || too_many_bytes) || lsn != self.get_last_record_lsn() {
+            return;
+        }
+
+        // Holding the write lock, the last record LSN can't move, and no newer
+        // versions of the key can be added.
+        let _write_guard = match self.write_lock.try_lock() {
+            Some(guard) => guard,
+            None => return,
+        };
+        if lsn != self.get_last_record_lsn() {
+            return;
+        }
+        let open_layer = match &self.layers.read().open_layer {
+            Some(open_layer) if open_layer.get_lsn_range().start <= lsn => Arc::clone(open_layer),
+            _ => return,
+        };
+        match open_layer.put_materialized_image(key, lsn, img.clone()) {
+            Ok(()) => debug!(
+                "materialized {} at {}, reconstructed from {} WAL records",
+                key, lsn, num_records
+            ),
+            Err(e) => warn!("could not materialize {} at {}: {:#}", key, lsn, e),
+        }
+    }
+
+    /// Count a page read that took longer than 'slow_getpage_threshold', and
+    /// log a warning about it, at most once per SLOW_GETPAGE_WARN_INTERVAL.
+    fn check_slow_get(
+        &self,
+        key: Key,
+        lsn: Lsn,
+        elapsed: Duration,
+        num_records: usize,
+        has_base_image: bool,
+    ) {
+        let threshold = self.conf.slow_getpage_threshold;
+        if threshold.is_zero() || elapsed < threshold {
+            return;
+        }
+        self.slow_getpage_counter.inc();
+
+        let mut last_warning = self.last_slow_getpage_warning.lock().unwrap();
+        let (last_warned_at, suppressed) = &mut *last_warning;
+        if matches!(last_warned_at, Some(at) if at.elapsed() < SLOW_GETPAGE_WARN_INTERVAL) {
+            *suppressed += 1;
+            return;
+        }
+
+        let page = match key_to_rel_block(key) {
+            Ok((rel, blknum)) if is_rel_block_key(key) => format!("rel {} blk {}", rel, blknum),
+            _ => format!("key {}", key),
+        };
+        warn!(
+            "slow GetPage request for {} at {} took {:?}: replayed {} WAL records, base image {}, {} more slow requests since the last warning",
+            page,
+            lsn,
+            elapsed,
+            num_records,
+            if has_base_image { "found" } else { "not found" },
+            suppressed,
+        );
+        *last_warned_at = Some(Instant::now());
+        *suppressed = 0;
+    }
+
     /// Update information about which layer files need to be retained on
     /// garbage collection. This is separate from actually performing the GC,
     /// and is updated more frequently, so that compaction can remove obsolete
@@ -2149,6 +3927,109 @@ impl LayeredTimeline {
         gc_info.pitr = pitr;
     }
 
+    ///
+    /// Take back from 'layers_to_remove' the layers needed to keep the
+    /// 'min_versions' newest versions of every key readable, and the older
+    /// layers that those are reconstructed on top of. Returns the number of
+    /// layers taken back.
+    ///
+    /// A delta layer is needed if some key in it has changed in fewer than
+    /// 'min_versions' newer incremental layers. The changed keys of each
+    /// layer are read from its index once, and counted per key.
+    ///
+    fn retain_newest_versions(
+        layers: &LayerMap,
+        layers_to_remove: &mut Vec<Arc<dyn Layer>>,
+        min_versions: usize,
+    ) -> Result<usize> {
+        let mut incremental: Vec<Arc<dyn Layer>> = layers
+            .iter_historic_layers()
+            .filter(|l| l.is_incremental() && !l.is_in_memory())
+            .cloned()
+            .chain(
+                layers
+                    .open_layer
+                    .iter()
+                    .chain(layers.frozen_layers.iter())
+                    .map(|l| Arc::clone(l) as Arc<dyn Layer>),
+            )
+            .collect();
+        incremental.sort_by_key(|l| std::cmp::Reverse(l.get_lsn_range().start));
+        let mut candidates: Vec<&Arc<dyn Layer>> = layers_to_remove
+            .iter()
+            .filter(|l| l.is_incremental())
+            .collect();
+        candidates.sort_by_key(|l| std::cmp::Reverse(l.get_lsn_range().end));
+
+        // Go through the candidates from newest to oldest, counting the
+        // layers that start after each one per key.
+        let mut needed = HashSet::new();
+        let mut newer_versions: HashMap<Key, usize> = HashMap::new();
+        let mut newer_layers = incremental.iter().peekable();
+        for candidate in candidates {
+            let lsn_end = candidate.get_lsn_range().end;
+            while let Some(l) = newer_layers.next_if(|l| l.get_lsn_range().start >= lsn_end) {
+                for key in l.get_changed_keys(&l.get_key_range(), &l.get_lsn_range())? {
+                    let count = newer_versions.entry(key).or_default();
+                    *count = min(*count + 1, min_versions);
+                }
+            }
+            let changed_keys = candidate
+                .get_changed_keys(&candidate.get_key_range(), &candidate.get_lsn_range())?;
+            if changed_keys
+                .iter()
+                .any(|key| newer_versions.get(key).copied().unwrap_or(0) < min_versions)
+            {
+                needed.insert(candidate.filename());
+            }
+        }
+
+        // A needed delta layer depends on the layers below it, up to an
+        // image layer that stays.
+        let doomed: HashSet<PathBuf> = layers_to_remove.iter().map(|l| l.filename()).collect();
+        loop {
+            let stays = |l: &Arc<dyn Layer>| {
+                let filename = l.filename();
+                !doomed.contains(&filename) || needed.contains(&filename)
+            };
+            let mut newly_needed = Vec::new();
+            'candidate: for l in layers_to_remove.iter() {
+                if needed.contains(&l.filename()) {
+                    continue;
+                }
+                let key_range = l.get_key_range();
+                let lsn_range = l.get_lsn_range();
+                for d in layers_to_remove.iter() {
+                    if !d.is_incremental() || !needed.contains(&d.filename()) {
+                        continue;
+                    }
+                    let d_key_range = d.get_key_range();
+                    let d_lsn_start = d.get_lsn_range().start;
+                    if lsn_range.start >= d_lsn_start || !range_overlaps(&key_range, &d_key_range) {
+                        continue;
+                    }
+                    let overlap = max(key_range.start, d_key_range.start)
+                        ..min(key_range.end, d_key_range.end);
+                    if !layers.image_layer_exists_among(
+                        &overlap,
+                        &(lsn_range.end..d_lsn_start),
+                        stays,
+                    )? {
+                        newly_needed.push(l.filename());
+                        continue 'candidate;
+                    }
+                }
+            }
+            if newly_needed.is_empty() {
+                break;
+            }
+            needed.extend(newly_needed);
+        }
+
+        layers_to_remove.retain(|l| !needed.contains(&l.filename()));
+        Ok(needed.len())
+    }
+
     ///
     /// Garbage collect layer files on a timeline that are no longer needed.
     ///
@@ -2165,16 +4046,19 @@ impl LayeredTimeline {
 
         let gc_info = self.gc_info.read().unwrap();
         let retain_lsns = &gc_info.retain_lsns;
-        let cutoff = min(gc_info.cutoff, disk_consistent_lsn);
+        let mut cutoff = min(gc_info.cutoff, disk_consistent_lsn);
+        if let Some(min_pinned_lsn) = self.min_pinned_lsn() {
+            cutoff = min(cutoff, min_pinned_lsn);
+        }
         let pitr = gc_info.pitr;
 
         // Calculate pitr cutoff point.
         // If we cannot determine a cutoff LSN, be conservative and don't GC anything.
         let mut pitr_cutoff_lsn: Lsn = *self.get_latest_gc_cutoff_lsn();
 
-        if let Ok(timeline) =
-            tenant_mgr::get_local_timeline_with_load(self.tenant_id, self.timeline_id)
-        {
+        let datadir_timeline =
+            tenant_mgr::get_local_timeline_with_load(self.tenant_id, self.timeline_id).ok();
+        if let Some(timeline) = &datadir_timeline {
             // First, calculate pitr_cutoff_timestamp and then convert it to LSN.
             // If we don't have enough data to convert to LSN,
             // play safe and don't remove any layers.
@@ -2215,7 +4099,31 @@ impl LayeredTimeline {
 
         // We need to ensure that no one branches at a point before latest_gc_cutoff_lsn.
         // See branch_timeline() for details.
-        *self.latest_gc_cutoff_lsn.write().unwrap() = new_gc_cutoff;
+        {
+            let mut latest_gc_cutoff_lsn = self.latest_gc_cutoff_lsn.write().unwrap();
+            // An LSN might have been pinned since we calculated the cutoff.
+            // Leave the GC for the next iteration in that case.
+            if let Some(min_pinned_lsn) = self.min_pinned_lsn() {
+                if min_pinned_lsn < new_gc_cutoff {
+                    info!(
+                        "LSN {} was pinned during GC, skipping GC for timeline {}",
+                        min_pinned_lsn, self.timeline_id
+                    );
+                    result.elapsed = now.elapsed()?;
+                    return Ok(result);
+                }
+            }
+            *latest_gc_cutoff_lsn = new_gc_cutoff;
+        }
+
+        // The LSN timestamps below the cutoff are of no use anymore. Save the
+        // rest, so that they don't need to be read again after a restart.
+        if let Some(timeline) = &datadir_timeline {
+            timeline.trim_lsn_timestamp_index(new_gc_cutoff);
+            if let Err(e) = timeline.save_lsn_timestamp_index() {
+                warn!("could not save the LSN timestamp index: {:#}", e);
+            }
+        }
 
         info!("GC starting");
 
@@ -2231,7 +4139,7 @@ impl LayeredTimeline {
         // 3. it doesn't need to be retained for 'retain_lsns';
         // 4. newer on-disk image layers cover the layer's whole key range
         //
-        let mut layers = self.layers.write().unwrap();
+        let mut layers = self.layers.write();
         'outer: for l in layers.iter_historic_layers() {
             // This layer is in the process of being flushed to disk.
             // It will be swapped out of the layer map, replaced with
@@ -2325,16 +4233,42 @@ impl LayeredTimeline {
             layers_to_remove.push(Arc::clone(l));
         }
 
-        // Actually delete the layers from disk and remove them from the map.
-        // (couldn't do this in the loop above, because you cannot modify a collection
-        // while iterating it. BTreeMap::retain() would be another option)
+        // 5. Is it needed to keep the newest versions of some key readable?
+        let min_versions = self.get_min_versions_per_block();
+        if min_versions > 1 {
+            let kept = Self::retain_newest_versions(&layers, &mut layers_to_remove, min_versions)?;
+            if kept > 0 {
+                debug!(
+                    "keeping {} layers for the {} newest versions of their keys",
+                    kept, min_versions
+                );
+            }
+            result.layers_needed_by_cutoff += kept as u64;
+        }
+
+        // Remove the layers from the map. (couldn't do this in the loop above,
+        // because you cannot modify a collection while iterating it.
+        // BTreeMap::retain() would be another option)
+        for doomed_layer in layers_to_remove.iter() {
+            layers.remove_historic(Arc::clone(doomed_layer));
+        }
+        // Release the lock before deleting the files. The deletions can be
+        // throttled, and readers and WAL ingestion must not wait for that.
+        drop(layers);
+
+        // Actually delete the layers from disk.
         let mut layer_paths_to_delete = HashSet::with_capacity(layers_to_remove.len());
         for doomed_layer in layers_to_remove {
+            fail_point!("gc-before-layer-delete");
+            // Deleting a file frees its blocks, count that against the
+            // tenant's I/O limit.
+            if let (Some(limiter), Some(path)) = (&self.io_limiter, doomed_layer.local_path()) {
+                limiter.acquire(path.metadata()?.len());
+            }
             doomed_layer.delete()?;
             if let Some(path) = doomed_layer.local_path() {
                 layer_paths_to_delete.insert(path);
             }
-            layers.remove_historic(doomed_layer);
             result.layers_removed += 1;
         }
 
@@ -2352,12 +4286,15 @@ impl LayeredTimeline {
 
     ///
     /// Reconstruct a value, using the given base image and WAL records in 'data'.
+    /// 'from_ancestor' tells if some of the data was collected from ancestor
+    /// timelines, for the error messages.
     ///
     fn reconstruct_value(
         &self,
         key: Key,
         request_lsn: Lsn,
         mut data: ValueReconstructState,
+        from_ancestor: bool,
     ) -> Result<Bytes> {
         // Perform WAL redo if needed
         data.records.reverse();
@@ -2370,9 +4307,20 @@ impl LayeredTimeline {
                     key,
                     img_lsn
                 );
+                self.memorize_recent_page(key, *img_lsn, img);
                 Ok(img.clone())
             } else {
-                bail!("base image for {} at {} not found", key, request_lsn);
+                bail!(
+                    "base image for {} at {} not found, {}, ancestor {}",
+                    key,
+                    request_lsn,
+                    describe_wal_records(&data.records),
+                    if from_ancestor {
+                        "consulted"
+                    } else {
+                        "not consulted"
+                    }
+                );
             }
         } else {
             // We need to do WAL redo.
@@ -2381,10 +4329,15 @@ impl LayeredTimeline {
             // the page
             if data.img.is_none() && !data.records.first().unwrap().1.will_init() {
                 bail!(
-                    "Base image for {} at {} not found, but got {} WAL records",
+                    "Base image for {} at {} not found, but got {}, ancestor {}",
                     key,
                     request_lsn,
-                    data.records.len()
+                    describe_wal_records(&data.records),
+                    if from_ancestor {
+                        "consulted"
+                    } else {
+                        "not consulted"
+                    }
                 );
             } else {
                 let base_img = if let Some((_lsn, img)) = data.img {
@@ -2402,9 +4355,9 @@ impl LayeredTimeline {
 
                 let last_rec_lsn = data.records.last().unwrap().0;
 
-                let img =
-                    self.walredo_mgr
-                        .request_redo(key, request_lsn, base_img, data.records)?;
+                // get() has already split the records into batches of at most
+                // 'max_redo_record_bytes', so they all go in one request.
+                let img = self.request_redo(key, request_lsn, base_img, &data.records)?;
 
                 if img.len() == page_cache::PAGE_SZ {
                     let cache = page_cache::get();
@@ -2416,11 +4369,61 @@ impl LayeredTimeline {
                         &img,
                     );
                 }
+                self.memorize_recent_page(key, last_rec_lsn, &img);
 
                 Ok(img)
             }
         }
     }
+
+    /// Pass a WAL redo request to the WAL redo manager, retrying once if the
+    /// WAL redo process died.
+    fn request_redo(
+        &self,
+        key: Key,
+        request_lsn: Lsn,
+        base_img: Option<Bytes>,
+        records: &[(Lsn, ZenithWalRecord)],
+    ) -> Result<Bytes> {
+        let _permit = walredo::redo_limiter().map(|limiter| limiter.acquire());
+        // The records are only borrowed, and cloning 'base_img' just bumps a
+        // reference count, so keeping them around for the retry is free.
+        match self
+            .walredo_mgr
+            .request_redo(key, request_lsn, base_img.clone(), records)
+        {
+            // A timeout means the process is wedged rather than dead. Waiting
+            // for it all over again would just double the latency.
+            Err(WalRedoError::IoError(e)) if e.kind() != std::io::ErrorKind::TimedOut => {
+                // Most likely, the WAL redo process died. The manager
+                // launches a new one on the next request, so retry once.
+                warn!(
+                    "WAL redo for {} at {} failed, retrying: {}",
+                    key, request_lsn, e
+                );
+                Ok(self
+                    .walredo_mgr
+                    .request_redo(key, request_lsn, base_img, records)?)
+            }
+            result => Ok(result?),
+        }
+    }
+}
+
+/// Describe the WAL records collected for reconstructing a value, for error
+/// messages: how many there are, and the range of LSNs they cover.
+fn describe_wal_records(records: &[(Lsn, ZenithWalRecord)]) -> String {
+    let min_lsn = records.iter().map(|(lsn, _)| *lsn).min();
+    let max_lsn = records.iter().map(|(lsn, _)| *lsn).max();
+    match (min_lsn, max_lsn) {
+        (Some(min_lsn), Some(max_lsn)) => format!(
+            "{} WAL records with LSNs {}..={}",
+            records.len(),
+            min_lsn,
+            max_lsn
+        ),
+        _ => "0 WAL records".to_string(),
+    }
 }
 
 /// Helper function for get_reconstruct_data() to add the path of layers traversed
@@ -2451,7 +4454,7 @@ fn layer_traversal_error(
 
 struct LayeredTimelineWriter<'a> {
     tl: &'a LayeredTimeline,
-    _write_guard: MutexGuard<'a, ()>,
+    _write_guard: OrderedGuard<MutexGuard<'a, ()>>,
 }
 
 impl Deref for LayeredTimelineWriter<'_> {
@@ -2467,6 +4470,10 @@ impl<'a> TimelineWriter<'_> for LayeredTimelineWriter<'a> {
         self.tl.put_value(key, lsn, value)
     }
 
+    fn put_batch(&self, lsn: Lsn, batch: Vec<(Key, Value)>) -> Result<()> {
+        self.tl.put_values(lsn, batch)
+    }
+
     fn delete(&self, key_range: Range<Key>, lsn: Lsn) -> Result<()> {
         self.tl.put_tombstone(key_range, lsn)
     }
@@ -2518,18 +4525,87 @@ fn rename_to_backup(path: PathBuf) -> anyhow::Result<()> {
     bail!("couldn't find an unused backup number for {:?}", path)
 }
 
+/// Path of the temporary file that save_metadata() writes to, before renaming
+/// it in place.
+fn metadata_temp_path(metadata_path: &Path) -> PathBuf {
+    metadata_path.with_file_name(format!("{}.temp", METADATA_FILE_NAME))
+}
+
+fn metadata_backup_path(metadata_path: &Path) -> PathBuf {
+    metadata_path.with_file_name(format!("{}.bak", METADATA_FILE_NAME))
+}
+
+/// Write the serialized metadata into the temporary file, retrying short
+/// writes. On error, part of the bytes may have been written, but only to
+/// the temporary file: the metadata file itself is only ever replaced by
+/// renaming a completely written file over it.
+fn write_metadata_bytes(file: &mut impl Write, metadata_bytes: &[u8]) -> std::io::Result<()> {
+    file.write_all(metadata_bytes)
+}
+
+/// Errors from reading a timeline's metadata file that callers may need to
+/// tell apart from other failures. They're returned inside `anyhow::Error`,
+/// use `downcast_ref` to check for them.
+#[derive(Debug, thiserror::Error)]
+pub enum TimelineMetadataError {
+    #[error("timeline {0} not found")]
+    TimelineNotFound(ZTimelineId),
+
+    /// The timeline directory exists, but has no metadata file. This is left
+    /// behind when timeline creation is interrupted, and has to be cleaned up
+    /// manually.
+    #[error("timeline directory {} has no metadata file, it may be a leftover of an interrupted timeline creation", .0.display())]
+    TimelineMetadataMissing(PathBuf),
+}
+
+/// Returned inside `anyhow::Error` when a timeline is loaded whose chain of
+/// ancestors is longer than the `max_ancestor_depth` setting.
+#[derive(Debug, thiserror::Error)]
+#[error("timeline {timeline_id} has more than {max_depth} ancestors")]
+pub struct AncestorChainTooDeep {
+    pub timeline_id: ZTimelineId,
+    pub max_depth: usize,
+}
+
+/// Returned inside `anyhow::Error` when creating a timeline would exceed the
+/// `max_timelines_per_tenant` setting.
+#[derive(Debug, thiserror::Error)]
+#[error("tenant {tenant_id} already has the maximum of {limit} timelines")]
+pub struct TooManyTimelines {
+    pub tenant_id: ZTenantId,
+    pub limit: usize,
+}
+
+fn read_metadata_file(
+    conf: &'static PageServerConf,
+    timeline_id: ZTimelineId,
+    tenant_id: ZTenantId,
+    metadata_path: &Path,
+) -> anyhow::Result<Vec<u8>> {
+    match std::fs::read(metadata_path) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let timeline_path = conf.timeline_path(&timeline_id, &tenant_id);
+            Err(if timeline_path.exists() {
+                TimelineMetadataError::TimelineMetadataMissing(timeline_path).into()
+            } else {
+                TimelineMetadataError::TimelineNotFound(timeline_id).into()
+            })
+        }
+        Err(e) => Err(anyhow::Error::new(e).context(format!(
+            "Failed to read metadata bytes from path {}",
+            metadata_path.display()
+        ))),
+    }
+}
+
 pub fn load_metadata(
     conf: &'static PageServerConf,
     timeline_id: ZTimelineId,
     tenant_id: ZTenantId,
 ) -> anyhow::Result<TimelineMetadata> {
     let metadata_path = metadata_path(conf, timeline_id, tenant_id);
-    let metadata_bytes = std::fs::read(&metadata_path).with_context(|| {
-        format!(
-            "Failed to read metadata bytes from path {}",
-            metadata_path.display()
-        )
-    })?;
+    let metadata_bytes = read_metadata_file(conf, timeline_id, tenant_id, &metadata_path)?;
     TimelineMetadata::from_bytes(&metadata_bytes).with_context(|| {
         format!(
             "Failed to parse metadata bytes from path {}",
@@ -2538,6 +4614,48 @@ pub fn load_metadata(
     })
 }
 
+///
+/// Like load_metadata(), but if the metadata file is corrupt, fall back to the
+/// backup of the previous version that save_metadata() leaves behind. If the
+/// backup is good, it's restored as the metadata file.
+///
+pub fn try_recover_metadata(
+    conf: &'static PageServerConf,
+    timeline_id: ZTimelineId,
+    tenant_id: ZTenantId,
+) -> anyhow::Result<TimelineMetadata> {
+    let metadata_path = metadata_path(conf, timeline_id, tenant_id);
+    let metadata_bytes = read_metadata_file(conf, timeline_id, tenant_id, &metadata_path)?;
+    let err = match TimelineMetadata::from_bytes(&metadata_bytes) {
+        Ok(metadata) => return Ok(metadata),
+        Err(err) => err,
+    };
+
+    let backup_path = metadata_backup_path(&metadata_path);
+    let backup_metadata = match std::fs::read(&backup_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|bytes| TimelineMetadata::from_bytes(&bytes))
+    {
+        Ok(metadata) => metadata,
+        Err(backup_err) => {
+            return Err(err.context(format!(
+                "Failed to parse metadata bytes from path {}, and no usable backup: {:#}",
+                metadata_path.display(),
+                backup_err
+            )))
+        }
+    };
+
+    warn!(
+        "metadata file {} is corrupt ({:#}), restoring it from {}",
+        metadata_path.display(),
+        err,
+        backup_path.display()
+    );
+    LayeredRepository::save_metadata(conf, timeline_id, tenant_id, &backup_metadata, false)?;
+    Ok(backup_metadata)
+}
+
 ///
 /// Tests that are specific to the layered storage format.
 ///
@@ -2548,9 +4666,12 @@ pub fn load_metadata(
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use crate::keyspace::KeySpaceAccum;
+    use crate::pgdatadir_mapping::DatadirTimeline;
     use crate::repository::repo_harness::*;
+    use crate::walredo::PostgresRedoManager;
+    use postgres_ffi::checksum_utils::page_set_checksum;
     use rand::{thread_rng, Rng};
+    use std::sync::atomic::AtomicUsize;
 
     #[test]
     fn corrupt_metadata() -> Result<()> {
@@ -2590,21 +4711,1667 @@ pub mod tests {
         Ok(())
     }
 
-    // Target file size in the unit tests. In production, the target
-    // file size is much larger, maybe 1 GB. But a small size makes it
-    // much faster to exercise all the logic for creating the files,
-    // garbage collection, compaction etc.
-    pub const TEST_FILE_SIZE: u64 = 4 * 1024 * 1024;
+    /// A writer that passes at most 'max_chunk' bytes per call on to
+    /// 'inner', and fails once 'limit' bytes have been written.
+    struct ShortWriter<W> {
+        inner: W,
+        written: usize,
+        max_chunk: usize,
+        limit: usize,
+    }
 
-    #[test]
-    fn test_images() -> Result<()> {
-        let repo = RepoHarness::create("test_images")?.load();
-        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+    impl<W: Write> Write for ShortWriter<W> {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            if self.written >= self.limit {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "no space left",
+                ));
+            }
+            let n = min(min(data.len(), self.max_chunk), self.limit - self.written);
+            let n = self.inner.write(&data[..n])?;
+            self.written += n;
+            Ok(n)
+        }
 
-        #[allow(non_snake_case)]
-        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
 
-        let writer = tline.writer();
+    #[test]
+    fn save_metadata_short_writes() -> Result<()> {
+        let harness = RepoHarness::create("save_metadata_short_writes")?;
+        let repo = harness.load();
+        repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        drop(repo);
+
+        let old_metadata = load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?;
+        let new_metadata = TimelineMetadata::new(
+            Lsn(0x20),
+            None,
+            None,
+            Lsn(0),
+            old_metadata.latest_gc_cutoff_lsn(),
+            old_metadata.initdb_lsn(),
+        );
+        let metadata_len = new_metadata.to_bytes()?.len();
+
+        // A write that can't complete is an error, not a silently torn file
+        let result = LayeredRepository::save_metadata_via(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            &new_metadata,
+            false,
+            |file, bytes| {
+                let mut writer = ShortWriter {
+                    inner: file,
+                    written: 0,
+                    max_chunk: 7,
+                    limit: metadata_len / 2,
+                };
+                write_metadata_bytes(&mut writer, bytes)
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?,
+            old_metadata
+        );
+
+        // Short writes are retried until all the bytes are written
+        LayeredRepository::save_metadata_via(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            &new_metadata,
+            false,
+            |file, bytes| {
+                let mut writer = ShortWriter {
+                    inner: file,
+                    written: 0,
+                    max_chunk: 7,
+                    limit: usize::MAX,
+                };
+                write_metadata_bytes(&mut writer, bytes)
+            },
+        )?;
+        assert_eq!(
+            load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?,
+            new_metadata
+        );
+
+        Ok(())
+    }
+
+    /// Simulate a crash at each step of save_metadata(), and check that the
+    /// timeline can still be loaded, with the old metadata.
+    #[test]
+    fn save_metadata_crash() -> Result<()> {
+        // Fail points are global, use an exclusive harness so that the other
+        // tests don't hit them.
+        let harness = RepoHarness::create_exclusive("save_metadata_crash")?;
+        let repo = harness.load();
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Forced)?;
+        drop(tline);
+        drop(repo);
+
+        let old_metadata = load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?;
+        let new_metadata = TimelineMetadata::new(
+            Lsn(0x20),
+            None,
+            None,
+            Lsn(0),
+            old_metadata.latest_gc_cutoff_lsn(),
+            old_metadata.initdb_lsn(),
+        );
+        let temp_path =
+            metadata_temp_path(&metadata_path(harness.conf, TIMELINE_ID, harness.tenant_id));
+
+        for failpoint in ["save-metadata-after-write", "save-metadata-before-rename"] {
+            fail::cfg(failpoint, "return").unwrap();
+            let result = LayeredRepository::save_metadata(
+                harness.conf,
+                TIMELINE_ID,
+                harness.tenant_id,
+                &new_metadata,
+                false,
+            );
+            fail::remove(failpoint);
+            assert!(result.is_err(), "{} should fail save_metadata", failpoint);
+            assert!(temp_path.exists());
+
+            let repo = harness.load();
+            let tline = repo.get_timeline_load(TIMELINE_ID)?;
+            assert_eq!(tline.get(TEST_KEY, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+            assert_eq!(
+                load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?,
+                old_metadata
+            );
+            // loading the timeline cleans up the incomplete file
+            assert!(!temp_path.exists());
+        }
+
+        LayeredRepository::save_metadata(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            &new_metadata,
+            false,
+        )?;
+        assert_eq!(
+            load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?,
+            new_metadata
+        );
+        assert!(!temp_path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn recover_torn_metadata() -> Result<()> {
+        let harness = RepoHarness::create("recover_torn_metadata")?;
+        let repo = harness.load();
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Forced)?;
+        drop(tline);
+        drop(repo);
+
+        // Save a new version, so that the current one becomes the backup
+        let old_metadata = load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?;
+        let new_metadata = TimelineMetadata::new(
+            Lsn(0x20),
+            None,
+            None,
+            Lsn(0),
+            old_metadata.latest_gc_cutoff_lsn(),
+            old_metadata.initdb_lsn(),
+        );
+        LayeredRepository::save_metadata(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            &new_metadata,
+            false,
+        )?;
+
+        // Corrupt the checksum in the metadata file
+        let path = metadata_path(harness.conf, TIMELINE_ID, harness.tenant_id);
+        let mut metadata_bytes = fs::read(&path)?;
+        metadata_bytes[0] ^= 0xFF;
+        fs::write(&path, &metadata_bytes)?;
+        assert!(load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id).is_err());
+
+        // Loading the timeline restores the backup
+        let repo = harness.load();
+        let tline = repo.get_timeline_load(TIMELINE_ID)?;
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+        assert_eq!(
+            load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?,
+            old_metadata
+        );
+        drop(tline);
+        drop(repo);
+
+        // With no good backup, there's nothing to recover from
+        fs::write(&path, &metadata_bytes)?;
+        fs::write(metadata_backup_path(&path), &metadata_bytes)?;
+        assert!(try_recover_metadata(harness.conf, TIMELINE_ID, harness.tenant_id).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_metadata() -> Result<()> {
+        let harness = RepoHarness::create("test_missing_metadata")?;
+
+        let err = load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TimelineMetadataError>(),
+            Some(TimelineMetadataError::TimelineNotFound(id)) if *id == TIMELINE_ID
+        ));
+
+        // A half-created timeline, with just the directory
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        fs::create_dir_all(&timeline_path)?;
+        for result in [
+            load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id),
+            try_recover_metadata(harness.conf, TIMELINE_ID, harness.tenant_id),
+        ] {
+            let err = result.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<TimelineMetadataError>(),
+                Some(TimelineMetadataError::TimelineMetadataMissing(path)) if *path == timeline_path
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_gc_horizon() -> Result<()> {
+        let repo = RepoHarness::create("test_set_gc_horizon")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let writer = tline.writer();
+        for lsn in [0x10, 0x20, 0x30, 0x40, 0x50] {
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(TEST_IMG("foo")))?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Forced)?;
+
+        repo.set_gc_horizon(0x30)?;
+        assert_eq!(repo.get_gc_horizon(), 0x30);
+        repo.gc_iteration(None, repo.get_gc_horizon(), Duration::ZERO, false)?;
+        assert_eq!(*tline.get_latest_gc_cutoff_lsn(), Lsn(0x20));
+
+        repo.set_gc_horizon(0x10)?;
+        assert_eq!(repo.get_gc_horizon(), 0x10);
+        repo.gc_iteration(None, repo.get_gc_horizon(), Duration::ZERO, false)?;
+        assert_eq!(*tline.get_latest_gc_cutoff_lsn(), Lsn(0x40));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_metadata_file() -> Result<()> {
+        let harness = RepoHarness::create("test_refresh_metadata_file")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let writer = tline.writer();
+        for lsn in [0x10, 0x20] {
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(TEST_IMG("foo")))?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        let disk_consistent_lsn = tline.get_disk_consistent_lsn();
+        assert_eq!(disk_consistent_lsn, Lsn(0x20));
+        let metadata = load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?;
+        assert_eq!(metadata.disk_consistent_lsn(), disk_consistent_lsn);
+
+        // Pretend that the last save was lost
+        let stale_metadata = TimelineMetadata::new(
+            Lsn(0),
+            None,
+            None,
+            Lsn(0),
+            metadata.latest_gc_cutoff_lsn(),
+            metadata.initdb_lsn(),
+        );
+        LayeredRepository::save_metadata(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            &stale_metadata,
+            false,
+        )?;
+
+        // The checkpointer catches the file up
+        repo.compaction_iteration()?;
+        let metadata = load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?;
+        assert_eq!(metadata.disk_consistent_lsn(), disk_consistent_lsn);
+        assert_eq!(metadata.prev_record_lsn(), Some(Lsn(0x10)));
+
+        // Once more WAL has arrived, 'prev_record_lsn' at the
+        // 'disk_consistent_lsn' is no longer known in memory. The file still
+        // has it, and keeps it.
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x30), Value::Image(TEST_IMG("foo")))?;
+        writer.finish_write(Lsn(0x30));
+        drop(writer);
+        repo.compaction_iteration()?;
+        let metadata = load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?;
+        assert_eq!(metadata.disk_consistent_lsn(), disk_consistent_lsn);
+        assert_eq!(metadata.prev_record_lsn(), Some(Lsn(0x10)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_separate_metadata_dir() -> Result<()> {
+        let mut harness = RepoHarness::create("test_separate_metadata_dir")?;
+        let mut conf = harness.conf.clone();
+        conf.metadata_dir = Some(conf.workdir.join("metadata"));
+        harness.conf = Box::leak(Box::new(conf));
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        drop(tline);
+        drop(repo);
+
+        // The metadata file is in the metadata directory, the layers are not
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let metadata_dir = harness.conf.workdir.join(format!(
+            "metadata/tenants/{}/timelines/{}",
+            harness.tenant_id, TIMELINE_ID
+        ));
+        assert_eq!(
+            metadata_path(harness.conf, TIMELINE_ID, harness.tenant_id),
+            metadata_dir.join(METADATA_FILE_NAME)
+        );
+        assert!(metadata_dir.join(METADATA_FILE_NAME).exists());
+        assert!(!timeline_path.join(METADATA_FILE_NAME).exists());
+        let layer_files = fs::read_dir(&timeline_path)?.count();
+        assert!(layer_files > 0);
+        assert_eq!(fs::read_dir(&metadata_dir)?.count(), 1);
+
+        // And the timeline can be loaded back
+        let repo = harness.load();
+        let tline = repo.get_timeline_load(TIMELINE_ID)?;
+        assert_eq!(tline.get_disk_consistent_lsn(), Lsn(0x10));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_to_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_flush_to_lsn")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let writer = tline.writer();
+        for lsn in [0x10, 0x20, 0x30] {
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(TEST_IMG("foo")))?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+        assert!(tline.get_disk_consistent_lsn() < Lsn(0x20));
+
+        tline.flush_to_lsn(Lsn(0x20))?;
+        assert!(tline.get_disk_consistent_lsn() >= Lsn(0x20));
+        assert_eq!(
+            load_metadata(repo.conf, TIMELINE_ID, repo.tenant_id)?.disk_consistent_lsn(),
+            tline.get_disk_consistent_lsn()
+        );
+
+        // Already durable
+        tline.flush_to_lsn(Lsn(0x10))?;
+
+        // Can't flush what hasn't been received yet
+        assert!(tline.flush_to_lsn(Lsn(0x40)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_oldest_readable_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_oldest_readable_lsn")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        for lsn in [0x10, 0x20, 0x30, 0x40, 0x50] {
+            let writer = tline.writer();
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(TEST_IMG("foo")))?;
+            writer.finish_write(Lsn(lsn));
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x30))?;
+        let branch = repo.get_timeline_load(NEW_TIMELINE_ID)?;
+        assert_eq!(tline.get_oldest_readable_lsn(), Lsn(0));
+        assert_eq!(branch.get_oldest_readable_lsn(), Lsn(0));
+
+        repo.gc_iteration(Some(TIMELINE_ID), 0x10, Duration::ZERO, false)?;
+        assert_eq!(tline.get_oldest_readable_lsn(), Lsn(0x40));
+        // The branch can still read the branch point from its ancestor, but
+        // nothing before it
+        assert_eq!(branch.get_oldest_readable_lsn(), Lsn(0x30));
+        assert_eq!(branch.get(TEST_KEY, Lsn(0x30))?, TEST_IMG("foo"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_pin_lsn")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        for lsn in [0x10, 0x20, 0x30, 0x40, 0x50] {
+            let writer = tline.writer();
+            writer.put(
+                TEST_KEY,
+                Lsn(lsn),
+                Value::Image(TEST_IMG(&format!("foo at {:x}", lsn))),
+            )?;
+            writer.finish_write(Lsn(lsn));
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+
+        let pin = tline.pin_lsn(Lsn(0x20))?;
+        let second_pin = tline.pin_lsn(Lsn(0x30))?;
+        assert_eq!(pin.lsn(), Lsn(0x20));
+
+        // The horizon would put the cutoff at 0x40, but the pin holds it back
+        repo.gc_iteration(Some(TIMELINE_ID), 0x10, Duration::ZERO, false)?;
+        assert_eq!(*tline.get_latest_gc_cutoff_lsn(), Lsn(0x20));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x20))?, TEST_IMG("foo at 20"));
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x20))?;
+
+        drop(pin);
+        repo.gc_iteration(Some(TIMELINE_ID), 0x10, Duration::ZERO, false)?;
+        assert_eq!(*tline.get_latest_gc_cutoff_lsn(), Lsn(0x30));
+
+        drop(second_pin);
+        repo.gc_iteration(Some(TIMELINE_ID), 0x10, Duration::ZERO, false)?;
+        assert_eq!(*tline.get_latest_gc_cutoff_lsn(), Lsn(0x40));
+
+        // Can't pin what's already been garbage collected
+        assert!(tline.pin_lsn(Lsn(0x30)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_min_versions_per_block() -> Result<()> {
+        let mut harness = RepoHarness::create("test_gc_min_versions_per_block")?;
+        harness.tenant_conf.min_versions_per_block = 2;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        // TEST_KEY changes at every LSN, the other key only once
+        let other_key = Key::from_hex("112222222233333333444444445500000002").unwrap();
+        for lsn in [0x10, 0x20, 0x30, 0x40, 0x50] {
+            let writer = tline.writer();
+            writer.put(
+                TEST_KEY,
+                Lsn(lsn),
+                Value::Image(TEST_IMG(&format!("foo at {:x}", lsn))),
+            )?;
+            if lsn == 0x10 {
+                writer.put(other_key, Lsn(lsn), Value::Image(TEST_IMG("other")))?;
+            }
+            writer.finish_write(Lsn(lsn));
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+
+        // With a zero horizon, only the latest version would survive. The
+        // cutoff still moves, but the two newest versions of TEST_KEY stay
+        // readable.
+        repo.gc_iteration(Some(TIMELINE_ID), 0, Duration::ZERO, false)?;
+        let latest_gc_cutoff_lsn = tline.get_latest_gc_cutoff_lsn();
+        assert_eq!(*latest_gc_cutoff_lsn, Lsn(0x50));
+        tline.check_key_lsn_is_in_scope(TEST_KEY, Lsn(0x40), &latest_gc_cutoff_lsn)?;
+        assert!(tline
+            .check_key_lsn_is_in_scope(TEST_KEY, Lsn(0x30), &latest_gc_cutoff_lsn)
+            .is_err());
+        // The only version of the other key is its newest
+        tline.check_key_lsn_is_in_scope(other_key, Lsn(0x10), &latest_gc_cutoff_lsn)?;
+        drop(latest_gc_cutoff_lsn);
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x40))?, TEST_IMG("foo at 40"));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x50))?, TEST_IMG("foo at 50"));
+        assert_eq!(tline.get(other_key, Lsn(0x10))?, TEST_IMG("other"));
+
+        // Without the setting, only the cutoff counts
+        repo.update_tenant_config(TenantConfOpt {
+            min_versions_per_block: Some(0),
+            ..TenantConfOpt::default()
+        })?;
+        let latest_gc_cutoff_lsn = tline.get_latest_gc_cutoff_lsn();
+        assert!(tline
+            .check_key_lsn_is_in_scope(TEST_KEY, Lsn(0x40), &latest_gc_cutoff_lsn)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_ancestor_chain() -> Result<()> {
+        let harness = RepoHarness::create("test_load_ancestor_chain")?;
+        let third_timeline_id = ZTimelineId::generate();
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        {
+            let repo = harness.load();
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+            let writer = tline.writer();
+            writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+            writer.finish_write(Lsn(0x10));
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Forced)?;
+
+            repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x10))?;
+            repo.branch_timeline(NEW_TIMELINE_ID, third_timeline_id, Lsn(0x10))?;
+        }
+
+        // After a restart, nothing is loaded yet
+        let repo = harness.load();
+        let chain = repo.load_ancestor_chain(third_timeline_id)?;
+        let chain_ids: Vec<_> = chain.iter().map(|t| t.timeline_id).collect();
+        assert_eq!(
+            chain_ids,
+            vec![third_timeline_id, NEW_TIMELINE_ID, TIMELINE_ID]
+        );
+        for timeline_id in chain_ids {
+            assert!(matches!(
+                repo.get_timeline(timeline_id),
+                Some(RepositoryTimeline::Loaded(_))
+            ));
+        }
+        assert_eq!(chain[0].get(TEST_KEY, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+        drop(chain);
+        drop(repo);
+
+        // Make the root timeline a child of the last one
+        let metadata = load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?;
+        let looped_metadata = TimelineMetadata::new(
+            metadata.disk_consistent_lsn(),
+            metadata.prev_record_lsn(),
+            Some(third_timeline_id),
+            Lsn(0x10),
+            metadata.latest_gc_cutoff_lsn(),
+            metadata.initdb_lsn(),
+        );
+        LayeredRepository::save_metadata(
+            harness.conf,
+            TIMELINE_ID,
+            harness.tenant_id,
+            &looped_metadata,
+            false,
+        )?;
+
+        let repo = harness.load();
+        let err = repo.load_ancestor_chain(third_timeline_id).unwrap_err();
+        assert!(err.to_string().contains("form a cycle"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recent_pages() -> Result<()> {
+        let repo = RepoHarness::create("test_recent_pages")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        #[allow(non_snake_case)]
+        let TEST_KEY_B: Key = Key::from_hex("112222222233333333444444445500000002").unwrap();
+
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+
+        // Repeated reads return the same buffer, also at later LSNs if the
+        // page hasn't changed
+        let first = tline.get(TEST_KEY, Lsn(0x10))?;
+        assert_eq!(first, TEST_IMG("foo at 0x10"));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x10))?.as_ptr(), first.as_ptr());
+        let writer = tline.writer();
+        writer.put(TEST_KEY_B, Lsn(0x20), Value::Image(TEST_IMG("bar at 0x20")))?;
+        writer.finish_write(Lsn(0x20));
+        drop(writer);
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x20))?.as_ptr(), first.as_ptr());
+
+        // A newer version isn't hidden by the cached one, nor the other way round
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x30), Value::Image(TEST_IMG("foo at 0x30")))?;
+        writer.finish_write(Lsn(0x30));
+        drop(writer);
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x30))?, TEST_IMG("foo at 0x30"));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x20))?, TEST_IMG("foo at 0x10"));
+
+        // Rewinding the timeline drops the versions after the new end
+        tline.reset_to_lsn(Lsn(0x20))?;
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x20))?, TEST_IMG("foo at 0x10"));
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x30), Value::Image(TEST_IMG("foo again")))?;
+        writer.finish_write(Lsn(0x30));
+        drop(writer);
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x30))?, TEST_IMG("foo again"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_ancestor_depth() -> Result<()> {
+        let mut harness = RepoHarness::create("test_max_ancestor_depth")?;
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        // A chain of five branches, each one the child of the previous one
+        let mut timeline_ids = vec![TIMELINE_ID];
+        {
+            let repo = harness.load();
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+            let writer = tline.writer();
+            writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+            writer.finish_write(Lsn(0x10));
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Flush)?;
+
+            for _ in 0..5 {
+                let timeline_id = ZTimelineId::generate();
+                repo.branch_timeline(*timeline_ids.last().unwrap(), timeline_id, Lsn(0x10))?;
+                timeline_ids.push(timeline_id);
+            }
+        }
+
+        harness.conf = Box::leak(Box::new(PageServerConf {
+            max_ancestor_depth: 3,
+            ..harness.conf.clone()
+        }));
+        let repo = harness.load();
+
+        let tline = repo.get_timeline_load(timeline_ids[3])?;
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+        drop(tline);
+
+        let err = repo.get_timeline_load(timeline_ids[5]).unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<AncestorChainTooDeep>(),
+                Some(AncestorChainTooDeep { timeline_id, max_depth: 3 }) if *timeline_id == timeline_ids[5]
+            ),
+            "{err:?}"
+        );
+        assert!(matches!(
+            repo.get_timeline(timeline_ids[5]),
+            Some(RepositoryTimeline::Unloaded { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_detach_from_ancestor() -> Result<()> {
+        let harness = RepoHarness::create("test_detach_from_ancestor")?;
+        let repo = harness.load();
+        #[allow(non_snake_case)]
+        let TEST_KEY_A: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        #[allow(non_snake_case)]
+        let TEST_KEY_B: Key = Key::from_hex("112222222233333333444444445500000002").unwrap();
+
+        // Some of the parent's data is on disk, some in memory
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let writer = tline.writer();
+        writer.put(TEST_KEY_A, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
+        writer.put(TEST_KEY_B, Lsn(0x10), Value::Image(TEST_IMG("bar at 0x10")))?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        let writer = tline.writer();
+        writer.put(TEST_KEY_A, Lsn(0x20), Value::Image(TEST_IMG("foo at 0x20")))?;
+        writer.finish_write(Lsn(0x20));
+        drop(writer);
+
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x20))?;
+        let writer = tline.writer();
+        writer.put(TEST_KEY_A, Lsn(0x30), Value::Image(TEST_IMG("foo at 0x30")))?;
+        writer.finish_write(Lsn(0x30));
+        drop(writer);
+        drop(tline);
+
+        let newtline = repo.get_timeline_load(NEW_TIMELINE_ID)?;
+        let writer = newtline.writer();
+        writer.put(TEST_KEY_B, Lsn(0x30), Value::Image(TEST_IMG("bar at 0x30")))?;
+        writer.finish_write(Lsn(0x30));
+        drop(writer);
+
+        // The timeline must not be in use
+        assert!(repo.detach_from_ancestor(NEW_TIMELINE_ID).is_err());
+        drop(newtline);
+        // Only branches can be detached
+        assert!(repo.detach_from_ancestor(TIMELINE_ID).is_err());
+
+        repo.detach_from_ancestor(NEW_TIMELINE_ID)?;
+
+        // The former parent can now be removed altogether
+        repo.detach_timeline(TIMELINE_ID)?;
+        fs::remove_dir_all(harness.timeline_path(&TIMELINE_ID))?;
+        drop(repo);
+
+        let repo = harness.load();
+        let newtline = repo.get_timeline_load(NEW_TIMELINE_ID)?;
+        assert_eq!(newtline.get_ancestor_timeline_id(), None);
+        // Nothing before the branch point was copied
+        assert_eq!(*newtline.get_latest_gc_cutoff_lsn(), Lsn(0x20));
+        assert_eq!(
+            newtline.get(TEST_KEY_A, Lsn(0x20))?,
+            TEST_IMG("foo at 0x20")
+        );
+        assert_eq!(
+            newtline.get(TEST_KEY_A, Lsn(0x30))?,
+            TEST_IMG("foo at 0x20")
+        );
+        assert_eq!(
+            newtline.get(TEST_KEY_B, Lsn(0x20))?,
+            TEST_IMG("bar at 0x10")
+        );
+        assert_eq!(
+            newtline.get(TEST_KEY_B, Lsn(0x30))?,
+            TEST_IMG("bar at 0x30")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_lsn_order() -> Result<()> {
+        let mut harness = RepoHarness::create("test_strict_lsn_order")?;
+        let mut conf = harness.conf.clone();
+        conf.strict_lsn_order = true;
+        harness.conf = Box::leak(Box::new(conf));
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        #[allow(non_snake_case)]
+        let TEST_KEY_B: Key = Key::from_hex("112222222233333333444444445500000002").unwrap();
+
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x20), Value::Image(TEST_IMG("foo at 0x20")))?;
+
+        // An older version of the same key is rejected, with either method
+        let err = writer
+            .put(TEST_KEY, Lsn(0x18), Value::Image(TEST_IMG("foo at 0x18")))
+            .expect_err("out-of-order write should fail");
+        assert!(
+            err.to_string().contains(&format!(
+                "write of key {} at 0/18 is older than its latest version at 0/20",
+                TEST_KEY
+            )),
+            "{err:#}"
+        );
+        // In strict mode, so is a write replacing the latest version
+        let err = writer
+            .put(TEST_KEY, Lsn(0x20), Value::Image(TEST_IMG("foo again")))
+            .expect_err("write at the same LSN should fail");
+        assert!(
+            err.to_string()
+                .contains("at 0/20 would replace its latest version"),
+            "{err:#}"
+        );
+        writer
+            .put_batch(
+                Lsn(0x18),
+                vec![
+                    (TEST_KEY_B, Value::Image(TEST_IMG("bar at 0x18"))),
+                    (TEST_KEY, Value::Image(TEST_IMG("foo at 0x18"))),
+                ],
+            )
+            .expect_err("out-of-order batch should fail");
+
+        // Other keys, and newer versions, are fine
+        writer.put(TEST_KEY_B, Lsn(0x18), Value::Image(TEST_IMG("bar at 0x18")))?;
+        writer.put(TEST_KEY, Lsn(0x28), Value::Image(TEST_IMG("foo at 0x28")))?;
+        writer.finish_write(Lsn(0x28));
+        drop(writer);
+
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x20))?, TEST_IMG("foo at 0x20"));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x28))?, TEST_IMG("foo at 0x28"));
+        assert_eq!(tline.get(TEST_KEY_B, Lsn(0x28))?, TEST_IMG("bar at 0x18"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_order_write_fails() -> Result<()> {
+        let repo = RepoHarness::create("test_out_of_order_write_fails")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x20), Value::Image(TEST_IMG("foo at 0x20")))?;
+        writer
+            .put(TEST_KEY, Lsn(0x18), Value::Image(TEST_IMG("foo at 0x18")))
+            .expect_err("out-of-order write should fail");
+
+        // A write at the same LSN replaces the latest version
+        writer.put(TEST_KEY, Lsn(0x20), Value::Image(TEST_IMG("foo again")))?;
+        writer.finish_write(Lsn(0x20));
+        drop(writer);
+
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x20))?, TEST_IMG("foo again"));
+
+        Ok(())
+    }
+
+    /// Writing keys with put_batch() must produce the same timeline as
+    /// writing them one by one.
+    #[test]
+    fn test_put_batch() -> Result<()> {
+        let repo = RepoHarness::create("test_put_batch")?.load();
+        let tline_single = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let tline_batch = repo.create_empty_timeline(NEW_TIMELINE_ID, Lsn(0))?;
+
+        let mut test_key = Key::from_hex("012222222233333333444444445500000000").unwrap();
+        let mut keys = Vec::new();
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+            let mut batch = Vec::new();
+            for blknum in 0..100 {
+                // Update a different subset of keys at each LSN
+                if u64::from(blknum) % 3 == (lsn.0 / 0x10) % 3 {
+                    continue;
+                }
+                test_key.field6 = blknum;
+                batch.push((
+                    test_key,
+                    Value::Image(TEST_IMG(&format!("{} at {}", blknum, lsn))),
+                ));
+            }
+            keys.extend(batch.iter().map(|(key, _)| *key));
+
+            let writer = tline_single.writer();
+            for (key, value) in batch.iter() {
+                writer.put(*key, lsn, value.clone())?;
+            }
+            writer.finish_write(lsn);
+            drop(writer);
+
+            let writer = tline_batch.writer();
+            writer.put_batch(lsn, batch)?;
+            writer.finish_write(lsn);
+        }
+        keys.sort();
+        keys.dedup();
+
+        for tline in [&tline_single, &tline_batch] {
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+        assert_eq!(
+            tline_single.get_last_record_lsn(),
+            tline_batch.get_last_record_lsn()
+        );
+        for lsn in [Lsn(0x10), Lsn(0x20), Lsn(0x30)] {
+            for key in keys.iter() {
+                match (tline_single.get(*key, lsn), tline_batch.get(*key, lsn)) {
+                    (Ok(single), Ok(batch)) => assert_eq!(single, batch),
+                    (Err(_), Err(_)) => {}
+                    (single, batch) => panic!(
+                        "{} at {}: individual put returned {:?}, put_batch returned {:?}",
+                        key, lsn, single, batch
+                    ),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// WAL redo manager that fails with an I/O error of the given kind, like
+    /// the real one does when the WAL redo process dies (BrokenPipe) or hangs
+    /// (TimedOut), on the given number of first requests.
+    struct FailingRedoManager {
+        error_kind: std::io::ErrorKind,
+        failures_left: AtomicUsize,
+        requests: AtomicUsize,
+    }
+
+    impl FailingRedoManager {
+        fn new(error_kind: std::io::ErrorKind, failures: usize) -> Self {
+            FailingRedoManager {
+                error_kind,
+                failures_left: AtomicUsize::new(failures),
+                requests: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl WalRedoManager for FailingRedoManager {
+        fn request_redo(
+            &self,
+            key: Key,
+            lsn: Lsn,
+            base_img: Option<Bytes>,
+            records: &[(Lsn, ZenithWalRecord)],
+        ) -> Result<Bytes, WalRedoError> {
+            self.requests.fetch_add(1, atomic::Ordering::Relaxed);
+            let failed = self
+                .failures_left
+                .fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |n| {
+                    n.checked_sub(1)
+                })
+                .is_ok();
+            if failed {
+                Err(WalRedoError::IoError(std::io::Error::new(
+                    self.error_kind,
+                    "WAL redo failed",
+                )))
+            } else {
+                TestRedoManager.request_redo(key, lsn, base_img, records)
+            }
+        }
+    }
+
+    #[test]
+    fn test_walredo_retry() -> Result<()> {
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        let record = ZenithWalRecord::Postgres {
+            will_init: true,
+            rec: Bytes::from_static(b"test record"),
+        };
+        let expected = TestRedoManager.request_redo(
+            TEST_KEY,
+            Lsn(0x10),
+            None,
+            &[(Lsn(0x10), record.clone())],
+        )?;
+
+        use std::io::ErrorKind::{BrokenPipe, TimedOut};
+        for (test_name, error_kind, failures, should_succeed, requests) in [
+            ("test_walredo_retry_ok", BrokenPipe, 0, true, 1),
+            ("test_walredo_retry_once", BrokenPipe, 1, true, 2),
+            ("test_walredo_retry_fail", BrokenPipe, 2, false, 2),
+            // A timed out request is not retried
+            ("test_walredo_retry_timeout", TimedOut, 1, false, 1),
+        ] {
+            let harness = RepoHarness::create(test_name)?;
+            let walredo_mgr = Arc::new(FailingRedoManager::new(error_kind, failures));
+            let repo = harness.try_load_with_redo_manager(walredo_mgr.clone())?;
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+            let writer = tline.writer();
+            writer.put(TEST_KEY, Lsn(0x10), Value::WalRecord(record.clone()))?;
+            writer.finish_write(Lsn(0x10));
+            drop(writer);
+
+            let result = tline.get(TEST_KEY, Lsn(0x10));
+            if should_succeed {
+                assert_eq!(result?, expected);
+            } else {
+                assert!(result.is_err());
+            }
+            assert_eq!(
+                walredo_mgr.requests.load(atomic::Ordering::Relaxed),
+                requests,
+                "{test_name}"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// A redo manager that takes a while to replay the records.
+    struct SlowRedoManager;
+
+    impl WalRedoManager for SlowRedoManager {
+        fn request_redo(
+            &self,
+            key: Key,
+            lsn: Lsn,
+            base_img: Option<Bytes>,
+            records: &[(Lsn, ZenithWalRecord)],
+        ) -> Result<Bytes, WalRedoError> {
+            std::thread::sleep(Duration::from_millis(20));
+            TestRedoManager.request_redo(key, lsn, base_img, records)
+        }
+    }
+
+    // A redo manager that produces pages with checksums, or with a stale
+    // checksum if 'corrupt' is set.
+    struct ChecksumRedoManager {
+        corrupt: bool,
+    }
+
+    impl WalRedoManager for ChecksumRedoManager {
+        fn request_redo(
+            &self,
+            key: Key,
+            _lsn: Lsn,
+            base_img: Option<Bytes>,
+            records: &[(Lsn, ZenithWalRecord)],
+        ) -> Result<Bytes, WalRedoError> {
+            let (_, blknum) = key_to_rel_block(key).unwrap();
+            let mut page = base_img.unwrap().to_vec();
+            page[100] = page[100].wrapping_add(records.len() as u8);
+            if !self.corrupt {
+                page_set_checksum(&mut page, pg_checksum_page(&page, blknum).unwrap());
+            }
+            Ok(Bytes::from(page))
+        }
+    }
+
+    #[test]
+    fn test_verify_page_checksums() -> Result<()> {
+        let rel = RelTag {
+            spcnode: 1663,
+            dbnode: 208101,
+            relnode: 2620,
+            forknum: 0,
+        };
+        let blknum = 5;
+        let key = crate::pgdatadir_mapping::rel_block_to_key(rel, blknum);
+
+        let mut base_img = vec![0u8; 8192];
+        // pd_upper, so that the page isn't new
+        base_img[14..16].copy_from_slice(&8192u16.to_le_bytes());
+        page_set_checksum(&mut base_img, pg_checksum_page(&base_img, blknum)?);
+
+        for (test_name, verify, corrupt, should_succeed) in [
+            ("test_verify_page_checksums_ok", true, false, true),
+            ("test_verify_page_checksums_corrupt", true, true, false),
+            ("test_verify_page_checksums_off", false, true, true),
+        ] {
+            let mut harness = RepoHarness::create(test_name)?;
+            let mut conf = harness.conf.clone();
+            conf.verify_page_checksums = verify;
+            harness.conf = Box::leak(Box::new(conf));
+            let repo =
+                harness.try_load_with_redo_manager(Arc::new(ChecksumRedoManager { corrupt }))?;
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+            let writer = tline.writer();
+            writer.put(key, Lsn(0x10), Value::Image(Bytes::from(base_img.clone())))?;
+            writer.finish_write(Lsn(0x10));
+            let record = ZenithWalRecord::Postgres {
+                will_init: false,
+                rec: Bytes::from_static(b"test record"),
+            };
+            writer.put(key, Lsn(0x20), Value::WalRecord(record))?;
+            writer.finish_write(Lsn(0x20));
+            drop(writer);
+
+            let keyspace = KeySpace {
+                ranges: vec![key..key.next()],
+            };
+            let result = tline.create_image_layer(&keyspace, Lsn(0x20));
+            if should_succeed {
+                result?;
+            } else {
+                let err = result.expect_err("corrupt page should be caught");
+                assert!(
+                    err.to_string().contains(&format!(
+                        "page checksum mismatch for rel {} blk {} at 0/20",
+                        rel, blknum
+                    )),
+                    "{err:#}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_last_record_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_subscribe_last_record_lsn")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let mut rx = tline.subscribe_last_record_lsn();
+        assert_eq!(*rx.borrow(), Lsn(0));
+        let subscriber = tokio::spawn(async move {
+            rx.changed().await.unwrap();
+            let lsn = *rx.borrow();
+            lsn
+        });
+
+        let writer = tline.writer();
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        let lsn = tokio::time::timeout(Duration::from_secs(10), subscriber).await??;
+        assert_eq!(lsn, Lsn(0x10));
+
+        // New subscribers start from the current LSN
+        assert_eq!(*tline.subscribe_last_record_lsn().borrow(), Lsn(0x10));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel_lsn_waits() -> Result<()> {
+        let repo = RepoHarness::create("test_cancel_lsn_waits")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let started = Instant::now();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let waiter = Arc::clone(&tline);
+        std::thread::spawn(move || tx.send(waiter.wait_lsn(Lsn(0x100))));
+
+        // Keep cancelling until the waiter has queued up and been woken
+        let res = loop {
+            tline.cancel_lsn_waits();
+            match rx.recv_timeout(Duration::from_millis(10)) {
+                Ok(res) => break res,
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(e) => panic!("waiter thread died: {e}"),
+            }
+        };
+        let err = res.expect_err("wait_lsn should fail");
+        assert!(
+            format!("{err:#}").contains("WAL receiver stopped"),
+            "{err:#}"
+        );
+        assert!(started.elapsed() < tline.conf.wait_lsn_timeout);
+
+        // Later waits work as usual
+        let writer = tline.writer();
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        tline.wait_lsn(Lsn(0x10))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_store() -> Result<()> {
+        let repo = RepoHarness::create("test_dump_store")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let rel = RelTag {
+            spcnode: 1663,
+            dbnode: 208101,
+            relnode: 2620,
+            forknum: 0,
+        };
+        let rel_key = crate::pgdatadir_mapping::rel_block_to_key(rel, 3);
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let mut written = HashSet::new();
+        let writer = tline.writer();
+        for lsn in [0x10, 0x20] {
+            writer.put(rel_key, Lsn(lsn), Value::Image(TEST_IMG("foo")))?;
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(TEST_IMG("bar")))?;
+            writer.finish_write(Lsn(lsn));
+            written.insert((rel_key.to_string(), Lsn(lsn).to_string()));
+            written.insert((TEST_KEY.to_string(), Lsn(lsn).to_string()));
+        }
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+
+        // Some more in the open layer
+        let writer = tline.writer();
+        let record = ZenithWalRecord::Postgres {
+            will_init: false,
+            rec: Bytes::from_static(b"test record"),
+        };
+        writer.put(rel_key, Lsn(0x30), Value::WalRecord(record))?;
+        writer.finish_write(Lsn(0x30));
+        written.insert((rel_key.to_string(), Lsn(0x30).to_string()));
+        drop(writer);
+
+        let mut buf = Vec::new();
+        tline.dump_store(&mut buf, true)?;
+
+        let mut dumped = HashSet::new();
+        for line in String::from_utf8(buf)?.lines() {
+            let entry: serde_json::Value = serde_json::from_str(line)?;
+            let key = entry["key"].as_str().unwrap().to_string();
+            let lsn = entry["lsn"].as_str().unwrap().to_string();
+            if key == rel_key.to_string() {
+                assert_eq!(entry["rel"], rel.to_string());
+                assert_eq!(entry["blknum"], 3);
+                let expected_kind = if lsn == Lsn(0x30).to_string() {
+                    "wal_record"
+                } else {
+                    "image"
+                };
+                assert_eq!(entry["kind"], expected_kind);
+            } else {
+                assert!(entry.get("rel").is_none());
+            }
+            assert!(entry["len"].as_u64().unwrap() > 0);
+            assert!(entry["crc32c"].is_u64());
+            dumped.insert((key, lsn));
+        }
+        assert_eq!(dumped, written);
+
+        // Without the values
+        let mut buf = Vec::new();
+        tline.dump_store(&mut buf, false)?;
+        let first: serde_json::Value =
+            serde_json::from_str(String::from_utf8(buf)?.lines().next().unwrap())?;
+        assert!(first.get("kind").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_coalesce_deltas() -> Result<()> {
+        let harness = RepoHarness::create("test_coalesce_deltas")?;
+        let redo_mgr = Arc::new(PostgresRedoManager::new(harness.conf, harness.tenant_id));
+        let repo = harness.try_load_with_redo_manager(redo_mgr.clone())?;
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        // Block 0 of CLOG segment 0
+        let key = Key {
+            field1: 0x01,
+            field2: 0,
+            field3: 1,
+            field4: 0,
+            field5: 0,
+            field6: 0,
+        };
+        let base_img = Bytes::from(vec![0u8; 8192]);
+        let committed = |xid, timestamp| ZenithWalRecord::ClogSetCommitted {
+            xids: vec![xid],
+            timestamp,
+        };
+        let records = vec![
+            (Lsn(0x20), committed(100, 1)),
+            (Lsn(0x30), committed(101, 2)),
+            (Lsn(0x40), committed(102, 3)),
+            (Lsn(0x50), committed(103, 4)),
+            (Lsn(0x60), committed(104, 5)),
+            (
+                Lsn(0x70),
+                ZenithWalRecord::ClogSetAborted { xids: vec![105] },
+            ),
+            (Lsn(0x80), committed(106, 6)),
+        ];
+
+        let writer = tline.writer();
+        writer.put(key, Lsn(0x10), Value::Image(base_img.clone()))?;
+        writer.finish_write(Lsn(0x10));
+        for (lsn, rec) in records.iter() {
+            writer.put(key, *lsn, Value::WalRecord(rec.clone()))?;
+            writer.finish_write(*lsn);
+        }
+        drop(writer);
+
+        // Nothing is combined above the GC cutoff
+        assert_eq!(tline.coalesce_deltas(key, Lsn(0x80))?, 0);
+
+        // A branch at 0x40 must still be readable
+        *tline.latest_gc_cutoff_lsn.write().unwrap() = Lsn(0x70);
+        tline.gc_info.write().unwrap().retain_lsns = vec![Lsn(0x40)];
+
+        // 0x20-0x40 and 0x50-0x60 are combined. The abort can't be combined
+        // with a commit, and 0x80 is above the cutoff.
+        assert_eq!(tline.coalesce_deltas(key, Lsn(0x80))?, 3);
+        assert_eq!(tline.coalesce_deltas(key, Lsn(0x80))?, 0);
+
+        let mut state = ValueReconstructState::new(None, 0);
+        tline.get_reconstruct_data(key, Lsn(0x80), &mut state)?;
+        assert_eq!(state.records.len(), 4);
+
+        // The combined chain reconstructs the same pages
+        for lsn in [Lsn(0x40), Lsn(0x70), Lsn(0x80)] {
+            let expected = redo_mgr.request_redo(
+                key,
+                lsn,
+                Some(base_img.clone()),
+                &records
+                    .iter()
+                    .filter(|(rec_lsn, _)| *rec_lsn <= lsn)
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            )?;
+            assert_eq!(tline.get(key, lsn)?, expected, "at {lsn}");
+        }
+
+        Ok(())
+    }
+
+    // A redo manager that appends the first byte of each record to the base
+    // image, and remembers how many records, and bytes of them, it got in
+    // each request.
+    #[derive(Default)]
+    struct BatchRecordingRedoManager {
+        batches: Mutex<Vec<(usize, usize)>>,
+    }
+
+    impl WalRedoManager for BatchRecordingRedoManager {
+        fn request_redo(
+            &self,
+            _key: Key,
+            _lsn: Lsn,
+            base_img: Option<Bytes>,
+            records: &[(Lsn, ZenithWalRecord)],
+        ) -> Result<Bytes, WalRedoError> {
+            let bytes = records.iter().map(|(_, rec)| rec.size()).sum();
+            self.batches.lock().unwrap().push((records.len(), bytes));
+            let mut img = base_img.unwrap().to_vec();
+            for (_, rec) in records {
+                match rec {
+                    ZenithWalRecord::Postgres { rec, .. } => img.push(rec[0]),
+                    _ => panic!("unexpected record"),
+                }
+            }
+            Ok(Bytes::from(img))
+        }
+    }
+
+    #[test]
+    fn test_max_redo_record_bytes() -> Result<()> {
+        let mut harness = RepoHarness::create("test_max_redo_record_bytes")?;
+        let mut conf = harness.conf.clone();
+        conf.max_redo_record_bytes = 1_000_000;
+        harness.conf = Box::leak(Box::new(conf));
+        let walredo_mgr = Arc::new(BatchRecordingRedoManager::default());
+        let repo = harness.try_load_with_redo_manager(walredo_mgr.clone())?;
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        // A few huge records. The last one is over the limit by itself.
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo")))?;
+        writer.finish_write(Lsn(0x10));
+        let sizes = [600_000, 300_000, 300_000, 900_000, 1_500_000];
+        for (i, size) in sizes.into_iter().enumerate() {
+            let lsn = Lsn(0x20 + 0x10 * i as u64);
+            let record = ZenithWalRecord::Postgres {
+                will_init: false,
+                rec: Bytes::from(vec![i as u8 + 1; size]),
+            };
+            writer.put(TEST_KEY, lsn, Value::WalRecord(record))?;
+            writer.finish_write(lsn);
+        }
+        drop(writer);
+
+        // Collecting the records stops at 1 MB, except that the first one is
+        // always taken, however large. Only the newest record is held then.
+        let mut state = ValueReconstructState::new(None, 1_000_000);
+        tline.get_reconstruct_data(TEST_KEY, Lsn(0x60), &mut state)?;
+        assert!(state.capped);
+        assert_eq!(state.records.len(), 1);
+        assert_eq!(state.record_bytes, 1_500_000);
+
+        // Starting from an intermediate image, only the records above it are
+        // collected.
+        let mut state = ValueReconstructState::new(Some((Lsn(0x2F), TEST_IMG("bar"))), 1_000_000);
+        tline.get_reconstruct_data(TEST_KEY, Lsn(0x4F), &mut state)?;
+        assert!(!state.capped);
+        assert_eq!(state.records.len(), 2);
+        assert_eq!(state.record_bytes, 600_000);
+
+        // The page is reconstructed from the oldest batch up, with at most
+        // 1 MB of records in memory and in each redo request, except for the
+        // record that is larger than that. The result is the same as applying
+        // them all at once.
+        let img = tline.get(TEST_KEY, Lsn(0x60))?;
+        let mut expected = TEST_IMG("foo").to_vec();
+        expected.extend([1, 2, 3, 4, 5]);
+        assert_eq!(img, expected);
+        assert_eq!(
+            *walredo_mgr.batches.lock().unwrap(),
+            vec![(1, 600_000), (2, 600_000), (1, 900_000), (1, 1_500_000)]
+        );
+
+        // The page was stored back, so the next reads don't need the records
+        let plan = tline.explain_get(TEST_KEY, Lsn(0x60))?;
+        assert_eq!(plan.base_image_lsn, Some(Lsn(0x60)));
+        assert!(plan.record_lsns.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_materialize_on_read() -> Result<()> {
+        let mut harness = RepoHarness::create("test_materialize_on_read")?;
+        let mut conf = harness.conf.clone();
+        conf.materialize_on_read_threshold = 3;
+        harness.conf = Box::leak(Box::new(conf));
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        #[allow(non_snake_case)]
+        let TEST_KEY_B: Key = Key::from_hex("112222222233333333444444445500000002").unwrap();
+
+        let writer = tline.writer();
+        for lsn in [0x10, 0x20, 0x30, 0x40] {
+            let record = ZenithWalRecord::Postgres {
+                will_init: lsn == 0x10,
+                rec: Bytes::from_static(b"test record"),
+            };
+            writer.put(TEST_KEY, Lsn(lsn), Value::WalRecord(record.clone()))?;
+            writer.put(TEST_KEY_B, Lsn(lsn), Value::WalRecord(record))?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+
+        // Reading an older version doesn't materialize anything
+        tline.get(TEST_KEY_B, Lsn(0x30))?;
+        let plan = tline.explain_get(TEST_KEY_B, Lsn(0x40))?;
+        assert_eq!(plan.base_image_lsn, None);
+        assert_eq!(plan.record_lsns.len(), 4);
+
+        // Reading the latest version stores the page, and the next read of
+        // it starts from there
+        let img = tline.get(TEST_KEY, Lsn(0x40))?;
+        let plan = tline.explain_get(TEST_KEY, Lsn(0x40))?;
+        assert_eq!(plan.base_image_lsn, Some(Lsn(0x40)));
+        assert!(plan.record_lsns.is_empty());
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x40))?, img);
+
+        // The older versions are still there
+        let plan = tline.explain_get(TEST_KEY, Lsn(0x30))?;
+        assert_eq!(plan.record_lsns.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_broken_chain_error() -> Result<()> {
+        let repo = RepoHarness::create("test_broken_chain_error")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        // Records that don't initialize the page, and no base image
+        let record = ZenithWalRecord::Postgres {
+            will_init: false,
+            rec: Bytes::from_static(b"test record"),
+        };
+        let mut data = ValueReconstructState::new(None, 0);
+        data.push_record(Lsn(0x30), record.clone());
+        data.push_record(Lsn(0x20), record.clone());
+        data.push_record(Lsn(0x10), record);
+        let err = tline
+            .reconstruct_value(TEST_KEY, Lsn(0x30), data, true)
+            .expect_err("reconstruction should fail");
+        let msg = format!("{err:#}");
+        assert!(msg.contains("3 WAL records"), "{msg}");
+        assert!(msg.contains("0/10..=0/30"), "{msg}");
+        assert!(msg.contains("ancestor consulted"), "{msg}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slow_getpage() -> Result<()> {
+        let mut harness = RepoHarness::create("test_slow_getpage")?;
+        let mut conf = harness.conf.clone();
+        conf.slow_getpage_threshold = Duration::from_millis(1);
+        harness.conf = Box::leak(Box::new(conf));
+        let repo = harness.try_load_with_redo_manager(Arc::new(SlowRedoManager))?;
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        #[allow(non_snake_case)]
+        let TEST_KEY_B: Key = Key::from_hex("112222222233333333444444445500000002").unwrap();
+
+        let record = Value::WalRecord(ZenithWalRecord::Postgres {
+            will_init: true,
+            rec: Bytes::from_static(b"test record"),
+        });
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x10), record.clone())?;
+        writer.put(TEST_KEY_B, Lsn(0x10), record)?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+
+        tline.get(TEST_KEY, Lsn(0x10))?;
+        assert_eq!(tline.slow_getpage_counter.get(), 1);
+        assert!(tline.last_slow_getpage_warning.lock().unwrap().0.is_some());
+
+        // The second slow read is counted, but not logged so soon after the first
+        tline.get(TEST_KEY_B, Lsn(0x10))?;
+        assert_eq!(tline.slow_getpage_counter.get(), 2);
+        assert_eq!(tline.last_slow_getpage_warning.lock().unwrap().1, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rel_access_stats() -> Result<()> {
+        let mut harness = RepoHarness::create("test_rel_access_stats")?;
+        let mut conf = harness.conf.clone();
+        conf.rel_access_stats = true;
+        harness.conf = Box::leak(Box::new(conf));
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        let rel_a = RelTag {
+            spcnode: 1663,
+            dbnode: 111,
+            relnode: 1000,
+            forknum: 0,
+        };
+        let rel_b = RelTag {
+            relnode: 1001,
+            ..rel_a
+        };
+        let block_key = |rel: RelTag, blknum: u32| {
+            Key::from_hex(&format!(
+                "00{:08X}{:08X}{:08X}{:02X}{:08X}",
+                rel.spcnode, rel.dbnode, rel.relnode, rel.forknum, blknum
+            ))
+            .unwrap()
+        };
+        let key_a = block_key(rel_a, 0);
+        let key_b = block_key(rel_b, 3);
+        assert_eq!(key_to_rel_block(key_b)?, (rel_b, 3));
+
+        let writer = tline.writer();
+        writer.put(key_a, Lsn(0x10), Value::Image(TEST_IMG("a at 0x10")))?;
+        writer.put(key_b, Lsn(0x10), Value::Image(TEST_IMG("b at 0x10")))?;
+        writer.finish_write(Lsn(0x10));
+        let record = ZenithWalRecord::Postgres {
+            will_init: false,
+            rec: Bytes::from_static(b"test record"),
+        };
+        writer.put(key_a, Lsn(0x20), Value::WalRecord(record))?;
+        writer.finish_write(Lsn(0x20));
+        drop(writer);
+
+        tline.get(key_a, Lsn(0x10))?;
+        tline.get(key_a, Lsn(0x20))?;
+        tline.get(key_b, Lsn(0x20))?;
+
+        let mut stats = tline.rel_access_stats();
+        stats.sort_by_key(|(rel, _)| rel.relnode);
+        assert_eq!(
+            stats,
+            vec![
+                (
+                    rel_a,
+                    AccessStats {
+                        reads: 2,
+                        reconstructs: 1,
+                        reconstruct_records: 1,
+                    }
+                ),
+                (
+                    rel_b,
+                    AccessStats {
+                        reads: 1,
+                        reconstructs: 0,
+                        reconstruct_records: 0,
+                    }
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_json() -> Result<()> {
+        let repo = RepoHarness::create("test_metadata_json")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x1_0000_0040), Value::Image(TEST_IMG("foo")))?;
+        writer.finish_write(Lsn(0x1_0000_0040));
+        drop(writer);
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x1_0000_0040))?;
+
+        assert_eq!(
+            repo.metadata_json(TIMELINE_ID)?,
+            serde_json::json!({
+                "disk_consistent_lsn": "0/0",
+                "prev_record_lsn": null,
+                "ancestor_timeline": null,
+                "ancestor_lsn": "0/0",
+                "latest_gc_cutoff_lsn": "0/0",
+                "initdb_lsn": "0/0",
+            })
+        );
+        assert_eq!(
+            repo.metadata_json(NEW_TIMELINE_ID)?,
+            serde_json::json!({
+                "disk_consistent_lsn": "1/40",
+                "prev_record_lsn": "0/0",
+                "ancestor_timeline": TIMELINE_ID.to_string(),
+                "ancestor_lsn": "1/40",
+                "latest_gc_cutoff_lsn": "0/0",
+                "initdb_lsn": "0/0",
+            })
+        );
+        assert!(repo.metadata_json(ZTimelineId::generate()).is_err());
+
+        Ok(())
+    }
+
+    // Target file size in the unit tests. In production, the target
+    // file size is much larger, maybe 1 GB. But a small size makes it
+    // much faster to exercise all the logic for creating the files,
+    // garbage collection, compaction etc.
+    pub const TEST_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+    #[test]
+    fn test_images() -> Result<()> {
+        let repo = RepoHarness::create("test_images")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let writer = tline.writer();
         writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo at 0x10")))?;
         writer.finish_write(Lsn(0x10));
         drop(writer);
@@ -2645,6 +6412,649 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_checkpoint_metrics() -> Result<()> {
+        let mut harness = RepoHarness::create("test_checkpoint_metrics")?;
+        harness.tenant_conf.image_creation_threshold = 1;
+        let repo = Arc::new(harness.load());
+        // Compaction looks up the timeline's partitioning through tenant_mgr.
+        crate::tenant_mgr::register_test_tenant(harness.tenant_id, Arc::clone(&repo));
+
+        let labels = [harness.tenant_id.to_string(), TIMELINE_ID.to_string()];
+        let labels = [labels[0].as_str(), labels[1].as_str()];
+        let pages_materialized = || {
+            CHECKPOINT_PAGES_MATERIALIZED
+                .with_label_values(&labels)
+                .get()
+        };
+        let bytes_written = || CHECKPOINT_BYTES_WRITTEN.with_label_values(&labels).get();
+        let last_completed = || LAST_CHECKPOINT_COMPLETED.with_label_values(&labels).get();
+
+        let tline: DatadirTimeline<LayeredRepository> =
+            DatadirTimeline::new(repo.create_empty_timeline(TIMELINE_ID, Lsn(8))?, 256 * 1024);
+        let mut m = tline.begin_modification(Lsn(8));
+        m.init_empty()?;
+        m.put_control_file(TEST_IMG("control file"))?;
+        m.put_checkpoint(TEST_IMG("checkpoint"))?;
+        m.commit()?;
+
+        let rel = RelTag {
+            spcnode: 1663,
+            dbnode: 111,
+            relnode: 1000,
+            forknum: 0,
+        };
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_relmap_file(rel.spcnode, rel.dbnode, TEST_IMG("relmap"))?;
+        m.put_rel_creation(rel, 2)?;
+        m.put_rel_page_image(rel, 0, TEST_IMG("blk 0 at 0x20"))?;
+        m.put_rel_page_image(rel, 1, TEST_IMG("blk 1 at 0x20"))?;
+        m.commit()?;
+
+        // Flushing the in-memory layer writes a delta layer
+        assert_eq!(last_completed(), 0);
+        tline.tline.checkpoint(CheckpointConfig::Flush)?;
+        assert!(bytes_written() > 0);
+        assert!(last_completed() > 0);
+        assert_eq!(pages_materialized(), 0);
+
+        // With a delta to materialize, a forced checkpoint creates an image layer
+        let bytes_before = bytes_written();
+        tline.tline.checkpoint(CheckpointConfig::Forced)?;
+        assert!(pages_materialized() > 0);
+        assert!(bytes_written() > bytes_before);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebase_loaded_timeline() -> Result<()> {
+        let harness = RepoHarness::create("test_rebase_loaded_timeline")?;
+        let repo = Arc::new(harness.load());
+        crate::tenant_mgr::register_test_tenant(harness.tenant_id, Arc::clone(&repo));
+
+        let tline: DatadirTimeline<LayeredRepository> =
+            DatadirTimeline::new(repo.create_empty_timeline(TIMELINE_ID, Lsn(8))?, 256 * 1024);
+        let mut m = tline.begin_modification(Lsn(8));
+        m.init_empty()?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_checkpoint(TEST_IMG("checkpoint at 0x20"))?;
+        m.commit()?;
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x20))?;
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_checkpoint(TEST_IMG("checkpoint at 0x30"))?;
+        m.commit()?;
+
+        // The branch is loaded and running, like any timeline a compute uses
+        let newtline =
+            crate::tenant_mgr::get_local_timeline_with_load(harness.tenant_id, NEW_TIMELINE_ID)?;
+        drop(newtline);
+        let err = repo
+            .rebase_branch(NEW_TIMELINE_ID, Lsn(0x30))
+            .expect_err("loaded timeline should be in use");
+        assert!(err.to_string().contains("is in use"), "{err:#}");
+
+        crate::tenant_mgr::rebase_timeline(harness.tenant_id, NEW_TIMELINE_ID, Lsn(0x30))?;
+        let newtline =
+            crate::tenant_mgr::get_local_timeline_with_load(harness.tenant_id, NEW_TIMELINE_ID)?;
+        assert_eq!(newtline.tline.get_ancestor_lsn(), Lsn(0x30));
+        assert_eq!(
+            newtline.get_checkpoint(Lsn(0x30))?,
+            TEST_IMG("checkpoint at 0x30")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_detach_loaded_timeline() -> Result<()> {
+        let harness = RepoHarness::create("test_detach_loaded_timeline")?;
+        let repo = Arc::new(harness.load());
+        crate::tenant_mgr::register_test_tenant(harness.tenant_id, Arc::clone(&repo));
+
+        let tline: DatadirTimeline<LayeredRepository> =
+            DatadirTimeline::new(repo.create_empty_timeline(TIMELINE_ID, Lsn(8))?, 256 * 1024);
+        let mut m = tline.begin_modification(Lsn(8));
+        m.init_empty()?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_checkpoint(TEST_IMG("checkpoint at 0x20"))?;
+        m.commit()?;
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x20))?;
+
+        // The branch is loaded and running, like any timeline a compute uses
+        let newtline =
+            crate::tenant_mgr::get_local_timeline_with_load(harness.tenant_id, NEW_TIMELINE_ID)?;
+        drop(newtline);
+        let err = repo
+            .detach_from_ancestor(NEW_TIMELINE_ID)
+            .expect_err("loaded timeline should be in use");
+        assert!(err.to_string().contains("is in use"), "{err:#}");
+
+        crate::tenant_mgr::detach_timeline_from_ancestor(harness.tenant_id, NEW_TIMELINE_ID)?;
+        let newtline =
+            crate::tenant_mgr::get_local_timeline_with_load(harness.tenant_id, NEW_TIMELINE_ID)?;
+        assert_eq!(newtline.tline.get_ancestor_timeline_id(), None);
+        assert_eq!(
+            newtline.get_checkpoint(Lsn(0x20))?,
+            TEST_IMG("checkpoint at 0x20")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction_removes_truncated_blocks() -> Result<()> {
+        let mut harness = RepoHarness::create("test_compaction_removes_truncated_blocks")?;
+        harness.tenant_conf.compaction_threshold = 1;
+        let repo = Arc::new(harness.load());
+        // Compaction looks up the timeline's partitioning through tenant_mgr.
+        crate::tenant_mgr::register_test_tenant(harness.tenant_id, Arc::clone(&repo));
+
+        let tline: DatadirTimeline<LayeredRepository> =
+            DatadirTimeline::new(repo.create_empty_timeline(TIMELINE_ID, Lsn(8))?, 256 * 1024);
+        let mut m = tline.begin_modification(Lsn(8));
+        m.init_empty()?;
+        m.put_control_file(TEST_IMG("control file"))?;
+        m.put_checkpoint(TEST_IMG("checkpoint"))?;
+        m.commit()?;
+
+        let rel_a = RelTag {
+            spcnode: 1663,
+            dbnode: 111,
+            relnode: 1000,
+            forknum: 0,
+        };
+        let rel_b = RelTag {
+            relnode: 1001,
+            ..rel_a
+        };
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_relmap_file(rel_a.spcnode, rel_a.dbnode, TEST_IMG("relmap"))?;
+        m.put_rel_creation(rel_a, 4)?;
+        for blknum in 0..4 {
+            m.put_rel_page_image(rel_a, blknum, TEST_IMG(&format!("a {} at 0x20", blknum)))?;
+        }
+        m.commit()?;
+
+        // The branch still sees all the blocks of rel_a
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x20))?;
+
+        let mut m = tline.begin_modification(Lsn(0x28));
+        m.put_rel_creation(rel_b, 4)?;
+        for blknum in 0..4 {
+            m.put_rel_page_image(rel_b, blknum, TEST_IMG(&format!("b {} at 0x28", blknum)))?;
+        }
+        m.commit()?;
+
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_truncation(rel_a, 1)?;
+        m.put_rel_truncation(rel_b, 1)?;
+        m.commit()?;
+
+        let mut m = tline.begin_modification(Lsn(0x40));
+        m.put_rel_page_image(rel_b, 0, TEST_IMG("b 0 at 0x40"))?;
+        m.commit()?;
+
+        // Pretend that GC has advanced the cutoff past the truncation.
+        *tline.tline.latest_gc_cutoff_lsn.write().unwrap() = Lsn(0x38);
+        tline
+            .tline
+            .update_gc_info(vec![Lsn(0x20)], Lsn(0x38), Duration::ZERO);
+
+        tline.tline.checkpoint(CheckpointConfig::Forced)?;
+
+        let mut blocks_a = HashSet::new();
+        let mut blocks_b = HashSet::new();
+        let layers = tline.tline.layers.read();
+        for layer in layers.iter_historic_layers() {
+            if !layer.is_incremental() {
+                continue;
+            }
+            for x in layer.iter() {
+                let (key, _lsn, _value) = x?;
+                if !is_rel_block_key(key) {
+                    continue;
+                }
+                let (rel, blknum) = key_to_rel_block(key)?;
+                if rel == rel_a {
+                    blocks_a.insert(blknum);
+                } else if rel == rel_b {
+                    blocks_b.insert(blknum);
+                }
+            }
+        }
+        drop(layers);
+
+        // Blocks of rel_b beyond the truncation point are gone, but rel_a's are
+        // kept, because the branch was created before the truncation.
+        assert_eq!(blocks_a, HashSet::from([0, 1, 2, 3]));
+        assert_eq!(blocks_b, HashSet::from([0]));
+
+        let branch: DatadirTimeline<LayeredRepository> =
+            DatadirTimeline::new(repo.get_timeline_load(NEW_TIMELINE_ID)?, 256 * 1024);
+        assert_eq!(
+            branch.get_rel_page_at_lsn(rel_a, 3, Lsn(0x20))?,
+            TEST_IMG("a 3 at 0x20")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_retained_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_min_retained_lsn")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        assert_eq!(repo.min_retained_lsn()?, Lsn::MAX);
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let writer = tline.writer();
+        for lsn in [0x10, 0x20, 0x30, 0x40] {
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(TEST_IMG("foo")))?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+
+        //  main ---+------+----->
+        //          |      |
+        //          |      +--- branch_a (0x30) ---+---->
+        //          |                              |
+        //          |                              +--- branch_c (0x30)
+        //          +--- branch_b (0x20)
+        let branch_a = ZTimelineId::generate();
+        let branch_b = ZTimelineId::generate();
+        let branch_c = ZTimelineId::generate();
+        repo.branch_timeline(TIMELINE_ID, branch_a, Lsn(0x30))?;
+        assert_eq!(repo.min_retained_lsn()?, Lsn(0x30));
+        repo.branch_timeline(branch_a, branch_c, Lsn(0x30))?;
+        assert_eq!(repo.min_retained_lsn()?, Lsn(0x30));
+        repo.branch_timeline(TIMELINE_ID, branch_b, Lsn(0x20))?;
+        assert_eq!(repo.min_retained_lsn()?, Lsn(0x20));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_timelines_per_tenant() -> Result<()> {
+        let mut harness = RepoHarness::create("test_max_timelines_per_tenant")?;
+        let mut conf = harness.conf.clone();
+        conf.max_timelines_per_tenant = 3;
+        harness.conf = Box::leak(Box::new(conf));
+        let repo = harness.load();
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x10), Value::Image(TEST_IMG("foo")))?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+
+        // Up to the limit, both creation paths work
+        repo.create_empty_timeline(NEW_TIMELINE_ID, Lsn(0))?;
+        repo.branch_timeline(TIMELINE_ID, ZTimelineId::generate(), Lsn(0x10))?;
+
+        // The next one fails, whichever way it's created
+        let err = repo
+            .branch_timeline(TIMELINE_ID, ZTimelineId::generate(), Lsn(0x10))
+            .expect_err("branching should fail");
+        assert!(
+            matches!(
+                err.downcast_ref::<TooManyTimelines>(),
+                Some(TooManyTimelines { limit: 3, .. })
+            ),
+            "{err:?}"
+        );
+        let err = repo
+            .create_empty_timeline(ZTimelineId::generate(), Lsn(0))
+            .expect_err("timeline creation should fail");
+        assert!(err.downcast_ref::<TooManyTimelines>().is_some(), "{err:?}");
+        assert_eq!(repo.list_timelines().len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_children() -> Result<()> {
+        let repo = RepoHarness::create("test_list_children")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        assert!(repo.list_children(TIMELINE_ID)?.is_empty());
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let writer = tline.writer();
+        for lsn in [0x10, 0x20, 0x30] {
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(TEST_IMG("foo")))?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+
+        let branch_a = ZTimelineId::generate();
+        let branch_b = ZTimelineId::generate();
+        let grandchild = ZTimelineId::generate();
+        repo.branch_timeline(TIMELINE_ID, branch_a, Lsn(0x30))?;
+        repo.branch_timeline(TIMELINE_ID, branch_b, Lsn(0x20))?;
+        repo.branch_timeline(branch_a, grandchild, Lsn(0x30))?;
+
+        // Only the direct children are returned
+        assert_eq!(
+            repo.list_children(TIMELINE_ID)?,
+            vec![(branch_b, Lsn(0x20)), (branch_a, Lsn(0x30))]
+        );
+        assert_eq!(repo.list_children(branch_a)?, vec![(grandchild, Lsn(0x30))]);
+        assert!(repo.list_children(branch_b)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_parallelism() -> Result<()> {
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        let timeline_ids = [
+            TIMELINE_ID,
+            NEW_TIMELINE_ID,
+            ZTimelineId::generate(),
+            ZTimelineId::generate(),
+        ];
+
+        // Build the same set of timelines in two repositories, and GC them
+        // sequentially and in parallel. The results must be the same.
+        let mut results = Vec::new();
+        for (gc_parallelism, test_name) in
+            [(1, "test_gc_parallelism_1"), (4, "test_gc_parallelism_4")]
+        {
+            // Fail points are global, use an exclusive harness so that the
+            // other tests don't hit them.
+            let mut harness = RepoHarness::create_exclusive(test_name)?;
+            let mut conf = harness.conf.clone();
+            conf.gc_parallelism = gc_parallelism;
+            harness.conf = Box::leak(Box::new(conf));
+            let repo = Arc::new(harness.load());
+
+            for timeline_id in timeline_ids {
+                let tline = repo.create_empty_timeline(timeline_id, Lsn(0))?;
+                for lsn in [0x10, 0x20, 0x30, 0x40] {
+                    let writer = tline.writer();
+                    writer.put(
+                        TEST_KEY,
+                        Lsn(lsn),
+                        Value::Image(TEST_IMG(&format!("foo at {lsn}"))),
+                    )?;
+                    writer.finish_write(Lsn(lsn));
+                    drop(writer);
+                    tline.checkpoint(CheckpointConfig::Forced)?;
+                }
+            }
+
+            // The timelines lock must not be held while the timelines are GC'd
+            let scenario = fail::FailScenario::setup();
+            let calls = Arc::new(AtomicUsize::new(0));
+            {
+                let repo = Arc::clone(&repo);
+                let calls = Arc::clone(&calls);
+                fail::cfg_callback("gc-before-timeline", move || {
+                    assert!(repo.timelines.try_lock().is_ok());
+                    calls.fetch_add(1, atomic::Ordering::Relaxed);
+                })
+                .unwrap();
+            }
+            let result = repo.gc_iteration(None, 0x10, Duration::ZERO, false);
+            scenario.teardown();
+            let result = result?;
+            assert!(calls.load(atomic::Ordering::Relaxed) >= timeline_ids.len());
+
+            let cutoffs: Vec<Lsn> = timeline_ids
+                .iter()
+                .map(|timeline_id| {
+                    let tline = repo.get_timeline_load(*timeline_id).unwrap();
+                    let cutoff = *tline.get_latest_gc_cutoff_lsn();
+                    cutoff
+                })
+                .collect();
+            results.push((
+                result.layers_total,
+                result.layers_needed_by_cutoff,
+                result.layers_needed_by_branches,
+                result.layers_removed,
+                cutoffs,
+            ));
+        }
+        assert_eq!(results[0], results[1]);
+        assert!(results[0].0 > 0, "expected some layers to be considered");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_tenant_integrity() -> Result<()> {
+        let harness = RepoHarness::create("test_check_tenant_integrity")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+        let writer = tline.writer();
+        for lsn in [0x10, 0x20, 0x30, 0x40] {
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(TEST_IMG("foo")))?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+
+        //  main ---+---> NEW_TIMELINE_ID ---+---> branch_b
+        let branch_b = ZTimelineId::generate();
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x40))?;
+        repo.branch_timeline(NEW_TIMELINE_ID, branch_b, Lsn(0x40))?;
+
+        let report = repo.check_tenant_integrity()?;
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.timelines_checked, 3);
+
+        // Remove the middle timeline
+        fs::remove_dir_all(harness.timeline_path(&NEW_TIMELINE_ID))?;
+
+        let report = repo.check_tenant_integrity()?;
+        assert!(!report.is_ok());
+        assert_eq!(report.timelines_checked, 2);
+        assert!(report.broken_metadata.is_empty());
+        assert_eq!(report.dangling_ancestors, vec![(branch_b, NEW_TIMELINE_ID)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tenant_io_limiter() -> Result<()> {
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000000").unwrap();
+
+        // Write 40 full page images, ~320 kB, and flush them to disk
+        fn write_and_flush(repo: &LayeredRepository, key: Key) -> Result<()> {
+            let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+            let writer = tline.writer();
+            let img = Bytes::from(vec![0u8; 8192]);
+            let mut key = key;
+            for _ in 0..40 {
+                writer.put(key, Lsn(0x10), Value::Image(img.clone()))?;
+                key = key.next();
+            }
+            writer.finish_write(Lsn(0x10));
+            drop(writer);
+
+            tline.checkpoint(CheckpointConfig::Flush)
+        }
+
+        // All the layer file writes go through the tenant's limiter. The rate
+        // is high enough that nothing has to wait.
+        let limiter = Arc::new(rate_limiter::RateLimiter::new(1024 * 1024 * 1024));
+        let repo = RepoHarness::create("test_tenant_io_limiter")?
+            .load_with_io_limiter(Arc::clone(&limiter));
+        write_and_flush(&repo, TEST_KEY)?;
+        let acquired = limiter.total_acquired();
+        assert!(acquired >= 40 * 8192, "{}", acquired);
+
+        // Another tenant's writes don't count against it
+        let other_repo = RepoHarness::create("test_tenant_io_limiter_other")?.load();
+        write_and_flush(&other_repo, TEST_KEY)?;
+        assert_eq!(limiter.total_acquired(), acquired);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tenant_io_limiter_gc() -> Result<()> {
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000000").unwrap();
+
+        // Writing the layers isn't throttled, only the GC deletions
+        let limiter = Arc::new(rate_limiter::RateLimiter::new(1024 * 1024 * 1024));
+        // Fail points are global, use an exclusive harness so that the other
+        // tests don't hit them.
+        let harness = RepoHarness::create_exclusive("test_tenant_io_limiter_gc")?;
+        let repo = harness.load_with_io_limiter(Arc::clone(&limiter));
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let img = Bytes::from(vec![0u8; 8192]);
+        for lsn in [0x10, 0x20, 0x30, 0x40, 0x50] {
+            let writer = tline.writer();
+            let mut key = TEST_KEY;
+            for _ in 0..10 {
+                writer.put(key, Lsn(lsn), Value::Image(img.clone()))?;
+                key = key.next();
+            }
+            writer.finish_write(Lsn(lsn));
+            drop(writer);
+            tline.checkpoint(CheckpointConfig::Forced)?;
+        }
+        // An image layer makes the older delta layers garbage
+        let keyspace = KeySpace {
+            ranges: vec![TEST_KEY..TEST_KEY.add(10)],
+        };
+        tline.create_image_layer(&keyspace, Lsn(0x40))?;
+
+        // Reads must not wait for the throttled deletions
+        let scenario = fail::FailScenario::setup();
+        {
+            let tline = Arc::clone(&tline);
+            let img = img.clone();
+            fail::cfg_callback("gc-before-layer-delete", move || {
+                let (tx, rx) = std::sync::mpsc::channel();
+                let tline = Arc::clone(&tline);
+                std::thread::spawn(move || {
+                    let _ = tx.send(tline.get(TEST_KEY, Lsn(0x50)).unwrap());
+                });
+                let page = rx
+                    .recv_timeout(Duration::from_secs(10))
+                    .expect("read blocked by GC");
+                assert_eq!(page, img);
+            })
+            .unwrap();
+        }
+
+        const RATE: u64 = 64 * 1024;
+        limiter.set_rate(RATE);
+        let acquired_before = limiter.total_acquired();
+        let start = Instant::now();
+        let result = repo.gc_iteration(Some(TIMELINE_ID), 0, Duration::ZERO, false);
+        let elapsed = start.elapsed();
+        scenario.teardown();
+        let result = result?;
+
+        // The bucket holds at most one second worth of tokens, the rest of
+        // the deleted bytes had to wait for it to refill.
+        let acquired = limiter.total_acquired() - acquired_before;
+        assert!(result.layers_removed > 0);
+        assert!(acquired >= 2 * RATE, "{}", acquired);
+        let min_elapsed = Duration::from_secs_f64((acquired - RATE) as f64 / RATE as f64);
+        assert!(elapsed >= min_elapsed, "{:?} < {:?}", elapsed, min_elapsed);
+
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x50))?, img);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_to_lsn() -> Result<()> {
+        let harness = RepoHarness::create("test_reset_to_lsn")?;
+        let repo = harness.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        // One delta layer spanning the reset point, and an open layer after it
+        let writer = tline.writer();
+        for lsn in [0x10, 0x20, 0x30, 0x40] {
+            let img = TEST_IMG(&format!("foo at {:#x}", lsn));
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(img))?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Flush)?;
+        let writer = tline.writer();
+        writer.put(TEST_KEY, Lsn(0x50), Value::Image(TEST_IMG("foo at 0x50")))?;
+        writer.finish_write(Lsn(0x50));
+        drop(writer);
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x50))?, TEST_IMG("foo at 0x50"));
+
+        // A stand-in for the timeline's WAL receiver, which must be stopped
+        let receiver_stopped = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&receiver_stopped);
+        thread_mgr::spawn(
+            thread_mgr::ThreadKind::WalReceiver,
+            Some(harness.tenant_id),
+            Some(TIMELINE_ID),
+            "test WAL receiver",
+            false,
+            move || {
+                while !thread_mgr::sleep_until_shutdown(Duration::from_secs(60)) {}
+                stopped.store(true, atomic::Ordering::SeqCst);
+                Ok(())
+            },
+        )?;
+
+        tline.reset_to_lsn(Lsn(0x20))?;
+        assert!(receiver_stopped.load(atomic::Ordering::SeqCst));
+        assert_eq!(tline.get_last_record_lsn(), Lsn(0x20));
+        assert_eq!(tline.get_disk_consistent_lsn(), Lsn(0x20));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x20))?, TEST_IMG("foo at 0x20"));
+        let metadata = load_metadata(harness.conf, TIMELINE_ID, harness.tenant_id)?;
+        assert_eq!(metadata.disk_consistent_lsn(), Lsn(0x20));
+        assert!(tline
+            .layers
+            .read()
+            .iter_historic_layers()
+            .all(|l| l.get_lsn_range().end <= Lsn(0x21)));
+
+        // Re-ingest different page versions after the reset point
+        let writer = tline.writer();
+        for lsn in [0x30, 0x40, 0x50] {
+            let img = TEST_IMG(&format!("bar at {:#x}", lsn));
+            writer.put(TEST_KEY, Lsn(lsn), Value::Image(img))?;
+            writer.finish_write(Lsn(lsn));
+        }
+        drop(writer);
+        tline.checkpoint(CheckpointConfig::Forced)?;
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x10))?, TEST_IMG("foo at 0x10"));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x20))?, TEST_IMG("foo at 0x20"));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x30))?, TEST_IMG("bar at 0x30"));
+        assert_eq!(tline.get(TEST_KEY, Lsn(0x50))?, TEST_IMG("bar at 0x50"));
+
+        // Cannot rewind past the end, or a branch before its branch point
+        assert!(tline.reset_to_lsn(Lsn(0x60)).is_err());
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x40))?;
+        let newtline = repo
+            .get_timeline_load(NEW_TIMELINE_ID)
+            .expect("Should have a local timeline");
+        assert!(newtline.reset_to_lsn(Lsn(0x30)).is_err());
+
+        Ok(())
+    }
+
     //
     // Insert 1000 key-value pairs with increasing keys, checkpoint,
     // repeat 50 times.
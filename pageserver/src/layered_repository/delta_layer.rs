@@ -32,7 +32,7 @@ use crate::layered_repository::storage_layer::{
     Layer, ValueReconstructResult, ValueReconstructState,
 };
 use crate::page_cache::{PageReadGuard, PAGE_SZ};
-use crate::repository::{Key, Value, KEY_SIZE};
+use crate::repository::{Key, PageVersionKind, Value, KEY_SIZE};
 use crate::virtual_file::VirtualFile;
 use crate::walrecord;
 use crate::{DELTA_FILE_MAGIC, STORAGE_FORMAT_VERSION};
@@ -283,7 +283,9 @@ impl Layer for DeltaLayer {
                     }
                     Value::WalRecord(rec) => {
                         let will_init = rec.will_init();
-                        reconstruct_state.records.push((entry_lsn, rec));
+                        if !reconstruct_state.push_record(entry_lsn, rec) {
+                            return Ok(ValueReconstructResult::Capped);
+                        }
                         if will_init {
                             // This WAL record initializes the page, so no need to go further back
                             need_image = false;
@@ -316,6 +318,62 @@ impl Layer for DeltaLayer {
         }
     }
 
+    fn get_changed_keys(&self, key_range: &Range<Key>, lsn_range: &Range<Lsn>) -> Result<Vec<Key>> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+            inner.index_start_blk,
+            inner.index_root_blk,
+            file,
+        );
+
+        let mut keys = Vec::new();
+        let search_key = DeltaKey::from_key_lsn(&key_range.start, Lsn(0));
+        tree_reader.visit(&search_key.0, VisitDirection::Forwards, |key, _value| {
+            let entry_key = DeltaKey::extract_key_from_buf(key);
+            if entry_key >= key_range.end {
+                return false;
+            }
+            if lsn_range.contains(&DeltaKey::extract_lsn_from_buf(key))
+                && keys.last() != Some(&entry_key)
+            {
+                keys.push(entry_key);
+            }
+            true
+        })?;
+
+        Ok(keys)
+    }
+
+    fn get_key_history(&self, key: Key) -> Result<Vec<(Lsn, PageVersionKind)>> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader = DiskBtreeReader::<_, DELTA_KEY_SIZE>::new(
+            inner.index_start_blk,
+            inner.index_root_blk,
+            file,
+        );
+
+        let mut offsets: Vec<(Lsn, u64)> = Vec::new();
+        let search_key = DeltaKey::from_key_lsn(&key, Lsn(0));
+        tree_reader.visit(&search_key.0, VisitDirection::Forwards, |buf, value| {
+            if DeltaKey::extract_key_from_buf(buf) != key {
+                return false;
+            }
+            offsets.push((DeltaKey::extract_lsn_from_buf(buf), BlobRef(value).pos()));
+            true
+        })?;
+
+        let mut cursor = file.block_cursor();
+        let mut history = Vec::with_capacity(offsets.len());
+        for (entry_lsn, pos) in offsets {
+            let buf = cursor.read_blob(pos)?;
+            let val = Value::des(&buf)?;
+            history.push((entry_lsn, PageVersionKind::of(&val, buf.len())));
+        }
+        Ok(history)
+    }
+
     fn delete(&self) -> Result<()> {
         // delete underlying file
         fs::remove_file(self.path())?;
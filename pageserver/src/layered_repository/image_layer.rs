@@ -28,7 +28,7 @@ use crate::layered_repository::storage_layer::{
     Layer, ValueReconstructResult, ValueReconstructState,
 };
 use crate::page_cache::PAGE_SZ;
-use crate::repository::{Key, Value, KEY_SIZE};
+use crate::repository::{Key, PageVersionKind, Value, KEY_SIZE};
 use crate::virtual_file::VirtualFile;
 use crate::{IMAGE_FILE_MAGIC, STORAGE_FORMAT_VERSION};
 use anyhow::{bail, ensure, Context, Result};
@@ -182,8 +182,40 @@ impl Layer for ImageLayer {
         }
     }
 
-    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Lsn, Value)>>> {
-        todo!();
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Result<(Key, Lsn, Value)>> + 'a> {
+        let inner = match self.load() {
+            Ok(inner) => inner,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+
+        match ImageValueIter::new(inner, self.lsn) {
+            Ok(iter) => Box::new(iter),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    /// An image layer holds a version of every page, it doesn't know which
+    /// ones actually changed.
+    fn get_changed_keys(
+        &self,
+        _key_range: &Range<Key>,
+        _lsn_range: &Range<Lsn>,
+    ) -> Result<Vec<Key>> {
+        Ok(Vec::new())
+    }
+
+    fn get_key_history(&self, key: Key) -> Result<Vec<(Lsn, PageVersionKind)>> {
+        let inner = self.load()?;
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader = DiskBtreeReader::new(inner.index_start_blk, inner.index_root_blk, file);
+
+        let mut keybuf: [u8; KEY_SIZE] = [0u8; KEY_SIZE];
+        key.write_to_byte_slice(&mut keybuf);
+        if tree_reader.get(&keybuf)?.is_some() {
+            Ok(vec![(self.lsn, PageVersionKind::Image)])
+        } else {
+            Ok(Vec::new())
+        }
     }
 
     fn delete(&self) -> Result<()> {
@@ -551,3 +583,52 @@ impl ImageLayerWriter {
         Ok(layer)
     }
 }
+
+/// Iterator over all the page images in an image layer, in key order.
+struct ImageValueIter<'a> {
+    inner: RwLockReadGuard<'a, ImageLayerInner>,
+    lsn: Lsn,
+    all_offsets: Vec<(Key, u64)>,
+    next_idx: usize,
+}
+
+impl<'a> Iterator for ImageValueIter<'a> {
+    type Item = Result<(Key, Lsn, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_res().transpose()
+    }
+}
+
+impl<'a> ImageValueIter<'a> {
+    fn new(inner: RwLockReadGuard<'a, ImageLayerInner>, lsn: Lsn) -> Result<Self> {
+        let file = inner.file.as_ref().unwrap();
+        let tree_reader =
+            DiskBtreeReader::<_, KEY_SIZE>::new(inner.index_start_blk, inner.index_root_blk, file);
+
+        let mut all_offsets: Vec<(Key, u64)> = Vec::new();
+        tree_reader.visit(&[0u8; KEY_SIZE], VisitDirection::Forwards, |key, value| {
+            all_offsets.push((Key::from_slice(key), value));
+            true
+        })?;
+
+        Ok(ImageValueIter {
+            inner,
+            lsn,
+            all_offsets,
+            next_idx: 0,
+        })
+    }
+
+    fn next_res(&mut self) -> Result<Option<(Key, Lsn, Value)>> {
+        if self.next_idx < self.all_offsets.len() {
+            let (key, offset) = self.all_offsets[self.next_idx];
+            let file = self.inner.file.as_ref().unwrap();
+            let blob = file.block_cursor().read_blob(offset)?;
+            self.next_idx += 1;
+            Ok(Some((key, self.lsn, Value::Image(Bytes::from(blob)))))
+        } else {
+            Ok(None)
+        }
+    }
+}
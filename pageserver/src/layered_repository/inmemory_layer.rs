@@ -12,9 +12,11 @@ use crate::layered_repository::ephemeral_file::EphemeralFile;
 use crate::layered_repository::storage_layer::{
     Layer, ValueReconstructResult, ValueReconstructState,
 };
-use crate::repository::{Key, Value};
+use crate::rate_limiter;
+use crate::repository::{Key, PageVersionKind, Value};
 use crate::walrecord;
 use anyhow::{bail, ensure, Result};
+use bytes::Bytes;
 use std::collections::HashMap;
 use tracing::*;
 use utils::{
@@ -68,6 +70,36 @@ impl InMemoryLayerInner {
     fn assert_writeable(&self) {
         assert!(self.end_lsn.is_none());
     }
+
+    /// Check that 'lsn' is not older than the latest version of 'key' in the
+    /// layer. The older version would be hidden from reads at later LSNs.
+    ///
+    /// A write at the same LSN as the latest version replaces it, see
+    /// append_or_update_last(). The key's value at that LSN is still the
+    /// newest one then, so that's allowed, unless 'strict' is set.
+    fn check_lsn_order(&self, key: Key, lsn: Lsn, strict: bool) -> Result<()> {
+        let last_lsn = self
+            .index
+            .get(&key)
+            .and_then(|versions| versions.as_slice().last())
+            .map(|(last_lsn, _)| *last_lsn);
+        if let Some(last_lsn) = last_lsn {
+            ensure!(
+                lsn >= last_lsn,
+                "write of key {} at {} is older than its latest version at {}",
+                key,
+                lsn,
+                last_lsn
+            );
+            ensure!(
+                !strict || lsn > last_lsn,
+                "write of key {} at {} would replace its latest version",
+                key,
+                lsn
+            );
+        }
+        Ok(())
+    }
 }
 
 impl Layer for InMemoryLayer {
@@ -139,7 +171,9 @@ impl Layer for InMemoryLayer {
                     }
                     Value::WalRecord(rec) => {
                         let will_init = rec.will_init();
-                        reconstruct_state.records.push((*entry_lsn, rec));
+                        if !reconstruct_state.push_record(*entry_lsn, rec) {
+                            return Ok(ValueReconstructResult::Capped);
+                        }
                         if will_init {
                             // This WAL record initializes the page, so no need to go further back
                             need_image = false;
@@ -165,6 +199,34 @@ impl Layer for InMemoryLayer {
         todo!();
     }
 
+    fn get_changed_keys(&self, key_range: &Range<Key>, lsn_range: &Range<Lsn>) -> Result<Vec<Key>> {
+        let inner = self.inner.read().unwrap();
+
+        Ok(inner
+            .index
+            .iter()
+            .filter(|(key, vec_map)| {
+                key_range.contains(key) && !vec_map.slice_range(lsn_range.clone()).is_empty()
+            })
+            .map(|(key, _)| *key)
+            .collect())
+    }
+
+    fn get_key_history(&self, key: Key) -> Result<Vec<(Lsn, PageVersionKind)>> {
+        let inner = self.inner.read().unwrap();
+
+        let mut reader = inner.file.block_cursor();
+        let mut history = Vec::new();
+        if let Some(vec_map) = inner.index.get(&key) {
+            for (entry_lsn, pos) in vec_map.as_slice() {
+                let buf = reader.read_blob(*pos)?;
+                let value = Value::des(&buf)?;
+                history.push((*entry_lsn, PageVersionKind::of(&value, buf.len())));
+            }
+        }
+        Ok(history)
+    }
+
     /// Nothing to do here. When you drop the last reference to the layer, it will
     /// be deallocated.
     fn delete(&self) -> Result<()> {
@@ -273,6 +335,8 @@ impl InMemoryLayer {
 
         inner.assert_writeable();
 
+        inner.check_lsn_order(key, lsn, self.conf.strict_lsn_order)?;
+
         let off = inner.file.write_blob(&Value::ser(&val)?)?;
 
         let vec_map = inner.index.entry(key).or_default();
@@ -285,6 +349,158 @@ impl InMemoryLayer {
         Ok(())
     }
 
+    /// Store a reconstructed image of 'key' at 'lsn', replacing the WAL record
+    /// stored at 'lsn', if any. 'lsn' must not be older than the latest version
+    /// of the key in this layer.
+    pub fn put_materialized_image(&self, key: Key, lsn: Lsn, img: Bytes) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+
+        inner.assert_writeable();
+
+        let off = inner.file.write_blob(&Value::ser(&Value::Image(img))?)?;
+        let vec_map = inner.index.entry(key).or_default();
+        if vec_map.append_or_update_last(lsn, off).is_err() {
+            bail!("key {} has a version newer than {}", key, lsn);
+        }
+
+        Ok(())
+    }
+
+    /// Call 'f' with every version of every key in the layer, in key and LSN
+    /// order. The values are read one at a time, so this can be used on
+    /// layers of any size. The lock is only held to copy the index and to
+    /// read each value, not while 'f' runs.
+    pub fn for_each_value(&self, mut f: impl FnMut(Key, Lsn, Value) -> Result<()>) -> Result<()> {
+        let entries = {
+            let inner = self.inner.read().unwrap();
+            let mut entries: Vec<(Key, Lsn, u64)> = inner
+                .index
+                .iter()
+                .flat_map(|(key, vec_map)| {
+                    vec_map
+                        .as_slice()
+                        .iter()
+                        .map(move |(lsn, pos)| (*key, *lsn, *pos))
+                })
+                .collect();
+            entries.sort_unstable_by_key(|(key, lsn, _)| (*key, *lsn));
+            entries
+        };
+
+        for (key, lsn, pos) in entries {
+            // The blobs are never overwritten, only appended
+            let buf = {
+                let inner = self.inner.read().unwrap();
+                let mut reader = inner.file.block_cursor();
+                reader.read_blob(pos)?
+            };
+            f(key, lsn, Value::des(&buf)?)?;
+        }
+        Ok(())
+    }
+
+    /// Replace runs of consecutive WAL records of 'key', up to and including
+    /// 'up_to_lsn', with single records that have the same effect, see
+    /// ZenithWalRecord::compose(). The combined record is stored at the LSN
+    /// of the last record of the run, so the value can no longer be read at
+    /// the LSNs in the middle of a run. Runs are split at 'retain_lsns',
+    /// where it must stay readable. Returns the number of records removed.
+    pub fn coalesce_records(&self, key: Key, up_to_lsn: Lsn, retain_lsns: &[Lsn]) -> Result<usize> {
+        let mut inner = self.inner.write().unwrap();
+
+        inner.assert_writeable();
+
+        let entries = match inner.index.get(&key) {
+            Some(vec_map) => vec_map.as_slice().to_vec(),
+            None => return Ok(0),
+        };
+
+        // Find the runs of records that can be combined. For each entry, we
+        // remember the record if it's one that can start a run, and whether
+        // it's a combined record that needs to be written out.
+        let mut runs: Vec<(Lsn, u64, Option<walrecord::ZenithWalRecord>, bool)> = Vec::new();
+        let mut reader = inner.file.block_cursor();
+        let mut collapsed = 0;
+        for (lsn, off) in entries {
+            let rec = if lsn <= up_to_lsn {
+                match Value::des(&reader.read_blob(off)?)? {
+                    Value::WalRecord(rec) => Some(rec),
+                    Value::Image(_) => None,
+                }
+            } else {
+                None
+            };
+            if let (Some(rec), Some((last_lsn, _, Some(last_rec), combined))) =
+                (&rec, runs.last_mut())
+            {
+                let retained = retain_lsns.iter().any(|r| *last_lsn <= *r && *r < lsn);
+                if !retained {
+                    if let Some(composed) = last_rec.compose(rec) {
+                        *last_lsn = lsn;
+                        *last_rec = composed;
+                        *combined = true;
+                        collapsed += 1;
+                        continue;
+                    }
+                }
+            }
+            runs.push((lsn, off, rec, false));
+        }
+        drop(reader);
+
+        if collapsed == 0 {
+            return Ok(0);
+        }
+
+        // Write out the combined records, and replace the index entries
+        let mut vec_map = VecMap::default();
+        for (lsn, off, rec, combined) in runs {
+            let off = match rec {
+                Some(rec) if combined => inner
+                    .file
+                    .write_blob(&Value::ser(&Value::WalRecord(rec))?)?,
+                _ => off,
+            };
+            if vec_map.append(lsn, off).is_err() {
+                bail!("key {} has out of order versions at {}", key, lsn);
+            }
+        }
+        inner.index.insert(key, vec_map);
+
+        Ok(collapsed)
+    }
+
+    /// Like put_value(), for many keys at once.
+    pub fn put_values(&self, lsn: Lsn, values: Vec<(Key, Value)>) -> Result<()> {
+        trace!(
+            "put_values {} keys at {}/{}",
+            values.len(),
+            self.timelineid,
+            lsn
+        );
+        let mut inner = self.inner.write().unwrap();
+
+        inner.assert_writeable();
+
+        // Check all the keys first, so that a failed batch isn't half-written
+        for (key, _val) in values.iter() {
+            inner.check_lsn_order(*key, lsn, self.conf.strict_lsn_order)?;
+        }
+
+        for (key, val) in values {
+            let off = inner.file.write_blob(&Value::ser(&val)?)?;
+
+            let vec_map = inner.index.entry(key).or_default();
+            let old = vec_map.append_or_update_last(lsn, off).unwrap().0;
+            if old.is_some() {
+                // We already had an entry for this LSN. That's odd..
+                warn!("Key {} at {} already exists", key, lsn);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn put_tombstone(&self, _key_range: Range<Key>, _lsn: Lsn) -> Result<()> {
         // TODO: Currently, we just leak the storage for any deleted keys
 
@@ -309,8 +525,13 @@ impl InMemoryLayer {
 
     /// Write this frozen in-memory layer to disk.
     ///
-    /// Returns a new delta layer with all the same data as this in-memory layer
-    pub fn write_to_disk(&self) -> Result<DeltaLayer> {
+    /// Returns a new delta layer with all the same data as this in-memory layer.
+    /// The writes are throttled by the global checkpoint I/O limiter, and by
+    /// 'tenant_io_limiter' if given.
+    pub fn write_to_disk(
+        &self,
+        tenant_io_limiter: Option<&rate_limiter::RateLimiter>,
+    ) -> Result<DeltaLayer> {
         // Grab the lock in read-mode. We hold it over the I/O, but because this
         // layer is not writeable anymore, no one should be trying to acquire the
         // write lock on it, so we shouldn't block anyone. There's one exception
@@ -342,6 +563,12 @@ impl InMemoryLayer {
             // Write all page versions
             for (lsn, pos) in vec_map.as_slice() {
                 cursor.read_blob_into_buf(*pos, &mut buf)?;
+                if let Some(limiter) = rate_limiter::checkpoint_io_limiter() {
+                    limiter.acquire(buf.len() as u64);
+                }
+                if let Some(limiter) = tenant_io_limiter {
+                    limiter.acquire(buf.len() as u64);
+                }
                 let val = Value::des(&buf)?;
                 delta_layer_writer.put_value(key, *lsn, val)?;
             }
@@ -209,13 +209,24 @@ impl LayerMap {
         &self,
         key_range: &Range<Key>,
         lsn_range: &Range<Lsn>,
+    ) -> Result<bool> {
+        self.image_layer_exists_among(key_range, lsn_range, |_| true)
+    }
+
+    /// Like image_layer_exists(), but only considers the image layers that
+    /// 'include' returns true for.
+    pub fn image_layer_exists_among(
+        &self,
+        key_range: &Range<Key>,
+        lsn_range: &Range<Lsn>,
+        include: impl Fn(&Arc<dyn Layer>) -> bool,
     ) -> Result<bool> {
         let mut range_remain = key_range.clone();
 
         loop {
             let mut made_progress = false;
             for l in self.historic_layers.iter() {
-                if l.is_incremental() {
+                if l.is_incremental() || !include(l) {
                     continue;
                 }
                 let img_lsn = l.get_lsn_range().start;
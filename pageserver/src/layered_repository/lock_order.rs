@@ -0,0 +1,209 @@
+//!
+//! Lock ordering checks for the timeline locks.
+//!
+//! Some of the locks in a LayeredTimeline must always be acquired in the same
+//! order, or two threads can deadlock. That's easy to get wrong in a refactor,
+//! and the resulting deadlocks are rare and hard to debug. So each of those
+//! locks is given a rank, and a thread must not acquire a lock while holding
+//! one of a higher rank. Locks of the same rank, e.g. the layer maps of a
+//! timeline and its ancestor, can be held together.
+//!
+//! In debug builds, the ranks of the locks held by each thread are tracked in
+//! a thread-local, and acquiring a lock out of order panics right away. In
+//! release builds, the checks compile to nothing.
+//!
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
+
+/// The locks that take part in the ordering checks, from the one that must
+/// be acquired first to the one that must be acquired last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockRank {
+    /// LayeredTimeline::write_lock
+    TimelineWrite,
+    /// LayeredTimeline::layers
+    LayerMap,
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    /// Ranks of the locks held by the current thread, in acquisition order.
+    static HELD_LOCKS: RefCell<Vec<LockRank>> = RefCell::new(Vec::new());
+}
+
+/// Records that the current thread holds a lock of the given rank, until
+/// dropped.
+pub struct RankGuard {
+    #[cfg(debug_assertions)]
+    rank: LockRank,
+}
+
+impl RankGuard {
+    /// Check that a lock of rank 'rank' can be acquired by the current
+    /// thread, and record it as held. Call this before acquiring the lock.
+    pub fn acquire(rank: LockRank) -> RankGuard {
+        #[cfg(debug_assertions)]
+        {
+            HELD_LOCKS.with(|held| {
+                let mut held = held.borrow_mut();
+                if let Some(highest) = held.iter().max() {
+                    debug_assert!(
+                        *highest <= rank,
+                        "lock order violation: acquiring {:?} while holding {:?}",
+                        rank,
+                        highest
+                    );
+                }
+                held.push(rank);
+            });
+            RankGuard { rank }
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            let _ = rank;
+            RankGuard {}
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for RankGuard {
+    fn drop(&mut self) {
+        // Locks are not necessarily released in the reverse order
+        HELD_LOCKS.with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(pos) = held.iter().rposition(|r| *r == self.rank) {
+                held.remove(pos);
+            }
+        });
+    }
+}
+
+/// A lock guard that also holds the rank of the lock. The lock is released
+/// before the rank.
+pub struct OrderedGuard<G> {
+    guard: G,
+    _rank: RankGuard,
+}
+
+impl<G: Deref> Deref for OrderedGuard<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for OrderedGuard<G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+/// A Mutex with a rank. Panics if the mutex is poisoned.
+pub struct OrderedMutex<T> {
+    rank: LockRank,
+    inner: Mutex<T>,
+}
+
+impl<T> OrderedMutex<T> {
+    pub fn new(rank: LockRank, value: T) -> Self {
+        OrderedMutex {
+            rank,
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> OrderedGuard<MutexGuard<'_, T>> {
+        let rank = RankGuard::acquire(self.rank);
+        OrderedGuard {
+            guard: self.inner.lock().unwrap(),
+            _rank: rank,
+        }
+    }
+
+    /// Like lock(), but returns None instead of waiting if the mutex is held.
+    pub fn try_lock(&self) -> Option<OrderedGuard<MutexGuard<'_, T>>> {
+        let rank = RankGuard::acquire(self.rank);
+        let guard = match self.inner.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => return None,
+            Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+        };
+        Some(OrderedGuard { guard, _rank: rank })
+    }
+}
+
+/// A RwLock with a rank. Panics if the lock is poisoned.
+pub struct OrderedRwLock<T> {
+    rank: LockRank,
+    inner: RwLock<T>,
+}
+
+impl<T> OrderedRwLock<T> {
+    pub fn new(rank: LockRank, value: T) -> Self {
+        OrderedRwLock {
+            rank,
+            inner: RwLock::new(value),
+        }
+    }
+
+    pub fn read(&self) -> OrderedGuard<RwLockReadGuard<'_, T>> {
+        let rank = RankGuard::acquire(self.rank);
+        OrderedGuard {
+            guard: self.inner.read().unwrap(),
+            _rank: rank,
+        }
+    }
+
+    pub fn write(&self) -> OrderedGuard<RwLockWriteGuard<'_, T>> {
+        let rank = RankGuard::acquire(self.rank);
+        OrderedGuard {
+            guard: self.inner.write().unwrap(),
+            _rank: rank,
+        }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+
+    /// Acquire a write lock and a layer map lock, in the given order
+    fn lock_both(write_lock_first: bool) {
+        let write_lock = OrderedMutex::new(LockRank::TimelineWrite, ());
+        let layers = OrderedRwLock::new(LockRank::LayerMap, 0);
+        if write_lock_first {
+            let _write_guard = write_lock.lock();
+            let _layers = layers.write();
+        } else {
+            let _layers = layers.read();
+            let _write_guard = write_lock.lock();
+        }
+    }
+
+    #[test]
+    fn correct_order() {
+        lock_both(true);
+        lock_both(true);
+
+        // Same rank is fine, as is reacquiring after release
+        let layers = OrderedRwLock::new(LockRank::LayerMap, 0);
+        let ancestor_layers = OrderedRwLock::new(LockRank::LayerMap, 0);
+        let guard = layers.read();
+        let ancestor_guard = ancestor_layers.read();
+        drop(guard);
+        drop(ancestor_guard);
+        let write_lock = OrderedMutex::new(LockRank::TimelineWrite, ());
+        let _write_guard = write_lock.lock();
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order violation")]
+    fn reversed_order() {
+        lock_both(false);
+    }
+}
@@ -28,6 +28,10 @@ const METADATA_MAX_SIZE: usize = 512;
 /// The name of the metadata file pageserver creates per timeline.
 pub const METADATA_FILE_NAME: &str = "metadata";
 
+/// The LSN timestamp index of a timeline, saved next to its metadata file.
+/// See DatadirTimeline::save_lsn_timestamp_index().
+pub const LSN_TIMESTAMPS_FILE_NAME: &str = "lsn_timestamps";
+
 /// Metadata stored on disk for each timeline
 ///
 /// The fields correspond to the values we hold in memory, in LayeredTimeline.
@@ -72,10 +76,32 @@ pub fn metadata_path(
     timelineid: ZTimelineId,
     tenantid: ZTenantId,
 ) -> PathBuf {
-    conf.timeline_path(&timelineid, &tenantid)
+    conf.timeline_metadata_dir(&timelineid, &tenantid)
         .join(METADATA_FILE_NAME)
 }
 
+/// Where the LSN timestamp index of a timeline is saved, next to the
+/// metadata file.
+pub fn lsn_timestamps_path(
+    conf: &'static PageServerConf,
+    timelineid: ZTimelineId,
+    tenantid: ZTenantId,
+) -> PathBuf {
+    conf.timeline_metadata_dir(&timelineid, &tenantid)
+        .join(LSN_TIMESTAMPS_FILE_NAME)
+}
+
+/// Is 'fname' the metadata file, or the backup or temporary copy that
+/// saving the metadata leaves next to it, or the LSN timestamp index saved
+/// with it?
+pub fn is_metadata_file(fname: &str) -> bool {
+    fname == METADATA_FILE_NAME
+        || fname.strip_suffix(".bak") == Some(METADATA_FILE_NAME)
+        || fname.strip_suffix(".temp") == Some(METADATA_FILE_NAME)
+        || fname == LSN_TIMESTAMPS_FILE_NAME
+        || fname.strip_suffix(".temp") == Some(LSN_TIMESTAMPS_FILE_NAME)
+}
+
 impl TimelineMetadata {
     pub fn new(
         disk_consistent_lsn: Lsn,
@@ -0,0 +1,197 @@
+//!
+//! Per-timeline cache of recently read page images.
+//!
+//! The global page cache stores pages in fixed-size buffers, so every hit
+//! has to copy the page out into a new `Bytes`. For a hot page that's read
+//! over and over, that allocation and copy dominates the cost of the read.
+//! This cache holds on to the `Bytes` returned by recent reads instead, so
+//! that repeated reads of the same page can hand out a clone of it, which
+//! just bumps a reference count.
+//!
+//! Only one version of each key is kept, the newest one memorized. Like the
+//! global page cache, a lookup returns a version that is not newer than the
+//! requested LSN, and the caller has to check for any WAL after it. When the
+//! cache is full, the least recently used page is evicted.
+//!
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use utils::lsn::Lsn;
+
+use crate::repository::Key;
+
+pub struct RecentPages {
+    capacity: usize,
+    inner: Mutex<RecentPagesInner>,
+}
+
+#[derive(Default)]
+struct RecentPagesInner {
+    /// The cached page versions, with the tick of their last use
+    pages: HashMap<Key, (Lsn, Bytes, u64)>,
+    /// Keys by the tick of their last use, oldest first
+    lru: BTreeMap<u64, Key>,
+    next_tick: u64,
+}
+
+impl RecentPagesInner {
+    /// Mark 'key' as the most recently used one
+    fn touch(&mut self, key: Key) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        if let Some((_, _, last_used)) = self.pages.get_mut(&key) {
+            self.lru.remove(last_used);
+            *last_used = tick;
+            self.lru.insert(tick, key);
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(&oldest) = self.lru.keys().next() {
+            let key = self.lru.remove(&oldest).unwrap();
+            self.pages.remove(&key);
+        }
+    }
+}
+
+impl RecentPages {
+    /// Create a cache that holds at most 'capacity' pages. With zero capacity,
+    /// nothing is cached.
+    pub fn new(capacity: usize) -> Self {
+        RecentPages {
+            capacity,
+            inner: Mutex::new(RecentPagesInner::default()),
+        }
+    }
+
+    /// Look up the cached version of 'key', if it's not newer than 'lsn'.
+    /// Returns the LSN of the version, and the image, without copying it.
+    pub fn lookup(&self, key: &Key, lsn: Lsn) -> Option<(Lsn, Bytes)> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let (cached_lsn, img) = match inner.pages.get(key) {
+            Some((cached_lsn, img, _)) if *cached_lsn <= lsn => (*cached_lsn, img.clone()),
+            _ => return None,
+        };
+        inner.touch(*key);
+        Some((cached_lsn, img))
+    }
+
+    /// Remember the image of 'key' at 'lsn'. An older version of the key is
+    /// replaced, a newer one is kept.
+    pub fn memorize(&self, key: Key, lsn: Lsn, img: Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        match inner.pages.get_mut(&key) {
+            Some((cached_lsn, _, _)) if *cached_lsn > lsn => return,
+            Some((cached_lsn, cached_img, _)) => {
+                *cached_lsn = lsn;
+                *cached_img = img;
+            }
+            None => {
+                if inner.pages.len() >= self.capacity {
+                    inner.evict_oldest();
+                }
+                let tick = inner.next_tick;
+                inner.next_tick += 1;
+                inner.pages.insert(key, (lsn, img, tick));
+                inner.lru.insert(tick, key);
+                return;
+            }
+        }
+        inner.touch(key);
+    }
+
+    /// Drop all the cached versions newer than 'lsn'.
+    pub fn drop_after(&self, lsn: Lsn) {
+        let mut inner = self.inner.lock().unwrap();
+        let RecentPagesInner { pages, lru, .. } = &mut *inner;
+        pages.retain(|_, (cached_lsn, _, last_used)| {
+            if *cached_lsn > lsn {
+                lru.remove(last_used);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key(n: u32) -> Key {
+        Key {
+            field1: 0,
+            field2: 1663,
+            field3: 12972,
+            field4: 16384,
+            field5: 0,
+            field6: n,
+        }
+    }
+
+    #[test]
+    fn lookup_returns_shared_image() {
+        let cache = RecentPages::new(10);
+        let img = Bytes::from(vec![1u8; 8192]);
+        cache.memorize(test_key(1), Lsn(0x20), img.clone());
+
+        assert!(cache.lookup(&test_key(1), Lsn(0x10)).is_none());
+        assert!(cache.lookup(&test_key(2), Lsn(0x20)).is_none());
+        let (lsn, cached) = cache.lookup(&test_key(1), Lsn(0x30)).unwrap();
+        assert_eq!(lsn, Lsn(0x20));
+        assert_eq!(cached.as_ptr(), img.as_ptr());
+
+        // An older version doesn't replace a newer one
+        cache.memorize(test_key(1), Lsn(0x10), Bytes::from(vec![2u8; 8192]));
+        assert_eq!(cache.lookup(&test_key(1), Lsn(0x30)).unwrap().0, Lsn(0x20));
+        cache.memorize(test_key(1), Lsn(0x30), Bytes::from(vec![3u8; 8192]));
+        assert_eq!(cache.lookup(&test_key(1), Lsn(0x30)).unwrap().0, Lsn(0x30));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = RecentPages::new(2);
+        cache.memorize(test_key(1), Lsn(0x10), Bytes::from_static(b"one"));
+        cache.memorize(test_key(2), Lsn(0x10), Bytes::from_static(b"two"));
+        // Reading key 1 makes key 2 the least recently used one
+        assert!(cache.lookup(&test_key(1), Lsn(0x10)).is_some());
+        cache.memorize(test_key(3), Lsn(0x10), Bytes::from_static(b"three"));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.lookup(&test_key(1), Lsn(0x10)).is_some());
+        assert!(cache.lookup(&test_key(2), Lsn(0x10)).is_none());
+        assert!(cache.lookup(&test_key(3), Lsn(0x10)).is_some());
+    }
+
+    #[test]
+    fn drop_after() {
+        let cache = RecentPages::new(10);
+        cache.memorize(test_key(1), Lsn(0x10), Bytes::from_static(b"one"));
+        cache.memorize(test_key(2), Lsn(0x20), Bytes::from_static(b"two"));
+        cache.drop_after(Lsn(0x10));
+
+        assert!(cache.lookup(&test_key(1), Lsn(0x30)).is_some());
+        assert!(cache.lookup(&test_key(2), Lsn(0x30)).is_none());
+        assert_eq!(cache.len(), 1);
+
+        let disabled = RecentPages::new(0);
+        disabled.memorize(test_key(1), Lsn(0x10), Bytes::from_static(b"one"));
+        assert!(disabled.is_empty());
+    }
+}
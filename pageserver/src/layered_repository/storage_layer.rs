@@ -2,7 +2,7 @@
 //! Common traits and structs for layers
 //!
 
-use crate::repository::{Key, Value};
+use crate::repository::{Key, PageVersionKind, Value};
 use crate::walrecord::ZenithWalRecord;
 use anyhow::Result;
 use bytes::Bytes;
@@ -48,10 +48,51 @@ where
 /// the same ValueReconstructState struct in the next 'get_value_reconstruct_data'
 /// call, to collect more records.
 ///
+/// If 'max_record_bytes' is set, collecting stops before the records would add
+/// up to more than that, and 'get_value_reconstruct_data' returns Capped. The
+/// caller then needs to reconstruct the version just before the oldest
+/// collected record first, and start over with it as the cached image.
+///
 #[derive(Debug)]
 pub struct ValueReconstructState {
     pub records: Vec<(Lsn, ZenithWalRecord)>,
     pub img: Option<(Lsn, Bytes)>,
+    /// Maximum total size of 'records', 0 means no limit
+    pub max_record_bytes: usize,
+    /// Total size of 'records'
+    pub record_bytes: usize,
+    /// Set when a record didn't fit under 'max_record_bytes'
+    pub capped: bool,
+}
+
+impl ValueReconstructState {
+    pub fn new(img: Option<(Lsn, Bytes)>, max_record_bytes: usize) -> Self {
+        ValueReconstructState {
+            records: Vec::new(),
+            img,
+            max_record_bytes,
+            record_bytes: 0,
+            capped: false,
+        }
+    }
+
+    /// Add a record older than the ones collected so far. Returns false,
+    /// without adding it, if that would take the records over
+    /// 'max_record_bytes'. The first record is always added, however large,
+    /// so that the reconstruction makes progress.
+    pub fn push_record(&mut self, lsn: Lsn, rec: ZenithWalRecord) -> bool {
+        let size = rec.size();
+        if self.max_record_bytes != 0
+            && !self.records.is_empty()
+            && self.record_bytes + size > self.max_record_bytes
+        {
+            self.capped = true;
+            return false;
+        }
+        self.record_bytes += size;
+        self.records.push((lsn, rec));
+        true
+    }
 }
 
 /// Return value from Layer::get_page_reconstruct_data
@@ -67,6 +108,10 @@ pub enum ValueReconstructResult {
     /// the returned LSN. This is usually considered an error, but might be OK
     /// in some circumstances.
     Missing,
+
+    /// The collected records reached the state's 'max_record_bytes' before
+    /// all the data needed to reconstruct the page was found.
+    Capped,
 }
 
 /// A Layer contains all data in a "rectangle" consisting of a range of keys and
@@ -127,6 +172,14 @@ pub trait Layer: Send + Sync {
         reconstruct_data: &mut ValueReconstructState,
     ) -> Result<ValueReconstructResult>;
 
+    /// Collect the keys in 'key_range' that have a version with an LSN in
+    /// 'lsn_range' in this layer. The result can contain duplicates.
+    fn get_changed_keys(&self, key_range: &Range<Key>, lsn_range: &Range<Lsn>) -> Result<Vec<Key>>;
+
+    /// Collect the LSN and kind of every version of 'key' in this layer,
+    /// in ascending LSN order.
+    fn get_key_history(&self, key: Key) -> Result<Vec<(Lsn, PageVersionKind)>>;
+
     /// Does this layer only contain some data for the key-range (incremental),
     /// or does it contain a version of every page? This is important to know
     /// for garbage collecting old layers: an incremental layer depends on
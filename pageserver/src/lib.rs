@@ -8,6 +8,7 @@ pub mod page_cache;
 pub mod page_service;
 pub mod pgdatadir_mapping;
 pub mod profiling;
+pub mod rate_limiter;
 pub mod reltag;
 pub mod repository;
 pub mod storage_sync;
@@ -366,6 +366,39 @@ impl PageCache {
         }
     }
 
+    ///
+    /// Drop all materialized page versions of the given timeline newer than
+    /// 'lsn'. Used when a timeline is rewound, and the page versions after
+    /// 'lsn' are replaced by re-ingested WAL.
+    ///
+    pub fn drop_materialized_pages_after(
+        &self,
+        drop_tenant_id: ZTenantId,
+        drop_timeline_id: ZTimelineId,
+        drop_lsn: Lsn,
+    ) {
+        for slot_idx in 0..self.slots.len() {
+            let slot = &self.slots[slot_idx];
+
+            let mut inner = slot.inner.write().unwrap();
+            if let Some(key) = &inner.key {
+                match key {
+                    CacheKey::MaterializedPage { hash_key, lsn }
+                        if hash_key.tenant_id == drop_tenant_id
+                            && hash_key.timeline_id == drop_timeline_id
+                            && *lsn > drop_lsn =>
+                    {
+                        // remove mapping for old buffer
+                        self.remove_mapping(key);
+                        inner.key = None;
+                        inner.dirty = false;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
     // Section 1.2: Public interface functions for working with Ephemeral pages.
 
     pub fn read_ephemeral_buf(&self, file_id: u64, blkno: u32) -> ReadBufResult {
@@ -557,7 +557,7 @@ impl PageServerHandler {
             }
         }
 
-        let db_size = total_blocks * pg_constants::BLCKSZ as i64;
+        let db_size = total_blocks * timeline.get_page_size() as i64;
 
         Ok(PagestreamBeMessage::DbSize(PagestreamDbSizeResponse {
             db_size,
@@ -572,6 +572,19 @@ impl PageServerHandler {
         let _enter = info_span!("get_page", rel = %req.rel, blkno = &req.blkno, req_lsn = %req.lsn)
             .entered();
         let latest_gc_cutoff_lsn = timeline.tline.get_latest_gc_cutoff_lsn();
+        if !req.latest && req.lsn != Lsn(0) && req.lsn < *latest_gc_cutoff_lsn {
+            // GC can keep a few older versions of each page, see
+            // min_versions_per_block
+            let page = timeline.get_rel_page_before_gc_cutoff(
+                req.rel,
+                req.blkno,
+                req.lsn,
+                &latest_gc_cutoff_lsn,
+            )?;
+            return Ok(PagestreamBeMessage::GetPage(PagestreamGetPageResponse {
+                page,
+            }));
+        }
         let lsn = Self::wait_or_get_last_lsn(timeline, req.lsn, req.latest, &latest_gc_cutoff_lsn)?;
         /*
         // Add a 1s delay to some requests. The delayed causes the requests to
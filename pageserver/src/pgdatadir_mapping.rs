@@ -10,22 +10,52 @@ use crate::keyspace::{KeyPartitioning, KeySpace, KeySpaceAccum};
 use crate::reltag::{RelTag, SlruKind};
 use crate::repository::*;
 use crate::repository::{Repository, Timeline};
+use crate::thread_mgr::{self, ThreadKind};
 use crate::walrecord::ZenithWalRecord;
-use anyhow::{bail, ensure, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::{Buf, Bytes};
-use postgres_ffi::xlog_utils::TimestampTz;
+use postgres_ffi::xlog_utils::{from_pg_timestamp, to_pg_timestamp, TimestampTz};
 use postgres_ffi::{pg_constants, Oid, TransactionId};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::cmp::{max, min, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
 use std::ops::Range;
-use std::sync::atomic::{AtomicIsize, Ordering};
-use std::sync::{Arc, Mutex, RwLockReadGuard};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicIsize, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::SystemTime;
 use tracing::{debug, error, trace, warn};
-use utils::{bin_ser::BeSer, lsn::Lsn};
+use utils::{bin_ser::BeSer, lsn::Lsn, vec_map::VecMap, zid::ZTimelineId};
 
 /// Block number within a relation or SLRU. This matches PostgreSQL's BlockNumber type.
 pub type BlockNumber = u32;
 
+/// Max value for the `prefetch_depth` setting.
+pub const MAX_PREFETCH_DEPTH: u32 = 256;
+
+/// Number of consecutive block reads of a relation, after which the access
+/// is considered a sequential scan and read-ahead kicks in.
+const PREFETCH_SEQUENTIAL_THRESHOLD: u32 = 3;
+
+/// Max number of prefetch threads running at the same time, per timeline.
+const MAX_PREFETCH_THREADS: usize = 4;
+
+/// Max number of relations to track sequential access for, per timeline.
+const MAX_PREFETCH_TRACKED_RELS: usize = 1024;
+
+/// Returned inside `anyhow::Error` when reading a relation that doesn't exist
+/// at the requested LSN, because it was never created or was created later.
+/// Reading a block beyond the end of an existing relation is not an error,
+/// see [`DatadirTimeline::get_rel_page_at_lsn`].
+#[derive(Debug, thiserror::Error)]
+#[error("relation {tag} not found at {lsn}")]
+pub struct RelationNotFound {
+    pub tag: RelTag,
+    pub lsn: Lsn,
+}
+
 pub struct DatadirTimeline<R>
 where
     R: Repository,
@@ -44,25 +74,297 @@ where
 
     /// Current logical size of the "datadir", at the last LSN.
     current_logical_size: AtomicIsize,
+
+    /// Relation directories, cached to speed up get_rel_exists() and
+    /// list_rels() calls at the tip of the timeline.
+    rel_dir_cache: RwLock<RelDirCache>,
+
+    /// Number of get_rel_exists() and list_rels() calls that had to fetch the
+    /// relation directory from the underlying store.
+    rel_dir_cache_misses: AtomicU64,
+
+    /// How many blocks to read ahead of a sequential scan. 0 disables read-ahead.
+    prefetch_depth: AtomicU32,
+    /// Sequential access detection state, per relation.
+    seq_access: Mutex<HashMap<RelTag, SeqAccess>>,
+    /// Number of prefetch threads currently running.
+    prefetch_threads: Arc<AtomicUsize>,
+
+    /// Size of the Postgres pages stored in this timeline, BLCKSZ.
+    page_size: usize,
+
+    /// The LSNs of the commit and checkpoint records with a timestamp, with
+    /// the latest timestamp seen up to each of them, for
+    /// find_lsn_for_timestamp(). Appended to as records are ingested, and
+    /// completed from the stored versions of the timestamp on first use.
+    lsn_timestamp_index: RwLock<LsnTimestampIndex>,
+    /// Where to save the LSN timestamp index, next to the timeline metadata
+    lsn_timestamps_path: Option<PathBuf>,
+}
+
+/// See 'lsn_timestamp_index' in DatadirTimeline
+#[derive(Default)]
+struct LsnTimestampIndex {
+    entries: VecMap<Lsn, TimestampTz>,
+    /// The stored timestamps after this LSN, other than the ones ingested
+    /// since the timeline was loaded, haven't been read into 'entries' yet.
+    /// None once they have.
+    unread_after: Option<Lsn>,
+    /// The entries up to this LSN are in the saved file
+    saved_lsn: Lsn,
 }
 
-#[derive(Debug)]
+impl LsnTimestampIndex {
+    /// Add (LSN, timestamp) pairs, which may overlap with the existing
+    /// entries, and recalculate the latest timestamps up to each LSN.
+    fn merge(&mut self, new_entries: Vec<(Lsn, TimestampTz)>) {
+        let mut all = self.entries.as_slice().to_vec();
+        all.extend(new_entries);
+        all.sort_by_key(|(lsn, _)| *lsn);
+
+        let mut entries = VecMap::default();
+        let mut max_timestamp = TimestampTz::MIN;
+        for (lsn, timestamp) in all {
+            max_timestamp = max(max_timestamp, timestamp);
+            // Same LSN as the previous entry, if it was in both
+            let _ = entries.append_or_update_last(lsn, max_timestamp);
+        }
+        self.entries = entries;
+    }
+}
+
+/// Contents of the saved LSN timestamp index
+#[derive(Serialize, Deserialize)]
+struct LsnTimestampIndexFile {
+    /// The entries are complete up to this LSN
+    lsn: Lsn,
+    entries: Vec<(Lsn, TimestampTz)>,
+}
+
+/// Tracks how a relation is being read, to detect sequential scans.
+#[derive(Default)]
+struct SeqAccess {
+    /// Block that the next read will ask for, if the scan continues.
+    next_blknum: BlockNumber,
+    /// Number of consecutive block reads so far.
+    run_len: u32,
+    /// Blocks below this have already been prefetched.
+    prefetched_upto: BlockNumber,
+}
+
+/// Cached contents of relation directories, by (spcnode, dbnode).
+///
+/// The cached directories are valid at any LSN >= 'valid_from'. Committing a
+/// modification that changes any relation directory drops the changed
+/// directories from the cache and moves 'valid_from' forward, so that
+/// lookups at older LSNs go to the underlying store.
+struct RelDirCache {
+    valid_from: Lsn,
+    dirs: HashMap<(Oid, Oid), HashSet<(Oid, u8)>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum LsnForTimestamp {
     Present(Lsn),
     Future(Lsn),
     Past(Lsn),
 }
 
+/// A change to a relation block, as returned by DatadirTimeline::change_stream().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChangeRecord {
+    pub lsn: Lsn,
+    pub rel: RelTag,
+    pub blknum: BlockNumber,
+    pub kind: PageVersionKind,
+}
+
+/// Iterator returned by DatadirTimeline::change_stream().
+///
+/// The versions of each block are stored in LSN order, but the blocks are
+/// ordered by relation and block number, so the changes of all the blocks
+/// are merged with a heap holding the next change of each block.
+struct ChangeStream<'a, R: Repository> {
+    tline: &'a DatadirTimeline<R>,
+    lsn_range: Range<Lsn>,
+    /// Changed blocks whose history hasn't been read yet. The histories are
+    /// read on the first call to next(), as the merge needs all of them.
+    keys: Option<Vec<Key>>,
+    /// The changes of each block in 'lsn_range', in LSN order
+    histories: Vec<(RelTag, BlockNumber, Vec<(Lsn, PageVersionKind)>)>,
+    /// (LSN, index in 'histories', index in the block's history) of the next
+    /// change of each block that has changes left
+    heap: BinaryHeap<Reverse<(Lsn, usize, usize)>>,
+}
+
+impl<'a, R: Repository> ChangeStream<'a, R> {
+    fn load_histories(&mut self, keys: Vec<Key>) -> Result<()> {
+        for key in keys {
+            let (rel, blknum) = key_to_rel_block(key)?;
+            let history: Vec<_> = self
+                .tline
+                .tline
+                .get_key_history(key)?
+                .into_iter()
+                .filter(|(lsn, _)| self.lsn_range.contains(lsn))
+                .collect();
+            if let Some((lsn, _)) = history.first() {
+                self.heap.push(Reverse((*lsn, self.histories.len(), 0)));
+                self.histories.push((rel, blknum, history));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, R: Repository> Iterator for ChangeStream<'a, R> {
+    type Item = Result<ChangeRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(keys) = self.keys.take() {
+            if let Err(e) = self.load_histories(keys) {
+                self.heap.clear();
+                return Some(Err(e));
+            }
+        }
+
+        let Reverse((lsn, block_idx, pos)) = self.heap.pop()?;
+        let (rel, blknum, history) = &self.histories[block_idx];
+        if let Some((next_lsn, _)) = history.get(pos + 1) {
+            self.heap.push(Reverse((*next_lsn, block_idx, pos + 1)));
+        }
+        Some(Ok(ChangeRecord {
+            lsn,
+            rel: *rel,
+            blknum: *blknum,
+            kind: history[pos].1,
+        }))
+    }
+}
+
+/// Max number of problems that validate() lists individually. The rest are
+/// only counted.
+const MAX_VALIDATION_PROBLEMS: usize = 100;
+
+/// Outcome of DatadirTimeline::validate().
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    /// Number of relations checked
+    pub rels_checked: usize,
+    /// Number of relation blocks checked
+    pub blocks_checked: u64,
+    /// Total number of problems found
+    pub num_problems: usize,
+    /// Descriptions of the first MAX_VALIDATION_PROBLEMS problems
+    pub problems: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.num_problems == 0
+    }
+
+    fn add_problem(&mut self, problem: String) {
+        self.num_problems += 1;
+        if self.problems.len() < MAX_VALIDATION_PROBLEMS {
+            self.problems.push(problem);
+        }
+    }
+}
+
 impl<R: Repository> DatadirTimeline<R> {
     pub fn new(tline: Arc<R::Timeline>, repartition_threshold: u64) -> Self {
+        let last_record_lsn = tline.get_last_record_lsn();
         DatadirTimeline {
             tline,
             partitioning: Mutex::new((KeyPartitioning::new(), Lsn(0))),
             current_logical_size: AtomicIsize::new(0),
             repartition_threshold,
+            rel_dir_cache: RwLock::new(RelDirCache {
+                valid_from: last_record_lsn,
+                dirs: HashMap::new(),
+            }),
+            rel_dir_cache_misses: AtomicU64::new(0),
+            prefetch_depth: AtomicU32::new(0),
+            seq_access: Mutex::new(HashMap::new()),
+            prefetch_threads: Arc::new(AtomicUsize::new(0)),
+            page_size: pg_constants::BLCKSZ as usize,
+            lsn_timestamp_index: RwLock::new(LsnTimestampIndex {
+                unread_after: Some(Lsn(0)),
+                ..Default::default()
+            }),
+            lsn_timestamps_path: None,
+        }
+    }
+
+    /// Use a non-default Postgres page size for the zero pages and the
+    /// logical size. WAL redo, basebackup and the page cache still assume
+    /// BLCKSZ pages, so the pageserver itself always uses the default.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Save the LSN timestamp index to 'path', and start from the index saved
+    /// there, if any, so that only the timestamps stored after it need to be
+    /// read on first use.
+    pub fn with_lsn_timestamps_path(mut self, path: PathBuf) -> Self {
+        match Self::load_lsn_timestamp_index(&path) {
+            Ok(Some(file)) => {
+                // Anything after the disk consistent LSN is ingested again.
+                // The file may also predate the last GC.
+                let lsn = min(file.lsn, self.tline.get_disk_consistent_lsn());
+                let gc_cutoff_lsn = *self.tline.get_latest_gc_cutoff_lsn();
+                let index = self.lsn_timestamp_index.get_mut().unwrap();
+                index.merge(
+                    file.entries
+                        .into_iter()
+                        .filter(|(entry_lsn, _)| *entry_lsn <= lsn && *entry_lsn >= gc_cutoff_lsn)
+                        .collect(),
+                );
+                index.unread_after = Some(lsn);
+                index.saved_lsn = lsn;
+            }
+            Ok(None) => {}
+            Err(e) => warn!(
+                "could not load LSN timestamp index from {}: {:#}",
+                path.display(),
+                e
+            ),
+        }
+        self.lsn_timestamps_path = Some(path);
+        self
+    }
+
+    fn load_lsn_timestamp_index(path: &Path) -> Result<Option<LsnTimestampIndexFile>> {
+        match std::fs::read(path) {
+            Ok(buf) => Ok(Some(LsnTimestampIndexFile::des(&buf)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
         }
     }
 
+    pub fn get_page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// An all-zeros page, returned for reads beyond the end of a relation
+    /// and used to fill gaps when a relation is extended.
+    pub fn zero_page(&self) -> Bytes {
+        if self.page_size == ZERO_PAGE.len() {
+            ZERO_PAGE.clone()
+        } else {
+            Bytes::from(vec![0u8; self.page_size])
+        }
+    }
+
+    /// Set how many blocks to read ahead of sequential scans. 0 disables
+    /// read-ahead.
+    pub fn set_prefetch_depth(&self, depth: u32) {
+        self.prefetch_depth
+            .store(min(depth, MAX_PREFETCH_DEPTH), Ordering::Relaxed);
+    }
+
     /// (Re-)calculate the logical size of the database at the latest LSN.
     ///
     /// This can be a slow operation.
@@ -75,6 +377,46 @@ impl<R: Repository> DatadirTimeline<R> {
         Ok(())
     }
 
+    /// Read all the relation directories at the latest LSN into rel_dir_cache,
+    /// so that the first reads and writes after loading the timeline don't
+    /// have to fetch them.
+    pub fn preload_rel_dir_cache(&self) -> Result<()> {
+        let last_lsn = self.tline.get_last_record_lsn();
+        for (spcnode, dbnode) in self.list_dbdirs(last_lsn)?.into_keys() {
+            self.with_rel_dir(spcnode, dbnode, last_lsn, |_| ())?;
+        }
+        Ok(())
+    }
+
+    /// Rewind the timeline to 'lsn', to re-ingest the WAL after it.
+    ///
+    /// See [`Timeline::reset_to_lsn`]. The cached relation directories and the
+    /// logical size are recalculated for the new end of the timeline.
+    pub fn reset_to_lsn(&self, lsn: Lsn) -> Result<()> {
+        self.tline.reset_to_lsn(lsn)?;
+        {
+            let mut cache = self.rel_dir_cache.write().unwrap();
+            cache.valid_from = lsn;
+            cache.dirs.clear();
+        }
+        {
+            let mut index = self.lsn_timestamp_index.write().unwrap();
+            index.entries = index.entries.split_at(&(lsn + 1)).0;
+            if index.saved_lsn > lsn {
+                // The saved index has entries past the new end of the timeline
+                if let Some(path) = &self.lsn_timestamps_path {
+                    if let Err(e) = std::fs::remove_file(path) {
+                        if e.kind() != std::io::ErrorKind::NotFound {
+                            return Err(e.into());
+                        }
+                    }
+                }
+                index.saved_lsn = Lsn(0);
+            }
+        }
+        self.init_logical_size()
+    }
+
     /// Start ingesting a WAL record, or other atomic modification of
     /// the timeline.
     ///
@@ -99,16 +441,58 @@ impl<R: Repository> DatadirTimeline<R> {
             pending_updates: HashMap::new(),
             pending_deletions: Vec::new(),
             pending_nblocks: 0,
+            pending_bytes: 0,
         }
     }
 
+    /// Start a read-only snapshot of the timeline at 'lsn'.
+    ///
+    /// All the reads through the returned ReadTxn see the state at the same
+    /// LSN, no matter how much WAL is ingested meanwhile. Use it to read
+    /// several relations consistently. `Lsn::MAX` means the latest LSN, as
+    /// of this call. Waits for the WAL up to 'lsn' to arrive.
+    ///
+    /// NOTE: An open ReadTxn doesn't hold back garbage collection. If the
+    /// GC cutoff moves past its LSN, reads may fail.
+    pub fn begin_read(&self, lsn: Lsn) -> Result<ReadTxn<R>> {
+        let lsn = self.resolve_lsn(lsn);
+        self.tline.wait_lsn(lsn)?;
+        {
+            let latest_gc_cutoff_lsn = self.tline.get_latest_gc_cutoff_lsn();
+            self.tline
+                .check_lsn_is_in_scope(lsn, &latest_gc_cutoff_lsn)?;
+        }
+        Ok(ReadTxn { tline: self, lsn })
+    }
+
     //------------------------------------------------------------------------------
     // Public GET functions
     //------------------------------------------------------------------------------
 
+    /// The relation GET functions accept `Lsn::MAX` to mean "the latest
+    /// version". It is replaced with the last record LSN once, when the call
+    /// starts, so that all the lookups made by one call see the same state,
+    /// even if more WAL is ingested meanwhile.
+    fn resolve_lsn(&self, lsn: Lsn) -> Lsn {
+        if lsn == Lsn::MAX {
+            self.get_last_record_lsn()
+        } else {
+            lsn
+        }
+    }
+
     /// Look up given page version.
+    ///
+    /// An unaligned LSN is allowed, and sees the same state as the preceding
+    /// aligned LSN. See [`Timeline::get`]. `Lsn::MAX` reads the latest
+    /// version, see [`Self::resolve_lsn`].
+    ///
+    /// A block beyond the end of the relation reads as an all-zeros page, like
+    /// in Postgres. If the relation doesn't exist at 'lsn', the error is a
+    /// [`RelationNotFound`].
     pub fn get_rel_page_at_lsn(&self, tag: RelTag, blknum: BlockNumber, lsn: Lsn) -> Result<Bytes> {
         ensure!(tag.relnode != 0, "invalid relnode");
+        let lsn = self.resolve_lsn(lsn);
 
         let nblocks = self.get_rel_size(tag, lsn)?;
         if blknum >= nblocks {
@@ -116,16 +500,212 @@ impl<R: Repository> DatadirTimeline<R> {
                 "read beyond EOF at {} blk {} at {}, size is {}: returning all-zeros page",
                 tag, blknum, lsn, nblocks
             );
-            return Ok(ZERO_PAGE.clone());
+            return Ok(self.zero_page());
         }
 
+        self.maybe_prefetch(tag, blknum, nblocks, lsn);
+
         let key = rel_block_to_key(tag, blknum);
         self.tline.get(key, lsn)
     }
 
-    /// Get size of a relation file
+    /// Look up a page version before the GC cutoff. That's only possible if
+    /// both the relation size and the page are among the newest versions
+    /// that GC keeps for their keys, see min_versions_per_block. Doesn't
+    /// prefetch, the following pages might not be readable at 'lsn'.
+    pub fn get_rel_page_before_gc_cutoff(
+        &self,
+        tag: RelTag,
+        blknum: BlockNumber,
+        lsn: Lsn,
+        latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
+    ) -> Result<Bytes> {
+        ensure!(tag.relnode != 0, "invalid relnode");
+        // The other forks need the relation directory to tell if they exist
+        ensure!(
+            tag.forknum == pg_constants::MAIN_FORKNUM,
+            "only the main fork can be read before the GC cutoff"
+        );
+
+        let size_key = rel_size_to_key(tag);
+        self.tline
+            .check_key_lsn_is_in_scope(size_key, lsn, latest_gc_cutoff_lsn)?;
+        let nblocks = self.tline.get(size_key, lsn)?.get_u32_le();
+        if blknum >= nblocks {
+            return Ok(self.zero_page());
+        }
+
+        let key = rel_block_to_key(tag, blknum);
+        self.tline
+            .check_key_lsn_is_in_scope(key, lsn, latest_gc_cutoff_lsn)?;
+        self.tline.get(key, lsn)
+    }
+
+    /// Look up the newest version of a page before 'exclusive_lsn', i.e. the
+    /// state of the page just before the record at 'exclusive_lsn' was
+    /// applied. For debugging a bad page.
+    pub fn get_rel_page_before_lsn(
+        &self,
+        tag: RelTag,
+        blknum: BlockNumber,
+        exclusive_lsn: Lsn,
+    ) -> Result<Bytes> {
+        // Reading at the preceding, unaligned, LSN sees all the versions
+        // before 'exclusive_lsn', and none at it.
+        let lsn = exclusive_lsn
+            .checked_sub(1u64)
+            .with_context(|| format!("no versions before LSN {}", exclusive_lsn))?;
+        self.get_rel_page_at_lsn(tag, blknum, lsn)
+    }
+
+    /// Detect sequential scans of a relation and reconstruct the blocks ahead
+    /// of the scan on a background thread, so that by the time the scan gets
+    /// to them, they're in the page cache already.
+    fn maybe_prefetch(&self, tag: RelTag, blknum: BlockNumber, nblocks: BlockNumber, lsn: Lsn) {
+        let depth = self.prefetch_depth.load(Ordering::Relaxed);
+        if depth == 0 {
+            return;
+        }
+
+        let blocks = {
+            let mut seq_access = self.seq_access.lock().unwrap();
+            if seq_access.len() >= MAX_PREFETCH_TRACKED_RELS && !seq_access.contains_key(&tag) {
+                seq_access.clear();
+            }
+            let seq = seq_access.entry(tag).or_default();
+            if seq.run_len > 0 && blknum == seq.next_blknum {
+                seq.run_len += 1;
+            } else {
+                // Not sequential, start over
+                seq.run_len = 1;
+                seq.prefetched_upto = blknum + 1;
+            }
+            seq.next_blknum = blknum + 1;
+
+            if seq.run_len < PREFETCH_SEQUENTIAL_THRESHOLD {
+                return;
+            }
+            // Top up the read-ahead window once the scan has consumed half of it
+            if seq.prefetched_upto > blknum + depth / 2 {
+                return;
+            }
+            let start = max(seq.prefetched_upto, blknum + 1);
+            let end = min(blknum.saturating_add(1 + depth), nblocks);
+            if start >= end {
+                return;
+            }
+            // Reserve a thread slot, without going over the limit when
+            // several scans start prefetching at the same time
+            if self
+                .prefetch_threads
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                    (n < MAX_PREFETCH_THREADS).then(|| n + 1)
+                })
+                .is_err()
+            {
+                return;
+            }
+            seq.prefetched_upto = end;
+            start..end
+        };
+
+        let tline = Arc::clone(&self.tline);
+        let prefetch_threads = Arc::clone(&self.prefetch_threads);
+        let spawn_result = thread_mgr::spawn(
+            ThreadKind::Prefetch,
+            Some(self.tline.get_tenant_id()),
+            Some(self.tline.get_timeline_id()),
+            "prefetch thread",
+            false,
+            move || {
+                for blknum in blocks {
+                    if thread_mgr::is_shutdown_requested() {
+                        break;
+                    }
+                    // The scan will run into the same error itself, if it's
+                    // not a transient one. Just stop prefetching.
+                    if let Err(e) = tline.get(rel_block_to_key(tag, blknum), lsn) {
+                        debug!(
+                            "prefetch of {} blk {} at {} failed: {:?}",
+                            tag, blknum, lsn, e
+                        );
+                        break;
+                    }
+                }
+                prefetch_threads.fetch_sub(1, Ordering::Relaxed);
+                Ok(())
+            },
+        );
+        if let Err(e) = spawn_result {
+            self.prefetch_threads.fetch_sub(1, Ordering::Relaxed);
+            warn!("failed to spawn prefetch thread: {}", e);
+        }
+    }
+
+    /// List every stored version of a relation block, with its LSN and whether
+    /// it's a page image or a WAL record. For debugging wrong-page bugs.
+    pub fn block_history(
+        &self,
+        tag: RelTag,
+        blknum: BlockNumber,
+    ) -> Result<Vec<(Lsn, PageVersionKind)>> {
+        ensure!(tag.relnode != 0, "invalid relnode");
+        self.tline.get_key_history(rel_block_to_key(tag, blknum))
+    }
+
+    /// Stream the changes to relation blocks with an LSN in from_lsn..to_lsn,
+    /// in LSN order, for consumers that want a log of changed pages instead
+    /// of the physical WAL. Changes at the same LSN are ordered by relation
+    /// and block number. Like block_history(), nothing is reconstructed, and
+    /// the changes inherited from the ancestor branch are included.
+    pub fn change_stream(
+        &self,
+        from_lsn: Lsn,
+        to_lsn: Lsn,
+    ) -> Result<impl Iterator<Item = Result<ChangeRecord>> + '_> {
+        ensure!(
+            from_lsn <= to_lsn,
+            "invalid LSN range {}..{}",
+            from_lsn,
+            to_lsn
+        );
+        let keys = if from_lsn < to_lsn {
+            self.tline
+                .get_changed_keys(rel_key_range(), from_lsn..to_lsn)?
+                .into_iter()
+                .filter(|key| is_rel_block_key(*key))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok(ChangeStream {
+            tline: self,
+            lsn_range: from_lsn..to_lsn,
+            keys: Some(keys),
+            histories: Vec::new(),
+            heap: BinaryHeap::new(),
+        })
+    }
+
+    /// Describe what reconstructing a relation block at 'lsn' involves,
+    /// without running WAL redo. `Lsn::MAX` means the latest version.
+    pub fn explain_get_page(
+        &self,
+        tag: RelTag,
+        blknum: BlockNumber,
+        lsn: Lsn,
+    ) -> Result<ReconstructPlan> {
+        ensure!(tag.relnode != 0, "invalid relnode");
+        let lsn = self.resolve_lsn(lsn);
+        self.tline.explain_get(rel_block_to_key(tag, blknum), lsn)
+    }
+
+    /// Get size of a relation file. `Lsn::MAX` means the latest size.
+    /// Fails with [`RelationNotFound`] if the relation doesn't exist at 'lsn'.
     pub fn get_rel_size(&self, tag: RelTag, lsn: Lsn) -> Result<BlockNumber> {
         ensure!(tag.relnode != 0, "invalid relnode");
+        let lsn = self.resolve_lsn(lsn);
 
         if (tag.forknum == pg_constants::FSM_FORKNUM
             || tag.forknum == pg_constants::VISIBILITYMAP_FORKNUM)
@@ -139,40 +719,195 @@ impl<R: Repository> DatadirTimeline<R> {
         }
 
         let key = rel_size_to_key(tag);
-        let mut buf = self.tline.get(key, lsn)?;
+        let mut buf = match self.tline.get(key, lsn) {
+            Ok(buf) => buf,
+            Err(err) => {
+                // Tell a relation that doesn't exist at 'lsn' apart from
+                // failures to read an existing one. Before the first relation
+                // in a database is created, there's no directory to look in.
+                let rel_exists = self.list_dbdirs(lsn).and_then(|dbdirs| {
+                    if dbdirs.contains_key(&(tag.spcnode, tag.dbnode)) {
+                        self.get_rel_exists(tag, lsn)
+                    } else {
+                        Ok(false)
+                    }
+                });
+                if matches!(rel_exists, Ok(false)) {
+                    return Err(RelationNotFound { tag, lsn }.into());
+                }
+                return Err(err);
+            }
+        };
         Ok(buf.get_u32_le())
     }
 
-    /// Does relation exist?
+    /// Get the sizes of all existing forks of a relation, by fork number.
+    /// `Lsn::MAX` means the latest sizes.
+    ///
+    /// The forks are found with one lookup of the relation directory, instead
+    /// of checking each fork's existence like get_rel_size() does.
+    pub fn get_rel_fork_sizes(
+        &self,
+        spcnode: Oid,
+        dbnode: Oid,
+        relnode: Oid,
+        lsn: Lsn,
+    ) -> Result<HashMap<u8, BlockNumber>> {
+        ensure!(relnode != 0, "invalid relnode");
+        let lsn = self.resolve_lsn(lsn);
+
+        let forknums: Vec<u8> = self.with_rel_dir(spcnode, dbnode, lsn, |rels| {
+            rels.iter()
+                .filter(|(rel, _)| *rel == relnode)
+                .map(|(_, forknum)| *forknum)
+                .collect()
+        })?;
+        forknums
+            .into_iter()
+            .map(|forknum| {
+                let tag = RelTag {
+                    spcnode,
+                    dbnode,
+                    relnode,
+                    forknum,
+                };
+                let mut buf = self.tline.get(rel_size_to_key(tag), lsn)?;
+                Ok((forknum, buf.get_u32_le()))
+            })
+            .collect()
+    }
+
+    /// Does relation exist? `Lsn::MAX` means at the latest LSN.
     pub fn get_rel_exists(&self, tag: RelTag, lsn: Lsn) -> Result<bool> {
         ensure!(tag.relnode != 0, "invalid relnode");
+        let lsn = self.resolve_lsn(lsn);
+
+        self.with_rel_dir(tag.spcnode, tag.dbnode, lsn, |rels| {
+            rels.contains(&(tag.relnode, tag.forknum))
+        })
+    }
+
+    /// Like get_rel_exists(), but doesn't add the relation directory to
+    /// rel_dir_cache if it's not there already. Reads only the directory of
+    /// the relation's database, so this is cheap on a cold timeline, and
+    /// doesn't fill the cache with directories that won't be used again.
+    pub fn rel_exists_cheap(&self, tag: RelTag, lsn: Lsn) -> Result<bool> {
+        ensure!(tag.relnode != 0, "invalid relnode");
+        let lsn = self.resolve_lsn(lsn);
+
+        {
+            let cache = self.rel_dir_cache.read().unwrap();
+            if lsn >= cache.valid_from {
+                if let Some(rels) = cache.dirs.get(&(tag.spcnode, tag.dbnode)) {
+                    return Ok(rels.contains(&(tag.relnode, tag.forknum)));
+                }
+            }
+        }
 
-        // fetch directory listing
         let key = rel_dir_to_key(tag.spcnode, tag.dbnode);
         let buf = self.tline.get(key, lsn)?;
         let dir = RelDirectory::des(&buf)?;
-
-        let exists = dir.rels.get(&(tag.relnode, tag.forknum)).is_some();
-
-        Ok(exists)
+        Ok(dir.rels.contains(&(tag.relnode, tag.forknum)))
     }
 
-    /// Get a list of all existing relations in given tablespace and database.
-    pub fn list_rels(&self, spcnode: Oid, dbnode: Oid, lsn: Lsn) -> Result<HashSet<RelTag>> {
+    /// Call 'f' with the relations in the given tablespace and database at
+    /// 'lsn', as (relnode, forknum) pairs. The directory is served from
+    /// rel_dir_cache if it hasn't changed since 'lsn', and added to it otherwise.
+    fn with_rel_dir<T>(
+        &self,
+        spcnode: Oid,
+        dbnode: Oid,
+        lsn: Lsn,
+        f: impl FnOnce(&HashSet<(Oid, u8)>) -> T,
+    ) -> Result<T> {
+        // Check the cache first, if the directory hasn't changed since 'lsn'
+        {
+            let cache = self.rel_dir_cache.read().unwrap();
+            if lsn >= cache.valid_from {
+                if let Some(rels) = cache.dirs.get(&(spcnode, dbnode)) {
+                    return Ok(f(rels));
+                }
+            }
+        }
+        self.rel_dir_cache_misses.fetch_add(1, Ordering::Relaxed);
+
         // fetch directory listing
         let key = rel_dir_to_key(spcnode, dbnode);
         let buf = self.tline.get(key, lsn)?;
         let dir = RelDirectory::des(&buf)?;
 
-        let rels: HashSet<RelTag> =
-            HashSet::from_iter(dir.rels.iter().map(|(relnode, forknum)| RelTag {
+        let result = f(&dir.rels);
+
+        // Remember the directory, unless it has been modified after 'lsn'
+        // while we were reading it.
+        let mut cache = self.rel_dir_cache.write().unwrap();
+        if lsn >= cache.valid_from {
+            cache.dirs.insert((spcnode, dbnode), dir.rels);
+        }
+
+        Ok(result)
+    }
+
+    /// Iterate over all blocks of a relation at given LSN.
+    ///
+    /// The pages are reconstructed one at a time, as the iterator is advanced,
+    /// so this can be used to scan relations of any size. Like
+    /// get_rel_page_at_lsn(), this falls back to the ancestor timelines for
+    /// data that was inherited at branch creation.
+    pub fn iter_rel_blocks(
+        &self,
+        tag: RelTag,
+        lsn: Lsn,
+    ) -> Result<impl Iterator<Item = Result<(BlockNumber, Bytes)>> + '_> {
+        let nblocks = self.get_rel_size(tag, lsn)?;
+        Ok((0..nblocks)
+            .map(move |blknum| Ok((blknum, self.get_rel_page_at_lsn(tag, blknum, lsn)?))))
+    }
+
+    /// Get the blocks of a relation that were modified by a WAL record with
+    /// 'from_lsn' < LSN <= 'to_lsn', in ascending order.
+    ///
+    /// This is meant for change data capture: the caller can read the
+    /// returned blocks at 'to_lsn' to get the changes since 'from_lsn'.
+    /// Blocks that were modified and then truncated away are included too.
+    pub fn get_rel_changed_blocks(
+        &self,
+        tag: RelTag,
+        from_lsn: Lsn,
+        to_lsn: Lsn,
+    ) -> Result<Vec<BlockNumber>> {
+        ensure!(tag.relnode != 0, "invalid relnode");
+        let to_lsn = self.resolve_lsn(to_lsn);
+        ensure!(
+            from_lsn <= to_lsn,
+            "invalid LSN range {}..{}",
+            from_lsn,
+            to_lsn
+        );
+
+        // The relation size key sorts after all the blocks
+        let keys = self.tline.get_changed_keys(
+            rel_block_to_key(tag, 0)..rel_size_to_key(tag),
+            from_lsn + 1..to_lsn + 1,
+        )?;
+        keys.into_iter()
+            .map(|key| Ok(key_to_rel_block(key)?.1))
+            .collect()
+    }
+
+    /// Get a list of all existing relations in given tablespace and database.
+    /// `Lsn::MAX` lists the relations at the latest LSN.
+    pub fn list_rels(&self, spcnode: Oid, dbnode: Oid, lsn: Lsn) -> Result<HashSet<RelTag>> {
+        let lsn = self.resolve_lsn(lsn);
+
+        self.with_rel_dir(spcnode, dbnode, lsn, |rels| {
+            HashSet::from_iter(rels.iter().map(|(relnode, forknum)| RelTag {
                 spcnode,
                 dbnode,
                 relnode: *relnode,
                 forknum: *forknum,
-            }));
-
-        Ok(rels)
+            }))
+        })
     }
 
     /// Look up given SLRU page version.
@@ -214,100 +949,32 @@ impl<R: Repository> DatadirTimeline<R> {
     /// 'search_timestamp' are visible, but nothing newer is.
     ///
     /// This is not exact. Commit timestamps are not guaranteed to be ordered,
-    /// so it's not well defined which LSN you get if there were multiple commits
-    /// "in flight" at that point in time.
+    /// so a commit counts as being before 'search_timestamp' only if all the
+    /// earlier ones are, too. The returned LSN is that of the last such
+    /// commit or checkpoint record. Only the records after the GC cutoff are
+    /// considered, see the LSN timestamp index.
     ///
     pub fn find_lsn_for_timestamp(&self, search_timestamp: TimestampTz) -> Result<LsnForTimestamp> {
-        let gc_cutoff_lsn_guard = self.tline.get_latest_gc_cutoff_lsn();
-        let min_lsn = *gc_cutoff_lsn_guard;
         let max_lsn = self.tline.get_last_record_lsn();
-
-        // LSNs are always 8-byte aligned. low/mid/high represent the
-        // LSN divided by 8.
-        let mut low = min_lsn.0 / 8;
-        let mut high = max_lsn.0 / 8 + 1;
-
-        let mut found_smaller = false;
-        let mut found_larger = false;
-        while low < high {
-            // cannot overflow, high and low are both smaller than u64::MAX / 2
-            let mid = (high + low) / 2;
-
-            let cmp = self.is_latest_commit_timestamp_ge_than(
-                search_timestamp,
-                Lsn(mid * 8),
-                &mut found_smaller,
-                &mut found_larger,
-            )?;
-
-            if cmp {
-                high = mid;
-            } else {
-                low = mid + 1;
-            }
-        }
-        match (found_smaller, found_larger) {
-            (false, false) => {
+        self.with_lsn_timestamp_index(|index| {
+            let entries = index.as_slice();
+            if entries.is_empty() {
                 // This can happen if no commit records have been processed yet, e.g.
                 // just after importing a cluster.
                 bail!("no commit timestamps found");
             }
-            (true, false) => {
+            let idx =
+                entries.partition_point(|(_, max_timestamp)| *max_timestamp < search_timestamp);
+            Ok(if idx == entries.len() {
                 // Didn't find any commit timestamps larger than the request
-                Ok(LsnForTimestamp::Future(max_lsn))
-            }
-            (false, true) => {
+                LsnForTimestamp::Future(max_lsn)
+            } else if idx == 0 {
                 // Didn't find any commit timestamps smaller than the request
-                Ok(LsnForTimestamp::Past(max_lsn))
-            }
-            (true, true) => {
-                // low is the LSN of the first commit record *after* the search_timestamp,
-                // Back off by one to get to the point just before the commit.
-                //
-                // FIXME: it would be better to get the LSN of the previous commit.
-                // Otherwise, if you restore to the returned LSN, the database will
-                // include physical changes from later commits that will be marked
-                // as aborted, and will need to be vacuumed away.
-                Ok(LsnForTimestamp::Present(Lsn((low - 1) * 8)))
-            }
-        }
-    }
-
-    ///
-    /// Subroutine of find_lsn_for_timestamp(). Returns true, if there are any
-    /// commits that committed after 'search_timestamp', at LSN 'probe_lsn'.
-    ///
-    /// Additionally, sets 'found_smaller'/'found_Larger, if encounters any commits
-    /// with a smaller/larger timestamp.
-    ///
-    fn is_latest_commit_timestamp_ge_than(
-        &self,
-        search_timestamp: TimestampTz,
-        probe_lsn: Lsn,
-        found_smaller: &mut bool,
-        found_larger: &mut bool,
-    ) -> Result<bool> {
-        for segno in self.list_slru_segments(SlruKind::Clog, probe_lsn)? {
-            let nblocks = self.get_slru_segment_size(SlruKind::Clog, segno, probe_lsn)?;
-            for blknum in (0..nblocks).rev() {
-                let clog_page =
-                    self.get_slru_page_at_lsn(SlruKind::Clog, segno, blknum, probe_lsn)?;
-
-                if clog_page.len() == pg_constants::BLCKSZ as usize + 8 {
-                    let mut timestamp_bytes = [0u8; 8];
-                    timestamp_bytes.copy_from_slice(&clog_page[pg_constants::BLCKSZ as usize..]);
-                    let timestamp = TimestampTz::from_be_bytes(timestamp_bytes);
-
-                    if timestamp >= search_timestamp {
-                        *found_larger = true;
-                        return Ok(true);
-                    } else {
-                        *found_smaller = true;
-                    }
-                }
-            }
-        }
-        Ok(false)
+                LsnForTimestamp::Past(max_lsn)
+            } else {
+                LsnForTimestamp::Present(entries[idx - 1].0)
+            })
+        })?
     }
 
     /// Get a list of SLRU segments
@@ -358,6 +1025,116 @@ impl<R: Repository> DatadirTimeline<R> {
         self.tline.get(CHECKPOINT_KEY, lsn)
     }
 
+    /// Get the timestamp of the last commit or checkpoint record at or before
+    /// 'lsn'. Returns None if no such record has been ingested yet.
+    pub fn get_lsn_timestamp(&self, lsn: Lsn) -> Result<Option<SystemTime>> {
+        let buf = match self.tline.get(LSN_TIMESTAMP_KEY, lsn) {
+            Ok(buf) => buf,
+            // Timelines created before the timestamps were tracked don't have it
+            Err(e) if e.downcast_ref::<KeyNotFound>().is_some() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(decode_lsn_timestamp(buf).map(from_pg_timestamp))
+    }
+
+    /// Find the last commit or checkpoint record with a timestamp at or before
+    /// 'timestamp', for recovery to a point in time. Returns None if all the
+    /// records are later than that, or there are none. Like
+    /// find_lsn_for_timestamp(), but a record at exactly 'timestamp' counts.
+    pub fn lsn_for_timestamp(&self, timestamp: SystemTime) -> Result<Option<Lsn>> {
+        if self.with_lsn_timestamp_index(|index| index.is_empty())? {
+            return Ok(None);
+        }
+        // Timestamps are in microseconds
+        let search_timestamp = to_pg_timestamp(timestamp) + 1;
+        Ok(match self.find_lsn_for_timestamp(search_timestamp)? {
+            LsnForTimestamp::Present(lsn) | LsnForTimestamp::Future(lsn) => Some(lsn),
+            LsnForTimestamp::Past(_) => None,
+        })
+    }
+
+    /// Call 'f' with the LSN timestamp index, reading the stored timestamps
+    /// into it first if needed.
+    fn with_lsn_timestamp_index<T>(
+        &self,
+        f: impl FnOnce(&VecMap<Lsn, TimestampTz>) -> T,
+    ) -> Result<T> {
+        let unread_after = {
+            let index = self.lsn_timestamp_index.read().unwrap();
+            match index.unread_after {
+                None => return Ok(f(&index.entries)),
+                Some(lsn) => lsn,
+            }
+        };
+
+        // Read them without holding the lock, so that ingestion can go on in
+        // the meanwhile, and merge with what was ingested.
+        let gc_cutoff_lsn = *self.tline.get_latest_gc_cutoff_lsn();
+        let mut stored = Vec::new();
+        for (lsn, _) in self.tline.get_key_history(LSN_TIMESTAMP_KEY)? {
+            if lsn <= unread_after || lsn < gc_cutoff_lsn {
+                continue;
+            }
+            let buf = self.tline.get(LSN_TIMESTAMP_KEY, lsn)?;
+            if let Some(timestamp) = decode_lsn_timestamp(buf) {
+                stored.push((lsn, timestamp));
+            }
+        }
+
+        let mut index = self.lsn_timestamp_index.write().unwrap();
+        if index.unread_after.is_some() {
+            index.merge(stored);
+            index.unread_after = None;
+        }
+        Ok(f(&index.entries))
+    }
+
+    /// Add a newly ingested timestamp to the LSN timestamp index.
+    fn note_lsn_timestamp(&self, lsn: Lsn, timestamp: TimestampTz) {
+        let mut index = self.lsn_timestamp_index.write().unwrap();
+        let max_timestamp = match index.entries.as_slice().last() {
+            Some((_, last_timestamp)) => max(*last_timestamp, timestamp),
+            None => timestamp,
+        };
+        let _ = index.entries.append_or_update_last(lsn, max_timestamp);
+    }
+
+    /// Drop the LSN timestamp index entries below the GC cutoff. The pages at
+    /// those LSNs can't be read anymore, so there's no point finding them.
+    pub fn trim_lsn_timestamp_index(&self, cutoff: Lsn) {
+        let mut index = self.lsn_timestamp_index.write().unwrap();
+        index.entries = index.entries.split_at(&cutoff).1;
+    }
+
+    /// Save the LSN timestamp index, up to the disk consistent LSN, to the
+    /// file next to the timeline metadata, if it has changed since the last
+    /// save. The entries after that are ingested again after a restart.
+    pub fn save_lsn_timestamp_index(&self) -> Result<()> {
+        let path = match &self.lsn_timestamps_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let lsn = self.tline.get_disk_consistent_lsn();
+        let entries = self.with_lsn_timestamp_index(|index| index.slice_range(..=lsn).to_vec())?;
+        if lsn <= self.lsn_timestamp_index.read().unwrap().saved_lsn {
+            return Ok(());
+        }
+
+        let buf = LsnTimestampIndexFile { lsn, entries }.ser()?;
+        let temp_path = path.with_extension("temp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+
+        let mut index = self.lsn_timestamp_index.write().unwrap();
+        index.saved_lsn = max(index.saved_lsn, lsn);
+        Ok(())
+    }
+
     /// Get the LSN of the last ingested WAL record.
     ///
     /// This is just a convenience wrapper that calls through to the underlying
@@ -398,24 +1175,124 @@ impl<R: Repository> DatadirTimeline<R> {
     /// Does the same as get_current_logical_size but counted on demand.
     /// Used to initialize the logical size tracking on startup.
     ///
-    /// Only relation blocks are counted currently. That excludes metadata,
-    /// SLRUs, twophase files etc.
+    /// The logical size includes the data files that Postgres would have on
+    /// disk:
+    ///
+    /// - relation blocks,
+    /// - SLRU blocks (pg_xact, pg_multixact),
+    /// - two-phase state files (pg_twophase).
+    ///
+    /// Metadata that has no counterpart on disk, like the directories and
+    /// the relation sizes, is not counted. Neither are the control file,
+    /// the checkpoint and the relmapper files, which are small and of fixed
+    /// size.
     pub fn get_current_logical_size_non_incremental(&self, lsn: Lsn) -> Result<usize> {
         // Fetch list of database dirs and iterate them
         let buf = self.tline.get(DBDIR_KEY, lsn)?;
         let dbdir = DbDirectory::des(&buf)?;
 
+        // Walk each relation directory as it is stored, rather than going
+        // through list_rels(), to avoid building a set of RelTags for every
+        // relation in the database. That matters at startup on databases with
+        // a huge number of relations.
         let mut total_size: usize = 0;
         for (spcnode, dbnode) in dbdir.dbdirs.keys() {
-            for rel in self.list_rels(*spcnode, *dbnode, lsn)? {
-                let relsize_key = rel_size_to_key(rel);
-                let mut buf = self.tline.get(relsize_key, lsn)?;
+            let buf = self.tline.get(rel_dir_to_key(*spcnode, *dbnode), lsn)?;
+            let rel_dir = RelDirectory::des(&buf)?;
+            for (relnode, forknum) in rel_dir.rels.iter() {
+                let rel = RelTag {
+                    spcnode: *spcnode,
+                    dbnode: *dbnode,
+                    relnode: *relnode,
+                    forknum: *forknum,
+                };
+                let mut buf = self.tline.get(rel_size_to_key(rel), lsn)?;
                 let relsize = buf.get_u32_le();
 
                 total_size += relsize as usize;
             }
         }
-        Ok(total_size * pg_constants::BLCKSZ as usize)
+
+        for kind in [
+            SlruKind::Clog,
+            SlruKind::MultiXactMembers,
+            SlruKind::MultiXactOffsets,
+        ] {
+            for segno in self.list_slru_segments(kind, lsn)? {
+                total_size += self.get_slru_segment_size(kind, segno, lsn)? as usize;
+            }
+        }
+        let mut total_size = total_size * self.page_size;
+
+        for xid in self.list_twophase_files(lsn)? {
+            total_size += self.tline.get(twophase_file_key(xid), lsn)?.len();
+        }
+
+        Ok(total_size)
+    }
+
+    ///
+    /// Check that the timeline's data is consistent at the last record LSN:
+    ///
+    /// - disk_consistent_lsn is not ahead of the last record LSN,
+    /// - every relation listed in the relation directories has a size,
+    /// - every block within the size of a relation can be reconstructed, i.e.
+    ///   its WAL records end at a page image or at a record that initializes
+    ///   the page.
+    ///
+    /// Nothing is modified. Problems are collected into the report, an error
+    /// is only returned if the database directory cannot be read at all.
+    ///
+    /// This reads every block of every relation, so it's slow.
+    pub fn validate(&self) -> Result<ValidationReport> {
+        let lsn = self.tline.get_last_record_lsn();
+        let mut report = ValidationReport::default();
+
+        let disk_consistent_lsn = self.tline.get_disk_consistent_lsn();
+        if disk_consistent_lsn > lsn {
+            report.add_problem(format!(
+                "disk_consistent_lsn {} is ahead of last record LSN {}",
+                disk_consistent_lsn, lsn
+            ));
+        }
+
+        let buf = self.tline.get(DBDIR_KEY, lsn)?;
+        let dbdir = DbDirectory::des(&buf)?;
+        for (spcnode, dbnode) in dbdir.dbdirs.keys() {
+            let mut rels = match self.list_rels(*spcnode, *dbnode, lsn) {
+                Ok(rels) => Vec::from_iter(rels),
+                Err(e) => {
+                    report.add_problem(format!(
+                        "cannot read relation directory of database {}/{}: {:#}",
+                        spcnode, dbnode, e
+                    ));
+                    continue;
+                }
+            };
+            rels.sort_by_key(|rel| (rel.relnode, rel.forknum));
+
+            for rel in rels {
+                report.rels_checked += 1;
+                let nblocks = match self.tline.get(rel_size_to_key(rel), lsn) {
+                    Ok(mut buf) => buf.get_u32_le(),
+                    Err(e) => {
+                        report.add_problem(format!("cannot read size of {}: {:#}", rel, e));
+                        continue;
+                    }
+                };
+                for blknum in 0..nblocks {
+                    report.blocks_checked += 1;
+                    if let Err(e) = self.tline.get(rel_block_to_key(rel, blknum), lsn) {
+                        report.add_problem(format!(
+                            "cannot reconstruct block {} of {}: {:#}",
+                            blknum, rel, e
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(report)
     }
 
     ///
@@ -491,6 +1368,59 @@ impl<R: Repository> DatadirTimeline<R> {
 
         result.add_key(CONTROLFILE_KEY);
         result.add_key(CHECKPOINT_KEY);
+        // Timelines created before the timestamps were tracked don't have it
+        match self.tline.get(LSN_TIMESTAMP_KEY, lsn) {
+            Ok(_) => result.add_key(LSN_TIMESTAMP_KEY),
+            Err(e) if e.downcast_ref::<KeyNotFound>().is_some() => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(result.to_keyspace())
+    }
+
+    ///
+    /// Collect the relation blocks that were truncated away before 'cutoff'.
+    ///
+    /// A block is included if it is beyond the end of its relation at
+    /// 'cutoff', at each of 'retain_lsns' (the branch points of child
+    /// timelines), and at the last record LSN. Page versions of such blocks
+    /// older than 'cutoff' are not needed by any GetPage@LSN request that
+    /// is still allowed, so they can be removed from storage.
+    ///
+    /// NOTE: This assumes that when a relation is extended again, the new
+    /// blocks are initialized from scratch, which is what Postgres does.
+    ///
+    pub fn collect_truncated_blocks(&self, cutoff: Lsn, retain_lsns: &[Lsn]) -> Result<KeySpace> {
+        let mut result = KeySpaceAccum::new();
+        let last_lsn = self.get_last_record_lsn();
+
+        let buf = self.tline.get(DBDIR_KEY, cutoff)?;
+        let dbdir = DbDirectory::des(&buf)?;
+
+        let mut dbs: Vec<(Oid, Oid)> = dbdir.dbdirs.keys().cloned().collect();
+        dbs.sort_unstable();
+        for (spcnode, dbnode) in dbs {
+            let mut rels: Vec<RelTag> = self
+                .list_rels(spcnode, dbnode, cutoff)?
+                .iter()
+                .cloned()
+                .collect();
+            rels.sort_unstable();
+            'rels: for rel in rels {
+                let mut nblocks = self.get_rel_size(rel, cutoff)?;
+                for lsn in retain_lsns.iter().chain(std::iter::once(&last_lsn)) {
+                    match self.get_rel_exists(rel, *lsn) {
+                        Ok(true) => nblocks = max(nblocks, self.get_rel_size(rel, *lsn)?),
+                        Ok(false) => {}
+                        // Err on the side of keeping the data, if we cannot tell
+                        // the size the relation had at a branch point.
+                        Err(_) => continue 'rels,
+                    }
+                }
+                // The size key sorts after all the blocks, and is not included
+                result.add_range(rel_block_to_key(rel, nblocks)..rel_size_to_key(rel));
+            }
+        }
 
         Ok(result.to_keyspace())
     }
@@ -509,6 +1439,109 @@ impl<R: Repository> DatadirTimeline<R> {
     }
 }
 
+/// A read-only snapshot of a timeline at a fixed LSN, see
+/// [`DatadirTimeline::begin_read`].
+pub struct ReadTxn<'a, R: Repository> {
+    tline: &'a DatadirTimeline<R>,
+    lsn: Lsn,
+}
+
+impl<'a, R: Repository> ReadTxn<'a, R> {
+    /// The LSN that all the reads see.
+    pub fn lsn(&self) -> Lsn {
+        self.lsn
+    }
+
+    pub fn get_rel_page(&self, tag: RelTag, blknum: BlockNumber) -> Result<Bytes> {
+        self.tline.get_rel_page_at_lsn(tag, blknum, self.lsn)
+    }
+
+    pub fn get_rel_size(&self, tag: RelTag) -> Result<BlockNumber> {
+        self.tline.get_rel_size(tag, self.lsn)
+    }
+
+    pub fn get_rel_exists(&self, tag: RelTag) -> Result<bool> {
+        self.tline.get_rel_exists(tag, self.lsn)
+    }
+
+    pub fn list_rels(&self, spcnode: Oid, dbnode: Oid) -> Result<HashSet<RelTag>> {
+        self.tline.list_rels(spcnode, dbnode, self.lsn)
+    }
+}
+
+/// Calculate the logical size of a whole tenant, from its timelines.
+///
+/// The logical size of a branch includes the data it shares with its ancestor,
+/// so simply adding up the sizes of all timelines would count that data once
+/// per branch. Instead, a branch only contributes what it has grown since the
+/// branch point: its current size minus the ancestor's size at the branch
+/// point, or nothing if it has shrunk. Timelines whose ancestor is not in
+/// 'timelines' are counted in full, like root timelines.
+///
+/// Calculating the ancestor's size at the branch point is expensive, see
+/// get_current_logical_size_non_incremental().
+pub fn get_tenant_logical_size<R: Repository>(
+    timelines: &HashMap<ZTimelineId, Arc<DatadirTimeline<R>>>,
+) -> Result<usize> {
+    let mut total_size = 0;
+    for tline in timelines.values() {
+        let size = tline.get_current_logical_size();
+        let ancestor = tline
+            .tline
+            .get_ancestor_timeline_id()
+            .and_then(|ancestor_id| timelines.get(&ancestor_id));
+        total_size += match ancestor {
+            Some(ancestor) => {
+                let branch_point_size = ancestor
+                    .get_current_logical_size_non_incremental(tline.tline.get_ancestor_lsn())?;
+                size.saturating_sub(branch_point_size)
+            }
+            None => size,
+        };
+    }
+    Ok(total_size)
+}
+
+/// Find the relations that have changed after LSN 'since' on each of the given
+/// timelines, e.g. to schedule incremental backups. A relation has changed if
+/// any of its blocks was written, or it was created, extended or truncated.
+/// Like get_rel_changed_blocks(), this includes changes inherited from the
+/// ancestor branch after 'since'. Timelines without changes are left out.
+///
+/// The timelines are scanned one at a time, so only one timeline's changed
+/// keys are held in memory at once.
+pub fn get_tenant_changed_rels<R: Repository>(
+    timelines: &HashMap<ZTimelineId, Arc<DatadirTimeline<R>>>,
+    since: Lsn,
+) -> Result<HashMap<ZTimelineId, HashSet<RelTag>>> {
+    let rel_keys = rel_key_range();
+
+    let mut result = HashMap::new();
+    for (timeline_id, tline) in timelines {
+        let last_record_lsn = tline.tline.get_last_record_lsn();
+        if since >= last_record_lsn {
+            continue;
+        }
+        let keys = tline
+            .tline
+            .get_changed_keys(rel_keys.clone(), since + 1..last_record_lsn + 1)
+            .with_context(|| format!("could not list changes on timeline {timeline_id}"))?;
+
+        let mut rels = HashSet::new();
+        for key in keys {
+            // Skip the directory keys, they're not part of any relation
+            if key.field4 == 0 {
+                continue;
+            }
+            rels.insert(key_to_rel_block(key)?.0);
+        }
+        if !rels.is_empty() {
+            result.insert(*timeline_id, rels);
+        }
+    }
+    Ok(result)
+}
+
 /// DatadirModification represents an operation to ingest an atomic set of
 /// updates to the repository. It is created by the 'begin_record'
 /// function. It is called for each WAL record, so that all the modifications
@@ -526,7 +1559,11 @@ pub struct DatadirModification<'a, R: Repository> {
     // underlying key-value store by the 'finish' function.
     pending_updates: HashMap<Key, Value>,
     pending_deletions: Vec<Range<Key>>,
+    // Change of the logical size, see get_current_logical_size_non_incremental().
+    // Relation and SLRU blocks are counted in 'pending_nblocks', two-phase
+    // files in 'pending_bytes'.
     pending_nblocks: isize,
+    pending_bytes: isize,
 }
 
 impl<'a, R: Repository> DatadirModification<'a, R> {
@@ -554,6 +1591,9 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         );
         self.put(slru_dir_to_key(SlruKind::MultiXactOffsets), empty_dir);
 
+        // No timestamped records seen yet
+        self.put(LSN_TIMESTAMP_KEY, Value::Image(Bytes::new()));
+
         Ok(())
     }
 
@@ -652,6 +1692,7 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
             Value::Image(Bytes::from(TwoPhaseDirectory::ser(&dir)?)),
         );
 
+        self.pending_bytes += img.len() as isize;
         self.put(twophase_file_key(xid), Value::Image(img));
         Ok(())
     }
@@ -666,6 +1707,14 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         Ok(())
     }
 
+    /// Remember the timestamp of a commit or checkpoint record, for
+    /// get_lsn_timestamp().
+    pub fn put_lsn_timestamp(&mut self, timestamp: TimestampTz) -> Result<()> {
+        let buf = timestamp.to_le_bytes();
+        self.put(LSN_TIMESTAMP_KEY, Value::Image(Bytes::from(buf.to_vec())));
+        Ok(())
+    }
+
     pub fn drop_dbdir(&mut self, spcnode: Oid, dbnode: Oid) -> Result<()> {
         // Remove entry from dbdir
         let buf = self.get(DBDIR_KEY)?;
@@ -815,6 +1864,8 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
 
         // even if nblocks > 0, we don't insert any actual blocks here
 
+        self.pending_nblocks += nblocks as isize;
+
         Ok(())
     }
 
@@ -827,8 +1878,12 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
     ) -> Result<()> {
         // Put size
         let size_key = slru_segment_size_to_key(kind, segno);
+        let old_size = self.get(size_key)?.get_u32_le();
         let buf = nblocks.to_le_bytes();
         self.put(size_key, Value::Image(Bytes::from(buf.to_vec())));
+
+        // Update logical database size.
+        self.pending_nblocks += nblocks as isize - old_size as isize;
         Ok(())
     }
 
@@ -839,7 +1894,12 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         let buf = self.get(dir_key)?;
         let mut dir = SlruSegmentDirectory::des(&buf)?;
 
-        if !dir.segments.remove(&segno) {
+        if dir.segments.remove(&segno) {
+            let old_size = self
+                .get(slru_segment_size_to_key(kind, segno))?
+                .get_u32_le();
+            self.pending_nblocks -= old_size as isize;
+        } else {
             warn!("slru segment {:?}/{} does not exist", kind, segno);
         }
         self.put(
@@ -865,7 +1925,9 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
         let buf = self.get(TWOPHASEDIR_KEY)?;
         let mut dir = TwoPhaseDirectory::des(&buf)?;
 
-        if !dir.xids.remove(&xid) {
+        if dir.xids.remove(&xid) {
+            self.pending_bytes -= self.get(twophase_file_key(xid))?.len() as isize;
+        } else {
             warn!("twophase file for xid {} does not exist", xid);
         }
         self.put(
@@ -886,22 +1948,46 @@ impl<'a, R: Repository> DatadirModification<'a, R> {
     pub fn commit(self) -> Result<()> {
         let writer = self.tline.tline.writer();
 
-        let pending_nblocks = self.pending_nblocks;
+        let pending_size =
+            self.pending_nblocks * self.tline.page_size as isize + self.pending_bytes;
+
+        // Invalidate cached relation directories that are about to change.
+        // This must happen before the new versions become visible.
+        let rel_dirs_changed =
+            !self.pending_deletions.is_empty() || self.pending_updates.keys().any(is_rel_dir_key);
+        if rel_dirs_changed {
+            let mut cache = self.tline.rel_dir_cache.write().unwrap();
+            cache.valid_from = self.lsn;
+            if self.pending_deletions.is_empty() {
+                for key in self.pending_updates.keys().filter(|k| is_rel_dir_key(k)) {
+                    cache.dirs.remove(&(key.field2, key.field3));
+                }
+            } else {
+                // Deletions can remove whole databases; just forget everything.
+                cache.dirs.clear();
+            }
+        }
+
+        let new_timestamp = match self.pending_updates.get(&LSN_TIMESTAMP_KEY) {
+            Some(Value::Image(img)) => decode_lsn_timestamp(img.clone()),
+            _ => None,
+        };
 
-        for (key, value) in self.pending_updates {
-            writer.put(key, self.lsn, value)?;
-        }
+        writer.put_batch(self.lsn, self.pending_updates.into_iter().collect())?;
         for key_range in self.pending_deletions {
             writer.delete(key_range.clone(), self.lsn)?;
         }
 
         writer.finish_write(self.lsn);
 
-        if pending_nblocks != 0 {
-            self.tline.current_logical_size.fetch_add(
-                pending_nblocks * pg_constants::BLCKSZ as isize,
-                Ordering::SeqCst,
-            );
+        if let Some(timestamp) = new_timestamp {
+            self.tline.note_lsn_timestamp(self.lsn, timestamp);
+        }
+
+        if pending_size != 0 {
+            self.tline
+                .current_logical_size
+                .fetch_add(pending_size, Ordering::SeqCst);
         }
 
         Ok(())
@@ -1014,6 +2100,7 @@ static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; pg_constants::BLCKSZ as usiz
 // 03 misc
 //    controlfile
 //    checkpoint
+//    lsn timestamp
 //
 // Below is a full list of the keyspace allocation:
 //
@@ -1052,6 +2139,9 @@ static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; pg_constants::BLCKSZ as usiz
 //
 // Checkpoint:
 // 03 00000000 00000000 00000000 00   00000001
+//
+// LsnTimestamp:
+// 03 00000000 00000000 00000000 00   00000002
 
 //-- Section 01: relation data and metadata
 
@@ -1104,7 +2194,11 @@ fn rel_dir_to_key(spcnode: Oid, dbnode: Oid) -> Key {
     }
 }
 
-fn rel_block_to_key(rel: RelTag, blknum: BlockNumber) -> Key {
+fn is_rel_dir_key(key: &Key) -> bool {
+    key.field1 == 0x00 && key.field4 == 0 && key.field5 == 0 && key.field6 == 1
+}
+
+pub fn rel_block_to_key(rel: RelTag, blknum: BlockNumber) -> Key {
     Key {
         field1: 0x00,
         field2: rel.spcnode,
@@ -1115,6 +2209,31 @@ fn rel_block_to_key(rel: RelTag, blknum: BlockNumber) -> Key {
     }
 }
 
+/// Is this the key of a relation block, as opposed to the relation's size or
+/// some other kind of data?
+pub fn is_rel_block_key(key: Key) -> bool {
+    key.field1 == 0x00 && key.field4 != 0 && key.field6 != 0xffffffff
+}
+
+/// All the relation blocks and sizes, and the directory entries in between
+fn rel_key_range() -> Range<Key> {
+    Key {
+        field1: 0x00,
+        field2: 0,
+        field3: 0,
+        field4: 0,
+        field5: 0,
+        field6: 0,
+    }..Key {
+        field1: 0x01,
+        field2: 0,
+        field3: 0,
+        field4: 0,
+        field5: 0,
+        field6: 0,
+    }
+}
+
 fn rel_size_to_key(rel: RelTag) -> Key {
     Key {
         field1: 0x00,
@@ -1276,6 +2395,25 @@ const CHECKPOINT_KEY: Key = Key {
     field6: 1,
 };
 
+// Timestamp of the last commit or checkpoint record
+const LSN_TIMESTAMP_KEY: Key = Key {
+    field1: 0x03,
+    field2: 0,
+    field3: 0,
+    field4: 0,
+    field5: 0,
+    field6: 2,
+};
+
+/// Decode the value stored at LSN_TIMESTAMP_KEY. It's empty until the first
+/// timestamp is ingested.
+fn decode_lsn_timestamp(mut buf: Bytes) -> Option<TimestampTz> {
+    if buf.is_empty() {
+        return None;
+    }
+    Some(buf.get_i64_le())
+}
+
 // Reverse mappings for a few Keys.
 // These are needed by WAL redo manager.
 
@@ -1318,7 +2456,7 @@ pub fn key_to_slru_block(key: Key) -> Result<(SlruKind, u32, BlockNumber)> {
 
 #[cfg(test)]
 pub fn create_test_timeline<R: Repository>(
-    repo: R,
+    repo: &R,
     timeline_id: utils::zid::ZTimelineId,
 ) -> Result<Arc<crate::DatadirTimeline<R>>> {
     let tline = repo.create_empty_timeline(timeline_id, Lsn(8))?;
@@ -1332,8 +2470,1020 @@ pub fn create_test_timeline<R: Repository>(
 #[allow(clippy::bool_assert_comparison)]
 #[cfg(test)]
 mod tests {
-    //use super::repo_harness::*;
-    //use super::*;
+    use super::*;
+    use crate::repository::repo_harness::*;
+    use crate::CheckpointConfig;
+    use std::str::FromStr;
+
+    /// Arbitrary relation tag, for testing.
+    const TESTREL: RelTag = RelTag {
+        spcnode: 0,
+        dbnode: 111,
+        relnode: 1000,
+        forknum: 0,
+    };
+
+    /// Check that reading the first blocks of a relation in order triggers
+    /// read-ahead of the blocks that follow.
+    ///
+    /// The test WAL redo manager doesn't produce page-sized images, so the
+    /// reconstructed pages don't stay in the page cache here. Check the
+    /// read-ahead bookkeeping instead.
+    #[test]
+    fn test_sequential_prefetch() -> Result<()> {
+        let repo = RepoHarness::create("test_sequential_prefetch")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        tline.set_prefetch_depth(8);
+
+        let lsn = Lsn(0x20);
+        let mut m = tline.begin_modification(lsn);
+        m.put_rel_creation(TESTREL, 16)?;
+        for blknum in 0..16 {
+            m.put_rel_page_image(TESTREL, blknum, TEST_IMG(&format!("blk {}", blknum)))?;
+        }
+        m.commit()?;
+
+        let prefetched_upto = || tline.seq_access.lock().unwrap()[&TESTREL].prefetched_upto;
+
+        // Random access doesn't trigger read-ahead
+        tline.get_rel_page_at_lsn(TESTREL, 10, lsn)?;
+        tline.get_rel_page_at_lsn(TESTREL, 5, lsn)?;
+        assert_eq!(prefetched_upto(), 6);
+
+        for blknum in 0..4 {
+            tline.get_rel_page_at_lsn(TESTREL, blknum, lsn)?;
+        }
+        while tline.prefetch_threads.load(Ordering::Relaxed) > 0 {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(prefetched_upto() > 4);
+        assert!(prefetched_upto() <= 16);
+
+        Ok(())
+    }
+
+    /// Timelines created before LSN_TIMESTAMP_KEY existed don't have it.
+    /// That's not an error, but other read errors are not hidden.
+    #[test]
+    fn test_missing_lsn_timestamp() -> Result<()> {
+        let repo = RepoHarness::create("test_missing_lsn_timestamp")?.load();
+        let tline =
+            DatadirTimeline::new(repo.create_empty_timeline(TIMELINE_ID, Lsn(8))?, 256 * 1024);
+
+        let err = tline.tline.get(LSN_TIMESTAMP_KEY, Lsn(8)).unwrap_err();
+        assert!(err.downcast_ref::<KeyNotFound>().is_some(), "{err:#}");
+        assert_eq!(tline.get_lsn_timestamp(Lsn(8))?, None);
+
+        // A version that can't be reconstructed is a different error, and
+        // it's reported
+        let writer = tline.tline.writer();
+        writer.put(
+            LSN_TIMESTAMP_KEY,
+            Lsn(0x10),
+            Value::WalRecord(ZenithWalRecord::Postgres {
+                will_init: false,
+                rec: Bytes::from_static(b"no base image"),
+            }),
+        )?;
+        writer.finish_write(Lsn(0x10));
+        drop(writer);
+        let err = tline.get_lsn_timestamp(Lsn(0x10)).unwrap_err();
+        assert!(err.downcast_ref::<KeyNotFound>().is_none(), "{err:#}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let repo = RepoHarness::create("test_validate")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 3)?;
+        for blknum in 0..3 {
+            m.put_rel_page_image(TESTREL, blknum, TEST_IMG(&format!("blk {}", blknum)))?;
+        }
+        m.commit()?;
+
+        let report = tline.validate()?;
+        assert!(report.is_ok(), "unexpected problems: {:?}", report.problems);
+        assert_eq!(report.rels_checked, 1);
+        assert_eq!(report.blocks_checked, 3);
+
+        // Extend the relation with a WAL record that doesn't initialize the
+        // page, and no base image for it.
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_extend(TESTREL, 4)?;
+        m.put_rel_wal_record(
+            TESTREL,
+            3,
+            ZenithWalRecord::Postgres {
+                will_init: false,
+                rec: Bytes::from_static(b"orphan record"),
+            },
+        )?;
+        m.commit()?;
+
+        let report = tline.validate()?;
+        assert_eq!(report.blocks_checked, 4);
+        assert_eq!(report.num_problems, 1);
+        assert!(
+            report.problems[0].starts_with(&format!("cannot reconstruct block 3 of {}", TESTREL)),
+            "unexpected problem: {}",
+            report.problems[0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tenant_logical_size() -> Result<()> {
+        let repo = RepoHarness::create("test_tenant_logical_size")?.load();
+        let branch_a = ZTimelineId::from_str("AA223344556677881122334455667788").unwrap();
+        let branch_b = ZTimelineId::from_str("BB223344556677881122334455667788").unwrap();
+        let blcksz = pg_constants::BLCKSZ as usize;
+
+        let mut timelines = HashMap::new();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 10)?;
+        m.commit()?;
+        timelines.insert(TIMELINE_ID, tline);
+
+        // Branch A grows the relation by 5 blocks, branch B truncates it.
+        for (branch_id, nblocks) in [(branch_a, 15), (branch_b, 2)] {
+            repo.branch_timeline(TIMELINE_ID, branch_id, Lsn(0x20))?;
+            let branch: DatadirTimeline<crate::RepositoryImpl> =
+                DatadirTimeline::new(repo.get_timeline_load(branch_id)?, 256 * 1024);
+            branch.init_logical_size()?;
+            let mut m = branch.begin_modification(Lsn(0x30));
+            if nblocks > 10 {
+                m.put_rel_extend(TESTREL, nblocks)?;
+            } else {
+                m.put_rel_truncation(TESTREL, nblocks)?;
+            }
+            m.commit()?;
+            assert_eq!(branch.get_current_logical_size(), nblocks as usize * blcksz);
+            timelines.insert(branch_id, Arc::new(branch));
+        }
+
+        // The naive sum would be 10 + 15 + 2 blocks. The parent's 10 blocks are
+        // counted once, branch A adds its 5 new blocks, and branch B nothing.
+        let total = get_tenant_logical_size(&timelines)?;
+        assert_eq!(total, 15 * blcksz);
+        let naive_total: usize = timelines
+            .values()
+            .map(|tline| tline.get_current_logical_size())
+            .sum();
+        assert_ne!(total, naive_total);
+
+        // Without its ancestor, a branch is counted in full.
+        timelines.remove(&TIMELINE_ID);
+        assert_eq!(get_tenant_logical_size(&timelines)?, 17 * blcksz);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tenant_changed_rels() -> Result<()> {
+        let repo = RepoHarness::create("test_tenant_changed_rels")?.load();
+        let rel = |relnode| RelTag { relnode, ..TESTREL };
+        let branch_id = ZTimelineId::from_str("AA223344556677881122334455667788").unwrap();
+
+        let mut timelines = HashMap::new();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(rel(1000), 1)?;
+        m.put_rel_creation(rel(1001), 1)?;
+        m.commit()?;
+
+        repo.branch_timeline(TIMELINE_ID, branch_id, Lsn(0x20))?;
+        let branch: DatadirTimeline<crate::RepositoryImpl> =
+            DatadirTimeline::new(repo.get_timeline_load(branch_id)?, 256 * 1024);
+        branch.init_logical_size()?;
+
+        // The parent writes to one relation, the branch truncates another one
+        // and creates a new one.
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_page_image(rel(1000), 0, TEST_IMG("foo blk 0 at 3"))?;
+        m.commit()?;
+        let mut m = branch.begin_modification(Lsn(0x40));
+        m.put_rel_truncation(rel(1001), 0)?;
+        m.put_rel_creation(rel(1002), 1)?;
+        m.commit()?;
+
+        timelines.insert(TIMELINE_ID, tline);
+        timelines.insert(branch_id, Arc::new(branch));
+
+        let changed = get_tenant_changed_rels(&timelines, Lsn(0x20))?;
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[&TIMELINE_ID], HashSet::from([rel(1000)]));
+        assert_eq!(changed[&branch_id], HashSet::from([rel(1001), rel(1002)]));
+
+        // Before the branch point, the branch inherits the parent's changes
+        let changed = get_tenant_changed_rels(&timelines, Lsn(0x10))?;
+        assert_eq!(changed[&TIMELINE_ID], HashSet::from([rel(1000), rel(1001)]));
+        assert_eq!(
+            changed[&branch_id],
+            HashSet::from([rel(1000), rel(1001), rel(1002)])
+        );
+
+        // Nothing changed after the last write
+        let changed = get_tenant_changed_rels(&timelines, Lsn(0x30))?;
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[&branch_id], HashSet::from([rel(1001), rel(1002)]));
+        assert!(get_tenant_changed_rels(&timelines, Lsn(0x40))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_logical_size_non_default_page_size() -> Result<()> {
+        let repo = RepoHarness::create("test_logical_size_non_default_page_size")?.load();
+        let page_size = 16384;
+        let tline: DatadirTimeline<crate::RepositoryImpl> =
+            DatadirTimeline::new(repo.create_empty_timeline(TIMELINE_ID, Lsn(8))?, 256 * 1024)
+                .with_page_size(page_size);
+        let mut m = tline.begin_modification(Lsn(8));
+        m.init_empty()?;
+        m.commit()?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 3)?;
+        for blknum in 0..3 {
+            m.put_rel_page_image(TESTREL, blknum, Bytes::from(vec![1u8; page_size]))?;
+        }
+        m.commit()?;
+
+        assert_eq!(
+            tline.get_current_logical_size_non_incremental(Lsn(0x20))?,
+            3 * page_size
+        );
+        assert_eq!(tline.get_current_logical_size(), 3 * page_size);
+
+        // Reads beyond the end of the relation return a zero page of the configured size
+        let page = tline.get_rel_page_at_lsn(TESTREL, 5, Lsn(0x20))?;
+        assert_eq!(page.len(), page_size);
+        assert!(page.iter().all(|b| *b == 0));
+
+        Ok(())
+    }
+
+    /// SLRU segments and two-phase files count towards the logical size, both
+    /// when it's tracked incrementally and when it's calculated from scratch.
+    #[test]
+    fn test_logical_size_non_rel_data() -> Result<()> {
+        let repo = RepoHarness::create("test_logical_size_non_rel_data")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let page_size = tline.get_page_size();
+        assert_eq!(tline.get_current_logical_size(), 0);
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_slru_segment_creation(SlruKind::Clog, 0, 2)?;
+        m.put_twophase_file(1000, Bytes::from(vec![1u8; 100]))?;
+        m.commit()?;
+        assert_eq!(tline.get_current_logical_size(), 2 * page_size + 100);
+
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_slru_extend(SlruKind::Clog, 0, 3)?;
+        m.put_twophase_file(1001, Bytes::from(vec![1u8; 50]))?;
+        m.commit()?;
+        assert_eq!(tline.get_current_logical_size(), 3 * page_size + 150);
+        assert_eq!(
+            tline.get_current_logical_size_non_incremental(Lsn(0x30))?,
+            3 * page_size + 150
+        );
+
+        let mut m = tline.begin_modification(Lsn(0x40));
+        m.drop_twophase_file(1000)?;
+        m.drop_slru_segment(SlruKind::Clog, 0)?;
+        m.commit()?;
+        assert_eq!(tline.get_current_logical_size(), 50);
+        assert_eq!(
+            tline.get_current_logical_size_non_incremental(Lsn(0x40))?,
+            50
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_rel_blocks() -> Result<()> {
+        let repo = RepoHarness::create("test_iter_rel_blocks")?.load();
+
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 4)?;
+        for blknum in 0..4 {
+            m.put_rel_page_image(
+                TESTREL,
+                blknum,
+                TEST_IMG(&format!("blk {} at 0x20", blknum)),
+            )?;
+        }
+        m.commit()?;
+
+        // Modify some of the blocks on a branch, the rest are inherited from
+        // the parent
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x20))?;
+        let child: DatadirTimeline<crate::RepositoryImpl> =
+            DatadirTimeline::new(repo.get_timeline_load(NEW_TIMELINE_ID)?, 256 * 1024);
+        let mut m = child.begin_modification(Lsn(0x30));
+        m.put_rel_page_image(TESTREL, 1, TEST_IMG("blk 1 at 0x30"))?;
+        m.put_rel_extend(TESTREL, 5)?;
+        m.put_rel_page_image(TESTREL, 4, TEST_IMG("blk 4 at 0x30"))?;
+        m.commit()?;
+
+        for (tline, lsn, nblocks) in [(&*tline, Lsn(0x20), 4), (&child, Lsn(0x30), 5)] {
+            let blocks = tline
+                .iter_rel_blocks(TESTREL, lsn)?
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(blocks.len(), nblocks);
+            for (i, (blknum, img)) in blocks.into_iter().enumerate() {
+                assert_eq!(blknum, i as BlockNumber);
+                assert_eq!(img, tline.get_rel_page_at_lsn(TESTREL, blknum, lsn)?);
+            }
+        }
+        assert_eq!(
+            child
+                .iter_rel_blocks(TESTREL, Lsn(0x30))?
+                .nth(1)
+                .unwrap()?
+                .1,
+            TEST_IMG("blk 1 at 0x30")
+        );
+        assert_eq!(
+            child
+                .iter_rel_blocks(TESTREL, Lsn(0x30))?
+                .nth(2)
+                .unwrap()?
+                .1,
+            TEST_IMG("blk 2 at 0x20")
+        );
+
+        Ok(())
+    }
+
+    /// Reads at an unaligned LSN see the state as of the preceding aligned
+    /// LSN, and not any changes at the next aligned LSN.
+    #[test]
+    fn test_read_at_unaligned_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_read_at_unaligned_lsn")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 1)?;
+        m.put_rel_page_image(TESTREL, 0, TEST_IMG("foo at 0x20"))?;
+        m.commit()?;
+
+        let mut m = tline.begin_modification(Lsn(0x28));
+        m.put_rel_extend(TESTREL, 2)?;
+        m.put_rel_page_image(TESTREL, 0, TEST_IMG("foo at 0x28"))?;
+        m.put_rel_page_image(TESTREL, 1, TEST_IMG("bar at 0x28"))?;
+        m.commit()?;
+
+        for lsn in [Lsn(0x21), Lsn(0x24), Lsn(0x27)] {
+            assert!(!lsn.is_aligned());
+            assert_eq!(tline.get_rel_size(TESTREL, lsn)?, 1);
+            assert_eq!(
+                tline.get_rel_page_at_lsn(TESTREL, 0, lsn)?,
+                TEST_IMG("foo at 0x20")
+            );
+        }
+        assert_eq!(tline.get_rel_size(TESTREL, Lsn(0x28))?, 2);
+        assert_eq!(
+            tline.get_rel_page_at_lsn(TESTREL, 0, Lsn(0x28))?,
+            TEST_IMG("foo at 0x28")
+        );
+
+        Ok(())
+    }
+
+    /// Reading at `Lsn::MAX` gives the same results as reading at the last
+    /// record LSN.
+    #[test]
+    fn test_read_at_max_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_read_at_max_lsn")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 1)?;
+        m.put_rel_page_image(TESTREL, 0, TEST_IMG("foo at 0x20"))?;
+        m.commit()?;
+
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_extend(TESTREL, 2)?;
+        m.put_rel_page_image(TESTREL, 1, TEST_IMG("bar at 0x30"))?;
+        m.commit()?;
+
+        let last_lsn = tline.get_last_record_lsn();
+        assert_eq!(last_lsn, Lsn(0x30));
+        assert_eq!(
+            tline.get_rel_size(TESTREL, Lsn::MAX)?,
+            tline.get_rel_size(TESTREL, last_lsn)?
+        );
+        assert!(tline.get_rel_exists(TESTREL, Lsn::MAX)?);
+        assert_eq!(
+            tline.list_rels(TESTREL.spcnode, TESTREL.dbnode, Lsn::MAX)?,
+            tline.list_rels(TESTREL.spcnode, TESTREL.dbnode, last_lsn)?
+        );
+        for blknum in 0..3 {
+            assert_eq!(
+                tline.get_rel_page_at_lsn(TESTREL, blknum, Lsn::MAX)?,
+                tline.get_rel_page_at_lsn(TESTREL, blknum, last_lsn)?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rel_changed_blocks() -> Result<()> {
+        let repo = RepoHarness::create("test_rel_changed_blocks")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 4)?;
+        for blknum in 0..4 {
+            m.put_rel_page_image(
+                TESTREL,
+                blknum,
+                TEST_IMG(&format!("blk {} at 0x20", blknum)),
+            )?;
+        }
+        m.commit()?;
+
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_page_image(TESTREL, 1, TEST_IMG("blk 1 at 0x30"))?;
+        m.commit()?;
+
+        // Make the older versions come from a delta layer on disk
+        tline.tline.checkpoint(CheckpointConfig::Flush)?;
+
+        let mut m = tline.begin_modification(Lsn(0x40));
+        m.put_rel_page_image(TESTREL, 2, TEST_IMG("blk 2 at 0x40"))?;
+        m.put_rel_extend(TESTREL, 5)?;
+        m.put_rel_page_image(TESTREL, 4, TEST_IMG("blk 4 at 0x40"))?;
+        m.commit()?;
+
+        let mut m = tline.begin_modification(Lsn(0x50));
+        m.put_rel_page_image(TESTREL, 1, TEST_IMG("blk 1 at 0x50"))?;
+        m.commit()?;
+
+        let changed = |from, to| tline.get_rel_changed_blocks(TESTREL, Lsn(from), Lsn(to));
+        assert_eq!(changed(0x10, 0x20)?, vec![0, 1, 2, 3]);
+        // The start of the range is exclusive, the end inclusive
+        assert_eq!(changed(0x20, 0x30)?, vec![1]);
+        assert_eq!(changed(0x20, 0x2f)?, Vec::<BlockNumber>::new());
+        assert_eq!(changed(0x30, 0x40)?, vec![2, 4]);
+        assert_eq!(changed(0x40, 0x40)?, Vec::<BlockNumber>::new());
+        assert_eq!(changed(0x40, 0x50)?, vec![1]);
+        assert_eq!(changed(0x20, 0x50)?, vec![1, 2, 4]);
+        assert!(changed(0x50, 0x40).is_err());
+
+        Ok(())
+    }
+
+    /// All reads through a ReadTxn see the same LSN, even if more WAL is
+    /// ingested between them.
+    #[test]
+    fn test_read_txn() -> Result<()> {
+        let repo = RepoHarness::create("test_read_txn")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let rel_a = TESTREL;
+        let rel_b = RelTag {
+            relnode: 1001,
+            ..rel_a
+        };
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(rel_a, 1)?;
+        m.put_rel_page_image(rel_a, 0, TEST_IMG("a at 0x20"))?;
+        m.put_rel_creation(rel_b, 1)?;
+        m.put_rel_page_image(rel_b, 0, TEST_IMG("b at 0x20"))?;
+        m.commit()?;
+
+        let txn = tline.begin_read(Lsn::MAX)?;
+        assert_eq!(txn.lsn(), Lsn(0x20));
+        assert_eq!(txn.get_rel_page(rel_a, 0)?, TEST_IMG("a at 0x20"));
+
+        // Concurrent ingest modifies both relations, and creates a new one
+        let rel_c = RelTag {
+            relnode: 1002,
+            ..rel_a
+        };
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_page_image(rel_a, 0, TEST_IMG("a at 0x30"))?;
+        m.put_rel_extend(rel_b, 2)?;
+        m.put_rel_page_image(rel_b, 0, TEST_IMG("b at 0x30"))?;
+        m.put_rel_page_image(rel_b, 1, TEST_IMG("b 1 at 0x30"))?;
+        m.put_rel_creation(rel_c, 0)?;
+        m.commit()?;
+
+        assert_eq!(txn.get_rel_page(rel_a, 0)?, TEST_IMG("a at 0x20"));
+        assert_eq!(txn.get_rel_page(rel_b, 0)?, TEST_IMG("b at 0x20"));
+        assert_eq!(txn.get_rel_size(rel_b)?, 1);
+        assert!(!txn.get_rel_exists(rel_c)?);
+        assert_eq!(txn.list_rels(0, 111)?, HashSet::from_iter([rel_a, rel_b]));
+
+        // A new snapshot sees the new state
+        let txn = tline.begin_read(Lsn::MAX)?;
+        assert_eq!(txn.lsn(), Lsn(0x30));
+        assert_eq!(txn.get_rel_page(rel_b, 0)?, TEST_IMG("b at 0x30"));
+        assert!(txn.get_rel_exists(rel_c)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_rel_page_before_lsn() -> Result<()> {
+        let repo = RepoHarness::create("test_get_rel_page_before_lsn")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+
+        let mut m = tline.begin_modification(Lsn(0x10));
+        m.put_rel_creation(TESTREL, 1)?;
+        m.put_rel_page_image(TESTREL, 0, TEST_IMG("foo at 0x10"))?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_page_image(TESTREL, 0, TEST_IMG("foo at 0x20"))?;
+        m.commit()?;
+
+        assert_eq!(
+            tline.get_rel_page_before_lsn(TESTREL, 0, Lsn(0x20))?,
+            TEST_IMG("foo at 0x10")
+        );
+        assert_eq!(
+            tline.get_rel_page_before_lsn(TESTREL, 0, Lsn(0x21))?,
+            TEST_IMG("foo at 0x20")
+        );
+        assert_eq!(
+            tline.get_rel_page_at_lsn(TESTREL, 0, Lsn(0x20))?,
+            TEST_IMG("foo at 0x20")
+        );
+        assert!(tline.get_rel_page_before_lsn(TESTREL, 0, Lsn(0)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_history() -> Result<()> {
+        let repo = RepoHarness::create("test_block_history")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let rec = ZenithWalRecord::Postgres {
+            will_init: false,
+            rec: Bytes::from_static(b"some WAL record"),
+        };
+        let rec_len = Value::WalRecord(rec.clone()).ser()?.len();
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 1)?;
+        m.put_rel_page_image(TESTREL, 0, TEST_IMG("foo at 0x20"))?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_wal_record(TESTREL, 0, rec.clone())?;
+        m.commit()?;
+        tline.tline.checkpoint(CheckpointConfig::Flush)?;
+
+        // The rest stays in the in-memory layer
+        let mut m = tline.begin_modification(Lsn(0x40));
+        m.put_rel_wal_record(TESTREL, 0, rec)?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x50));
+        m.put_rel_page_image(TESTREL, 0, TEST_IMG("foo at 0x50"))?;
+        m.commit()?;
+
+        assert_eq!(
+            tline.block_history(TESTREL, 0)?,
+            vec![
+                (Lsn(0x20), PageVersionKind::Image),
+                (Lsn(0x30), PageVersionKind::Delta { rec_len }),
+                (Lsn(0x40), PageVersionKind::Delta { rec_len }),
+                (Lsn(0x50), PageVersionKind::Image),
+            ]
+        );
+        assert!(tline.block_history(TESTREL, 1)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_stream() -> Result<()> {
+        let repo = RepoHarness::create("test_change_stream")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let rel_a = TESTREL;
+        let rel_b = RelTag {
+            relnode: 1001,
+            ..rel_a
+        };
+        let rec = ZenithWalRecord::Postgres {
+            will_init: false,
+            rec: Bytes::from_static(b"some WAL record"),
+        };
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(rel_a, 3)?;
+        m.put_rel_creation(rel_b, 1)?;
+        m.commit()?;
+        // Write the blocks in an order that's different from the key order
+        for (lsn, rel, blknum) in [
+            (0x30, rel_b, 0),
+            (0x40, rel_a, 2),
+            (0x50, rel_a, 0),
+            (0x60, rel_b, 0),
+            (0x70, rel_a, 1),
+            (0x80, rel_a, 2),
+        ] {
+            let mut m = tline.begin_modification(Lsn(lsn));
+            m.put_rel_page_image(rel, blknum, TEST_IMG(&format!("{blknum} at {lsn}")))?;
+            m.commit()?;
+            if lsn == 0x50 {
+                tline.tline.checkpoint(CheckpointConfig::Flush)?;
+            }
+        }
+        let mut m = tline.begin_modification(Lsn(0x90));
+        m.put_rel_wal_record(rel_a, 0, rec)?;
+        m.commit()?;
+
+        let changes = tline
+            .change_stream(Lsn(0x30), Lsn(0x90))?
+            .collect::<Result<Vec<_>>>()?;
+        let lsns: Vec<Lsn> = changes.iter().map(|c| c.lsn).collect();
+        assert_eq!(
+            lsns,
+            [0x30, 0x40, 0x50, 0x60, 0x70, 0x80].map(Lsn).to_vec(),
+            "{changes:?}"
+        );
+        assert_eq!(
+            changes[1],
+            ChangeRecord {
+                lsn: Lsn(0x40),
+                rel: rel_a,
+                blknum: 2,
+                kind: PageVersionKind::Image,
+            }
+        );
+
+        // The WAL record at the end of the range is included, and the
+        // relation creation is not a block change
+        let changes = tline
+            .change_stream(Lsn(0x20), Lsn(0x91))?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(changes.len(), 7);
+        assert!(changes.windows(2).all(|w| w[0].lsn <= w[1].lsn));
+        assert!(matches!(
+            changes.last(),
+            Some(ChangeRecord {
+                lsn: Lsn(0x90),
+                blknum: 0,
+                kind: PageVersionKind::Delta { .. },
+                ..
+            })
+        ));
+
+        assert_eq!(tline.change_stream(Lsn(0x40), Lsn(0x40))?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_get_page() -> Result<()> {
+        let repo = RepoHarness::create("test_explain_get_page")?.load();
+        let rec = ZenithWalRecord::Postgres {
+            will_init: false,
+            rec: Bytes::from_static(b"some WAL record"),
+        };
+        let branch_id = ZTimelineId::from_str("AA223344556677881122334455667788").unwrap();
+
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 1)?;
+        m.put_rel_page_image(TESTREL, 0, TEST_IMG("foo at 0x20"))?;
+        m.commit()?;
+        for lsn in [0x30, 0x40] {
+            let mut m = tline.begin_modification(Lsn(lsn));
+            m.put_rel_wal_record(TESTREL, 0, rec.clone())?;
+            m.commit()?;
+        }
+        tline.tline.checkpoint(CheckpointConfig::Flush)?;
+
+        assert_eq!(
+            tline.explain_get_page(TESTREL, 0, Lsn(0x40))?,
+            ReconstructPlan {
+                base_image_lsn: Some(Lsn(0x20)),
+                record_lsns: vec![Lsn(0x30), Lsn(0x40)],
+                crosses_timeline: false,
+            }
+        );
+        let plan = tline.explain_get_page(TESTREL, 0, Lsn(0x35))?;
+        assert_eq!(plan.record_lsns, vec![Lsn(0x30)]);
+        let plan = tline.explain_get_page(TESTREL, 0, Lsn(0x20))?;
+        assert_eq!(plan.base_image_lsn, Some(Lsn(0x20)));
+        assert!(plan.record_lsns.is_empty());
+
+        // On a branch, the older versions come from the parent
+        repo.branch_timeline(TIMELINE_ID, branch_id, Lsn(0x40))?;
+        let branch: DatadirTimeline<crate::RepositoryImpl> =
+            DatadirTimeline::new(repo.get_timeline_load(branch_id)?, 256 * 1024);
+        let mut m = branch.begin_modification(Lsn(0x50));
+        m.put_rel_wal_record(TESTREL, 0, rec)?;
+        m.commit()?;
+        assert_eq!(
+            branch.explain_get_page(TESTREL, 0, Lsn::MAX)?,
+            ReconstructPlan {
+                base_image_lsn: Some(Lsn(0x20)),
+                record_lsns: vec![Lsn(0x30), Lsn(0x40), Lsn(0x50)],
+                crosses_timeline: true,
+            }
+        );
+
+        // A new image on the branch ends the search there
+        let mut m = branch.begin_modification(Lsn(0x60));
+        m.put_rel_page_image(TESTREL, 0, TEST_IMG("foo at 0x60"))?;
+        m.commit()?;
+        assert_eq!(
+            branch.explain_get_page(TESTREL, 0, Lsn(0x60))?,
+            ReconstructPlan {
+                base_image_lsn: Some(Lsn(0x60)),
+                record_lsns: vec![],
+                crosses_timeline: false,
+            }
+        );
+
+        Ok(())
+    }
+
+    /// Image layer creation walks the keyspace in ascending Key order. Check
+    /// that this visits relations in ascending RelTag order, and the blocks
+    /// of each relation in ascending block number order, so that the order of
+    /// page materializations is deterministic.
+    #[test]
+    fn test_rel_key_order() {
+        let mut blocks = Vec::new();
+        for spcnode in [1663, 0] {
+            for dbnode in [5, 111, 1] {
+                for relnode in [16384, 1259, 0xFFFF_FFFF] {
+                    for forknum in [pg_constants::VISIBILITYMAP_FORKNUM, 0] {
+                        for blknum in [3, 0, 0x7FFF_FFFF] {
+                            let rel = RelTag {
+                                spcnode,
+                                dbnode,
+                                relnode,
+                                forknum,
+                            };
+                            blocks.push((rel, blknum));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut by_key = blocks.clone();
+        by_key.sort_by_key(|(rel, blknum)| rel_block_to_key(*rel, *blknum));
+        blocks.sort();
+        assert_eq!(by_key, blocks);
+    }
+
+    /// Check that get_rel_exists() at the tip is served from the cache, and
+    /// that the cache doesn't interfere with lookups at older LSNs.
+    #[test]
+    fn test_rel_exists_cache() -> Result<()> {
+        let repo = RepoHarness::create("test_rel_exists_cache")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(TESTREL, 0)?;
+        m.commit()?;
+
+        let misses = || tline.rel_dir_cache_misses.load(Ordering::Relaxed);
+
+        assert!(tline.get_rel_exists(TESTREL, Lsn(0x20))?);
+        let misses_before = misses();
+        for _ in 0..10 {
+            assert!(tline.get_rel_exists(TESTREL, Lsn(0x20))?);
+        }
+        assert_eq!(misses(), misses_before);
+
+        // Dropping the relation invalidates the cached directory
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_drop(TESTREL)?;
+        m.commit()?;
+        assert!(!tline.get_rel_exists(TESTREL, Lsn(0x30))?);
+        assert!(!tline.get_rel_exists(TESTREL, Lsn(0x30))?);
+
+        // The relation still existed at the older LSN
+        assert!(tline.get_rel_exists(TESTREL, Lsn(0x20))?);
+
+        Ok(())
+    }
+
+    /// Check that preload_rel_dir_cache() fills the cache for a freshly
+    /// loaded timeline, so that the first lookups don't miss.
+    #[test]
+    fn test_preload_rel_dir_cache() -> Result<()> {
+        let repo = RepoHarness::create("test_preload_rel_dir_cache")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let rel_a = TESTREL;
+        let rel_b = RelTag {
+            dbnode: 222,
+            ..rel_a
+        };
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(rel_a, 0)?;
+        m.put_rel_creation(rel_b, 0)?;
+        m.commit()?;
+
+        // Same as loading the timeline again
+        let loaded = DatadirTimeline::<crate::layered_repository::LayeredRepository>::new(
+            tline.tline.clone(),
+            256 * 1024,
+        );
+        assert!(loaded.rel_dir_cache.read().unwrap().dirs.is_empty());
+
+        loaded.preload_rel_dir_cache()?;
+        assert_eq!(loaded.rel_dir_cache.read().unwrap().dirs.len(), 2);
+        let misses_before = loaded.rel_dir_cache_misses.load(Ordering::Relaxed);
+        assert!(loaded.get_rel_exists(rel_a, Lsn(0x20))?);
+        assert!(loaded.get_rel_exists(rel_b, Lsn(0x20))?);
+        assert_eq!(
+            loaded.rel_dir_cache_misses.load(Ordering::Relaxed),
+            misses_before
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rel_exists_cheap() -> Result<()> {
+        let repo = RepoHarness::create("test_rel_exists_cheap")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let rel_a = TESTREL;
+        let rel_b = RelTag {
+            relnode: 1001,
+            ..rel_a
+        };
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(rel_a, 0)?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_creation(rel_b, 0)?;
+        m.commit()?;
+
+        // Same as loading the timeline again
+        let loaded = DatadirTimeline::<crate::layered_repository::LayeredRepository>::new(
+            tline.tline.clone(),
+            256 * 1024,
+        );
+        assert!(loaded.rel_exists_cheap(rel_a, Lsn(0x20))?);
+        assert!(!loaded.rel_exists_cheap(rel_b, Lsn(0x20))?);
+        assert!(loaded.rel_exists_cheap(rel_a, Lsn(0x30))?);
+        assert!(loaded.rel_exists_cheap(rel_b, Lsn(0x30))?);
+        assert!(loaded.rel_dir_cache.read().unwrap().dirs.is_empty());
+
+        // Agrees with get_rel_exists(), which does fill the cache
+        assert!(loaded.get_rel_exists(rel_b, Lsn(0x30))?);
+        assert_eq!(loaded.rel_dir_cache.read().unwrap().dirs.len(), 1);
+        assert!(!loaded.rel_exists_cheap(rel_b, Lsn(0x20))?);
+        assert!(loaded.rel_exists_cheap(rel_b, Lsn(0x30))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_rel_fork_sizes() -> Result<()> {
+        let repo = RepoHarness::create("test_get_rel_fork_sizes")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let main = TESTREL;
+        let fsm = RelTag {
+            forknum: pg_constants::FSM_FORKNUM,
+            ..main
+        };
+        let other = RelTag {
+            relnode: 1001,
+            ..main
+        };
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(main, 10)?;
+        m.put_rel_creation(other, 5)?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_creation(fsm, 3)?;
+        m.put_rel_extend(main, 12)?;
+        m.commit()?;
+
+        assert_eq!(
+            tline.get_rel_fork_sizes(0, 111, 1000, Lsn(0x20))?,
+            HashMap::from([(pg_constants::MAIN_FORKNUM, 10)])
+        );
+        assert_eq!(
+            tline.get_rel_fork_sizes(0, 111, 1000, Lsn(0x30))?,
+            HashMap::from([
+                (pg_constants::MAIN_FORKNUM, 12),
+                (pg_constants::FSM_FORKNUM, 3)
+            ])
+        );
+        assert!(tline
+            .get_rel_fork_sizes(0, 111, 1002, Lsn(0x30))?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_relation_not_found() -> Result<()> {
+        let repo = RepoHarness::create("test_relation_not_found")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let rel_a = TESTREL;
+        let rel_b = RelTag {
+            relnode: 1001,
+            ..rel_a
+        };
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(rel_a, 1)?;
+        m.put_rel_page_image(rel_a, 0, TEST_IMG("foo blk 0 at 2"))?;
+        m.commit()?;
+
+        let assert_not_found = |tag: RelTag, lsn: Lsn| {
+            let err = tline
+                .get_rel_page_at_lsn(tag, 0, lsn)
+                .expect_err("reading a nonexistent relation should fail");
+            match err.downcast_ref::<RelationNotFound>() {
+                Some(RelationNotFound {
+                    tag: err_tag,
+                    lsn: err_lsn,
+                }) => {
+                    assert_eq!(*err_tag, tag);
+                    assert_eq!(*err_lsn, lsn);
+                }
+                None => panic!("unexpected error: {err:#}"),
+            }
+        };
+
+        // A block beyond the end of an existing relation reads as zeros
+        assert_eq!(
+            tline.get_rel_page_at_lsn(rel_a, 0, Lsn(0x20))?,
+            TEST_IMG("foo blk 0 at 2")
+        );
+        assert_eq!(
+            tline.get_rel_page_at_lsn(rel_a, 1, Lsn(0x20))?,
+            tline.zero_page()
+        );
+
+        // A relation that was never created, before its database existed
+        // and after
+        assert_not_found(rel_b, Lsn(0x10));
+        assert_not_found(rel_b, Lsn(0x20));
+        // Before the relation was created
+        assert_not_found(rel_a, Lsn(0x10));
+
+        Ok(())
+    }
+
+    /// Check that list_rels() at the tip is served from the cache, and returns
+    /// the same as reading the directory from the store.
+    #[test]
+    fn test_list_rels_cache() -> Result<()> {
+        let repo = RepoHarness::create("test_list_rels_cache")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        let rel = |relnode| RelTag { relnode, ..TESTREL };
+
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(rel(1000), 0)?;
+        m.put_rel_creation(rel(1001), 0)?;
+        m.commit()?;
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_drop(rel(1001))?;
+        m.put_rel_creation(rel(1002), 0)?;
+        m.commit()?;
+
+        let misses = || tline.rel_dir_cache_misses.load(Ordering::Relaxed);
+
+        // The first call reads the directory from the store
+        let misses_before = misses();
+        let scanned = tline.list_rels(0, 111, Lsn(0x30))?;
+        assert_eq!(misses(), misses_before + 1);
+        assert_eq!(scanned, HashSet::from([rel(1000), rel(1002)]));
+
+        // The following calls at the tip are served from the cache
+        for lsn in [Lsn(0x30), Lsn::MAX] {
+            assert_eq!(tline.list_rels(0, 111, lsn)?, scanned);
+        }
+        assert!(tline.get_rel_exists(rel(1002), Lsn(0x30))?);
+        assert_eq!(misses(), misses_before + 1);
+
+        // Older LSNs still read the store
+        assert_eq!(
+            tline.list_rels(0, 111, Lsn(0x20))?,
+            HashSet::from([rel(1000), rel(1001)])
+        );
+        assert_eq!(misses(), misses_before + 2);
+
+        Ok(())
+    }
 
     /*
         fn assert_current_logical_size<R: Repository>(timeline: &DatadirTimeline<R>, lsn: Lsn) {
@@ -1414,7 +3564,7 @@ mod tests {
     #[test]
     fn test_read_beyond_eof() -> Result<()> {
         let repo = RepoHarness::create("test_read_beyond_eof")?.load();
-        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
 
         make_some_layers(&tline, Lsn(0x20))?;
         let mut writer = tline.begin_record(Lsn(0x60));
@@ -0,0 +1,181 @@
+//!
+//! Token bucket rate limiter for background I/O.
+//!
+//! Checkpointing can write out a lot of data in a burst, when many layers
+//! need flushing or new image layers need to be created at the same time.
+//! That can saturate the disk and hurt the latency of GetPage requests. The
+//! limiter caps the rate of those writes. It's a classic token bucket: the
+//! bucket holds up to one second worth of tokens, so short bursts go through
+//! without waiting, but the long-term rate never exceeds the limit.
+//!
+//! Besides the global checkpoint limiter, a tenant can be given a limiter of
+//! its own, to keep it from starving the other tenants on the same disk. See
+//! `tenant_io_limiter`.
+//!
+use crate::config::PageServerConf;
+use once_cell::sync::OnceCell;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static CHECKPOINT_IO_LIMITER: OnceCell<Option<RateLimiter>> = OnceCell::new();
+
+///
+/// Initialize the checkpoint I/O rate limiter. This must be called once at
+/// page server startup. With no limit, writes are not throttled at all.
+///
+pub fn init(checkpoint_io_rate_limit: Option<u64>) {
+    let limiter = checkpoint_io_rate_limit
+        .filter(|rate| *rate > 0)
+        .map(RateLimiter::new);
+    if CHECKPOINT_IO_LIMITER.set(limiter).is_err() {
+        panic!("checkpoint I/O rate limiter already initialized");
+    }
+}
+
+///
+/// Get the checkpoint I/O rate limiter, if one is configured.
+///
+pub fn checkpoint_io_limiter() -> Option<&'static RateLimiter> {
+    // In unit tests, page server startup doesn't happen and no one calls
+    // init(). Don't throttle anything then.
+    CHECKPOINT_IO_LIMITER.get_or_init(|| None).as_ref()
+}
+
+///
+/// Create the I/O rate limiter for a tenant, if a per-tenant limit is
+/// configured. Every tenant gets a limiter of its own.
+///
+pub fn tenant_io_limiter(conf: &PageServerConf) -> Option<Arc<RateLimiter>> {
+    conf.tenant_io_rate_limit
+        .filter(|rate| *rate > 0)
+        .map(|rate| Arc::new(RateLimiter::new(rate)))
+}
+
+pub struct RateLimiter {
+    inner: Mutex<RateLimiterInner>,
+}
+
+struct RateLimiterInner {
+    /// Tokens (bytes) added to the bucket per second. Also the bucket size.
+    rate: u64,
+    /// Tokens currently in the bucket. Goes negative when a caller takes more
+    /// than is available; subsequent callers wait for the debt to be paid off.
+    tokens: f64,
+    last_refill: Instant,
+    /// Total tokens taken from the bucket, for tests and statistics.
+    total_acquired: u64,
+}
+
+impl RateLimiterInner {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = f64::min(self.tokens + elapsed * self.rate as f64, self.rate as f64);
+        self.last_refill = now;
+    }
+
+    /// Take 'bytes' tokens, and return how long the caller has to wait until
+    /// the bucket is out of debt again.
+    fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        self.total_acquired += bytes;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "rate limit must be positive");
+        RateLimiter {
+            inner: Mutex::new(RateLimiterInner {
+                rate: bytes_per_sec,
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+                total_acquired: 0,
+            }),
+        }
+    }
+
+    ///
+    /// Take 'bytes' tokens from the bucket. If there aren't enough tokens
+    /// available, sleeps until the bucket has refilled.
+    ///
+    pub fn acquire(&self, bytes: u64) {
+        let wait = self.inner.lock().unwrap().take(bytes, Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Total number of bytes that have gone through the limiter.
+    pub fn total_acquired(&self) -> u64 {
+        self.inner.lock().unwrap().total_acquired
+    }
+
+    ///
+    /// Change the rate limit. Tokens already in the bucket are kept, up to
+    /// the new bucket size.
+    ///
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        assert!(bytes_per_sec > 0, "rate limit must be positive");
+        let mut inner = self.inner.lock().unwrap();
+        inner.refill(Instant::now());
+        inner.rate = bytes_per_sec;
+        inner.tokens = f64::min(inner.tokens, bytes_per_sec as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        const PAGE_SZ: u64 = 8192;
+        // 50 pages per second. The first second worth of pages goes through
+        // from the initially full bucket, the rest has to wait.
+        let limiter = RateLimiter::new(50 * PAGE_SZ);
+
+        let start = Instant::now();
+        for _ in 0..100 {
+            limiter.acquire(PAGE_SZ);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(950));
+    }
+
+    #[test]
+    fn test_burst_is_not_throttled() {
+        let limiter = RateLimiter::new(1024 * 1024);
+
+        let start = Instant::now();
+        limiter.acquire(512 * 1024);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_set_rate() {
+        const PAGE_SZ: u64 = 8192;
+        let limiter = RateLimiter::new(1000 * PAGE_SZ);
+
+        // Lowering the rate also shrinks the bucket, so only 50 pages are
+        // available right away, and the next 50 cost another second.
+        limiter.set_rate(50 * PAGE_SZ);
+        let mut inner = limiter.inner.lock().unwrap();
+        let now = inner.last_refill;
+        assert!(inner.tokens <= (50 * PAGE_SZ) as f64);
+        assert_eq!(inner.take(50 * PAGE_SZ, now), Duration::ZERO);
+        assert_eq!(inner.take(50 * PAGE_SZ, now), Duration::from_secs(1));
+
+        // Refills at the new rate
+        let later = now + Duration::from_secs(2);
+        assert_eq!(inner.take(50 * PAGE_SZ, later), Duration::ZERO);
+        assert_eq!(inner.total_acquired, 150 * PAGE_SZ);
+    }
+}
@@ -11,9 +11,10 @@ use std::fmt::Display;
 use std::ops::{AddAssign, Range};
 use std::sync::{Arc, RwLockReadGuard};
 use std::time::Duration;
+use tokio::sync::watch;
 use utils::{
     lsn::{Lsn, RecordLsn},
-    zid::ZTimelineId,
+    zid::{ZTenantId, ZTimelineId},
 };
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Ord, PartialOrd, Serialize, Deserialize)]
@@ -157,6 +158,16 @@ impl Key {
     }
 }
 
+/// The error of [`Timeline::get`] when there is no version of the key at or
+/// before the requested LSN. Use `downcast_ref` to tell it apart from other
+/// read errors.
+#[derive(Debug, thiserror::Error)]
+#[error("key {key} not found at {lsn}")]
+pub struct KeyNotFound {
+    pub key: Key,
+    pub lsn: Lsn,
+}
+
 /// A 'value' stored for a one Key.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Value {
@@ -182,6 +193,40 @@ impl Value {
     }
 }
 
+/// The kind of a stored page version, as reported by
+/// [`Timeline::get_key_history`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageVersionKind {
+    /// A full page image
+    Image,
+    /// A WAL record, with the size of the stored value in bytes
+    Delta { rec_len: usize },
+}
+
+impl PageVersionKind {
+    pub fn of(value: &Value, stored_len: usize) -> Self {
+        match value {
+            Value::Image(_) => PageVersionKind::Image,
+            Value::WalRecord(_) => PageVersionKind::Delta {
+                rec_len: stored_len,
+            },
+        }
+    }
+}
+
+/// What reconstructing a page version involves, as reported by
+/// [`Timeline::explain_get`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconstructPlan {
+    /// LSN of the page image that the WAL records are applied on, if any.
+    /// Without one, the first record initializes the page.
+    pub base_image_lsn: Option<Lsn>,
+    /// LSNs of the WAL records to apply, in ascending order
+    pub record_lsns: Vec<Lsn>,
+    /// Did collecting the data involve ancestor timelines?
+    pub crosses_timeline: bool,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum TimelineSyncStatusUpdate {
     Downloaded,
@@ -199,7 +244,7 @@ impl Display for TimelineSyncStatusUpdate {
 /// A repository corresponds to one .zenith directory. One repository holds multiple
 /// timelines, forked off from the same initial call to 'initdb'.
 pub trait Repository: Send + Sync {
-    type Timeline: Timeline;
+    type Timeline: Timeline + 'static;
 
     /// Updates timeline based on the `TimelineSyncStatusUpdate`, received from the remote storage synchronization.
     /// See [`crate::remote_storage`] for more details about the synchronization.
@@ -231,6 +276,19 @@ pub trait Repository: Send + Sync {
     /// Branch a timeline
     fn branch_timeline(&self, src: ZTimelineId, dst: ZTimelineId, start_lsn: Lsn) -> Result<()>;
 
+    /// Move the branch point of a timeline forward, to a later LSN of its ancestor.
+    /// The timeline must not have any data of its own yet, and must not be in use.
+    /// Use [`tenant_mgr::rebase_timeline`](crate::tenant_mgr::rebase_timeline)
+    /// to unload a running timeline first.
+    fn rebase_branch(&self, timelineid: ZTimelineId, new_ancestor_lsn: Lsn) -> Result<()>;
+
+    /// Copy the pages a timeline inherits from its ancestor into the timeline
+    /// itself, and make it a root timeline that no longer depends on the
+    /// ancestor. The timeline must not be in use. Use
+    /// [`tenant_mgr::detach_timeline_from_ancestor`](crate::tenant_mgr::detach_timeline_from_ancestor)
+    /// to unload a running timeline first.
+    fn detach_from_ancestor(&self, timelineid: ZTimelineId) -> Result<()>;
+
     /// Flush all data to disk.
     ///
     /// this is used at graceful shutdown.
@@ -340,6 +398,16 @@ pub trait Timeline: Send + Sync {
     ///
     fn wait_lsn(&self, lsn: Lsn) -> Result<()>;
 
+    /// Make all the current wait_lsn() calls return with an error. Called when
+    /// the WAL receiver exits with an error, so that the waiters don't all
+    /// wait for their timeouts, for WAL that is not going to arrive.
+    fn cancel_lsn_waits(&self);
+
+    /// Get notified of every advancement of the last record LSN, for async
+    /// consumers that follow the end of the timeline. To wait for a specific
+    /// LSN, use wait_lsn().
+    fn subscribe_last_record_lsn(&self) -> watch::Receiver<Lsn>;
+
     /// Lock and get timeline's GC cuttof
     fn get_latest_gc_cutoff_lsn(&self) -> RwLockReadGuard<Lsn>;
 
@@ -351,14 +419,48 @@ pub trait Timeline: Send + Sync {
     /// the Repository implementation may incorrectly return a value from an ancestor
     /// branch, for example, or waste a lot of cycles chasing the non-existing key.
     ///
+    /// The LSN doesn't need to be aligned. Values are only ever stored at aligned
+    /// LSNs (the writer rejects others), so looking up a key at an unaligned LSN
+    /// returns the same as looking it up at the preceding aligned LSN.
+    ///
+    /// If there is no version of the key at all, the error is a [`KeyNotFound`].
+    ///
     fn get(&self, key: Key, lsn: Lsn) -> Result<Bytes>;
 
+    /// Get the keys in 'key_range' that have a new version with an LSN in
+    /// 'lsn_range', in ascending order and without duplicates. The versions
+    /// inherited from the ancestor branch are included.
+    ///
+    /// The LSN range must not start before the GC cutoff, as some of the
+    /// versions before it may have been garbage collected already.
+    fn get_changed_keys(&self, key_range: Range<Key>, lsn_range: Range<Lsn>) -> Result<Vec<Key>>;
+
+    /// Get the LSN and kind of every stored version of 'key', in ascending
+    /// LSN order, including the versions inherited from the ancestor branch.
+    /// Nothing is reconstructed. Meant for debugging.
+    fn get_key_history(&self, key: Key) -> Result<Vec<(Lsn, PageVersionKind)>>;
+
+    /// Collect the data needed to reconstruct 'key' at 'lsn' like get() does,
+    /// but only describe it instead of running WAL redo. The materialized page
+    /// cache is not consulted, so the plan shows what's stored in the layers.
+    /// Meant for debugging slow page reconstruction.
+    fn explain_get(&self, key: Key, lsn: Lsn) -> Result<ReconstructPlan>;
+
+    /// Get the ids of the tenant and of this timeline
+    fn get_tenant_id(&self) -> ZTenantId;
+    fn get_timeline_id(&self) -> ZTimelineId;
+
     /// Get the ancestor's timeline id
     fn get_ancestor_timeline_id(&self) -> Option<ZTimelineId>;
 
     /// Get the LSN where this branch was created
     fn get_ancestor_lsn(&self) -> Lsn;
 
+    /// Get the oldest LSN that can still be read on this timeline. Older
+    /// versions are either before initdb, or may have been garbage collected,
+    /// on this timeline or, before the branch point, on its ancestors.
+    fn get_oldest_readable_lsn(&self) -> Lsn;
+
     //------------------------------------------------------------------------------
     // Public PUT functions, to update the repository with new page versions.
     //
@@ -388,6 +490,23 @@ pub trait Timeline: Send + Sync {
     /// know anything about them here in the repository.
     fn checkpoint(&self, cconf: CheckpointConfig) -> Result<()>;
 
+    /// Make sure that everything up to 'lsn' is durable on local disk, i.e.
+    /// that get_disk_consistent_lsn() >= 'lsn', flushing the in-memory layers
+    /// if needed. 'lsn' must not be ahead of the last record LSN.
+    fn flush_to_lsn(&self, lsn: Lsn) -> Result<()>;
+
+    ///
+    /// Discard everything after 'lsn', so that the WAL from that point on
+    /// can be ingested again, e.g. to re-sync a timeline from the safekeepers.
+    ///
+    /// The WAL receiver for the timeline is stopped first, and it's up to the
+    /// caller to launch it again, so this must not be called from the WAL
+    /// receiver thread itself. 'lsn' must be aligned, not older than the
+    /// branch point or the GC cutoff, and not newer than the last record LSN.
+    /// Child branches created after 'lsn' are not checked for, the caller
+    /// must make sure there are none.
+    fn reset_to_lsn(&self, lsn: Lsn) -> Result<()>;
+
     ///
     /// Check that it is valid to request operations with that lsn.
     fn check_lsn_is_in_scope(
@@ -395,6 +514,17 @@ pub trait Timeline: Send + Sync {
         lsn: Lsn,
         latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
     ) -> Result<()>;
+
+    ///
+    /// Like check_lsn_is_in_scope(), but also allows reading 'key' at an
+    /// 'lsn' before the GC cutoff, if the version there is one of the
+    /// newest versions that GC keeps for the key.
+    fn check_key_lsn_is_in_scope(
+        &self,
+        key: Key,
+        lsn: Lsn,
+        latest_gc_cutoff_lsn: &RwLockReadGuard<Lsn>,
+    ) -> Result<()>;
 }
 
 /// Various functions to mutate the timeline.
@@ -408,6 +538,17 @@ pub trait TimelineWriter<'a> {
     /// current end-of-file.
     fn put(&self, key: Key, lsn: Lsn, value: Value) -> Result<()>;
 
+    /// Put a batch of new page versions, all at the same LSN.
+    ///
+    /// This is equivalent to calling put() for each of them, but
+    /// implementations can do it more efficiently.
+    fn put_batch(&self, lsn: Lsn, batch: Vec<(Key, Value)>) -> Result<()> {
+        for (key, value) in batch {
+            self.put(key, lsn, value)?;
+        }
+        Ok(())
+    }
+
     fn delete(&self, key_range: Range<Key>, lsn: Lsn) -> Result<()>;
 
     /// Track the end of the latest digested WAL record.
@@ -431,6 +572,7 @@ pub mod repo_harness {
     use crate::{
         config::PageServerConf,
         layered_repository::LayeredRepository,
+        rate_limiter::RateLimiter,
         walredo::{WalRedoError, WalRedoManager},
     };
 
@@ -469,6 +611,7 @@ pub mod repo_harness {
                 gc_period: Some(tenant_conf.gc_period),
                 image_creation_threshold: Some(tenant_conf.image_creation_threshold),
                 pitr_interval: Some(tenant_conf.pitr_interval),
+                min_versions_per_block: Some(tenant_conf.min_versions_per_block),
             }
         }
     }
@@ -526,8 +669,26 @@ pub mod repo_harness {
         }
 
         pub fn try_load(&self) -> Result<RepositoryImpl> {
-            let walredo_mgr = Arc::new(TestRedoManager);
+            self.try_load_with_redo_manager(Arc::new(TestRedoManager))
+        }
+
+        pub fn try_load_with_redo_manager(
+            &self,
+            walredo_mgr: Arc<dyn WalRedoManager + Send + Sync>,
+        ) -> Result<RepositoryImpl> {
+            self.try_load_with(walredo_mgr, None)
+        }
+
+        pub fn load_with_io_limiter(&self, io_limiter: Arc<RateLimiter>) -> RepositoryImpl {
+            self.try_load_with(Arc::new(TestRedoManager), Some(io_limiter))
+                .expect("failed to load test repo")
+        }
 
+        fn try_load_with(
+            &self,
+            walredo_mgr: Arc<dyn WalRedoManager + Send + Sync>,
+            io_limiter: Option<Arc<RateLimiter>>,
+        ) -> Result<RepositoryImpl> {
             let repo = LayeredRepository::new(
                 self.conf,
                 TenantConfOpt::from(self.tenant_conf),
@@ -535,6 +696,7 @@ pub mod repo_harness {
                 self.tenant_id,
                 RemoteIndex::empty(),
                 false,
+                io_limiter,
             );
             // populate repo with locally available timelines
             for timeline_dir_entry in fs::read_dir(self.conf.timelines_path(&self.tenant_id))
@@ -572,7 +734,7 @@ pub mod repo_harness {
             key: Key,
             lsn: Lsn,
             base_img: Option<Bytes>,
-            records: Vec<(Lsn, ZenithWalRecord)>,
+            records: &[(Lsn, ZenithWalRecord)],
         ) -> Result<Bytes, WalRedoError> {
             let s = format!(
                 "redo for {} to get to {}, with {} and {} records",
@@ -694,6 +856,51 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_rebase_branch() -> Result<()> {
+        let repo = RepoHarness::create("test_rebase_branch")?.load();
+        let tline = repo.create_empty_timeline(TIMELINE_ID, Lsn(0))?;
+        let writer = tline.writer();
+
+        #[allow(non_snake_case)]
+        let TEST_KEY: Key = Key::from_hex("112222222233333333444444445500000001").unwrap();
+
+        writer.put(TEST_KEY, Lsn(0x20), test_value("foo at 0x20"))?;
+        writer.finish_write(Lsn(0x20));
+        repo.branch_timeline(TIMELINE_ID, NEW_TIMELINE_ID, Lsn(0x20))?;
+
+        writer.put(TEST_KEY, Lsn(0x30), test_value("foo at 0x30"))?;
+        writer.finish_write(Lsn(0x30));
+        drop(writer);
+
+        // The new branch point must be between the old one and the end of the ancestor
+        assert!(repo.rebase_branch(NEW_TIMELINE_ID, Lsn(0x18)).is_err());
+        assert!(repo.rebase_branch(NEW_TIMELINE_ID, Lsn(0x40)).is_err());
+        // Only branches can be rebased
+        assert!(repo.rebase_branch(TIMELINE_ID, Lsn(0x30)).is_err());
+
+        repo.rebase_branch(NEW_TIMELINE_ID, Lsn(0x30))?;
+        let newtline = repo
+            .get_timeline_load(NEW_TIMELINE_ID)
+            .expect("Should have a local timeline");
+        assert_eq!(newtline.get_ancestor_lsn(), Lsn(0x30));
+        assert_eq!(newtline.get_last_record_lsn(), Lsn(0x30));
+        assert_eq!(
+            std::str::from_utf8(&newtline.get(TEST_KEY, Lsn(0x30))?)?,
+            "foo at 0x30"
+        );
+
+        // Once the branch has data of its own, it can't be rebased anymore
+        let new_writer = newtline.writer();
+        new_writer.put(TEST_KEY, Lsn(0x40), test_value("bar at 0x40"))?;
+        new_writer.finish_write(Lsn(0x40));
+        drop(new_writer);
+        drop(newtline);
+        assert!(repo.rebase_branch(NEW_TIMELINE_ID, Lsn(0x30)).is_err());
+
+        Ok(())
+    }
+
     fn make_some_layers<T: Timeline>(tline: &T, start_lsn: Lsn) -> Result<()> {
         let mut lsn = start_lsn;
         #[allow(non_snake_case)]
@@ -159,7 +159,6 @@ use lazy_static::lazy_static;
 use once_cell::sync::OnceCell;
 use remote_storage::{GenericRemoteStorage, RemoteStorage};
 use tokio::{
-    fs,
     runtime::Runtime,
     time::{Duration, Instant},
 };
@@ -175,8 +174,8 @@ use crate::{
     config::PageServerConf,
     layered_repository::{
         ephemeral_file::is_ephemeral_file,
-        metadata::{metadata_path, TimelineMetadata, METADATA_FILE_NAME},
-        LayeredRepository,
+        metadata::{is_metadata_file, metadata_path, TimelineMetadata},
+        try_recover_metadata, LayeredRepository,
     },
     repository::TimelineSyncStatusUpdate,
     storage_sync::{self, index::RemoteIndex},
@@ -360,7 +359,7 @@ fn collect_timelines_for_tenant(
         match timelines_dir_entry {
             Ok(timelines_dir_entry) => {
                 let timeline_path = timelines_dir_entry.path();
-                match collect_timeline_files(&timeline_path) {
+                match collect_timeline_files(config, tenant_id, &timeline_path) {
                     Ok((timeline_id, metadata, timeline_files)) => {
                         timelines.insert(
                             ZTenantTimelineId {
@@ -390,10 +389,11 @@ fn collect_timelines_for_tenant(
 // discover timeline files and extract timeline metadata
 //  NOTE: ephemeral files are excluded from the list
 fn collect_timeline_files(
+    config: &'static PageServerConf,
+    tenant_id: ZTenantId,
     timeline_dir: &Path,
 ) -> anyhow::Result<(ZTimelineId, TimelineMetadata, HashSet<PathBuf>)> {
     let mut timeline_files = HashSet::new();
-    let mut timeline_metadata_path = None;
 
     let timeline_id = timeline_dir
         .file_name()
@@ -406,8 +406,11 @@ fn collect_timeline_files(
     for entry in timeline_dir_entries {
         let entry_path = entry.context("Failed to list timeline dir entry")?.path();
         if entry_path.is_file() {
-            if entry_path.file_name().and_then(OsStr::to_str) == Some(METADATA_FILE_NAME) {
-                timeline_metadata_path = Some(entry_path);
+            let file_name = entry_path.file_name().and_then(OsStr::to_str);
+            if file_name.map_or(false, is_metadata_file) {
+                // read from metadata_path() below, it may be elsewhere. Its
+                // backup and temporary copies are not layers either.
+                continue;
             } else if is_ephemeral_file(&entry_path.file_name().unwrap().to_string_lossy()) {
                 debug!("skipping ephemeral file {}", entry_path.display());
                 continue;
@@ -442,14 +445,13 @@ fn collect_timeline_files(
     //   be aware of that and retry attach if awaits_download for timeline switched from true to false
     //   but timelinne didn't appear locally.
     //   Check what happens with remote index in that case.
-    let timeline_metadata_path = match timeline_metadata_path {
-        Some(path) => path,
-        None => bail!("No metadata file found in the timeline directory"),
-    };
-    let metadata = TimelineMetadata::from_bytes(
-        &std::fs::read(&timeline_metadata_path).context("Failed to read timeline metadata file")?,
-    )
-    .context("Failed to parse timeline metadata file bytes")?;
+    // The metadata file is in the timeline directory, unless 'metadata_dir' is set
+    let timeline_metadata_path = metadata_path(config, timeline_id, tenant_id);
+    if !timeline_metadata_path.exists() {
+        bail!("No metadata file found in the timeline directory");
+    }
+    let metadata = try_recover_metadata(config, timeline_id, tenant_id)
+        .context("Failed to load timeline metadata file")?;
 
     Ok((timeline_id, metadata, timeline_files))
 }
@@ -1194,14 +1196,20 @@ async fn update_local_metadata(
 
     let local_metadata_path = metadata_path(conf, sync_id.timeline_id, sync_id.tenant_id);
     let local_lsn = if local_metadata_path.exists() {
-        let local_metadata = read_metadata_file(&local_metadata_path)
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to load local metadata from path '{}'",
-                    local_metadata_path.display()
-                )
-            })?;
+        let ZTenantTimelineId {
+            tenant_id,
+            timeline_id,
+        } = sync_id;
+        let local_metadata =
+            tokio::task::spawn_blocking(move || try_recover_metadata(conf, timeline_id, tenant_id))
+                .await
+                .context("failed to join metadata load task")?
+                .with_context(|| {
+                    format!(
+                        "Failed to load local metadata from path '{}'",
+                        local_metadata_path.display()
+                    )
+                })?;
 
         Some(local_metadata.disk_consistent_lsn())
     } else {
@@ -1275,15 +1283,6 @@ async fn delete_timeline_data<P, S>(
     register_sync_status(sync_start, task_name, Some(sync_status));
 }
 
-async fn read_metadata_file(metadata_path: &Path) -> anyhow::Result<TimelineMetadata> {
-    TimelineMetadata::from_bytes(
-        &fs::read(metadata_path)
-            .await
-            .context("Failed to read local metadata bytes from fs")?,
-    )
-    .context("Failed to parse metadata bytes")
-}
-
 async fn upload_timeline_data<P, S>(
     conf: &'static PageServerConf,
     (storage, index, sync_queue): (&S, &RemoteIndex, &SyncQueue),
@@ -1415,6 +1414,10 @@ where
             },
         }
     };
+    index.notify_remote_consistent_lsn(
+        sync_id,
+        updated_remote_timeline.metadata.disk_consistent_lsn(),
+    );
 
     let timeline_path = conf.timeline_path(&sync_id.timeline_id, &sync_id.tenant_id);
     let new_index_part =
@@ -1603,6 +1606,7 @@ fn register_sync_status(sync_start: Instant, sync_name: &str, sync_status: Optio
 
 #[cfg(test)]
 mod test_utils {
+    use tokio::fs;
     use utils::lsn::Lsn;
 
     use crate::repository::repo_harness::RepoHarness;
@@ -1649,8 +1653,8 @@ mod test_utils {
 
 #[cfg(test)]
 mod tests {
-    use super::test_utils::dummy_metadata;
-    use crate::repository::repo_harness::TIMELINE_ID;
+    use super::test_utils::{create_local_timeline, dummy_metadata};
+    use crate::repository::repo_harness::{RepoHarness, TIMELINE_ID};
     use hex_literal::hex;
     use utils::lsn::Lsn;
 
@@ -1839,4 +1843,31 @@ mod tests {
             "Should have one task left out of the batch"
         );
     }
+
+    #[tokio::test]
+    async fn collect_timeline_files_recovers_metadata() -> anyhow::Result<()> {
+        let harness = RepoHarness::create("collect_timeline_files_recovers_metadata")?;
+        let metadata = dummy_metadata(Lsn(0x30));
+        let upload = create_local_timeline(&harness, TIMELINE_ID, &["a"], metadata.clone()).await?;
+
+        // A good backup of the metadata, a leftover temporary file, and a
+        // corrupt metadata file
+        let path = metadata_path(harness.conf, TIMELINE_ID, harness.tenant_id);
+        let mut metadata_bytes = metadata.to_bytes()?;
+        std::fs::write(path.with_file_name("metadata.bak"), &metadata_bytes)?;
+        std::fs::write(path.with_file_name("metadata.temp"), &metadata_bytes)?;
+        metadata_bytes[0] ^= 0xFF;
+        std::fs::write(&path, &metadata_bytes)?;
+
+        let (timeline_id, collected_metadata, files) = collect_timeline_files(
+            harness.conf,
+            harness.tenant_id,
+            &harness.timeline_path(&TIMELINE_ID),
+        )?;
+        assert_eq!(timeline_id, TIMELINE_ID);
+        assert_eq!(collected_metadata, metadata);
+        assert_eq!(files, upload.layers_to_upload);
+
+        Ok(())
+    }
 }
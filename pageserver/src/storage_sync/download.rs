@@ -11,9 +11,7 @@ use tokio::{
 };
 use tracing::{debug, error, info, warn};
 
-use crate::{
-    config::PageServerConf, layered_repository::metadata::metadata_path, storage_sync::SyncTask,
-};
+use crate::{config::PageServerConf, storage_sync::SyncTask};
 use utils::zid::ZTenantTimelineId;
 
 use super::{
@@ -33,8 +31,10 @@ where
     P: Debug + Send + Sync + 'static,
     S: RemoteStorage<RemoteObjectId = P> + Send + Sync + 'static,
 {
-    let index_part_path = metadata_path(conf, sync_id.timeline_id, sync_id.tenant_id)
-        .with_file_name(IndexPart::FILE_NAME)
+    // Next to the layer files, even if the metadata file is kept elsewhere
+    let index_part_path = conf
+        .timeline_path(&sync_id.timeline_id, &sync_id.tenant_id)
+        .join(IndexPart::FILE_NAME)
         .with_extension(IndexPart::FILE_EXTENSION);
     let part_storage_path = storage
         .remote_object_id(&index_part_path)
@@ -463,10 +463,11 @@ mod tests {
             metadata.to_bytes()?,
         );
 
-        let local_index_part_path =
-            metadata_path(harness.conf, sync_id.timeline_id, sync_id.tenant_id)
-                .with_file_name(IndexPart::FILE_NAME)
-                .with_extension(IndexPart::FILE_EXTENSION);
+        let local_index_part_path = harness
+            .conf
+            .timeline_path(&sync_id.timeline_id, &sync_id.tenant_id)
+            .join(IndexPart::FILE_NAME)
+            .with_extension(IndexPart::FILE_EXTENSION);
         let storage_path = storage.remote_object_id(&local_index_part_path)?;
         fs::create_dir_all(storage_path.parent().unwrap()).await?;
         fs::write(&storage_path, serde_json::to_vec(&index_part)?).await?;
@@ -3,15 +3,15 @@
 //! remote timeline layers and its metadata.
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map, HashMap, HashSet},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use anyhow::{anyhow, Context, Ok};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
 
 use crate::{config::PageServerConf, layered_repository::metadata::TimelineMetadata};
 use utils::{lsn::Lsn, zid::ZTenantTimelineId};
@@ -48,13 +48,26 @@ pub struct RemoteTimelineIndex {
 }
 
 /// A wrapper to synchronize the access to the index, should be created and used before dealing with any [`RemoteTimelineIndex`].
-pub struct RemoteIndex(Arc<RwLock<RemoteTimelineIndex>>);
+pub struct RemoteIndex {
+    index: Arc<RwLock<RemoteTimelineIndex>>,
+    /// Per-timeline channels, notified every time a newer `disk_consistent_lsn` gets into the index after an upload.
+    /// Lets the interested parties (e.g. WAL receivers, reporting the remote consistent LSN to safekeepers)
+    /// to learn about the uploads without locking and querying the index.
+    remote_consistent_lsn_watches: Arc<Mutex<HashMap<ZTenantTimelineId, LsnWatch>>>,
+}
+
+/// Keeps a receiver alongside the sender, so the channel stays open and the latest value is always stored,
+/// even if there are no subscribers at the moment.
+struct LsnWatch {
+    sender: watch::Sender<Lsn>,
+    receiver: watch::Receiver<Lsn>,
+}
 
 impl RemoteIndex {
     pub fn empty() -> Self {
-        Self(Arc::new(RwLock::new(RemoteTimelineIndex {
+        Self::new(RemoteTimelineIndex {
             timeline_entries: HashMap::new(),
-        })))
+        })
     }
 
     pub fn from_parts(
@@ -70,23 +83,75 @@ impl RemoteIndex {
             timeline_entries.insert(sync_id, remote_timeline);
         }
 
-        Ok(Self(Arc::new(RwLock::new(RemoteTimelineIndex {
-            timeline_entries,
-        }))))
+        Ok(Self::new(RemoteTimelineIndex { timeline_entries }))
+    }
+
+    fn new(index: RemoteTimelineIndex) -> Self {
+        Self {
+            index: Arc::new(RwLock::new(index)),
+            remote_consistent_lsn_watches: Arc::new(Mutex::new(HashMap::new())),
+        }
     }
 
     pub async fn read(&self) -> tokio::sync::RwLockReadGuard<'_, RemoteTimelineIndex> {
-        self.0.read().await
+        self.index.read().await
     }
 
     pub async fn write(&self) -> tokio::sync::RwLockWriteGuard<'_, RemoteTimelineIndex> {
-        self.0.write().await
+        self.index.write().await
+    }
+
+    /// Subscribes to the remote consistent LSN updates of the timeline.
+    /// The receiver holds [`Lsn(0)`] until the first notification for the timeline after the subscription,
+    /// so callers should still consult the index for the initial value.
+    pub fn watch_remote_consistent_lsn(&self, id: ZTenantTimelineId) -> watch::Receiver<Lsn> {
+        let mut watches = self.remote_consistent_lsn_watches.lock().unwrap();
+        watches
+            .entry(id)
+            .or_insert_with(|| LsnWatch::new(Lsn(0)))
+            .receiver
+            .clone()
+    }
+
+    /// Notifies the timeline subscribers about a new remote consistent LSN.
+    /// LSNs that are not newer than the already notified one are ignored.
+    /// The watch is dropped once all of its subscribers are gone.
+    pub fn notify_remote_consistent_lsn(&self, id: ZTenantTimelineId, lsn: Lsn) {
+        let mut watches = self.remote_consistent_lsn_watches.lock().unwrap();
+        if let hash_map::Entry::Occupied(o) = watches.entry(id) {
+            let lsn_watch = o.get();
+            // The receiver we keep ourselves is always counted.
+            if lsn_watch.sender.receiver_count() <= 1 {
+                o.remove();
+            } else if *lsn_watch.receiver.borrow() < lsn {
+                // Cannot fail, since we hold a receiver ourselves.
+                let _ = lsn_watch.sender.send(lsn);
+            }
+        }
+    }
+
+    /// Drops the remote consistent LSN watch of a timeline that is removed from the pageserver.
+    pub fn forget_remote_consistent_lsn(&self, id: ZTenantTimelineId) {
+        self.remote_consistent_lsn_watches
+            .lock()
+            .unwrap()
+            .remove(&id);
+    }
+}
+
+impl LsnWatch {
+    fn new(lsn: Lsn) -> Self {
+        let (sender, receiver) = watch::channel(lsn);
+        Self { sender, receiver }
     }
 }
 
 impl Clone for RemoteIndex {
     fn clone(&self) -> Self {
-        Self(Arc::clone(&self.0))
+        Self {
+            index: Arc::clone(&self.index),
+            remote_consistent_lsn_watches: Arc::clone(&self.remote_consistent_lsn_watches),
+        }
     }
 }
 
@@ -253,6 +318,39 @@ mod tests {
     use super::*;
     use crate::repository::repo_harness::{RepoHarness, TIMELINE_ID};
 
+    #[test]
+    fn remote_consistent_lsn_watches_pruning() {
+        let id = ZTenantTimelineId::generate();
+        let remote_index = RemoteIndex::empty();
+        let num_watches = || {
+            remote_index
+                .remote_consistent_lsn_watches
+                .lock()
+                .unwrap()
+                .len()
+        };
+
+        // Without subscribers, notifications don't create a watch
+        remote_index.notify_remote_consistent_lsn(id, Lsn(0x10));
+        assert_eq!(num_watches(), 0);
+
+        let receiver = remote_index.watch_remote_consistent_lsn(id);
+        remote_index.notify_remote_consistent_lsn(id, Lsn(0x20));
+        assert_eq!(*receiver.borrow(), Lsn(0x20));
+        assert_eq!(num_watches(), 1);
+
+        // The next notification after the last subscriber is gone drops the watch
+        drop(receiver);
+        remote_index.notify_remote_consistent_lsn(id, Lsn(0x30));
+        assert_eq!(num_watches(), 0);
+
+        // And so does the removal of the timeline
+        let _receiver = remote_index.watch_remote_consistent_lsn(id);
+        assert_eq!(num_watches(), 1);
+        remote_index.forget_remote_consistent_lsn(id);
+        assert_eq!(num_watches(), 0);
+    }
+
     #[test]
     fn index_part_conversion() {
         let harness = RepoHarness::create("index_part_conversion").unwrap();
@@ -14,9 +14,7 @@ use super::{
     index::{IndexPart, RemoteTimeline},
     LayersUpload, SyncData, SyncQueue,
 };
-use crate::{
-    config::PageServerConf, layered_repository::metadata::metadata_path, storage_sync::SyncTask,
-};
+use crate::{config::PageServerConf, storage_sync::SyncTask};
 
 /// Serializes and uploads the given index part data to the remote storage.
 pub(super) async fn upload_index_part<P, S>(
@@ -34,8 +32,10 @@ where
     let index_part_size = index_part_bytes.len();
     let index_part_bytes = tokio::io::BufReader::new(std::io::Cursor::new(index_part_bytes));
 
-    let index_part_path = metadata_path(conf, sync_id.timeline_id, sync_id.tenant_id)
-        .with_file_name(IndexPart::FILE_NAME)
+    // Next to the layer files, even if the metadata file is kept elsewhere
+    let index_part_path = conf
+        .timeline_path(&sync_id.timeline_id, &sync_id.tenant_id)
+        .join(IndexPart::FILE_NAME)
         .with_extension(IndexPart::FILE_EXTENSION);
     let index_part_storage_path =
         storage
@@ -34,6 +34,7 @@ pub mod defaults {
     pub const DEFAULT_GC_PERIOD: &str = "100 s";
     pub const DEFAULT_IMAGE_CREATION_THRESHOLD: usize = 3;
     pub const DEFAULT_PITR_INTERVAL: &str = "30 days";
+    pub const DEFAULT_MIN_VERSIONS_PER_BLOCK: usize = 0;
 }
 
 /// Per-tenant configuration options
@@ -68,6 +69,10 @@ pub struct TenantConf {
     // Page versions older than this are garbage collected away.
     #[serde(with = "humantime_serde")]
     pub pitr_interval: Duration,
+    // Garbage collection keeps at least this many of the newest versions
+    // of every page readable, even if they're older than the cutoff.
+    // 0 or 1 disables it, the newest version is always kept.
+    pub min_versions_per_block: usize,
 }
 
 /// Same as TenantConf, but this struct preserves the information about
@@ -85,6 +90,7 @@ pub struct TenantConfOpt {
     pub image_creation_threshold: Option<usize>,
     #[serde(with = "humantime_serde")]
     pub pitr_interval: Option<Duration>,
+    pub min_versions_per_block: Option<usize>,
 }
 
 impl TenantConfOpt {
@@ -108,6 +114,9 @@ impl TenantConfOpt {
                 .image_creation_threshold
                 .unwrap_or(global_conf.image_creation_threshold),
             pitr_interval: self.pitr_interval.unwrap_or(global_conf.pitr_interval),
+            min_versions_per_block: self
+                .min_versions_per_block
+                .unwrap_or(global_conf.min_versions_per_block),
         }
     }
 
@@ -136,6 +145,9 @@ impl TenantConfOpt {
         if let Some(pitr_interval) = other.pitr_interval {
             self.pitr_interval = Some(pitr_interval);
         }
+        if let Some(min_versions_per_block) = other.min_versions_per_block {
+            self.min_versions_per_block = Some(min_versions_per_block);
+        }
     }
 }
 
@@ -155,6 +167,7 @@ impl TenantConf {
             image_creation_threshold: DEFAULT_IMAGE_CREATION_THRESHOLD,
             pitr_interval: humantime::parse_duration(DEFAULT_PITR_INTERVAL)
                 .expect("cannot parse default PITR interval"),
+            min_versions_per_block: DEFAULT_MIN_VERSIONS_PER_BLOCK,
         }
     }
 
@@ -175,6 +188,7 @@ impl TenantConf {
             gc_period: Duration::from_secs(10),
             image_creation_threshold: defaults::DEFAULT_IMAGE_CREATION_THRESHOLD,
             pitr_interval: Duration::from_secs(60 * 60),
+            min_versions_per_block: defaults::DEFAULT_MIN_VERSIONS_PER_BLOCK,
         }
     }
 }
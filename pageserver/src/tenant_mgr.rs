@@ -2,8 +2,11 @@
 //! page server.
 
 use crate::config::PageServerConf;
-use crate::layered_repository::{load_metadata, LayeredRepository};
+use crate::layered_repository::metadata::lsn_timestamps_path;
+use crate::layered_repository::{try_recover_metadata, LayeredRepository};
 use crate::pgdatadir_mapping::DatadirTimeline;
+use crate::rate_limiter;
+use crate::reltag::RelTag;
 use crate::repository::{Repository, TimelineSyncStatusUpdate};
 use crate::storage_sync::index::RemoteIndex;
 use crate::storage_sync::{self, LocalTimelineInitStatus, SyncStartupData};
@@ -12,19 +15,20 @@ use crate::thread_mgr;
 use crate::thread_mgr::ThreadKind;
 use crate::timelines;
 use crate::timelines::CreateRepo;
+use crate::walreceiver;
 use crate::walredo::PostgresRedoManager;
 use crate::{DatadirTimelineImpl, RepositoryImpl};
 use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::sync::Arc;
 use tracing::*;
 use utils::lsn::Lsn;
 
-use utils::zid::{ZTenantId, ZTimelineId};
+use utils::zid::{ZTenantId, ZTenantTimelineId, ZTimelineId};
 
 mod tenants_state {
     use std::{
@@ -99,6 +103,9 @@ impl fmt::Display for TenantState {
 /// Timelines that are only partially available locally (remote storage has more data than this pageserver)
 /// are scheduled for download and added to the repository once download is completed.
 pub fn init_tenant_mgr(conf: &'static PageServerConf) -> anyhow::Result<RemoteIndex> {
+    remove_removed_tenants_metadata(conf)
+        .context("Failed to clean up the metadata of removed tenants")?;
+
     let SyncStartupData {
         remote_index,
         local_timeline_init_statuses,
@@ -123,6 +130,48 @@ pub fn init_tenant_mgr(conf: &'static PageServerConf) -> anyhow::Result<RemoteIn
     Ok(remote_index)
 }
 
+/// With a separate 'metadata_dir', removing a tenant's directory leaves its
+/// metadata files behind. Remove those of the tenants that are gone, so that
+/// they are not picked up if a tenant with the same id is created again.
+fn remove_removed_tenants_metadata(conf: &'static PageServerConf) -> anyhow::Result<()> {
+    let tenants_metadata_dir = match conf.tenants_metadata_dir() {
+        Some(dir) if dir.exists() => dir,
+        _ => return Ok(()),
+    };
+    for entry in std::fs::read_dir(&tenants_metadata_dir)? {
+        let entry = entry?;
+        let tenant_id = match entry.file_name().to_str().map(str::parse::<ZTenantId>) {
+            Some(Ok(tenant_id)) => tenant_id,
+            _ => continue,
+        };
+        if !conf.tenant_path(&tenant_id).exists() {
+            remove_tenant_metadata(conf, tenant_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the metadata files of all the timelines of a tenant, if they are
+/// kept in a separate 'metadata_dir'.
+pub fn remove_tenant_metadata(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+) -> anyhow::Result<()> {
+    if let Some(tenants_metadata_dir) = conf.tenants_metadata_dir() {
+        let tenant_metadata_dir = tenants_metadata_dir.join(tenant_id.to_string());
+        if tenant_metadata_dir.exists() {
+            info!("removing metadata of tenant {tenant_id}");
+            std::fs::remove_dir_all(&tenant_metadata_dir).with_context(|| {
+                format!(
+                    "Failed to remove tenant metadata directory '{}'",
+                    tenant_metadata_dir.display()
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
 /// Updates tenants' repositories, changing their timelines state in memory.
 pub fn apply_timeline_sync_status_updates(
     conf: &'static PageServerConf,
@@ -173,9 +222,20 @@ pub fn shutdown_all_tenants() {
     }
     drop(m);
 
-    thread_mgr::shutdown_threads(Some(ThreadKind::WalReceiver), None, None);
-    thread_mgr::shutdown_threads(Some(ThreadKind::GarbageCollector), None, None);
+    // The order matters here:
+    //
+    // 1. Stop the compactor and GC threads first, so that they don't start
+    //    another iteration while the rest of the tenant is being shut down.
+    // 2. Stop the WAL receivers. No new WAL is ingested after this point.
+    //    Also stop any read-ahead still running for the page service.
+    // 3. Wait for any layer flush that the WAL receivers kicked off in the
+    //    background, so that it doesn't race with the final checkpoint below.
+    // 4. Checkpoint, to flush the remaining in-memory data to disk.
     thread_mgr::shutdown_threads(Some(ThreadKind::Compactor), None, None);
+    thread_mgr::shutdown_threads(Some(ThreadKind::GarbageCollector), None, None);
+    thread_mgr::shutdown_threads(Some(ThreadKind::WalReceiver), None, None);
+    thread_mgr::shutdown_threads(Some(ThreadKind::Prefetch), None, None);
+    thread_mgr::shutdown_threads(Some(ThreadKind::LayerFlushThread), None, None);
 
     // Ok, no background threads running anymore. Flush any remaining data in
     // memory to disk.
@@ -336,6 +396,51 @@ pub fn get_local_timeline_with_load(
     Ok(page_tline)
 }
 
+/// Make a repository created by a unit test known to this module, so that the
+/// code looking up timelines through it, like compaction, can find them.
+#[cfg(test)]
+pub fn register_test_tenant(tenant_id: ZTenantId, repo: Arc<RepositoryImpl>) {
+    tenants_state::write_tenants().insert(
+        tenant_id,
+        Tenant {
+            state: TenantState::Active,
+            repo,
+            local_timelines: HashMap::new(),
+        },
+    );
+}
+
+/// Logical size of all the local timelines of a tenant that are loaded into
+/// memory, counting data shared between branches only once. See
+/// [`get_tenant_logical_size`](crate::pgdatadir_mapping::get_tenant_logical_size).
+pub fn get_tenant_logical_size(tenant_id: ZTenantId) -> anyhow::Result<usize> {
+    // Don't hold the lock while calculating, it can take a while.
+    let local_timelines = tenants_state::read_tenants()
+        .get(&tenant_id)
+        .with_context(|| format!("Tenant {tenant_id} not found"))?
+        .local_timelines
+        .clone();
+
+    crate::pgdatadir_mapping::get_tenant_logical_size(&local_timelines)
+}
+
+/// Relations that have changed after LSN 'since' on each of the local timelines
+/// of a tenant that are loaded into memory. See
+/// [`get_tenant_changed_rels`](crate::pgdatadir_mapping::get_tenant_changed_rels).
+pub fn get_tenant_changed_rels(
+    tenant_id: ZTenantId,
+    since: Lsn,
+) -> anyhow::Result<HashMap<ZTimelineId, HashSet<RelTag>>> {
+    // Don't hold the lock while scanning, it can take a while.
+    let local_timelines = tenants_state::read_tenants()
+        .get(&tenant_id)
+        .with_context(|| format!("Tenant {tenant_id} not found"))?
+        .local_timelines
+        .clone();
+
+    crate::pgdatadir_mapping::get_tenant_changed_rels(&local_timelines, since)
+}
+
 pub fn detach_timeline(
     conf: &'static PageServerConf,
     tenant_id: ZTenantId,
@@ -343,6 +448,7 @@ pub fn detach_timeline(
 ) -> anyhow::Result<()> {
     // shutdown the timeline threads (this shuts down the walreceiver)
     thread_mgr::shutdown_threads(None, Some(tenant_id), Some(timeline_id));
+    walreceiver::forget_wal_receivers(tenant_id, Some(timeline_id));
 
     match tenants_state::write_tenants().get_mut(&tenant_id) {
         Some(tenant) => {
@@ -351,6 +457,10 @@ pub fn detach_timeline(
                 .detach_timeline(timeline_id)
                 .context("Failed to detach inmem tenant timeline")?;
             tenant.local_timelines.remove(&timeline_id);
+            tenant
+                .repo
+                .get_remote_index()
+                .forget_remote_consistent_lsn(ZTenantTimelineId::new(tenant_id, timeline_id));
         }
         None => bail!("Tenant {tenant_id} not found in local tenant state"),
     }
@@ -362,10 +472,61 @@ pub fn detach_timeline(
             local_timeline_directory.display()
         )
     })?;
+    let metadata_directory = conf.timeline_metadata_dir(&timeline_id, &tenant_id);
+    if metadata_directory != local_timeline_directory {
+        std::fs::remove_dir_all(&metadata_directory).with_context(|| {
+            format!(
+                "Failed to remove timeline metadata directory '{}'",
+                metadata_directory.display()
+            )
+        })?;
+    }
 
     Ok(())
 }
 
+/// Move the branch point of a timeline forward, see
+/// [`Repository::rebase_branch`]. The timeline is unloaded first, and loaded
+/// again with the new branch point on next access.
+pub fn rebase_timeline(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    new_ancestor_lsn: Lsn,
+) -> anyhow::Result<()> {
+    let repo = unload_local_timeline(tenant_id, timeline_id)?;
+    repo.rebase_branch(timeline_id, new_ancestor_lsn)
+}
+
+/// Make a timeline independent of its ancestor, see
+/// [`Repository::detach_from_ancestor`]. The timeline is unloaded first, and
+/// loaded again without the ancestor on next access.
+pub fn detach_timeline_from_ancestor(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+) -> anyhow::Result<()> {
+    let repo = unload_local_timeline(tenant_id, timeline_id)?;
+    repo.detach_from_ancestor(timeline_id)
+}
+
+/// Stop the threads of a local timeline, like its WAL receiver, and forget the
+/// loaded timeline, so that nothing here keeps using it. Compute connections
+/// that still use the timeline are not closed; the repository refuses to
+/// change a timeline that is in use.
+fn unload_local_timeline(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+) -> anyhow::Result<Arc<RepositoryImpl>> {
+    thread_mgr::shutdown_threads(None, Some(tenant_id), Some(timeline_id));
+    walreceiver::forget_wal_receivers(tenant_id, Some(timeline_id));
+
+    let mut m = tenants_state::write_tenants();
+    let tenant = m
+        .get_mut(&tenant_id)
+        .with_context(|| format!("Tenant {tenant_id} not found"))?;
+    tenant.local_timelines.remove(&timeline_id);
+    Ok(Arc::clone(&tenant.repo))
+}
+
 fn load_local_timeline(
     repo: &RepositoryImpl,
     timeline_id: ZTimelineId,
@@ -374,11 +535,16 @@ fn load_local_timeline(
         format!("Inmem timeline {timeline_id} not found in tenant's repository")
     })?;
     let repartition_distance = repo.get_checkpoint_distance() / 10;
-    let page_tline = Arc::new(DatadirTimelineImpl::new(
-        inmem_timeline,
-        repartition_distance,
-    ));
+    let page_tline = Arc::new(
+        DatadirTimelineImpl::new(inmem_timeline, repartition_distance).with_lsn_timestamps_path(
+            lsn_timestamps_path(repo.conf, timeline_id, repo.tenant_id()),
+        ),
+    );
+    page_tline.set_prefetch_depth(repo.get_prefetch_depth());
     page_tline.init_logical_size()?;
+    if repo.get_preload_metadata() {
+        page_tline.preload_rel_dir_cache()?;
+    }
     Ok(page_tline)
 }
 
@@ -408,7 +574,7 @@ pub fn list_tenants() -> Vec<TenantInfo> {
 /// - failed to load the timeline's metadata
 /// - the timeline's disk consistent LSN is zero
 fn check_broken_timeline(repo: &LayeredRepository, timeline_id: ZTimelineId) -> anyhow::Result<()> {
-    let metadata = load_metadata(repo.conf, timeline_id, repo.tenant_id())
+    let metadata = try_recover_metadata(repo.conf, timeline_id, repo.tenant_id())
         .context("failed to load metadata")?;
 
     // A timeline with zero disk consistent LSN can happen when the page server
@@ -520,6 +686,7 @@ fn load_local_repo(
             tenant_id,
             remote_index.clone(),
             conf.remote_storage_config.is_some(),
+            rate_limiter::tenant_io_limiter(conf),
         ));
         Tenant {
             state: TenantState::Idle,
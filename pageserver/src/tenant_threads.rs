@@ -3,8 +3,8 @@
 use crate::repository::Repository;
 use crate::tenant_mgr;
 use crate::tenant_mgr::TenantState;
+use crate::thread_mgr;
 use anyhow::Result;
-use std::time::Duration;
 use tracing::*;
 use utils::zid::ZTenantId;
 
@@ -28,7 +28,9 @@ fn compact_loop_ext(tenantid: ZTenantId) -> Result<()> {
         let repo = tenant_mgr::get_repository_for_tenant(tenantid)?;
         let compaction_period = repo.get_compaction_period();
 
-        std::thread::sleep(compaction_period);
+        if thread_mgr::sleep_until_shutdown(compaction_period) {
+            break;
+        }
         trace!("compaction thread for tenant {} waking up", tenantid);
 
         // Compact timelines
@@ -61,13 +63,8 @@ pub fn gc_loop(tenantid: ZTenantId) -> Result<()> {
             repo.gc_iteration(None, gc_horizon, repo.get_pitr_interval(), false)?;
         }
 
-        // TODO Write it in more adequate way using
-        // condvar.wait_timeout() or something
-        let mut sleep_time = repo.get_gc_period().as_secs();
-        while sleep_time > 0 && tenant_mgr::get_tenant_state(tenantid) == Some(TenantState::Active)
-        {
-            sleep_time -= 1;
-            std::thread::sleep(Duration::from_secs(1));
+        if thread_mgr::sleep_until_shutdown(repo.get_gc_period()) {
+            break;
         }
     }
     trace!(
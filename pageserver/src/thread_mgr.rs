@@ -24,7 +24,9 @@
 //! `shutdown_watcher()` function to get a Future that will become ready if
 //! the current thread has been requested to shut down. You can use that with
 //! Tokio select!(), but note that it relies on thread-local storage, so it
-//! will only work with the "current-thread" Tokio runtime!
+//! will only work with the "current-thread" Tokio runtime! Threads that
+//! sleep between iterations of work should use `sleep_until_shutdown()`
+//! instead of `std::thread::sleep()`, so that they wake up promptly.
 //!
 //!
 //! TODO: This would be a good place to also handle panics in a somewhat sane way.
@@ -37,9 +39,10 @@ use std::collections::HashMap;
 use std::panic;
 use std::panic::AssertUnwindSafe;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 
 use tokio::sync::watch;
 
@@ -103,6 +106,9 @@ pub enum ThreadKind {
     // Thread that flushes frozen in-memory layers to disk
     LayerFlushThread,
 
+    // Thread that reconstructs pages ahead of a sequential scan
+    Prefetch,
+
     // Thread for synchronizing pageserver layer files with the remote storage.
     // Shared by all tenants.
     StorageSync,
@@ -124,6 +130,10 @@ struct PageServerThread {
     shutdown_requested: AtomicBool,
     shutdown_tx: watch::Sender<()>,
 
+    // Wakes up the thread from sleep_until_shutdown()
+    shutdown_lock: Mutex<()>,
+    shutdown_cvar: Condvar,
+
     /// Handle for waiting for the thread to exit. It can be None, if the
     /// the thread has already exited.
     join_handle: Mutex<Option<JoinHandle<()>>>,
@@ -154,6 +164,8 @@ where
 
         shutdown_requested: AtomicBool::new(false),
         shutdown_tx,
+        shutdown_lock: Mutex::new(()),
+        shutdown_cvar: Condvar::new(),
 
         join_handle: Mutex::new(None),
     };
@@ -292,6 +304,10 @@ pub fn shutdown_threads(
             thread.shutdown_requested.store(true, Ordering::Relaxed);
             // FIXME: handle error?
             let _ = thread.shutdown_tx.send(());
+            // Take the lock so that the wakeup cannot slip in between the
+            // flag check and the wait in sleep_until_shutdown()
+            let _guard = thread.shutdown_lock.lock().unwrap();
+            thread.shutdown_cvar.notify_all();
             victim_threads.push(Arc::clone(thread));
         }
     }
@@ -335,3 +351,95 @@ pub fn is_shutdown_requested() -> bool {
         }
     })
 }
+
+/// Lets other threads check if the thread that created it has been requested
+/// to shut down, e.g. the helper threads that do part of its work.
+#[derive(Clone)]
+pub struct ShutdownWatch(Option<Arc<PageServerThread>>);
+
+impl ShutdownWatch {
+    /// Like is_shutdown_requested(), for the thread that created the watch.
+    pub fn is_shutdown_requested(&self) -> bool {
+        self.0.as_ref().map_or(false, |thread| {
+            thread.shutdown_requested.load(Ordering::Relaxed)
+        })
+    }
+}
+
+/// Watch the current thread for shutdown requests, see ShutdownWatch.
+pub fn shutdown_watch() -> ShutdownWatch {
+    ShutdownWatch(CURRENT_THREAD.with(|ct| ct.borrow().as_ref().map(Arc::clone)))
+}
+
+/// Sleep for the given duration, or until the current thread is requested to
+/// shut down, whichever comes first. Returns true if shutdown was requested.
+pub fn sleep_until_shutdown(duration: Duration) -> bool {
+    let thread = CURRENT_THREAD.with(|ct| ct.borrow().as_ref().map(Arc::clone));
+    match thread {
+        Some(thread) => {
+            let guard = thread.shutdown_lock.lock().unwrap();
+            let _ = thread
+                .shutdown_cvar
+                .wait_timeout_while(guard, duration, |_| {
+                    !thread.shutdown_requested.load(Ordering::Relaxed)
+                })
+                .unwrap();
+            thread.shutdown_requested.load(Ordering::Relaxed)
+        }
+        None => {
+            if !cfg!(test) {
+                warn!("sleep_until_shutdown() called in an unexpected thread");
+            }
+            thread::sleep(duration);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    /// A background loop that sleeps between iterations must notice a
+    /// shutdown request right away, and must not run another iteration
+    /// once shutdown_threads() has returned.
+    #[test]
+    fn test_shutdown_wakes_up_sleeping_thread() -> anyhow::Result<()> {
+        let tenant_id = ZTenantId::generate();
+        let iterations = Arc::new(AtomicU64::new(0));
+
+        let iterations_clone = Arc::clone(&iterations);
+        spawn(
+            ThreadKind::Compactor,
+            Some(tenant_id),
+            None,
+            "test sleeping thread",
+            false,
+            move || {
+                loop {
+                    iterations_clone.fetch_add(1, Ordering::Relaxed);
+                    if sleep_until_shutdown(Duration::from_secs(600)) {
+                        break;
+                    }
+                }
+                Ok(())
+            },
+        )?;
+
+        while iterations.load(Ordering::Relaxed) == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let start = Instant::now();
+        shutdown_threads(Some(ThreadKind::Compactor), Some(tenant_id), None);
+        assert!(start.elapsed() < Duration::from_secs(60));
+
+        let iterations_at_shutdown = iterations.load(Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(iterations.load(Ordering::Relaxed), iterations_at_shutdown);
+        assert_eq!(iterations_at_shutdown, 1);
+
+        Ok(())
+    }
+}
@@ -23,6 +23,7 @@ use utils::{
 use crate::{
     config::PageServerConf,
     layered_repository::metadata::TimelineMetadata,
+    rate_limiter,
     repository::{LocalTimelineState, Repository},
     storage_sync::index::RemoteIndex,
     tenant_config::TenantConfOpt,
@@ -212,6 +213,8 @@ pub fn create_repo(
         "cannot create new tenant repo: '{}' directory already exists",
         tenant_id
     );
+    // Don't pick up the metadata of a removed tenant with the same id
+    tenant_mgr::remove_tenant_metadata(conf, tenant_id)?;
 
     // top-level dir may exist if we are creating it through CLI
     crashsafe_dir::create_dir_all(&repo_dir)
@@ -229,6 +232,7 @@ pub fn create_repo(
         tenant_id,
         remote_index,
         conf.remote_storage_config.is_some(),
+        rate_limiter::tenant_io_limiter(conf),
     )))
 }
 
@@ -4,8 +4,8 @@
 //! and if too many files are open globally in the system, least-recently
 //! used ones are closed.
 //!
-//! To track which files have been recently used, we use the clock algorithm
-//! with a 'recently_used' flag on each slot.
+//! Which file to close is decided by an [`EvictionPolicy`], chosen at init.
+//! The default is the clock algorithm, with a 'recently_used' flag on each slot.
 //!
 //! This is similar to PostgreSQL's virtual file descriptor facility in
 //! src/backend/storage/file/fd.c
@@ -17,9 +17,12 @@ use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{RwLock, RwLockWriteGuard};
+use std::sync::{Mutex, RwLock, RwLockWriteGuard};
 
-use metrics::{register_histogram_vec, register_int_gauge_vec, HistogramVec, IntGaugeVec};
+use metrics::{
+    register_histogram_vec, register_int_counter, register_int_gauge_vec, HistogramVec, IntCounter,
+    IntGaugeVec,
+};
 
 // Metrics collected on disk IO operations
 const STORAGE_IO_TIME_BUCKETS: &[f64] = &[
@@ -49,6 +52,13 @@ lazy_static! {
     )
     .expect("failed to define a metric");
 }
+lazy_static! {
+    static ref SLOT_RECYCLE_RACES: IntCounter = register_int_counter!(
+        "pageserver_virtual_file_slot_recycle_races_total",
+        "Number of times a file had to be reopened because its slot was recycled for another file"
+    )
+    .expect("failed to define a metric");
+}
 
 ///
 /// A virtual file descriptor. You can use this just like std::fs::File, but internally
@@ -107,21 +117,31 @@ struct SlotHandle {
 ///
 /// OPEN_FILES starts in uninitialized state, and it's initialized by
 /// the virtual_file::init() function. It must be called exactly once at page
-/// server startup.
+/// server startup. The number of slots in use can later be changed with
+/// resize(). The array is allocated up front with room to grow, so that
+/// file operations never need to lock the whole array.
 static OPEN_FILES: OnceCell<OpenFiles> = OnceCell::new();
 
+/// The slots array is allocated with room for at least this many slots, to
+/// leave room for resize() to grow the cache.
+const MIN_SLOTS_CAPACITY: usize = 16 * 1024;
+
 struct OpenFiles {
-    slots: &'static [Slot],
+    slots: Box<[Slot]>,
 
-    /// clock arm for the clock algorithm
-    next: AtomicUsize,
+    /// Number of slots in use, from the start of 'slots'. The slots after it
+    /// are empty.
+    num_slots: AtomicUsize,
+
+    /// Serializes resize() calls
+    resize_lock: Mutex<()>,
+
+    /// Chooses the slot to reuse when a file needs to be opened
+    policy: Box<dyn EvictionPolicy>,
 }
 
 struct Slot {
     inner: RwLock<SlotInner>,
-
-    /// has this file been used since last clock sweep?
-    recently_used: AtomicBool,
 }
 
 struct SlotInner {
@@ -133,47 +153,115 @@ struct SlotInner {
     file: Option<File>,
 }
 
+///
+/// Decides which file to close, when all the slots are in use and another
+/// file needs to be opened.
+///
+/// The policy is called concurrently from many threads, while the slots are
+/// being used, so it must track the slots' usage with interior mutability.
+///
+pub trait EvictionPolicy: Send + Sync {
+    /// The file in slot 'index' was opened or accessed.
+    fn touch(&self, index: usize);
+
+    /// The file in slot 'index' was closed, and the slot is free.
+    fn forget(&self, index: usize);
+
+    /// Propose a slot to reuse among the first 'num_slots' slots, or None to
+    /// skip a round. If the proposed slot is busy, the caller asks again. After
+    /// a few rounds, the caller stops looking for an idle slot and sets
+    /// 'must_choose': then a slot must be returned, and the caller waits for it
+    /// to become free.
+    fn next_victim(&self, num_slots: usize, must_choose: bool) -> Option<usize>;
+
+    /// Called once before use, with the max number of slots that the policy
+    /// needs to track. Fewer of them may be in use at a time.
+    fn init(&mut self, capacity: usize);
+}
+
+///
+/// The clock (second-chance) algorithm. The clock arm sweeps over the slots,
+/// and reuses the first one that hasn't been used since the previous sweep.
+///
+#[derive(Default)]
+pub struct ClockPolicy {
+    /// has the file in each slot been used since last clock sweep?
+    recently_used: Vec<AtomicBool>,
+
+    /// clock arm for the clock algorithm
+    next: AtomicUsize,
+}
+
+impl EvictionPolicy for ClockPolicy {
+    fn touch(&self, index: usize) {
+        self.recently_used[index].store(true, Ordering::Relaxed);
+    }
+
+    fn forget(&self, index: usize) {
+        self.recently_used[index].store(false, Ordering::Relaxed);
+    }
+
+    fn next_victim(&self, num_slots: usize, must_choose: bool) -> Option<usize> {
+        let next = self.next.fetch_add(1, Ordering::AcqRel) % num_slots;
+
+        // If the recently_used flag on this slot is set, continue the clock
+        // sweep.
+        if must_choose || !self.recently_used[next].swap(false, Ordering::Release) {
+            Some(next)
+        } else {
+            None
+        }
+    }
+
+    fn init(&mut self, capacity: usize) {
+        self.recently_used
+            .resize_with(capacity, || AtomicBool::new(false));
+    }
+}
+
 impl OpenFiles {
     /// Find a slot to use, evicting an existing file descriptor if needed.
     ///
     /// On return, we hold a lock on the slot, and its 'tag' has been updated
-    /// recently_used has been set. It's all ready for reuse.
+    /// and the policy has been told it's in use. It's all ready for reuse.
     fn find_victim_slot(&self) -> (SlotHandle, RwLockWriteGuard<SlotInner>) {
         //
-        // Run the clock algorithm to find a slot to replace.
+        // Ask the eviction policy for a slot to replace.
         //
-        let num_slots = self.slots.len();
         let mut retries = 0;
-        let mut slot;
         let mut slot_guard;
         let index;
         loop {
-            let next = self.next.fetch_add(1, Ordering::AcqRel) % num_slots;
-            slot = &self.slots[next];
-
-            // If the recently_used flag on this slot is set, continue the clock
-            // sweep. Otherwise try to use this slot. If we cannot acquire the
-            // lock, also continue the clock sweep.
+            let num_slots = self.num_slots();
+            // If the policy passes, or we cannot acquire the lock on the slot
+            // it proposed, ask again.
             //
             // We only continue in this manner for a while, though. If we loop
             // through the array twice without finding a victim, just pick the
             // next slot and wait until we can reuse it. This way, we avoid
             // spinning in the extreme case that all the slots are busy with an
             // I/O operation.
-            if retries < num_slots * 2 {
-                if !slot.recently_used.swap(false, Ordering::Release) {
-                    if let Ok(guard) = slot.inner.try_write() {
+            let must_choose = retries >= num_slots * 2;
+            if let Some(next) = self.policy.next_victim(num_slots, must_choose) {
+                let slot = &self.slots[next];
+                let guard = if must_choose {
+                    Some(slot.inner.write().unwrap())
+                } else {
+                    slot.inner.try_write().ok()
+                };
+                // If the cache was shrunk concurrently, the slot might not be
+                // in use anymore. resize() closes the files in such slots
+                // after updating 'num_slots', so it's enough to check it while
+                // holding the lock.
+                if let Some(guard) = guard {
+                    if next < self.num_slots() {
                         slot_guard = guard;
                         index = next;
                         break;
                     }
                 }
-                retries += 1;
-            } else {
-                slot_guard = slot.inner.write().unwrap();
-                index = next;
-                break;
             }
+            retries += 1;
         }
 
         //
@@ -192,7 +280,7 @@ impl OpenFiles {
 
         // Prepare the slot for reuse and return it
         slot_guard.tag += 1;
-        slot.recently_used.store(true, Ordering::Relaxed);
+        self.policy.touch(index);
         (
             SlotHandle {
                 index,
@@ -291,19 +379,19 @@ impl VirtualFile {
             let mut handle = *self.handle.read().unwrap();
             loop {
                 // Check if the slot contains our File
-                {
-                    let slot = &open_files.slots[handle.index];
-                    let slot_guard = slot.inner.read().unwrap();
+                let recycled = {
+                    let slot_guard = open_files.slots[handle.index].inner.read().unwrap();
                     if slot_guard.tag == handle.tag {
                         if let Some(file) = &slot_guard.file {
                             // Found a cached file descriptor.
-                            slot.recently_used.store(true, Ordering::Relaxed);
+                            open_files.policy.touch(handle.index);
                             return Ok(STORAGE_IO_TIME
                                 .with_label_values(&[op, &self.tenantid, &self.timelineid])
                                 .observe_closure_duration(|| func(file)));
                         }
                     }
-                }
+                    slot_guard.tag != handle.tag
+                };
 
                 // The slot didn't contain our File. We will have to open it ourselves,
                 // but before that, grab a write lock on handle in the VirtualFile, so
@@ -316,6 +404,9 @@ impl VirtualFile {
                     handle = *handle_guard;
                     continue;
                 }
+                if recycled {
+                    SLOT_RECYCLE_RACES.inc();
+                }
                 break handle_guard;
             }
         };
@@ -360,10 +451,10 @@ impl Drop for VirtualFile {
 
         // We could check with a read-lock first, to avoid waiting on an
         // unrelated I/O.
-        let slot = &get_open_files().slots[handle.index];
-        let mut slot_guard = slot.inner.write().unwrap();
+        let open_files = get_open_files();
+        let mut slot_guard = open_files.slots[handle.index].inner.write().unwrap();
         if slot_guard.tag == handle.tag {
-            slot.recently_used.store(false, Ordering::Relaxed);
+            open_files.policy.forget(handle.index);
             // Unlike files evicted by replacement algorithm, here
             // we group close time by tenantid/timelineid.
             // At allows to compare number/time of "normal" file closes
@@ -449,33 +540,81 @@ impl FileExt for VirtualFile {
 }
 
 impl OpenFiles {
-    fn new(num_slots: usize) -> OpenFiles {
-        let mut slots = Box::new(Vec::with_capacity(num_slots));
-        for _ in 0..num_slots {
-            let slot = Slot {
-                recently_used: AtomicBool::new(false),
+    fn new(num_slots: usize, mut policy: Box<dyn EvictionPolicy>) -> OpenFiles {
+        let capacity = num_slots.max(MIN_SLOTS_CAPACITY);
+        policy.init(capacity);
+        let slots = (0..capacity)
+            .map(|_| Slot {
                 inner: RwLock::new(SlotInner { tag: 0, file: None }),
-            };
-            slots.push(slot);
+            })
+            .collect();
+        OpenFiles {
+            slots,
+            num_slots: AtomicUsize::new(num_slots),
+            resize_lock: Mutex::new(()),
+            policy,
         }
+    }
 
-        OpenFiles {
-            next: AtomicUsize::new(0),
-            slots: Box::leak(slots),
+    fn num_slots(&self) -> usize {
+        self.num_slots.load(Ordering::Acquire)
+    }
+
+    /// Change the number of slots in use. Files in the surviving slots stay
+    /// open, and their handles remain valid. Files in removed slots are
+    /// closed; the VirtualFiles that owned them will re-open them on next
+    /// access.
+    fn resize(&self, new_num_slots: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            new_num_slots > 0,
+            "virtual file cache must have at least one slot"
+        );
+        anyhow::ensure!(
+            new_num_slots <= self.slots.len(),
+            "virtual file cache cannot grow beyond {} slots",
+            self.slots.len()
+        );
+
+        let _guard = self.resize_lock.lock().unwrap();
+        let old_num_slots = self.num_slots.swap(new_num_slots, Ordering::AcqRel);
+
+        // Close the files in the removed slots. Bump their tags, so that the
+        // handles pointing to them are not valid anymore even if the slots are
+        // taken back into use later.
+        for index in new_num_slots..old_num_slots {
+            let mut slot_guard = self.slots[index].inner.write().unwrap();
+            slot_guard.tag += 1;
+            if let Some(old_file) = slot_guard.file.take() {
+                STORAGE_IO_TIME
+                    .with_label_values(&["close", "-", "-"])
+                    .observe_closure_duration(|| drop(old_file));
+            }
+            self.policy.forget(index);
         }
+        Ok(())
     }
 }
 
 ///
-/// Initialize the virtual file module. This must be called once at page
-/// server startup.
+/// Initialize the virtual file module, with the given eviction policy. This
+/// must be called once at page server startup.
 ///
-pub fn init(num_slots: usize) {
-    if OPEN_FILES.set(OpenFiles::new(num_slots)).is_err() {
+pub fn init(num_slots: usize, policy: Box<dyn EvictionPolicy>) {
+    if OPEN_FILES.set(OpenFiles::new(num_slots, policy)).is_err() {
         panic!("virtual_file::init called twice");
     }
 }
 
+///
+/// Change the number of file descriptors the virtual file module may keep
+/// open, e.g. after the file descriptor limit has been raised. Growing keeps
+/// all currently open files, shrinking closes the files in the removed slots.
+/// The cache can grow up to the larger of its initial size and 16k slots.
+///
+pub fn resize(new_num_slots: usize) -> anyhow::Result<()> {
+    get_open_files().resize(new_num_slots)
+}
+
 const TEST_MAX_FILE_DESCRIPTORS: usize = 10;
 
 // Get a handle to the global slots array.
@@ -489,7 +628,9 @@ fn get_open_files() -> &'static OpenFiles {
     // unit tests.
     //
     if cfg!(test) {
-        OPEN_FILES.get_or_init(|| OpenFiles::new(TEST_MAX_FILE_DESCRIPTORS))
+        OPEN_FILES.get_or_init(|| {
+            OpenFiles::new(TEST_MAX_FILE_DESCRIPTORS, Box::new(ClockPolicy::default()))
+        })
     } else {
         OPEN_FILES.get().expect("virtual_file::init not called yet")
     }
@@ -644,6 +785,36 @@ mod tests {
         Ok(())
     }
 
+    /// When the slot of a file is given to another file, the next access has
+    /// to reopen it, which is counted.
+    #[test]
+    fn test_vfile_slot_recycle_counter() -> Result<(), Error> {
+        let testdir = crate::config::PageServerConf::test_repo_dir("vfile_slot_recycle_counter");
+        std::fs::create_dir_all(&testdir)?;
+        let path = testdir.join("file");
+        std::fs::write(&path, b"foobar")?;
+
+        let mut buf = [0u8; 6];
+        let file = VirtualFile::open(&path)?;
+        file.read_exact_at(&mut buf, 0)?;
+
+        // Open enough other files to recycle every slot, including the one of 'file'.
+        let num_slots = get_open_files().num_slots();
+        let mut others = Vec::new();
+        for _ in 0..num_slots * 2 {
+            let other = VirtualFile::open(&path)?;
+            other.read_exact_at(&mut buf, 0)?;
+            others.push(other);
+        }
+
+        let races_before = SLOT_RECYCLE_RACES.get();
+        file.read_exact_at(&mut buf, 0)?;
+        assert_eq!(&buf, b"foobar");
+        assert!(SLOT_RECYCLE_RACES.get() > races_before);
+
+        Ok(())
+    }
+
     /// Test using VirtualFiles from many threads concurrently. This tests both using
     /// a lot of VirtualFiles concurrently, causing evictions, and also using the same
     /// VirtualFile from multiple threads concurrently.
@@ -699,4 +870,116 @@ mod tests {
 
         Ok(())
     }
+
+    /// Test growing the file descriptor cache at runtime, while files are open,
+    /// and then using more files concurrently than the original size would hold.
+    #[test]
+    fn test_vfile_resize() -> Result<(), Error> {
+        const NEW_MAX_FILE_DESCRIPTORS: usize = TEST_MAX_FILE_DESCRIPTORS * 3;
+        const THREADS: usize = NEW_MAX_FILE_DESCRIPTORS;
+
+        let testdir = crate::config::PageServerConf::test_repo_dir("vfile_resize");
+        std::fs::create_dir_all(&testdir)?;
+
+        for i in 0..THREADS {
+            let path = testdir.join(format!("file_{}", i));
+            let mut file = VirtualFile::create(&path)?;
+            file.write_all(format!("contents of file {}", i).as_bytes())?;
+        }
+
+        // Grow the cache so that all the files fit in it at once.
+        resize(NEW_MAX_FILE_DESCRIPTORS).unwrap();
+        assert!(get_open_files().num_slots() >= NEW_MAX_FILE_DESCRIPTORS);
+
+        let files: Vec<_> = (0..THREADS)
+            .map(|i| VirtualFile::open(&testdir.join(format!("file_{}", i))))
+            .collect::<Result<_, _>>()?;
+        let files = Arc::new(files);
+
+        let mut threads = Vec::new();
+        for threadno in 0..THREADS {
+            let files = files.clone();
+            let thread = thread::Builder::new()
+                .name(format!("test_vfile_resize thread {}", threadno))
+                .spawn(move || {
+                    let expected = format!("contents of file {}", threadno);
+                    for _ in 0..100 {
+                        let mut buf = vec![0u8; expected.len()];
+                        files[threadno].read_exact_at(&mut buf, 0).unwrap();
+                        assert_eq!(buf, expected.as_bytes());
+                    }
+                })
+                .unwrap();
+            threads.push(thread);
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Shrinking closes files, but they can still be used afterwards.
+        resize(TEST_MAX_FILE_DESCRIPTORS).unwrap();
+        for (i, file) in files.iter().enumerate() {
+            let expected = format!("contents of file {}", i);
+            let mut buf = vec![0u8; expected.len()];
+            file.read_exact_at(&mut buf, 0)?;
+            assert_eq!(buf, expected.as_bytes());
+        }
+
+        assert!(resize(0).is_err());
+        assert!(resize(get_open_files().slots.len() + 1).is_err());
+
+        Ok(())
+    }
+
+    /// A policy that always evicts the same slot.
+    struct FixedPolicy {
+        victim: usize,
+        touched: Vec<AtomicBool>,
+    }
+
+    impl EvictionPolicy for FixedPolicy {
+        fn touch(&self, index: usize) {
+            self.touched[index].store(true, Ordering::Relaxed);
+        }
+
+        fn forget(&self, _index: usize) {}
+
+        fn next_victim(&self, _num_slots: usize, _must_choose: bool) -> Option<usize> {
+            Some(self.victim)
+        }
+
+        fn init(&mut self, capacity: usize) {
+            self.touched
+                .resize_with(capacity, || AtomicBool::new(false));
+        }
+    }
+
+    #[test]
+    fn test_eviction_policy() {
+        let open_files = OpenFiles::new(
+            4,
+            Box::new(FixedPolicy {
+                victim: 2,
+                touched: Vec::new(),
+            }),
+        );
+        for _ in 0..3 {
+            let (handle, slot_guard) = open_files.find_victim_slot();
+            assert_eq!(handle.index, 2);
+            assert_eq!(handle.tag, slot_guard.tag);
+        }
+
+        // The clock algorithm skips the recently used slots
+        let mut clock = ClockPolicy::default();
+        clock.init(4);
+        clock.touch(0);
+        clock.touch(1);
+        assert_eq!(clock.next_victim(3, false), None);
+        assert_eq!(clock.next_victim(3, false), None);
+        assert_eq!(clock.next_victim(3, false), Some(2));
+        // The sweep cleared the flags
+        assert_eq!(clock.next_victim(3, false), Some(0));
+        clock.touch(1);
+        assert_eq!(clock.next_victim(3, true), Some(1));
+    }
 }
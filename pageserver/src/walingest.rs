@@ -31,6 +31,7 @@ use bytes::{Buf, Bytes, BytesMut};
 use tracing::*;
 
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
 
 use crate::pgdatadir_mapping::*;
 use crate::reltag::{RelTag, SlruKind};
@@ -42,8 +43,6 @@ use postgres_ffi::TransactionId;
 use postgres_ffi::{pg_constants, CheckPoint};
 use utils::lsn::Lsn;
 
-static ZERO_PAGE: Bytes = Bytes::from_static(&[0u8; 8192]);
-
 pub struct WalIngest<'a, R: Repository> {
     timeline: &'a DatadirTimeline<R>,
 
@@ -141,7 +140,7 @@ impl<'a, R: Repository> WalIngest<'a, R> {
                     SlruKind::Clog,
                     segno,
                     rpageno,
-                    ZERO_PAGE.clone(),
+                    timeline.zero_page(),
                 )?;
             } else {
                 assert!(info == pg_constants::CLOG_TRUNCATE);
@@ -191,7 +190,7 @@ impl<'a, R: Repository> WalIngest<'a, R> {
                     SlruKind::MultiXactOffsets,
                     segno,
                     rpageno,
-                    ZERO_PAGE.clone(),
+                    timeline.zero_page(),
                 )?;
             } else if info == pg_constants::XLOG_MULTIXACT_ZERO_MEM_PAGE {
                 let pageno = buf.get_u32_le();
@@ -202,7 +201,7 @@ impl<'a, R: Repository> WalIngest<'a, R> {
                     SlruKind::MultiXactMembers,
                     segno,
                     rpageno,
-                    ZERO_PAGE.clone(),
+                    timeline.zero_page(),
                 )?;
             } else if info == pg_constants::XLOG_MULTIXACT_CREATE_ID {
                 let xlrec = XlMultiXactCreate::decode(&mut buf);
@@ -228,6 +227,9 @@ impl<'a, R: Repository> WalIngest<'a, R> {
                 let mut checkpoint_bytes = [0u8; SIZEOF_CHECKPOINT];
                 buf.copy_to_slice(&mut checkpoint_bytes);
                 let xlog_checkpoint = CheckPoint::decode(&checkpoint_bytes)?;
+                modification.put_lsn_timestamp(to_pg_timestamp(
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(xlog_checkpoint.time as u64),
+                ))?;
                 trace!(
                     "xlog_checkpoint.oldestXid={}, checkpoint.oldestXid={}",
                     xlog_checkpoint.oldestXid,
@@ -626,6 +628,10 @@ impl<'a, R: Repository> WalIngest<'a, R> {
         parsed: &XlXactParsedRecord,
         is_commit: bool,
     ) -> Result<()> {
+        if is_commit {
+            modification.put_lsn_timestamp(parsed.xact_time)?;
+        }
+
         // Record update of CLOG pages
         let mut pageno = parsed.xid / pg_constants::CLOG_XACTS_PER_PAGE;
         let mut segno = pageno / pg_constants::SLRU_PAGES_PER_SEGMENT;
@@ -976,8 +982,9 @@ impl<'a, R: Repository> WalIngest<'a, R> {
             modification.put_rel_extend(rel, new_nblocks)?;
 
             // fill the gap with zeros
+            let zero_page = modification.tline.zero_page();
             for gap_blknum in old_nblocks..blknum {
-                modification.put_rel_page_image(rel, gap_blknum, ZERO_PAGE.clone())?;
+                modification.put_rel_page_image(rel, gap_blknum, zero_page.clone())?;
             }
             self.relsize_cache.insert(rel, new_nblocks);
         }
@@ -1035,8 +1042,9 @@ impl<'a, R: Repository> WalIngest<'a, R> {
             modification.put_slru_extend(kind, segno, new_nblocks)?;
 
             // fill the gap with zeros
+            let zero_page = modification.tline.zero_page();
             for gap_blknum in old_nblocks..blknum {
-                modification.put_slru_page_image(kind, segno, gap_blknum, ZERO_PAGE.clone())?;
+                modification.put_slru_page_image(kind, segno, gap_blknum, zero_page.clone())?;
             }
         }
         Ok(())
@@ -1081,7 +1089,7 @@ mod tests {
     #[test]
     fn test_relsize() -> Result<()> {
         let repo = RepoHarness::create("test_relsize")?.load();
-        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
         let mut walingest = init_walingest_test(&tline)?;
 
         let mut m = tline.begin_modification(Lsn(0x20));
@@ -1178,7 +1186,7 @@ mod tests {
         assert_eq!(tline.get_rel_size(TESTREL_A, Lsn(0x70))?, 2);
         assert_eq!(
             tline.get_rel_page_at_lsn(TESTREL_A, 0, Lsn(0x70))?,
-            ZERO_PAGE
+            tline.zero_page()
         );
         assert_eq!(
             tline.get_rel_page_at_lsn(TESTREL_A, 1, Lsn(0x70))?,
@@ -1193,7 +1201,7 @@ mod tests {
         for blk in 2..1500 {
             assert_eq!(
                 tline.get_rel_page_at_lsn(TESTREL_A, blk, Lsn(0x80))?,
-                ZERO_PAGE
+                tline.zero_page()
             );
         }
         assert_eq!(
@@ -1209,7 +1217,7 @@ mod tests {
     #[test]
     fn test_drop_extend() -> Result<()> {
         let repo = RepoHarness::create("test_drop_extend")?.load();
-        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
         let mut walingest = init_walingest_test(&tline)?;
 
         let mut m = tline.begin_modification(Lsn(0x20));
@@ -1249,7 +1257,7 @@ mod tests {
     #[test]
     fn test_truncate_extend() -> Result<()> {
         let repo = RepoHarness::create("test_truncate_extend")?.load();
-        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
         let mut walingest = init_walingest_test(&tline)?;
 
         // Create a 20 MB relation (the size is arbitrary)
@@ -1337,7 +1345,7 @@ mod tests {
     #[test]
     fn test_large_rel() -> Result<()> {
         let repo = RepoHarness::create("test_large_rel")?.load();
-        let tline = create_test_timeline(repo, TIMELINE_ID)?;
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
         let mut walingest = init_walingest_test(&tline)?;
 
         let mut lsn = 0x10;
@@ -1397,4 +1405,42 @@ mod tests {
 
         Ok(())
     }
+
+    /// Check that the logical size computed by walking the relation directories
+    /// matches the sum of the sizes of all the relations listed by list_rels().
+    #[test]
+    fn test_logical_size_many_rels() -> Result<()> {
+        let repo = RepoHarness::create("test_logical_size_many_rels")?.load();
+        let tline = create_test_timeline(&repo, TIMELINE_ID)?;
+        init_walingest_test(&tline)?;
+
+        let lsn = Lsn(0x20);
+        let mut m = tline.begin_modification(lsn);
+        for dbnode in [111, 112] {
+            for relnode in 1000..1500 {
+                let rel = RelTag {
+                    spcnode: 0,
+                    dbnode,
+                    relnode,
+                    forknum: 0,
+                };
+                m.put_rel_creation(rel, relnode % 7)?;
+            }
+        }
+        m.commit()?;
+
+        let mut expected: usize = 0;
+        for (spcnode, dbnode) in tline.list_dbdirs(lsn)?.keys() {
+            for rel in tline.list_rels(*spcnode, *dbnode, lsn)? {
+                expected += tline.get_rel_size(rel, lsn)? as usize;
+            }
+        }
+        assert!(expected > 0);
+        assert_eq!(
+            tline.get_current_logical_size_non_incremental(lsn)?,
+            expected * pg_constants::BLCKSZ as usize
+        );
+
+        Ok(())
+    }
 }
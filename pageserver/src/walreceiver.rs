@@ -6,34 +6,42 @@
 //! We keep one WAL receiver active per timeline.
 
 use crate::config::PageServerConf;
+use crate::pgdatadir_mapping::DatadirTimeline;
 use crate::repository::{Repository, Timeline};
+use crate::storage_sync::index::RemoteIndex;
 use crate::tenant_mgr;
 use crate::thread_mgr;
 use crate::thread_mgr::ThreadKind;
 use crate::walingest::WalIngest;
-use anyhow::{bail, Context, Error, Result};
-use bytes::BytesMut;
+use anyhow::{bail, ensure, Context, Error, Result};
+use bytes::{Bytes, BytesMut};
 use fail::fail_point;
 use lazy_static::lazy_static;
+use metrics::{register_int_counter_vec, register_int_gauge, IntCounter, IntCounterVec, IntGauge};
 use postgres_ffi::waldecoder::*;
+use postgres_ffi::ControlFileData;
 use postgres_protocol::message::backend::ReplicationMessage;
 use postgres_types::PgLsn;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use std::cell::Cell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::ControlFlow;
 use std::str::FromStr;
 use std::sync::Mutex;
 use std::thread_local;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::pin;
+use tokio::sync::watch;
 use tokio_postgres::replication::ReplicationStream;
-use tokio_postgres::{Client, NoTls, SimpleQueryMessage, SimpleQueryRow};
+use tokio_postgres::tls::NoTlsStream;
+use tokio_postgres::{Client, Connection, NoTls, SimpleQueryMessage, SimpleQueryRow, Socket};
 use tokio_stream::StreamExt;
 use tracing::*;
 use utils::{
     lsn::Lsn,
     pq_proto::ZenithFeedback,
+    wal_compression::WalCompression,
     zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
@@ -50,11 +58,195 @@ pub struct WalReceiverEntry {
     last_received_msg_lsn: Option<Lsn>,
     /// the timestamp (in microseconds) of the last received message
     last_received_msg_ts: Option<u128>,
+    /// the end of WAL on the WAL producer, as of the last message that told us about it
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    end_of_wal: Option<Lsn>,
+    /// whether we have received all the WAL up to `end_of_wal` at least once
+    caught_up: bool,
+}
+
+impl WalReceiverEntry {
+    fn set_end_of_wal(&mut self, end_of_wal: Lsn) {
+        self.end_of_wal = Some(end_of_wal);
+    }
+
+    /// Note that we have received WAL up to 'lsn'. Returns true if that
+    /// made us catch up with the WAL producer.
+    fn note_received(&mut self, lsn: Lsn) -> bool {
+        match self.end_of_wal {
+            Some(end_of_wal) if !self.caught_up && lsn >= end_of_wal => {
+                self.caught_up = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Is the WAL receiver still receiving messages, as of 'now'?
+    fn ingest_status(&self, now: SystemTime) -> IngestStatus {
+        match self.last_received_msg_ts {
+            Some(ts) => {
+                let last_received = SystemTime::UNIX_EPOCH + Duration::from_micros(ts as u64);
+                match now.duration_since(last_received) {
+                    Ok(age) if age > WAL_RECEIVER_STALL_TIMEOUT => {
+                        IngestStatus::Stalled(last_received)
+                    }
+                    _ => IngestStatus::Active,
+                }
+            }
+            // Still connecting, or waiting for the first message
+            None => IngestStatus::Active,
+        }
+    }
+}
+
+/// A WAL receiver that hasn't received any message for this long is
+/// considered stalled. The WAL producer sends keepalives much more often.
+const WAL_RECEIVER_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether WAL is being streamed into a timeline, see [`is_ingesting`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IngestStatus {
+    /// A WAL receiver is running, and has recently received a message
+    Active,
+    /// A WAL receiver is running, but hasn't received anything since the given time
+    Stalled(SystemTime),
+    /// The WAL receiver has exited, and no new one has been launched
+    Stopped,
+    /// No WAL receiver has run for the timeline since the pageserver started
+    NeverStarted,
+    /// The WAL receiver is waiting for one of the 'max_wal_receivers' slots
+    Queued,
+}
+
+/// Tracks the WAL receivers that count against 'max_wal_receivers', and the
+/// ones waiting for a slot, in launch order.
+#[derive(Default)]
+struct WalReceiverSlots {
+    running: HashSet<(ZTenantId, ZTimelineId)>,
+    /// Timelines waiting for a slot, with their WAL producer connection string
+    queued: VecDeque<((ZTenantId, ZTimelineId), String)>,
+}
+
+impl WalReceiverSlots {
+    /// Take a slot for a new WAL receiver. Returns false if all 'max' slots
+    /// are taken, in which case the receiver is queued, or, if it was queued
+    /// already, its connection string updated. A queued receiver that is
+    /// launched again when a slot is free takes it. 'max' of 0 means no limit.
+    fn acquire(
+        &mut self,
+        max: usize,
+        key: (ZTenantId, ZTimelineId),
+        wal_producer_connstr: &str,
+    ) -> bool {
+        let full = max != 0 && self.running.len() >= max;
+        if let Some(pos) = self.queued.iter().position(|(k, _)| *k == key) {
+            if full {
+                self.queued[pos].1 = wal_producer_connstr.to_string();
+                return false;
+            }
+            self.queued.remove(pos);
+            WAL_RECEIVERS_QUEUED.set(self.queued.len() as i64);
+        } else if full {
+            self.queued
+                .push_back((key, wal_producer_connstr.to_string()));
+            WAL_RECEIVERS_QUEUED.set(self.queued.len() as i64);
+            return false;
+        }
+        self.running.insert(key);
+        true
+    }
+
+    /// Release the slot of a WAL receiver that has exited. Returns the next
+    /// queued receiver that can run in its place, if any; it has its slot
+    /// already. The queued receivers of 'skip_tenant' are passed over, and
+    /// stay queued.
+    fn release(
+        &mut self,
+        max: usize,
+        key: (ZTenantId, ZTimelineId),
+        skip_tenant: Option<ZTenantId>,
+    ) -> Option<((ZTenantId, ZTimelineId), String)> {
+        self.running.remove(&key);
+        if max != 0 && self.running.len() >= max {
+            return None;
+        }
+        let pos = self
+            .queued
+            .iter()
+            .position(|((tenant_id, _), _)| Some(*tenant_id) != skip_tenant)?;
+        let next = self.queued.remove(pos).unwrap();
+        WAL_RECEIVERS_QUEUED.set(self.queued.len() as i64);
+        self.running.insert(next.0);
+        Some(next)
+    }
+
+    /// Drop the queued receivers that match 'matches'
+    fn forget(&mut self, matches: impl Fn(&(ZTenantId, ZTimelineId)) -> bool) {
+        self.queued.retain(|(key, _)| !matches(key));
+        WAL_RECEIVERS_QUEUED.set(self.queued.len() as i64);
+    }
+
+    fn is_queued(&self, key: (ZTenantId, ZTimelineId)) -> bool {
+        self.queued.iter().any(|(k, _)| *k == key)
+    }
 }
 
 lazy_static! {
     static ref WAL_RECEIVERS: Mutex<HashMap<(ZTenantId, ZTimelineId), WalReceiverEntry>> =
         Mutex::new(HashMap::new());
+    static ref RECOVERY_TARGETS: Mutex<HashMap<(ZTenantId, ZTimelineId), Lsn>> =
+        Mutex::new(HashMap::new());
+    /// Timelines whose WAL receiver has exited, to tell them apart from the
+    /// ones that never had one in is_ingesting()
+    static ref STOPPED_WAL_RECEIVERS: Mutex<HashSet<(ZTenantId, ZTimelineId)>> =
+        Mutex::new(HashSet::new());
+    static ref WAL_RECEIVER_SLOTS: Mutex<WalReceiverSlots> =
+        Mutex::new(WalReceiverSlots::default());
+    static ref WAL_RECEIVERS_QUEUED: IntGauge = register_int_gauge!(
+        "pageserver_wal_receivers_queued",
+        "Number of timelines waiting for a WAL receiver slot, see max_wal_receivers"
+    )
+    .expect("failed to define a metric");
+    static ref WAL_RECORDS_INGESTED: IntCounterVec = register_int_counter_vec!(
+        "pageserver_wal_records_ingested_total",
+        "Number of WAL records ingested by the WAL receiver",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+    static ref WAL_BYTES_INGESTED: IntCounterVec = register_int_counter_vec!(
+        "pageserver_wal_bytes_ingested_total",
+        "Bytes of WAL records ingested by the WAL receiver",
+        &["tenant_id", "timeline_id"]
+    )
+    .expect("failed to define a metric");
+}
+
+/// The ingest counters of one WAL receiver
+struct IngestMetrics {
+    records: IntCounter,
+    bytes: IntCounter,
+}
+
+impl IngestMetrics {
+    fn new(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> Self {
+        let labels = [tenant_id.to_string(), timeline_id.to_string()];
+        let labels = [labels[0].as_str(), labels[1].as_str()];
+        IngestMetrics {
+            records: WAL_RECORDS_INGESTED.with_label_values(&labels),
+            bytes: WAL_BYTES_INGESTED.with_label_values(&labels),
+        }
+    }
+
+    /// Drop the timeline's series, so that they don't linger after the WAL
+    /// receiver has exited.
+    fn remove(tenant_id: ZTenantId, timeline_id: ZTimelineId) {
+        let labels = [tenant_id.to_string(), timeline_id.to_string()];
+        let labels = [labels[0].as_str(), labels[1].as_str()];
+        // Fails if nothing was ingested, and there's no series to remove
+        let _ = WAL_RECORDS_INGESTED.remove_label_values(&labels);
+        let _ = WAL_BYTES_INGESTED.remove_label_values(&labels);
+    }
 }
 
 thread_local! {
@@ -67,9 +259,28 @@ thread_local! {
 fn drop_wal_receiver(tenantid: ZTenantId, timelineid: ZTimelineId) {
     let mut receivers = WAL_RECEIVERS.lock().unwrap();
     receivers.remove(&(tenantid, timelineid));
+    STOPPED_WAL_RECEIVERS
+        .lock()
+        .unwrap()
+        .insert((tenantid, timelineid));
 }
 
-// Launch a new WAL receiver, or tell one that's running about change in connection string
+/// Forget the WAL receivers of a detached timeline, or of all the timelines
+/// of a tenant if 'timeline_id' is None, including the queued ones. Their
+/// threads must have been shut down already.
+pub fn forget_wal_receivers(tenant_id: ZTenantId, timeline_id: Option<ZTimelineId>) {
+    let matches = |(t, tl): &(ZTenantId, ZTimelineId)| {
+        *t == tenant_id && timeline_id.map_or(true, |timeline_id| *tl == timeline_id)
+    };
+    STOPPED_WAL_RECEIVERS
+        .lock()
+        .unwrap()
+        .retain(|key| !matches(key));
+    WAL_RECEIVER_SLOTS.lock().unwrap().forget(matches);
+}
+
+// Launch a new WAL receiver, or tell one that's running about change in connection string.
+// If 'max_wal_receivers' are running already, the new one is queued.
 pub fn launch_wal_receiver(
     conf: &'static PageServerConf,
     tenantid: ZTenantId,
@@ -84,26 +295,29 @@ pub fn launch_wal_receiver(
             receiver.wal_producer_connstr = wal_producer_connstr.into();
         }
         None => {
-            let thread_id = thread_mgr::spawn(
-                ThreadKind::WalReceiver,
-                Some(tenantid),
-                Some(timelineid),
-                "WAL receiver thread",
-                false,
-                move || {
-                    IS_WAL_RECEIVER.with(|c| c.set(true));
-                    thread_main(conf, tenantid, timelineid);
-                    Ok(())
-                },
-            )?;
-
-            let receiver = WalReceiverEntry {
-                thread_id,
-                wal_producer_connstr: wal_producer_connstr.into(),
-                last_received_msg_lsn: None,
-                last_received_msg_ts: None,
-            };
-            receivers.insert((tenantid, timelineid), receiver);
+            let mut slots = WAL_RECEIVER_SLOTS.lock().unwrap();
+            if !slots.acquire(
+                conf.max_wal_receivers,
+                (tenantid, timelineid),
+                wal_producer_connstr,
+            ) {
+                info!(
+                    "max_wal_receivers ({}) reached, queued WAL receiver for tenant {} timeline {}",
+                    conf.max_wal_receivers, tenantid, timelineid
+                );
+                return Ok(());
+            }
+            if let Err(e) = spawn_wal_receiver(
+                &mut receivers,
+                conf,
+                tenantid,
+                timelineid,
+                wal_producer_connstr,
+            ) {
+                slots.running.remove(&(tenantid, timelineid));
+                return Err(e);
+            }
+            drop(slots);
 
             // Update tenant state and start tenant threads, if they are not running yet.
             tenant_mgr::activate_tenant(tenantid)?;
@@ -112,6 +326,84 @@ pub fn launch_wal_receiver(
     Ok(())
 }
 
+/// Start the WAL receiver thread, and register it in `WAL_RECEIVERS`. The
+/// caller must have taken a slot for it in `WAL_RECEIVER_SLOTS`.
+fn spawn_wal_receiver(
+    receivers: &mut HashMap<(ZTenantId, ZTimelineId), WalReceiverEntry>,
+    conf: &'static PageServerConf,
+    tenantid: ZTenantId,
+    timelineid: ZTimelineId,
+    wal_producer_connstr: &str,
+) -> Result<()> {
+    let thread_id = thread_mgr::spawn(
+        ThreadKind::WalReceiver,
+        Some(tenantid),
+        Some(timelineid),
+        "WAL receiver thread",
+        false,
+        move || {
+            IS_WAL_RECEIVER.with(|c| c.set(true));
+            thread_main(conf, tenantid, timelineid);
+            Ok(())
+        },
+    )?;
+
+    let receiver = WalReceiverEntry {
+        thread_id,
+        wal_producer_connstr: wal_producer_connstr.into(),
+        last_received_msg_lsn: None,
+        last_received_msg_ts: None,
+        end_of_wal: None,
+        caught_up: false,
+    };
+    receivers.insert((tenantid, timelineid), receiver);
+    STOPPED_WAL_RECEIVERS
+        .lock()
+        .unwrap()
+        .remove(&(tenantid, timelineid));
+    Ok(())
+}
+
+/// Give up the slot of a WAL receiver that has exited, and launch the queued
+/// ones that can run now.
+fn release_wal_receiver_slot(
+    conf: &'static PageServerConf,
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+) {
+    let mut receivers = WAL_RECEIVERS.lock().unwrap();
+    let mut slots = WAL_RECEIVER_SLOTS.lock().unwrap();
+    let mut exited = (tenant_id, timeline_id);
+    // If the receiver was shut down with its tenant or timeline, don't start
+    // more receivers for that tenant. The others still get the slot.
+    let skip_tenant = if thread_mgr::is_shutdown_requested() {
+        Some(tenant_id)
+    } else {
+        None
+    };
+    while let Some((next, connstr)) = slots.release(conf.max_wal_receivers, exited, skip_tenant) {
+        info!(
+            "launching queued WAL receiver for tenant {} timeline {}",
+            next.0, next.1
+        );
+        match spawn_wal_receiver(&mut receivers, conf, next.0, next.1, &connstr) {
+            Ok(()) => {
+                if let Err(e) = tenant_mgr::activate_tenant(next.0) {
+                    warn!("could not activate tenant {}: {:#}", next.0, e);
+                }
+                break;
+            }
+            Err(e) => {
+                error!(
+                    "could not launch queued WAL receiver for tenant {} timeline {}: {:#}",
+                    next.0, next.1, e
+                );
+                exited = next;
+            }
+        }
+    }
+}
+
 /// Look up a WAL receiver's data in the global `WAL_RECEIVERS`
 pub fn get_wal_receiver_entry(
     tenant_id: ZTenantId,
@@ -121,6 +413,82 @@ pub fn get_wal_receiver_entry(
     receivers.get(&(tenant_id, timeline_id)).cloned()
 }
 
+/// Is WAL currently being streamed into the timeline? Unlike
+/// get_wal_receiver_entry(), this tells apart a WAL receiver that has
+/// stopped from one that never ran, and one that is running but not
+/// receiving anything.
+pub fn is_ingesting(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> IngestStatus {
+    let receivers = WAL_RECEIVERS.lock().unwrap();
+    match receivers.get(&(tenant_id, timeline_id)) {
+        Some(entry) => entry.ingest_status(SystemTime::now()),
+        None => {
+            if WAL_RECEIVER_SLOTS
+                .lock()
+                .unwrap()
+                .is_queued((tenant_id, timeline_id))
+            {
+                return IngestStatus::Queued;
+            }
+            let stopped = STOPPED_WAL_RECEIVERS.lock().unwrap();
+            if stopped.contains(&(tenant_id, timeline_id)) {
+                IngestStatus::Stopped
+            } else {
+                IngestStatus::NeverStarted
+            }
+        }
+    }
+}
+
+/// Snapshot of all the WAL receivers in the global `WAL_RECEIVERS`
+pub fn list_wal_receivers() -> Vec<((ZTenantId, ZTimelineId), WalReceiverEntry)> {
+    let receivers = WAL_RECEIVERS.lock().unwrap();
+    receivers
+        .iter()
+        .map(|(key, entry)| (*key, entry.clone()))
+        .collect()
+}
+
+///
+/// Set or clear the LSN at which WAL ingestion into the timeline stops, for
+/// recovery to a specific point in time. Once the WAL receiver has ingested
+/// the record ending at the target, it stops streaming and leaves
+/// last_record_lsn there. The target should be at a record boundary; if it
+/// isn't, ingestion stops at the last record that ends before it.
+///
+pub fn set_recovery_target_lsn(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    recovery_target_lsn: Option<Lsn>,
+) {
+    let mut targets = RECOVERY_TARGETS.lock().unwrap();
+    match recovery_target_lsn {
+        Some(lsn) => targets.insert((tenant_id, timeline_id), lsn),
+        None => targets.remove(&(tenant_id, timeline_id)),
+    };
+}
+
+pub fn get_recovery_target_lsn(tenant_id: ZTenantId, timeline_id: ZTimelineId) -> Option<Lsn> {
+    let targets = RECOVERY_TARGETS.lock().unwrap();
+    targets.get(&(tenant_id, timeline_id)).copied()
+}
+
+/// Update a WAL receiver's data in the global `WAL_RECEIVERS`
+fn update_wal_receiver_entry<T>(
+    tenant_id: ZTenantId,
+    timeline_id: ZTimelineId,
+    f: impl FnOnce(&mut WalReceiverEntry) -> T,
+) -> Result<T> {
+    let mut receivers = WAL_RECEIVERS.lock().unwrap();
+    match receivers.get_mut(&(tenant_id, timeline_id)) {
+        Some(entry) => Ok(f(entry)),
+        None => bail!(
+            "no WAL receiver entry found for tenant {} and timeline {}",
+            tenant_id,
+            timeline_id
+        ),
+    }
+}
+
 //
 // This is the entry point for the WAL receiver thread.
 //
@@ -149,6 +517,11 @@ fn thread_main(conf: &'static PageServerConf, tenant_id: ZTenantId, timeline_id:
     // TODO cleanup info messages
     if let Err(e) = res {
         info!("WAL streaming connection failed ({})", e);
+
+        // Don't keep the readers waiting for WAL until they time out
+        if let Ok(timeline) = tenant_mgr::get_local_timeline_with_load(tenant_id, timeline_id) {
+            timeline.tline.cancel_lsn_waits();
+        }
     } else {
         info!(
             "walreceiver disconnected tenant {}, timelineid {}",
@@ -156,30 +529,43 @@ fn thread_main(conf: &'static PageServerConf, tenant_id: ZTenantId, timeline_id:
         );
     }
 
+    IngestMetrics::remove(tenant_id, timeline_id);
+
     // Drop it from list of active WAL_RECEIVERS
     // so that next callmemaybe request launched a new thread
     drop_wal_receiver(tenant_id, timeline_id);
+    release_wal_receiver_slot(conf, tenant_id, timeline_id);
+}
+
+/// Connection string for streaming WAL from 'wal_producer_connstr', with an
+/// 'application_name' that identifies this pageserver and tenant.
+fn wal_receiver_connect_config(
+    conf: &PageServerConf,
+    tenant_id: ZTenantId,
+    wal_producer_connstr: &str,
+) -> String {
+    format!(
+        "{} application_name={}_{}_{} replication=true",
+        wal_producer_connstr, conf.wal_receiver_application_name, conf.id, tenant_id
+    )
 }
 
 fn walreceiver_main(
-    _conf: &PageServerConf,
+    conf: &PageServerConf,
     tenant_id: ZTenantId,
     timeline_id: ZTimelineId,
     wal_producer_connstr: &str,
 ) -> anyhow::Result<(), Error> {
     // Connect to the database in replication mode.
     info!("connecting to {:?}", wal_producer_connstr);
-    let connect_cfg = format!(
-        "{} application_name=pageserver replication=true",
-        wal_producer_connstr
-    );
+    let connect_cfg = wal_receiver_connect_config(conf, tenant_id, wal_producer_connstr);
 
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
 
     let (mut replication_client, connection) =
-        runtime.block_on(tokio_postgres::connect(&connect_cfg, NoTls))?;
+        runtime.block_on(connect_with_timeout(&connect_cfg, conf.wal_connect_timeout))?;
     // This is from tokio-postgres docs, but it is a bit weird in our case because we extensively use block_on
     runtime.spawn(async move {
         if let Err(e) = connection.await {
@@ -201,7 +587,9 @@ fn walreceiver_main(
     let identify = runtime.block_on(identify_system(&mut replication_client))?;
     info!("{:?}", identify);
     let end_of_wal = Lsn::from(u64::from(identify.xlogpos));
-    let mut caught_up = false;
+    update_wal_receiver_entry(tenant_id, timeline_id, |entry| {
+        entry.set_end_of_wal(end_of_wal)
+    })?;
 
     let repo = tenant_mgr::get_repository_for_tenant(tenant_id)
         .with_context(|| format!("no repository found for tenant {}", tenant_id))?;
@@ -212,7 +600,13 @@ fn walreceiver_main(
                 timeline_id, tenant_id
             )
         })?;
-    let remote_index = repo.get_remote_index();
+    let mut remote_consistent_lsn = RemoteConsistentLsn::new(
+        repo.get_remote_index(),
+        ZTenantTimelineId {
+            tenant_id,
+            timeline_id,
+        },
+    );
 
     //
     // Start streaming the WAL, from where we left off previously.
@@ -226,6 +620,18 @@ fn walreceiver_main(
         bail!("No previous WAL position");
     }
 
+    check_page_size(&*timeline, last_rec_lsn)?;
+
+    if let Some(target) = get_recovery_target_lsn(tenant_id, timeline_id) {
+        if last_rec_lsn >= target {
+            info!(
+                "already at recovery target {}, last_record_lsn {}",
+                target, last_rec_lsn
+            );
+            return Ok(());
+        }
+    }
+
     // There might be some padding after the last full record, skip it.
     startpoint += startpoint.calc_padding(8u32);
 
@@ -234,7 +640,19 @@ fn walreceiver_main(
         last_rec_lsn, startpoint, end_of_wal
     );
 
-    let query = format!("START_REPLICATION PHYSICAL {}", startpoint);
+    // Ask for compressed WAL, if the WAL producer supports it.
+    let compression = identify
+        .wal_compression
+        .as_deref()
+        .and_then(WalCompression::negotiate);
+    let query = match compression {
+        Some(compression) => format!(
+            "START_REPLICATION PHYSICAL {} COMPRESSION {}",
+            startpoint, compression
+        ),
+        None => format!("START_REPLICATION PHYSICAL {}", startpoint),
+    };
+    info!("streaming WAL with compression {:?}", compression);
 
     let copy_stream = runtime.block_on(replication_client.copy_both_simple(&query))?;
     let physical_stream = ReplicationStream::new(copy_stream);
@@ -243,6 +661,8 @@ fn walreceiver_main(
     let mut waldecoder = WalStreamDecoder::new(startpoint);
 
     let mut walingest = WalIngest::new(&*timeline, startpoint)?;
+    let ingest_metrics = IngestMetrics::new(tenant_id, timeline_id);
+    let mut target_reached = false;
 
     while let Some(replication_message) = runtime.block_on(async {
         let shutdown_watcher = thread_mgr::shutdown_watcher();
@@ -259,39 +679,37 @@ fn walreceiver_main(
         let replication_message = replication_message?;
         let status_update = match replication_message {
             ReplicationMessage::XLogData(xlog_data) => {
-                // Pass the WAL data to the decoder, and see if we can decode
-                // more records as a result.
-                let data = xlog_data.data();
+                let data = decompress_xlog_data(xlog_data.data().clone(), compression)?;
                 let startlsn = Lsn::from(xlog_data.wal_start());
-                let endlsn = startlsn + data.len() as u64;
-
-                trace!("received XLogData between {} and {}", startlsn, endlsn);
-
-                waldecoder.feed_bytes(data);
-
-                while let Some((lsn, recdata)) = waldecoder.poll_decode()? {
-                    let _enter = info_span!("processing record", lsn = %lsn).entered();
+                let recovery_target_lsn = get_recovery_target_lsn(tenant_id, timeline_id);
 
-                    // It is important to deal with the aligned records as lsn in getPage@LSN is
-                    // aligned and can be several bytes bigger. Without this alignment we are
-                    // at risk of hitting a deadlock.
-                    anyhow::ensure!(lsn.is_aligned());
-
-                    walingest.ingest_record(&timeline, recdata, lsn)?;
+                match ingest_xlog_data(
+                    &mut waldecoder,
+                    &mut walingest,
+                    &timeline,
+                    &data,
+                    startlsn,
+                    recovery_target_lsn,
+                    &mut last_rec_lsn,
+                    &ingest_metrics,
+                )? {
+                    ControlFlow::Break(last_lsn) => {
+                        target_reached = true;
+                        Some(last_lsn)
+                    }
+                    ControlFlow::Continue(None) => None,
+                    ControlFlow::Continue(Some(endlsn)) => {
+                        if update_wal_receiver_entry(tenant_id, timeline_id, |entry| {
+                            entry.note_received(endlsn)
+                        })? {
+                            info!("caught up at LSN {}", endlsn);
+                        }
 
-                    fail_point!("walreceiver-after-ingest");
+                        timeline.tline.check_checkpoint_distance()?;
 
-                    last_rec_lsn = lsn;
-                }
-
-                if !caught_up && endlsn >= end_of_wal {
-                    info!("caught up at LSN {}", endlsn);
-                    caught_up = true;
+                        Some(endlsn)
+                    }
                 }
-
-                timeline.tline.check_checkpoint_distance()?;
-
-                Some(endlsn)
             }
 
             ReplicationMessage::PrimaryKeepAlive(keepalive) => {
@@ -306,6 +724,10 @@ fn walreceiver_main(
                     reply_requested,
                 );
 
+                update_wal_receiver_entry(tenant_id, timeline_id, |entry| {
+                    entry.set_end_of_wal(Lsn::from(wal_end))
+                })?;
+
                 if reply_requested {
                     Some(last_rec_lsn)
                 } else {
@@ -317,19 +739,7 @@ fn walreceiver_main(
         };
 
         if let Some(last_lsn) = status_update {
-            let timeline_remote_consistent_lsn = runtime.block_on(async {
-                remote_index
-                    .read()
-                    .await
-                    // here we either do not have this timeline in remote index
-                    // or there were no checkpoints for it yet
-                    .timeline_entry(&ZTenantTimelineId {
-                        tenant_id,
-                        timeline_id,
-                    })
-                    .map(|remote_timeline| remote_timeline.metadata.disk_consistent_lsn())
-                    .unwrap_or(Lsn(0)) // no checkpoint was uploaded
-            });
+            let timeline_remote_consistent_lsn = runtime.block_on(remote_consistent_lsn.get());
 
             // The last LSN we processed. It is not guaranteed to survive pageserver crash.
             let write_lsn = u64::from(last_lsn);
@@ -341,26 +751,14 @@ fn walreceiver_main(
             let ts = SystemTime::now();
 
             // Update the current WAL receiver's data stored inside the global hash table `WAL_RECEIVERS`
-            {
-                let mut receivers = WAL_RECEIVERS.lock().unwrap();
-                let entry = match receivers.get_mut(&(tenant_id, timeline_id)) {
-                    Some(e) => e,
-                    None => {
-                        anyhow::bail!(
-                            "no WAL receiver entry found for tenant {} and timeline {}",
-                            tenant_id,
-                            timeline_id
-                        );
-                    }
-                };
-
+            update_wal_receiver_entry(tenant_id, timeline_id, |entry| {
                 entry.last_received_msg_lsn = Some(last_lsn);
                 entry.last_received_msg_ts = Some(
                     ts.duration_since(SystemTime::UNIX_EPOCH)
                         .expect("Received message time should be before UNIX EPOCH!")
                         .as_micros(),
                 );
-            }
+            })?;
 
             // Send zenith feedback message.
             // Regular standby_status_update fields are put into this message.
@@ -370,6 +768,8 @@ fn walreceiver_main(
                 ps_flushlsn: flush_lsn,
                 ps_applylsn: apply_lsn,
                 ps_replytime: ts,
+                // Only safekeepers set it, for the proposer
+                backpressure_lag: 0,
             };
 
             debug!("zenith_status_update {:?}", zenith_status_update);
@@ -382,11 +782,265 @@ fn walreceiver_main(
                     .zenith_status_update(data.len() as u64, &data),
             )?;
         }
+
+        if target_reached {
+            info!("stopping WAL streaming at recovery target");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Failure to connect to the WAL producer.
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    /// The WAL producer didn't complete the connection within the connect
+    /// timeout. It may be down or unreachable, try again after a while.
+    #[error("timed out connecting to WAL producer after {0:?}")]
+    Connection(Duration),
+    #[error(transparent)]
+    Postgres(#[from] tokio_postgres::Error),
+}
+
+/// Connect to the WAL producer, giving up after 'timeout'. Without a timeout,
+/// an unreachable address would leave the WAL receiver hanging in connect
+/// until the OS gives up, which can take minutes.
+async fn connect_with_timeout(
+    connect_cfg: &str,
+    timeout: Duration,
+) -> Result<(Client, Connection<Socket, NoTlsStream>), ConnectError> {
+    match tokio::time::timeout(timeout, tokio_postgres::connect(connect_cfg, NoTls)).await {
+        Ok(res) => Ok(res?),
+        Err(_) => Err(ConnectError::Connection(timeout)),
+    }
+}
+
+/// Get the WAL carried in an XLogData message. With compression, each
+/// message is compressed on its own.
+fn decompress_xlog_data(data: Bytes, compression: Option<WalCompression>) -> Result<Bytes> {
+    match compression {
+        Some(compression) => Ok(Bytes::from(compression.decompress(&data)?)),
+        None => Ok(data),
+    }
+}
+
+///
+/// Ingest a decoded WAL record, unless the timeline already contains it.
+///
+/// The safekeeper can send us WAL that we have already processed, e.g. when
+/// the stream restarts at an earlier point after a reconnect. Applying such a
+/// record a second time would roll back changes made by the records after it,
+/// so skip it. Returns false if the record was skipped.
+///
+fn ingest_new_record<R: Repository>(
+    walingest: &mut WalIngest<'_, R>,
+    timeline: &DatadirTimeline<R>,
+    recdata: Bytes,
+    lsn: Lsn,
+) -> Result<bool> {
+    let last_record_lsn = timeline.get_last_record_lsn();
+    if lsn <= last_record_lsn {
+        debug!(
+            "skipping WAL record at {}, already ingested up to {}",
+            lsn, last_record_lsn
+        );
+        return Ok(false);
+    }
+
+    walingest.ingest_record(timeline, recdata, lsn)?;
+    Ok(true)
+}
+
+///
+/// Pass the WAL in one XLogData message to the decoder, and ingest the records
+/// that can be decoded as a result. Returns the LSN to report back to the WAL
+/// producer, or `ControlFlow::Break` with the last ingested LSN, if the
+/// recovery target was reached.
+///
+fn ingest_xlog_data<R: Repository>(
+    waldecoder: &mut WalStreamDecoder,
+    walingest: &mut WalIngest<'_, R>,
+    timeline: &DatadirTimeline<R>,
+    data: &[u8],
+    startlsn: Lsn,
+    recovery_target_lsn: Option<Lsn>,
+    last_rec_lsn: &mut Lsn,
+    metrics: &IngestMetrics,
+) -> Result<ControlFlow<Lsn, Option<Lsn>>> {
+    // An empty message carries no WAL, so there's nothing to ingest, and
+    // nothing new to report either. Feedback without new WAL is only sent
+    // when a keepalive asks for it.
+    if data.is_empty() {
+        trace!("received empty XLogData at {}", startlsn);
+        return Ok(ControlFlow::Continue(None));
+    }
+
+    let endlsn = startlsn + data.len() as u64;
+    trace!("received XLogData between {} and {}", startlsn, endlsn);
+
+    waldecoder.feed_bytes(data);
+
+    while let Some((lsn, recdata)) = waldecoder
+        .poll_decode()
+        .map_err(|e| decode_error_with_context(e, data, startlsn))?
+    {
+        let _enter = info_span!("processing record", lsn = %lsn).entered();
+
+        // It is important to deal with the aligned records as lsn in getPage@LSN is
+        // aligned and can be several bytes bigger. Without this alignment we are
+        // at risk of hitting a deadlock.
+        anyhow::ensure!(lsn.is_aligned());
+
+        let rec_len = recdata.len();
+        let prev_rec_lsn = *last_rec_lsn;
+        let flow = ingest_up_to_target(
+            walingest,
+            timeline,
+            recdata,
+            lsn,
+            recovery_target_lsn,
+            last_rec_lsn,
+        )?;
+        // Skipped records don't count
+        if *last_rec_lsn != prev_rec_lsn {
+            metrics.records.inc();
+            metrics.bytes.inc_by(rec_len as u64);
+        }
+        if flow.is_break() {
+            // Report what we have ingested, not what we received.
+            return Ok(ControlFlow::Break(*last_rec_lsn));
+        }
+    }
+
+    Ok(ControlFlow::Continue(Some(endlsn)))
+}
+
+///
+/// Ingest a decoded WAL record, honoring the recovery target, if any. A
+/// record that ends past the target is not ingested. Returns
+/// `ControlFlow::Break` once the target has been reached, and the WAL
+/// receiver should stop.
+///
+fn ingest_up_to_target<R: Repository>(
+    walingest: &mut WalIngest<'_, R>,
+    timeline: &DatadirTimeline<R>,
+    recdata: Bytes,
+    lsn: Lsn,
+    recovery_target_lsn: Option<Lsn>,
+    last_rec_lsn: &mut Lsn,
+) -> Result<ControlFlow<()>> {
+    if let Some(target) = recovery_target_lsn {
+        if lsn > target {
+            info!(
+                "WAL record at {} ends past recovery target {}, stopping at {}",
+                lsn, target, last_rec_lsn
+            );
+            return Ok(ControlFlow::Break(()));
+        }
     }
 
+    if ingest_new_record(walingest, timeline, recdata, lsn)? {
+        fail_point!("walreceiver-after-ingest");
+
+        *last_rec_lsn = lsn;
+    }
+
+    if recovery_target_lsn == Some(lsn) {
+        info!("reached recovery target {}", lsn);
+        return Ok(ControlFlow::Break(()));
+    }
+    Ok(ControlFlow::Continue(()))
+}
+
+/// Refuse to stream WAL from a Postgres compiled with a different BLCKSZ
+/// than the timeline's page size: the page images in that WAL would not fit.
+fn check_page_size<R: Repository>(timeline: &DatadirTimeline<R>, lsn: Lsn) -> Result<()> {
+    let control_file = ControlFileData::decode(&timeline.get_control_file(lsn)?)?;
+    ensure!(
+        control_file.blcksz as usize == timeline.get_page_size(),
+        "timeline was initialized with a block size of {} bytes, but the pageserver uses {} byte pages",
+        control_file.blcksz,
+        timeline.get_page_size()
+    );
     Ok(())
 }
 
+/// How often to look up the remote consistent LSN in the remote index, in case the upload notification got missed.
+const REMOTE_INDEX_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tracks the last LSN uploaded to the remote storage, reported to the safekeepers as `apply_lsn`.
+///
+/// The upload path notifies about every new remote `disk_consistent_lsn` via a watch channel,
+/// so there's no need to lock the remote index on every status update.
+/// The index is still consulted periodically, to pick up the state loaded on startup.
+struct RemoteConsistentLsn {
+    remote_index: RemoteIndex,
+    sync_id: ZTenantTimelineId,
+    watch: watch::Receiver<Lsn>,
+    lsn: Lsn,
+    last_index_refresh: Option<Instant>,
+}
+
+impl RemoteConsistentLsn {
+    fn new(remote_index: RemoteIndex, sync_id: ZTenantTimelineId) -> Self {
+        let watch = remote_index.watch_remote_consistent_lsn(sync_id);
+        Self {
+            remote_index,
+            sync_id,
+            watch,
+            lsn: Lsn(0),
+            last_index_refresh: None,
+        }
+    }
+
+    async fn get(&mut self) -> Lsn {
+        self.lsn = self.lsn.max(*self.watch.borrow());
+
+        let refresh_due = self.last_index_refresh.map_or(true, |refreshed| {
+            refreshed.elapsed() >= REMOTE_INDEX_REFRESH_INTERVAL
+        });
+        if refresh_due {
+            let index_lsn = self
+                .remote_index
+                .read()
+                .await
+                // here we either do not have this timeline in remote index
+                // or there were no checkpoints for it yet
+                .timeline_entry(&self.sync_id)
+                .map(|remote_timeline| remote_timeline.metadata.disk_consistent_lsn())
+                .unwrap_or(Lsn(0)); // no checkpoint was uploaded
+            self.lsn = self.lsn.max(index_lsn);
+            self.last_index_refresh = Some(Instant::now());
+        }
+
+        self.lsn
+    }
+}
+
+/// Turn a WAL decoding error into one that also tells which XLogData message
+/// was being decoded, and where in it the decoder choked. The decoder may
+/// have failed on data received in an earlier message, if the record started
+/// there.
+fn decode_error_with_context(err: WalDecodeError, data: &[u8], startlsn: Lsn) -> Error {
+    let endlsn = startlsn + data.len() as u64;
+    let context = if err.lsn() >= startlsn && err.lsn() < endlsn {
+        let offset = (err.lsn().0 - startlsn.0) as usize;
+        let snippet = &data[offset..std::cmp::min(offset + 64, data.len())];
+        debug!("WAL bytes at {}: {}", err.lsn(), hex::encode(snippet));
+        format!(
+            "failed to decode WAL at byte offset {} of XLogData between {} and {}",
+            offset, startlsn, endlsn
+        )
+    } else {
+        format!(
+            "failed to decode WAL, while processing XLogData between {} and {}",
+            startlsn, endlsn
+        )
+    };
+    Error::new(err).context(context)
+}
+
 /// Data returned from the postgres `IDENTIFY_SYSTEM` command
 ///
 /// See the [postgres docs] for more details.
@@ -401,6 +1055,9 @@ pub struct IdentifySystem {
     timeline: u32,
     xlogpos: PgLsn,
     dbname: Option<String>,
+    /// WAL compression methods supported by the server. Only safekeepers
+    /// report this, in an extra column.
+    wal_compression: Option<String>,
 }
 
 /// There was a problem parsing the response to
@@ -431,8 +1088,773 @@ pub async fn identify_system(client: &mut Client) -> Result<IdentifySystem, Erro
             timeline: get_parse(first_row, 1)?,
             xlogpos: get_parse(first_row, 2)?,
             dbname: get_parse(first_row, 3).ok(),
+            wal_compression: first_row.try_get(4).ok().flatten().map(|s| s.to_string()),
         })
     } else {
         Err(IdentifyError.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layered_repository::metadata::lsn_timestamps_path;
+    use crate::pgdatadir_mapping::{create_test_timeline, LsnForTimestamp};
+    use crate::repository::repo_harness::*;
+    use crate::repository::CheckpointConfig;
+    use crate::DatadirTimelineImpl;
+    use bytes::BufMut;
+    use postgres_ffi::pg_constants;
+    use postgres_ffi::xlog_utils::{to_pg_timestamp, SIZEOF_CHECKPOINT, XLOG_SIZE_OF_XLOG_RECORD};
+    use postgres_ffi::CheckPoint;
+    use std::sync::Arc;
+    use utils::zid::NodeId;
+
+    /// Build a WAL record with the given resource manager, info bits, XID and
+    /// main data, and no block references.
+    fn test_record(rmid: u8, info: u8, xid: u32, main_data: &[u8]) -> Bytes {
+        let mut rec = BytesMut::new();
+        // XLogRecord header
+        rec.put_u32_le((XLOG_SIZE_OF_XLOG_RECORD + 2 + main_data.len()) as u32); // xl_tot_len
+        rec.put_u32_le(xid); // xl_xid
+        rec.put_u64_le(0); // xl_prev
+        rec.put_u8(info); // xl_info
+        rec.put_u8(rmid); // xl_rmid
+        rec.put_u16_le(0); // padding
+        rec.put_u32_le(0); // xl_crc, not checked when decoding a single record
+        assert_eq!(rec.len(), XLOG_SIZE_OF_XLOG_RECORD);
+
+        rec.put_u8(pg_constants::XLR_BLOCK_ID_DATA_SHORT);
+        rec.put_u8(main_data.len() as u8);
+        rec.put_slice(main_data);
+        rec.freeze()
+    }
+
+    /// Create a test timeline with an all-zeros checkpoint at 0x10, ready to
+    /// ingest WAL from there.
+    fn test_timeline(harness: &RepoHarness) -> Result<Arc<DatadirTimelineImpl>> {
+        let tline = create_test_timeline(&harness.load(), TIMELINE_ID)?;
+        let mut m = tline.begin_modification(Lsn(0x10));
+        m.put_checkpoint(Bytes::from_static(&[0u8; SIZEOF_CHECKPOINT]))?;
+        m.commit()?;
+        Ok(tline)
+    }
+
+    /// A WAL receiver entry that hasn't received anything from 'connstr' yet.
+    fn test_entry(connstr: &str) -> WalReceiverEntry {
+        WalReceiverEntry {
+            thread_id: 1,
+            wal_producer_connstr: connstr.to_string(),
+            last_received_msg_lsn: None,
+            last_received_msg_ts: None,
+            end_of_wal: None,
+            caught_up: false,
+        }
+    }
+
+    /// Ingest the given records into a new timeline, and return the checkpoint
+    /// that the timeline ends up with.
+    fn ingest_stream(test_name: &'static str, records: &[(Lsn, Bytes)]) -> Result<CheckPoint> {
+        let tline = test_timeline(&RepoHarness::create(test_name)?)?;
+
+        let mut walingest = WalIngest::new(&tline, Lsn(0x10))?;
+        for (lsn, recdata) in records {
+            ingest_new_record(&mut walingest, &tline, recdata.clone(), *lsn)?;
+        }
+
+        let last_lsn = tline.get_last_record_lsn();
+        Ok(CheckPoint::decode(&tline.get_checkpoint(last_lsn)?)?)
+    }
+
+    #[test]
+    fn test_lsn_timestamp() -> Result<()> {
+        let tline = test_timeline(&RepoHarness::create("test_lsn_timestamp")?)?;
+
+        let time = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let commit = |xid, secs| {
+            test_record(
+                pg_constants::RM_XACT_ID,
+                pg_constants::XLOG_XACT_COMMIT,
+                xid,
+                &to_pg_timestamp(time(secs)).to_le_bytes(),
+            )
+        };
+        let mut checkpoint = CheckPoint::decode(&[0u8; SIZEOF_CHECKPOINT])?;
+        checkpoint.time = 1_650_000_300;
+        let records = [
+            (Lsn(0x20), commit(100, 1_650_000_000)),
+            (
+                Lsn(0x30),
+                test_record(
+                    pg_constants::RM_XLOG_ID,
+                    pg_constants::XLOG_NEXTOID,
+                    0,
+                    &100u32.to_le_bytes(),
+                ),
+            ),
+            (Lsn(0x40), commit(101, 1_650_000_100)),
+            (
+                Lsn(0x60),
+                test_record(
+                    pg_constants::RM_XLOG_ID,
+                    pg_constants::XLOG_CHECKPOINT_ONLINE,
+                    0,
+                    &checkpoint.encode()?,
+                ),
+            ),
+        ];
+
+        let mut walingest = WalIngest::new(&tline, Lsn(0x10))?;
+        for (lsn, recdata) in records {
+            ingest_new_record(&mut walingest, &tline, recdata, lsn)?;
+        }
+
+        assert_eq!(tline.get_lsn_timestamp(Lsn(0x10))?, None);
+        for (lsn, secs) in [
+            (0x20, 1_650_000_000),
+            (0x38, 1_650_000_000),
+            (0x40, 1_650_000_100),
+            (0x58, 1_650_000_100),
+            (0x60, 1_650_000_300),
+        ] {
+            assert_eq!(tline.get_lsn_timestamp(Lsn(lsn))?, Some(time(secs)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lsn_for_timestamp() -> Result<()> {
+        let harness = RepoHarness::create("test_lsn_for_timestamp")?;
+        let tline = test_timeline(&harness)?;
+
+        let time = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let commit = |xid, secs| {
+            test_record(
+                pg_constants::RM_XACT_ID,
+                pg_constants::XLOG_XACT_COMMIT,
+                xid,
+                &to_pg_timestamp(time(secs)).to_le_bytes(),
+            )
+        };
+
+        // Nothing to find before the first commit
+        assert_eq!(tline.lsn_for_timestamp(time(1_650_000_000))?, None);
+
+        // The commit at 0x40 has an earlier timestamp than the one before it
+        let mut walingest = WalIngest::new(&tline, Lsn(0x10))?;
+        for (lsn, recdata) in [
+            (Lsn(0x20), commit(100, 1_650_000_000)),
+            (Lsn(0x30), commit(101, 1_650_000_200)),
+            (Lsn(0x40), commit(102, 1_650_000_100)),
+        ] {
+            ingest_new_record(&mut walingest, &tline, recdata, lsn)?;
+        }
+        // Records ingested after the index was built are added to it
+        assert_eq!(
+            tline.lsn_for_timestamp(time(1_650_000_000))?,
+            Some(Lsn(0x20))
+        );
+        ingest_new_record(
+            &mut walingest,
+            &tline,
+            commit(103, 1_650_000_300),
+            Lsn(0x50),
+        )?;
+
+        let check = |tline: &crate::DatadirTimelineImpl| -> Result<()> {
+            for (secs, lsn) in [
+                (1_649_999_999, None),
+                (1_650_000_000, Some(Lsn(0x20))),
+                (1_650_000_150, Some(Lsn(0x20))),
+                (1_650_000_200, Some(Lsn(0x40))),
+                (1_650_000_299, Some(Lsn(0x40))),
+                (1_650_000_300, Some(Lsn(0x50))),
+                (1_700_000_000, Some(Lsn(0x50))),
+            ] {
+                assert_eq!(tline.lsn_for_timestamp(time(secs))?, lsn, "at {secs}");
+                // And back, from the LSN to the commit timestamp
+                if let Some(lsn) = lsn {
+                    let commit_time = tline.get_lsn_timestamp(lsn)?.unwrap();
+                    assert!(commit_time <= time(secs));
+                }
+            }
+            Ok(())
+        };
+        check(&tline)?;
+
+        // The index is rebuilt from the stored timestamps when the timeline
+        // is loaded again
+        let reloaded = crate::DatadirTimelineImpl::new(tline.tline.clone(), 256 * 1024);
+        check(&reloaded)?;
+
+        // The index is saved next to the metadata, up to the disk consistent
+        // LSN, and loaded from there
+        let path = lsn_timestamps_path(harness.conf, TIMELINE_ID, harness.tenant_id);
+        let with_path = |tline: &crate::DatadirTimelineImpl| {
+            crate::DatadirTimelineImpl::new(tline.tline.clone(), 256 * 1024)
+                .with_lsn_timestamps_path(path.clone())
+        };
+        tline.tline.checkpoint(CheckpointConfig::Forced)?;
+        with_path(&tline).save_lsn_timestamp_index()?;
+        assert!(path.exists());
+        check(&with_path(&tline))?;
+
+        // GC drops the entries below the cutoff
+        tline.trim_lsn_timestamp_index(Lsn(0x40));
+        assert_eq!(tline.lsn_for_timestamp(time(1_650_000_150))?, None);
+        assert_eq!(
+            tline.lsn_for_timestamp(time(1_650_000_200))?,
+            Some(Lsn(0x40))
+        );
+        assert_eq!(
+            tline.find_lsn_for_timestamp(to_pg_timestamp(time(1_650_000_250)))?,
+            LsnForTimestamp::Present(Lsn(0x40))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_skip_replayed_records() -> Result<()> {
+        let nextoid = |oid: u32| {
+            test_record(
+                pg_constants::RM_XLOG_ID,
+                pg_constants::XLOG_NEXTOID,
+                0,
+                &oid.to_le_bytes(),
+            )
+        };
+        let prefix = [
+            (Lsn(0x20), nextoid(100)),
+            (Lsn(0x40), nextoid(200)),
+            (Lsn(0x60), nextoid(300)),
+        ];
+        // A record that doesn't touch the next OID, but makes the ingestion
+        // write out the whole checkpoint again, because the XID advances.
+        let last = (
+            Lsn(0x80),
+            test_record(pg_constants::RM_STANDBY_ID, 0, 1000, &[]),
+        );
+
+        let mut stream = prefix.to_vec();
+        stream.push(last.clone());
+
+        // Same stream, but the first two records are sent again before the last
+        // one, like after a reconnect to a safekeeper.
+        let mut replayed_stream = prefix.to_vec();
+        replayed_stream.extend_from_slice(&prefix[..2]);
+        replayed_stream.push(last);
+
+        let expected = ingest_stream("test_skip_replayed_records_expected", &stream)?;
+        assert_eq!(expected.nextOid, 300);
+        let result = ingest_stream("test_skip_replayed_records", &replayed_stream)?;
+        assert_eq!(result.nextOid, expected.nextOid);
+        assert_eq!(result.nextXid.value, expected.nextXid.value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_target() -> Result<()> {
+        let nextoid = |oid: u32| {
+            test_record(
+                pg_constants::RM_XLOG_ID,
+                pg_constants::XLOG_NEXTOID,
+                0,
+                &oid.to_le_bytes(),
+            )
+        };
+        let records = [
+            (Lsn(0x20), nextoid(100)),
+            (Lsn(0x40), nextoid(200)),
+            (Lsn(0x60), nextoid(300)),
+            (Lsn(0x80), nextoid(400)),
+        ];
+
+        // Ingest the stream with the given target, until told to stop.
+        let ingest_with_target = |test_name: &'static str, target: Lsn| -> Result<(Lsn, u32)> {
+            let tline = test_timeline(&RepoHarness::create(test_name)?)?;
+
+            let mut walingest = WalIngest::new(&tline, Lsn(0x10))?;
+            let mut last_rec_lsn = Lsn(0x10);
+            for (lsn, recdata) in records.iter() {
+                let flow = ingest_up_to_target(
+                    &mut walingest,
+                    &tline,
+                    recdata.clone(),
+                    *lsn,
+                    Some(target),
+                    &mut last_rec_lsn,
+                )?;
+                if flow.is_break() {
+                    break;
+                }
+            }
+            assert_eq!(tline.get_last_record_lsn(), last_rec_lsn);
+            let checkpoint = CheckPoint::decode(&tline.get_checkpoint(last_rec_lsn)?)?;
+            Ok((last_rec_lsn, checkpoint.nextOid))
+        };
+
+        // Target at a record boundary: that record is the last one ingested.
+        assert_eq!(
+            ingest_with_target("test_recovery_target", Lsn(0x40))?,
+            (Lsn(0x40), 200)
+        );
+        // Target in the middle of a record: the record is dropped.
+        assert_eq!(
+            ingest_with_target("test_recovery_target_mid_record", Lsn(0x50))?,
+            (Lsn(0x40), 200)
+        );
+
+        let tenant_id = ZTenantId::generate();
+        assert_eq!(get_recovery_target_lsn(tenant_id, TIMELINE_ID), None);
+        set_recovery_target_lsn(tenant_id, TIMELINE_ID, Some(Lsn(0x40)));
+        assert_eq!(
+            get_recovery_target_lsn(tenant_id, TIMELINE_ID),
+            Some(Lsn(0x40))
+        );
+        set_recovery_target_lsn(tenant_id, TIMELINE_ID, None);
+        assert_eq!(get_recovery_target_lsn(tenant_id, TIMELINE_ID), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remote_consistent_lsn_notification() {
+        let sync_id = ZTenantTimelineId {
+            tenant_id: ZTenantId::generate(),
+            timeline_id: TIMELINE_ID,
+        };
+        let remote_index = RemoteIndex::empty();
+        let mut remote_consistent_lsn = RemoteConsistentLsn::new(remote_index.clone(), sync_id);
+
+        // Nothing uploaded yet, the first call consults the index.
+        assert_eq!(remote_consistent_lsn.get().await, Lsn(0));
+
+        // The index refresh is not due yet, but the upload notification is picked up right away.
+        remote_index.notify_remote_consistent_lsn(sync_id, Lsn(0x40));
+        assert_eq!(remote_consistent_lsn.get().await, Lsn(0x40));
+
+        // Notifications for other timelines and older LSNs do not change the reported value.
+        remote_index.notify_remote_consistent_lsn(
+            ZTenantTimelineId {
+                tenant_id: sync_id.tenant_id,
+                timeline_id: NEW_TIMELINE_ID,
+            },
+            Lsn(0x80),
+        );
+        remote_index.notify_remote_consistent_lsn(sync_id, Lsn(0x20));
+        assert_eq!(remote_consistent_lsn.get().await, Lsn(0x40));
+
+        remote_index.notify_remote_consistent_lsn(sync_id, Lsn(0x60));
+        assert_eq!(remote_consistent_lsn.get().await, Lsn(0x60));
+    }
+
+    #[test]
+    fn test_wal_receiver_connect_config() {
+        let mut conf = PageServerConf::dummy_conf(PageServerConf::test_repo_dir(
+            "test_wal_receiver_connect_config",
+        ));
+        conf.id = NodeId(7);
+        conf.wal_receiver_application_name = "ps-east".to_string();
+        let tenant_id = ZTenantId::generate();
+
+        let connect_cfg = wal_receiver_connect_config(&conf, tenant_id, "host=sk1 port=5454");
+        assert_eq!(
+            connect_cfg,
+            format!("host=sk1 port=5454 application_name=ps-east_7_{tenant_id} replication=true")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() -> Result<()> {
+        // The kernel completes the TCP handshake for the listener, but
+        // nobody ever answers the startup message.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let timeout = Duration::from_millis(500);
+        let started = Instant::now();
+        let res = connect_with_timeout(
+            &format!("host=127.0.0.1 port={port} application_name=pageserver replication=true"),
+            timeout,
+        )
+        .await;
+        let elapsed = started.elapsed();
+        assert!(
+            matches!(res, Err(ConnectError::Connection(t)) if t == timeout),
+            "{:?}",
+            res.err()
+        );
+        assert!(elapsed >= timeout, "{:?}", elapsed);
+        assert!(elapsed < timeout + Duration::from_secs(5), "{:?}", elapsed);
+        drop(listener);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_receiver_entry_caught_up() -> Result<()> {
+        let mut entry = test_entry("host=localhost");
+
+        // Nothing to catch up with until we know the end of WAL.
+        assert!(!entry.note_received(Lsn(0x40)));
+        assert!(!entry.caught_up);
+
+        entry.set_end_of_wal(Lsn(0x80));
+        assert!(!entry.note_received(Lsn(0x60)));
+        assert!(!entry.caught_up);
+        assert!(entry.note_received(Lsn(0x80)));
+        assert!(entry.caught_up);
+
+        // The producer moving ahead doesn't make us fall behind again.
+        entry.set_end_of_wal(Lsn(0x100));
+        assert!(!entry.note_received(Lsn(0xC0)));
+        assert!(entry.caught_up);
+
+        let json = serde_json::to_value(&entry)?;
+        assert_eq!(json["caught_up"], true);
+        assert_eq!(json["end_of_wal"], Lsn(0x100).to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_wal_receivers() {
+        let mock_receiver = |connstr: &str| WalReceiverEntry {
+            last_received_msg_lsn: Some(Lsn(0x40)),
+            ..test_entry(connstr)
+        };
+        let key1 = (ZTenantId::generate(), ZTimelineId::generate());
+        let key2 = (ZTenantId::generate(), ZTimelineId::generate());
+        {
+            let mut receivers = WAL_RECEIVERS.lock().unwrap();
+            receivers.insert(key1, mock_receiver("host=sk1"));
+            receivers.insert(key2, mock_receiver("host=sk2"));
+        }
+
+        // Other tests may run receivers concurrently, so look for ours only.
+        let list = list_wal_receivers();
+        let find = |key| list.iter().find(|(k, _)| *k == key).map(|(_, e)| e);
+        assert_eq!(find(key1).unwrap().wal_producer_connstr, "host=sk1");
+        assert_eq!(find(key2).unwrap().wal_producer_connstr, "host=sk2");
+        assert_eq!(find(key2).unwrap().last_received_msg_lsn, Some(Lsn(0x40)));
+
+        drop_wal_receiver(key1.0, key1.1);
+        drop_wal_receiver(key2.0, key2.1);
+        assert!(!list_wal_receivers()
+            .iter()
+            .any(|(k, _)| *k == key1 || *k == key2));
+    }
+
+    #[test]
+    fn test_wal_receiver_slots() {
+        let mut slots = WalReceiverSlots::default();
+        let key1 = (ZTenantId::generate(), ZTimelineId::generate());
+        let key2 = (ZTenantId::generate(), ZTimelineId::generate());
+        let key3 = (ZTenantId::generate(), ZTimelineId::generate());
+
+        // With a cap of 1, the second receiver is queued until the first stops
+        assert!(slots.acquire(1, key1, "host=sk1"));
+        assert!(!slots.acquire(1, key2, "host=sk1"));
+        assert!(!slots.acquire(1, key3, "host=sk1"));
+        // Launching a queued one again only updates its connection string
+        assert!(!slots.acquire(1, key2, "host=sk2"));
+        assert!(slots.is_queued(key2));
+        assert!(!slots.is_queued(key1));
+
+        // The queued receivers are started in order, one per exited receiver
+        assert_eq!(
+            slots.release(1, key1, None),
+            Some((key2, "host=sk2".to_string()))
+        );
+        assert!(!slots.is_queued(key2));
+        assert_eq!(
+            slots.release(1, key2, None),
+            Some((key3, "host=sk1".to_string()))
+        );
+        assert_eq!(slots.release(1, key3, None), None);
+        assert!(slots.running.is_empty());
+
+        // Without a cap, nothing is queued
+        assert!(slots.acquire(0, key1, "host=sk1"));
+        assert!(slots.acquire(0, key2, "host=sk1"));
+        assert_eq!(slots.release(0, key1, None), None);
+    }
+
+    #[test]
+    fn test_wal_receiver_slots_queued_relaunch() {
+        let mut slots = WalReceiverSlots::default();
+        let key1 = (ZTenantId::generate(), ZTimelineId::generate());
+        let key2 = (ZTenantId::generate(), ZTimelineId::generate());
+
+        assert!(slots.acquire(1, key1, "host=sk1"));
+        assert!(!slots.acquire(1, key2, "host=sk1"));
+
+        // If the slot is freed without launching anything, for example when
+        // spawning the thread failed, launching the queued receiver again
+        // takes the slot.
+        slots.running.remove(&key1);
+        assert!(slots.acquire(1, key2, "host=sk2"));
+        assert!(!slots.is_queued(key2));
+        assert!(slots.running.contains(&key2));
+    }
+
+    #[test]
+    fn test_wal_receiver_slots_shutdown() {
+        let mut slots = WalReceiverSlots::default();
+        let tenant1 = ZTenantId::generate();
+        let tenant2 = ZTenantId::generate();
+        let key1 = (tenant1, ZTimelineId::generate());
+        let key2 = (tenant1, ZTimelineId::generate());
+        let key3 = (tenant2, ZTimelineId::generate());
+
+        assert!(slots.acquire(1, key1, "host=sk1"));
+        assert!(!slots.acquire(1, key2, "host=sk1"));
+        assert!(!slots.acquire(1, key3, "host=sk1"));
+
+        // A receiver that exits because its tenant is shutting down frees the
+        // slot for another tenant's queued receiver, passing over its own.
+        assert_eq!(
+            slots.release(1, key1, Some(tenant1)),
+            Some((key3, "host=sk1".to_string()))
+        );
+        assert!(slots.is_queued(key2));
+
+        // Detaching the tenant drops its queued receivers
+        slots.forget(|(tenant_id, _)| *tenant_id == tenant1);
+        assert!(!slots.is_queued(key2));
+        assert_eq!(slots.release(1, key3, None), None);
+        assert!(slots.running.is_empty());
+    }
+
+    #[test]
+    fn test_ingest_status() {
+        let now = SystemTime::now();
+        let micros = |t: SystemTime| {
+            t.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_micros()
+        };
+        let mut entry = test_entry("host=localhost");
+        assert_eq!(entry.ingest_status(now), IngestStatus::Active);
+
+        entry.last_received_msg_ts = Some(micros(now - Duration::from_secs(1)));
+        assert_eq!(entry.ingest_status(now), IngestStatus::Active);
+
+        let last_received = now - Duration::from_secs(60);
+        entry.last_received_msg_ts = Some(micros(last_received));
+        match entry.ingest_status(now) {
+            IngestStatus::Stalled(since) => {
+                assert_eq!(micros(since), micros(last_received))
+            }
+            status => panic!("unexpected status {status:?}"),
+        }
+
+        // Presence of the entry in the registry
+        let (tenant_id, timeline_id) = (ZTenantId::generate(), ZTimelineId::generate());
+        assert_eq!(
+            is_ingesting(tenant_id, timeline_id),
+            IngestStatus::NeverStarted
+        );
+        entry.last_received_msg_ts = Some(micros(SystemTime::now()));
+        WAL_RECEIVERS
+            .lock()
+            .unwrap()
+            .insert((tenant_id, timeline_id), entry);
+        assert_eq!(is_ingesting(tenant_id, timeline_id), IngestStatus::Active);
+        drop_wal_receiver(tenant_id, timeline_id);
+        assert_eq!(is_ingesting(tenant_id, timeline_id), IngestStatus::Stopped);
+
+        // Detaching the timeline forgets about it
+        forget_wal_receivers(tenant_id, Some(timeline_id));
+        assert_eq!(
+            is_ingesting(tenant_id, timeline_id),
+            IngestStatus::NeverStarted
+        );
+        assert!(!STOPPED_WAL_RECEIVERS
+            .lock()
+            .unwrap()
+            .contains(&(tenant_id, timeline_id)));
+    }
+
+    #[test]
+    fn test_decode_error_context() {
+        // Garbage at the beginning of a WAL segment, where the decoder expects
+        // a valid long page header.
+        let startlsn = Lsn(0x0100_0000);
+        let data = vec![0xAA; 8192];
+        let mut waldecoder = WalStreamDecoder::new(startlsn);
+        waldecoder.feed_bytes(&data);
+
+        let err = waldecoder
+            .poll_decode()
+            .map_err(|e| decode_error_with_context(e, &data, startlsn))
+            .expect_err("decoding garbage should fail");
+        let msg = format!("{:#}", err);
+        assert!(
+            msg.contains("byte offset 0 of XLogData between 0/1000000 and 0/1002000"),
+            "unexpected error: {}",
+            msg
+        );
+        assert!(msg.contains("at 0/1000000"), "unexpected error: {}", msg);
+    }
+
+    /// Build a WAL stream starting at the beginning of a segment: the segment
+    /// header followed by 'n' records. Returns the stream, and the end LSN and
+    /// contents of each record.
+    fn test_wal_stream(startlsn: Lsn, n: u32) -> Result<(BytesMut, Vec<(Lsn, Bytes)>)> {
+        use postgres_ffi::xlog_utils::{
+            generate_wal_segment, XLOG_RECORD_CRC_OFFS, XLOG_SIZE_OF_XLOG_LONG_PHD,
+        };
+
+        let mut wal = BytesMut::new();
+        wal.put_slice(&generate_wal_segment(1, 0)?[..XLOG_SIZE_OF_XLOG_LONG_PHD]);
+        let mut expected = Vec::new();
+        for oid in 0..n {
+            let mut rec = BytesMut::from(
+                &test_record(
+                    pg_constants::RM_XLOG_ID,
+                    pg_constants::XLOG_NEXTOID,
+                    0,
+                    &oid.to_le_bytes(),
+                )[..],
+            );
+            let crc = crc32c::crc32c_append(0, &rec[XLOG_RECORD_CRC_OFFS + 4..]);
+            let crc = crc32c::crc32c_append(crc, &rec[..XLOG_RECORD_CRC_OFFS]);
+            rec[XLOG_RECORD_CRC_OFFS..XLOG_RECORD_CRC_OFFS + 4].copy_from_slice(&crc.to_le_bytes());
+
+            wal.put_slice(&rec);
+            expected.push(((startlsn + wal.len() as u64).align(), rec.freeze()));
+            let padding = (startlsn + wal.len() as u64).calc_padding(8u32);
+            wal.put_bytes(0, padding as usize);
+        }
+        Ok((wal, expected))
+    }
+
+    /// Compress WAL the way the safekeeper does, chunk by chunk, and check that
+    /// decompressing and decoding it yields the original records.
+    #[test]
+    fn test_compressed_wal_stream() -> Result<()> {
+        let startlsn = Lsn(0x0100_0000);
+        let (wal, expected) = test_wal_stream(startlsn, 100)?;
+
+        // Chunk sizes that don't line up with record boundaries
+        let compression = WalCompression::Deflate;
+        let mut waldecoder = WalStreamDecoder::new(startlsn);
+        let mut decoded = Vec::new();
+        for chunk in wal.chunks(100) {
+            let compressed = Bytes::from(compression.compress(chunk)?);
+            assert_ne!(&compressed[..], chunk);
+            let data = decompress_xlog_data(compressed, Some(compression))?;
+            assert_eq!(&data[..], chunk);
+
+            waldecoder.feed_bytes(&data);
+            while let Some(record) = waldecoder.poll_decode()? {
+                decoded.push(record);
+            }
+        }
+        assert_eq!(decoded, expected);
+
+        // Without compression, the data is passed through as is
+        let data = decompress_xlog_data(wal.clone().freeze(), None)?;
+        assert_eq!(&data[..], &wal[..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_xlog_data() -> Result<()> {
+        let startlsn = Lsn(0x0100_0000);
+        let (wal, expected) = test_wal_stream(startlsn, 10)?;
+
+        let tline = test_timeline(&RepoHarness::create("test_empty_xlog_data")?)?;
+
+        let mut walingest = WalIngest::new(&tline, Lsn(0x10))?;
+        let mut waldecoder = WalStreamDecoder::new(startlsn);
+        let metrics = IngestMetrics::new(ZTenantId::generate(), TIMELINE_ID);
+        let mut last_rec_lsn = Lsn(0x10);
+        let mut chunk_start = startlsn;
+        for chunk in wal.chunks(100) {
+            // An empty message before each chunk changes nothing, and doesn't
+            // call for feedback.
+            let prev_last_rec_lsn = last_rec_lsn;
+            let flow = ingest_xlog_data(
+                &mut waldecoder,
+                &mut walingest,
+                &tline,
+                &[],
+                chunk_start,
+                None,
+                &mut last_rec_lsn,
+                &metrics,
+            )?;
+            assert_eq!(flow, ControlFlow::Continue(None));
+            assert_eq!(last_rec_lsn, prev_last_rec_lsn);
+
+            let endlsn = chunk_start + chunk.len() as u64;
+            let flow = ingest_xlog_data(
+                &mut waldecoder,
+                &mut walingest,
+                &tline,
+                chunk,
+                chunk_start,
+                None,
+                &mut last_rec_lsn,
+                &metrics,
+            )?;
+            assert_eq!(flow, ControlFlow::Continue(Some(endlsn)));
+            chunk_start = endlsn;
+        }
+        assert_eq!(last_rec_lsn, expected.last().unwrap().0);
+        assert_eq!(tline.get_last_record_lsn(), last_rec_lsn);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ingest_metrics() -> Result<()> {
+        let startlsn = Lsn(0x0100_0000);
+        let (wal, expected) = test_wal_stream(startlsn, 10)?;
+
+        let tline = test_timeline(&RepoHarness::create("test_ingest_metrics")?)?;
+
+        let tenant_id = ZTenantId::generate();
+        let labels = [tenant_id.to_string(), TIMELINE_ID.to_string()];
+        let labels = [labels[0].as_str(), labels[1].as_str()];
+        let records = || WAL_RECORDS_INGESTED.with_label_values(&labels).get();
+        let bytes = || WAL_BYTES_INGESTED.with_label_values(&labels).get();
+
+        let mut walingest = WalIngest::new(&tline, Lsn(0x10))?;
+        let metrics = IngestMetrics::new(tenant_id, TIMELINE_ID);
+        let mut last_rec_lsn = Lsn(0x10);
+        let mut ingest = |startlsn: Lsn, data: &[u8]| {
+            ingest_xlog_data(
+                &mut WalStreamDecoder::new(startlsn),
+                &mut walingest,
+                &tline,
+                data,
+                startlsn,
+                None,
+                &mut last_rec_lsn,
+                &metrics,
+            )
+        };
+
+        ingest(startlsn, &wal)?;
+        let expected_bytes: usize = expected.iter().map(|(_, rec)| rec.len()).sum();
+        assert_eq!(records(), expected.len() as u64);
+        assert_eq!(bytes(), expected_bytes as u64);
+
+        // Records that are sent again are skipped, and not counted
+        ingest(startlsn, &wal)?;
+        assert_eq!(records(), expected.len() as u64);
+
+        // The series go away when the WAL receiver exits
+        IngestMetrics::remove(tenant_id, TIMELINE_ID);
+        assert_eq!(records(), 0);
+        assert_eq!(bytes(), 0);
+
+        Ok(())
+    }
+}
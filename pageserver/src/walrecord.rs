@@ -55,6 +55,71 @@ impl ZenithWalRecord {
             _ => false,
         }
     }
+
+    /// Approximate size of the record, in bytes. Used to limit the amount of
+    /// WAL passed to the WAL redo manager at once.
+    pub fn size(&self) -> usize {
+        match self {
+            ZenithWalRecord::Postgres { rec, .. } => rec.len(),
+            ZenithWalRecord::ClogSetCommitted { xids, .. }
+            | ZenithWalRecord::ClogSetAborted { xids } => {
+                xids.len() * std::mem::size_of::<TransactionId>()
+            }
+            ZenithWalRecord::MultixactMembersCreate { members, .. } => {
+                members.len() * std::mem::size_of::<MultiXactMember>()
+            }
+            ZenithWalRecord::ClearVisibilityMapFlags { .. }
+            | ZenithWalRecord::MultixactOffsetCreate { .. } => std::mem::size_of::<Self>(),
+        }
+    }
+
+    /// Combine this record with 'next', a record for the same page that is
+    /// applied right after it, into one record with the same effect. Returns
+    /// None if the two can't be combined.
+    ///
+    /// Only records that set or clear some bits in the page are combined, so
+    /// applying the combined record over a page that already has the effect
+    /// of some of the original records still gives the same result.
+    pub fn compose(&self, next: &ZenithWalRecord) -> Option<ZenithWalRecord> {
+        match (self, next) {
+            (
+                ZenithWalRecord::ClearVisibilityMapFlags {
+                    new_heap_blkno,
+                    old_heap_blkno,
+                    flags,
+                },
+                ZenithWalRecord::ClearVisibilityMapFlags {
+                    new_heap_blkno: next_new_heap_blkno,
+                    old_heap_blkno: next_old_heap_blkno,
+                    flags: next_flags,
+                },
+            ) if new_heap_blkno == next_new_heap_blkno && old_heap_blkno == next_old_heap_blkno => {
+                Some(ZenithWalRecord::ClearVisibilityMapFlags {
+                    new_heap_blkno: *new_heap_blkno,
+                    old_heap_blkno: *old_heap_blkno,
+                    flags: flags | next_flags,
+                })
+            }
+            // The timestamp stored with the page is the one of the last commit
+            (
+                ZenithWalRecord::ClogSetCommitted { xids, .. },
+                ZenithWalRecord::ClogSetCommitted {
+                    xids: next_xids,
+                    timestamp,
+                },
+            ) => Some(ZenithWalRecord::ClogSetCommitted {
+                xids: [&xids[..], &next_xids[..]].concat(),
+                timestamp: *timestamp,
+            }),
+            (
+                ZenithWalRecord::ClogSetAborted { xids },
+                ZenithWalRecord::ClogSetAborted { xids: next_xids },
+            ) => Some(ZenithWalRecord::ClogSetAborted {
+                xids: [&xids[..], &next_xids[..]].concat(),
+            }),
+            _ => None,
+        }
+    }
 }
 
 /// DecodedBkpBlock represents per-page data contained in a WAL record.
@@ -22,6 +22,7 @@ use byteorder::{ByteOrder, LittleEndian};
 use bytes::{BufMut, Bytes, BytesMut};
 use lazy_static::lazy_static;
 use nix::poll::*;
+use once_cell::sync::OnceCell;
 use serde::Serialize;
 use std::fs;
 use std::fs::OpenOptions;
@@ -31,7 +32,7 @@ use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::process::Stdio;
 use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
-use std::sync::Mutex;
+use std::sync::{Condvar, Mutex};
 use std::time::Duration;
 use std::time::Instant;
 use tracing::*;
@@ -42,7 +43,10 @@ use crate::pgdatadir_mapping::{key_to_rel_block, key_to_slru_block};
 use crate::reltag::{RelTag, SlruKind};
 use crate::repository::Key;
 use crate::walrecord::ZenithWalRecord;
-use metrics::{register_histogram, register_int_counter, Histogram, IntCounter};
+use metrics::{
+    register_gauge_vec, register_histogram, register_int_counter, register_int_counter_vec, Gauge,
+    GaugeVec, Histogram, IntCounter, IntCounterVec,
+};
 use postgres_ffi::nonrelfile_utils::mx_offset_to_flags_bitshift;
 use postgres_ffi::nonrelfile_utils::mx_offset_to_flags_offset;
 use postgres_ffi::nonrelfile_utils::mx_offset_to_member_offset;
@@ -77,7 +81,7 @@ pub trait WalRedoManager: Send + Sync {
         key: Key,
         lsn: Lsn,
         base_img: Option<Bytes>,
-        records: Vec<(Lsn, ZenithWalRecord)>,
+        records: &[(Lsn, ZenithWalRecord)],
     ) -> Result<Bytes, WalRedoError>;
 }
 
@@ -93,7 +97,7 @@ impl crate::walredo::WalRedoManager for DummyRedoManager {
         _key: Key,
         _lsn: Lsn,
         _base_img: Option<Bytes>,
-        _records: Vec<(Lsn, ZenithWalRecord)>,
+        _records: &[(Lsn, ZenithWalRecord)],
     ) -> Result<Bytes, WalRedoError> {
         Err(WalRedoError::InvalidState)
     }
@@ -118,6 +122,141 @@ lazy_static! {
         "Number of WAL records replayed in WAL redo process"
     )
     .unwrap();
+    static ref WAL_REDO_LIMITER_WAIT_TIME: Histogram = register_histogram!(
+        "pageserver_wal_redo_limiter_wait_seconds",
+        "Time spent waiting for a free slot under the max_concurrent_redo limit"
+    )
+    .expect("failed to define a metric");
+}
+
+// Metrics on the WAL redo process of each tenant, to notice a process that
+// keeps dying even though the requests succeed on retry.
+lazy_static! {
+    static ref WAL_REDO_RESTARTS: IntCounterVec = register_int_counter_vec!(
+        "pageserver_walredo_restarts_total",
+        "Number of times the WAL redo process was relaunched after it was killed",
+        &["tenant_id"]
+    )
+    .expect("failed to define a metric");
+    static ref WAL_REDO_PROCESS_UPTIME: GaugeVec = register_gauge_vec!(
+        "pageserver_walredo_process_uptime_seconds",
+        "Time since the current WAL redo process was launched, as of its last request",
+        &["tenant_id"]
+    )
+    .expect("failed to define a metric");
+}
+
+/// Tracks the launches and kills of a tenant's WAL redo process, for the
+/// restart and uptime metrics.
+struct RedoProcessMetrics {
+    restarts: IntCounter,
+    uptime: Gauge,
+    launched_before: bool,
+    launched_at: Option<Instant>,
+}
+
+impl RedoProcessMetrics {
+    fn new(tenantid: ZTenantId) -> Self {
+        let tenant_id = tenantid.to_string();
+        RedoProcessMetrics {
+            restarts: WAL_REDO_RESTARTS.with_label_values(&[&tenant_id]),
+            uptime: WAL_REDO_PROCESS_UPTIME.with_label_values(&[&tenant_id]),
+            launched_before: false,
+            launched_at: None,
+        }
+    }
+
+    /// A new process was launched. Every launch after the first one is a
+    /// restart, as the previous process was killed.
+    fn launched(&mut self) {
+        if self.launched_before {
+            self.restarts.inc();
+        }
+        self.launched_before = true;
+        self.launched_at = Some(Instant::now());
+        self.uptime.set(0.0);
+    }
+
+    /// The process is serving a request
+    fn in_use(&self) {
+        if let Some(launched_at) = self.launched_at {
+            self.uptime.set(launched_at.elapsed().as_secs_f64());
+        }
+    }
+
+    fn killed(&mut self) {
+        self.launched_at = None;
+        self.uptime.set(0.0);
+    }
+}
+
+static REDO_LIMITER: OnceCell<Option<RedoLimiter>> = OnceCell::new();
+
+///
+/// Initialize the limit on concurrent WAL redo requests. This must be called
+/// once at page server startup. 0 means no limit.
+///
+pub fn init_redo_limiter(max_concurrent_redo: usize) {
+    let limiter = (max_concurrent_redo > 0).then(|| RedoLimiter::new(max_concurrent_redo));
+    if REDO_LIMITER.set(limiter).is_err() {
+        panic!("WAL redo limiter already initialized");
+    }
+}
+
+///
+/// Get the limiter that callers of request_redo() should go through, if
+/// a limit is configured.
+///
+pub fn redo_limiter() -> Option<&'static RedoLimiter> {
+    // In unit tests, page server startup doesn't happen. No limit then.
+    REDO_LIMITER.get_or_init(|| None).as_ref()
+}
+
+///
+/// Counting semaphore to bound the number of WAL redo requests in flight.
+/// Under a storm of reads needing reconstruction, the excess requests wait
+/// here instead of piling onto the WAL redo processes.
+///
+pub struct RedoLimiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// A slot taken from RedoLimiter. Returned to it on drop.
+pub struct RedoPermit<'a> {
+    limiter: &'a RedoLimiter,
+}
+
+impl RedoLimiter {
+    pub fn new(max_concurrent: usize) -> Self {
+        assert!(
+            max_concurrent > 0,
+            "redo concurrency limit must be positive"
+        );
+        RedoLimiter {
+            available: Mutex::new(max_concurrent),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free slot and take it.
+    pub fn acquire(&self) -> RedoPermit {
+        let start = Instant::now();
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        WAL_REDO_LIMITER_WAIT_TIME.observe(start.elapsed().as_secs_f64());
+        RedoPermit { limiter: self }
+    }
+}
+
+impl Drop for RedoPermit<'_> {
+    fn drop(&mut self) {
+        *self.limiter.available.lock().unwrap() += 1;
+        self.limiter.released.notify_one();
+    }
 }
 
 ///
@@ -132,6 +271,8 @@ pub struct PostgresRedoManager {
     conf: &'static PageServerConf,
 
     process: Mutex<Option<PostgresRedoProcess>>,
+    /// Only locked while holding 'process'
+    process_metrics: Mutex<RedoProcessMetrics>,
 }
 
 /// Can this request be served by zenith redo functions
@@ -178,7 +319,7 @@ impl WalRedoManager for PostgresRedoManager {
         key: Key,
         lsn: Lsn,
         base_img: Option<Bytes>,
-        records: Vec<(Lsn, ZenithWalRecord)>,
+        records: &[(Lsn, ZenithWalRecord)],
     ) -> Result<Bytes, WalRedoError> {
         if records.is_empty() {
             error!("invalid WAL redo request with no records");
@@ -234,6 +375,7 @@ impl PostgresRedoManager {
             tenantid,
             conf,
             process: Mutex::new(None),
+            process_metrics: Mutex::new(RedoProcessMetrics::new(tenantid)),
         }
     }
 
@@ -255,12 +397,16 @@ impl PostgresRedoManager {
         let mut process_guard = self.process.lock().unwrap();
         let lock_time = Instant::now();
 
+        let mut process_metrics = self.process_metrics.lock().unwrap();
+
         // launch the WAL redo process on first use
         if process_guard.is_none() {
             let p = PostgresRedoProcess::launch(self.conf, &self.tenantid)?;
             *process_guard = Some(p);
+            process_metrics.launched();
         }
         let process = process_guard.as_mut().unwrap();
+        process_metrics.in_use();
 
         WAL_REDO_WAIT_TIME.observe(lock_time.duration_since(start_time).as_secs_f64());
 
@@ -290,6 +436,7 @@ impl PostgresRedoManager {
             );
             let process = process_guard.take().unwrap();
             process.kill();
+            process_metrics.killed();
         }
         result
     }
@@ -728,7 +875,7 @@ impl PostgresRedoProcess {
             }?;
 
             if n == 0 {
-                return Err(Error::new(ErrorKind::Other, "WAL redo timed out"));
+                return Err(Error::new(ErrorKind::TimedOut, "WAL redo timed out"));
             }
 
             // If we have some messages in stderr, forward them to the log.
@@ -829,3 +976,60 @@ fn build_get_page_msg(tag: BufferTag, buf: &mut Vec<u8>) {
     tag.ser_into(buf)
         .expect("serialize BufferTag should always succeed");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_redo_process_metrics() {
+        let mut metrics = RedoProcessMetrics::new(ZTenantId::generate());
+        assert_eq!(metrics.restarts.get(), 0);
+
+        // The first launch is not a restart
+        metrics.launched();
+        metrics.in_use();
+        assert_eq!(metrics.restarts.get(), 0);
+
+        metrics.killed();
+        assert_eq!(metrics.uptime.get(), 0.0);
+        metrics.launched();
+        assert_eq!(metrics.restarts.get(), 1);
+
+        metrics.killed();
+        metrics.launched();
+        assert_eq!(metrics.restarts.get(), 2);
+        std::thread::sleep(Duration::from_millis(10));
+        metrics.in_use();
+        assert!(metrics.uptime.get() > 0.0);
+    }
+
+    #[test]
+    fn test_redo_limiter() {
+        let limiter = Arc::new(RedoLimiter::new(2));
+        let permit1 = limiter.acquire();
+        let _permit2 = limiter.acquire();
+
+        // The third request has to wait until one of the first two is done.
+        let (tx, rx) = mpsc::channel();
+        let waiter = {
+            let limiter = Arc::clone(&limiter);
+            thread::spawn(move || {
+                let _permit = limiter.acquire();
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        drop(permit1);
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("waiter should get the released slot");
+        waiter.join().unwrap();
+
+        // The waiter's slot was returned too
+        let _permit3 = limiter.acquire();
+    }
+}
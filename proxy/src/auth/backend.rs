@@ -15,11 +15,39 @@ use crate::{
     waiters::{self, Waiter, Waiters},
 };
 use lazy_static::lazy_static;
+use metrics::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 lazy_static! {
     static ref CPLANE_WAITERS: Waiters<mgmt::ComputeReady> = Default::default();
+    static ref CPLANE_AUTH_SECONDS: HistogramVec = register_histogram_vec!(
+        "proxy_cplane_auth_seconds",
+        "Time spent waiting for the cloud to authenticate a client, by auth method",
+        &["method"]
+    )
+    .unwrap();
+    static ref CPLANE_AUTH_RESPONSES: IntCounterVec = register_int_counter_vec!(
+        "proxy_cplane_auth_responses_total",
+        "Number of responses from the cloud to authentication requests, by auth method and outcome",
+        &["method", "outcome"]
+    )
+    .unwrap();
+}
+
+/// Auth methods, as reported in the metrics.
+const METHOD_MD5: &str = "md5";
+const METHOD_LINK: &str = "link";
+
+/// Record the cloud's final response to an authentication request:
+/// `ready` if it let the client in, `error` otherwise. The `not_ready`
+/// outcome is recorded separately, as it's followed by a final response.
+fn observe_cplane_auth_result<T, E>(method: &str, result: &Result<T, E>) {
+    let outcome = if result.is_ok() { "ready" } else { "error" };
+    CPLANE_AUTH_RESPONSES
+        .with_label_values(&[method, outcome])
+        .inc();
 }
 
 /// Give caller an opportunity to wait for the cloud's reply.
@@ -50,6 +78,44 @@ pub struct DatabaseInfo {
     pub password: Option<String>,
 }
 
+/// Connection params from the cloud that we can't connect with.
+#[derive(Debug, Error, PartialEq)]
+pub enum MalformedDatabaseInfo {
+    #[error("Compute node host is empty")]
+    EmptyHost,
+
+    #[error("Compute node port is zero")]
+    ZeroPort,
+
+    #[error("Database name is empty")]
+    EmptyDbname,
+
+    #[error("User name is empty")]
+    EmptyUser,
+}
+
+impl DatabaseInfo {
+    /// Check the params before using them, so that a malformed reply from
+    /// the cloud doesn't end up as a confusing connection error later.
+    pub fn validate(&self) -> Result<(), MalformedDatabaseInfo> {
+        use MalformedDatabaseInfo::*;
+        if self.host.is_empty() {
+            return Err(EmptyHost);
+        }
+        // The port is a u16, so it can't be over 65535.
+        if self.port == 0 {
+            return Err(ZeroPort);
+        }
+        if self.dbname.is_empty() {
+            return Err(EmptyDbname);
+        }
+        if self.user.is_empty() {
+            return Err(EmptyUser);
+        }
+        Ok(())
+    }
+}
+
 // Manually implement debug to omit personal and sensitive info.
 impl std::fmt::Debug for DatabaseInfo {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -87,15 +153,17 @@ pub(super) async fn handle_user(
     match config.auth_backend {
         LegacyConsole => {
             legacy_console::handle_user(
-                &config.auth_endpoint,
+                &config.http_client,
+                &config.auth_endpoints(),
                 &config.auth_link_uri,
+                config.session_id_len,
                 client,
                 &creds,
             )
             .await
         }
         Console => {
-            console::Api::new(&config.auth_endpoint, &creds)?
+            console::Api::new(&config.http_client, &config.auth_endpoint, &creds)?
                 .handle_user(client)
                 .await
         }
@@ -104,6 +172,50 @@ pub(super) async fn handle_user(
                 .handle_user(client)
                 .await
         }
-        Link => link::handle_user(&config.auth_link_uri, client).await,
+        Link => link::handle_user(&config.auth_link_uri, config.session_id_len, client).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_info() -> DatabaseInfo {
+        DatabaseInfo {
+            host: "localhost".to_owned(),
+            port: 5432,
+            dbname: "postgres".to_owned(),
+            user: "john_doe".to_owned(),
+            password: None,
+        }
+    }
+
+    #[test]
+    fn validate_database_info() {
+        assert_eq!(db_info().validate(), Ok(()));
+
+        let info = DatabaseInfo {
+            host: "".to_owned(),
+            ..db_info()
+        };
+        assert_eq!(info.validate(), Err(MalformedDatabaseInfo::EmptyHost));
+
+        let info = DatabaseInfo {
+            port: 0,
+            ..db_info()
+        };
+        assert_eq!(info.validate(), Err(MalformedDatabaseInfo::ZeroPort));
+
+        let info = DatabaseInfo {
+            dbname: "".to_owned(),
+            ..db_info()
+        };
+        assert_eq!(info.validate(), Err(MalformedDatabaseInfo::EmptyDbname));
+
+        let info = DatabaseInfo {
+            user: "".to_owned(),
+            ..db_info()
+        };
+        assert_eq!(info.validate(), Err(MalformedDatabaseInfo::EmptyUser));
     }
 }
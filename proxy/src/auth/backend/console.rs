@@ -72,6 +72,7 @@ pub enum AuthInfo {
 
 #[must_use]
 pub(super) struct Api<'a> {
+    http_client: &'a reqwest::Client,
     endpoint: &'a ApiUrl,
     creds: &'a ClientCredentials,
     /// Cache project name, since we'll need it several times.
@@ -80,8 +81,13 @@ pub(super) struct Api<'a> {
 
 impl<'a> Api<'a> {
     /// Construct an API object containing the auth parameters.
-    pub(super) fn new(endpoint: &'a ApiUrl, creds: &'a ClientCredentials) -> Result<Self> {
+    pub(super) fn new(
+        http_client: &'a reqwest::Client,
+        endpoint: &'a ApiUrl,
+        creds: &'a ClientCredentials,
+    ) -> Result<Self> {
         Ok(Self {
+            http_client,
             endpoint,
             creds,
             project: creds.project_name()?,
@@ -106,7 +112,12 @@ impl<'a> Api<'a> {
         // TODO: use a proper logger
         println!("cplane request: {url}");
 
-        let resp = reqwest::get(url.into_inner()).await.map_err(io_error)?;
+        let resp = self
+            .http_client
+            .get(url.into_inner())
+            .send()
+            .await
+            .map_err(io_error)?;
         if !resp.status().is_success() {
             return Err(ConsoleAuthError::HttpStatus(resp.status()));
         }
@@ -128,7 +139,12 @@ impl<'a> Api<'a> {
         // TODO: use a proper logger
         println!("cplane request: {url}");
 
-        let resp = reqwest::get(url.into_inner()).await.map_err(io_error)?;
+        let resp = self
+            .http_client
+            .get(url.into_inner())
+            .send()
+            .await
+            .map_err(io_error)?;
         if !resp.status().is_success() {
             return Err(ConsoleAuthError::HttpStatus(resp.status()));
         }
@@ -1,11 +1,12 @@
 //! Cloud API V1.
 
-use super::DatabaseInfo;
+use super::{DatabaseInfo, MalformedDatabaseInfo};
 use crate::{
     auth::{self, ClientCredentials},
     compute,
     error::UserFacingError,
     stream::PqStream,
+    url::ApiUrl,
     waiters,
 };
 use serde::{Deserialize, Serialize};
@@ -29,6 +30,14 @@ pub enum AuthErrorImpl {
     #[error(transparent)]
     Transport(#[from] reqwest::Error),
 
+    /// The console replied with connection params we can't use.
+    #[error("Console responded with malformed connection info: {0}")]
+    MalformedDatabaseInfo(#[from] MalformedDatabaseInfo),
+
+    /// None of the console endpoints answered the request.
+    #[error("All console endpoints failed: {0}")]
+    ConsoleUnavailable(String),
+
     #[error(transparent)]
     WaiterRegister(#[from] waiters::RegisterError),
 
@@ -45,6 +54,17 @@ impl AuthError {
     pub fn auth_failed(msg: impl Into<String>) -> Self {
         Self(Box::new(AuthErrorImpl::AuthFailed(msg.into())))
     }
+
+    /// Did the request fail because the console endpoint is down, so that
+    /// another endpoint might be able to answer it?
+    fn is_console_unavailable(&self) -> bool {
+        use AuthErrorImpl::*;
+        match self.0.as_ref() {
+            Transport(_) => true,
+            HttpStatus(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
 }
 
 impl<T> From<T> for AuthError
@@ -76,50 +96,116 @@ enum ProxyAuthResponse {
     NotReady { ready: bool }, // TODO: get rid of `ready`
 }
 
+async fn request_proxy_auth(
+    http_client: &reqwest::Client,
+    url: reqwest::Url,
+) -> Result<ProxyAuthResponse, AuthError> {
+    println!("cloud request: {}", url);
+    let resp = http_client.get(url).send().await?;
+    if !resp.status().is_success() {
+        return Err(AuthErrorImpl::HttpStatus(resp.status()).into());
+    }
+
+    let auth_info: ProxyAuthResponse = serde_json::from_str(resp.text().await?.as_str())?;
+    println!("got auth info: #{:?}", auth_info);
+    Ok(auth_info)
+}
+
+/// Send the same auth request to each of the console endpoints in turn, until
+/// one of them answers it.
+async fn request_proxy_auth_with_failover(
+    http_client: &reqwest::Client,
+    auth_endpoints: &[&ApiUrl],
+    creds: &ClientCredentials,
+    md5_response: &str,
+    salt: &[u8; 4],
+    psql_session_id: &str,
+) -> Result<ProxyAuthResponse, AuthError> {
+    let mut failures = Vec::new();
+    for auth_endpoint in auth_endpoints {
+        let mut url = (*auth_endpoint).clone();
+        url.query_pairs_mut()
+            .append_pair("login", &creds.user)
+            .append_pair("database", &creds.dbname)
+            .append_pair("md5response", md5_response)
+            .append_pair("salt", &hex::encode(salt))
+            .append_pair("psql_session_id", psql_session_id);
+
+        match request_proxy_auth(http_client, url.into_inner()).await {
+            Err(e) if e.is_console_unavailable() => {
+                println!("console endpoint {} failed: {}", auth_endpoint, e);
+                failures.push((auth_endpoint, e));
+            }
+            result => return result,
+        }
+    }
+
+    // With a single endpoint, report its error as is.
+    if failures.len() == 1 {
+        return Err(failures.pop().unwrap().1);
+    }
+    let summary = failures
+        .iter()
+        .map(|(auth_endpoint, e)| format!("{auth_endpoint}: {e}"))
+        .collect::<Vec<_>>()
+        .join("; ");
+    Err(AuthErrorImpl::ConsoleUnavailable(summary).into())
+}
+
 async fn authenticate_proxy_client(
-    auth_endpoint: &reqwest::Url,
+    http_client: &reqwest::Client,
+    auth_endpoints: &[&ApiUrl],
     creds: &ClientCredentials,
     md5_response: &str,
     salt: &[u8; 4],
     psql_session_id: &str,
 ) -> Result<DatabaseInfo, AuthError> {
-    let mut url = auth_endpoint.clone();
-    url.query_pairs_mut()
-        .append_pair("login", &creds.user)
-        .append_pair("database", &creds.dbname)
-        .append_pair("md5response", md5_response)
-        .append_pair("salt", &hex::encode(salt))
-        .append_pair("psql_session_id", psql_session_id);
-
     super::with_waiter(psql_session_id, |waiter| async {
-        println!("cloud request: {}", url);
-        // TODO: leverage `reqwest::Client` to reuse connections
-        let resp = reqwest::get(url).await?;
-        if !resp.status().is_success() {
-            return Err(AuthErrorImpl::HttpStatus(resp.status()).into());
-        }
+        let _timer = super::CPLANE_AUTH_SECONDS
+            .with_label_values(&[super::METHOD_MD5])
+            .start_timer();
+        let result: Result<DatabaseInfo, AuthError> = async {
+            let auth_info = request_proxy_auth_with_failover(
+                http_client,
+                auth_endpoints,
+                creds,
+                md5_response,
+                salt,
+                psql_session_id,
+            )
+            .await?;
 
-        let auth_info: ProxyAuthResponse = serde_json::from_str(resp.text().await?.as_str())?;
-        println!("got auth info: #{:?}", auth_info);
+            use ProxyAuthResponse::*;
+            let db_info = match auth_info {
+                Ready { conn_info } => conn_info,
+                Error { error } => return Err(AuthErrorImpl::AuthFailed(error).into()),
+                NotReady { .. } => {
+                    super::CPLANE_AUTH_RESPONSES
+                        .with_label_values(&[super::METHOD_MD5, "not_ready"])
+                        .inc();
+                    waiter.await?.map_err(AuthErrorImpl::AuthFailed)?
+                }
+            };
+            db_info.validate()?;
 
-        use ProxyAuthResponse::*;
-        let db_info = match auth_info {
-            Ready { conn_info } => conn_info,
-            Error { error } => return Err(AuthErrorImpl::AuthFailed(error).into()),
-            NotReady { .. } => waiter.await?.map_err(AuthErrorImpl::AuthFailed)?,
-        };
+            Ok(db_info)
+        }
+        .await;
 
-        Ok(db_info)
+        super::observe_cplane_auth_result(super::METHOD_MD5, &result);
+        result
     })
     .await
 }
 
 async fn handle_existing_user(
-    auth_endpoint: &reqwest::Url,
+    http_client: &reqwest::Client,
+    auth_endpoints: &[&ApiUrl],
+    session_id_len: usize,
     client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
     creds: &ClientCredentials,
 ) -> Result<compute::NodeInfo, auth::AuthError> {
-    let psql_session_id = super::link::new_psql_session_id();
+    let psql_session_id = super::link::new_psql_session_id(session_id_len);
     let md5_salt = rand::random();
 
     client
@@ -131,7 +217,8 @@ async fn handle_existing_user(
     let md5_response = parse_password(&msg).ok_or(auth::AuthErrorImpl::MalformedPassword)?;
 
     let db_info = authenticate_proxy_client(
-        auth_endpoint,
+        http_client,
+        auth_endpoints,
         creds,
         md5_response,
         &md5_salt,
@@ -150,15 +237,17 @@ async fn handle_existing_user(
 }
 
 pub async fn handle_user(
-    auth_endpoint: &reqwest::Url,
+    http_client: &reqwest::Client,
+    auth_endpoints: &[&ApiUrl],
     auth_link_uri: &reqwest::Url,
+    session_id_len: usize,
     client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin + Send>,
     creds: &ClientCredentials,
 ) -> auth::Result<compute::NodeInfo> {
     if creds.is_existing_user() {
-        handle_existing_user(auth_endpoint, client, creds).await
+        handle_existing_user(http_client, auth_endpoints, session_id_len, client, creds).await
     } else {
-        super::link::handle_user(auth_link_uri, client).await
+        super::link::handle_user(auth_link_uri, session_id_len, client).await
     }
 }
 
@@ -170,6 +259,8 @@ fn parse_password(bytes: &[u8]) -> Option<&str> {
 mod tests {
     use super::*;
     use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[test]
     fn test_proxy_auth_response() {
@@ -201,4 +292,142 @@ mod tests {
         .unwrap();
         assert!(matches!(auth, ProxyAuthResponse::NotReady { .. }));
     }
+
+    /// Start a fake console that answers every request with the given status
+    /// and body, and return its auth endpoint.
+    fn fake_console(status: hyper::StatusCode, body: &'static str) -> anyhow::Result<ApiUrl> {
+        Ok(fake_console_counting_connections(status, body)?.0)
+    }
+
+    /// Like [`fake_console`], but also return the number of connections
+    /// the console has accepted.
+    fn fake_console_counting_connections(
+        status: hyper::StatusCode,
+        body: &'static str,
+    ) -> anyhow::Result<(ApiUrl, Arc<AtomicUsize>)> {
+        use hyper::service::{make_service_fn, service_fn};
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let make_service = {
+            let connections = Arc::clone(&connections);
+            make_service_fn(move |_| {
+                connections.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    Ok::<_, std::convert::Infallible>(service_fn(move |_| async move {
+                        hyper::Response::builder()
+                            .status(status)
+                            .body(hyper::Body::from(body))
+                    }))
+                }
+            })
+        };
+        let server = hyper::Server::try_bind(&"127.0.0.1:0".parse()?)?.serve(make_service);
+        let auth_endpoint = format!("http://{}/authenticate_proxy_request/", server.local_addr());
+        tokio::spawn(server);
+        Ok((auth_endpoint.parse()?, connections))
+    }
+
+    #[tokio::test]
+    async fn reuse_console_connection() -> anyhow::Result<()> {
+        let (console, connections) = fake_console_counting_connections(
+            hyper::StatusCode::OK,
+            r#"{"ready": true, "conn_info": {"host": "localhost", "port": 5432, "dbname": "postgres", "user": "john_doe"}}"#,
+        )?;
+        let creds = ClientCredentials {
+            user: "john_doe@zenith".to_owned(),
+            dbname: "postgres".to_owned(),
+            sni_data: None,
+        };
+
+        let http_client = reqwest::Client::new();
+        for _ in 0..3 {
+            authenticate_proxy_client(
+                &http_client,
+                &[&console],
+                &creds,
+                "md5",
+                &[1, 2, 3, 4],
+                &super::super::link::new_psql_session_id(16),
+            )
+            .await?;
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failover_to_next_endpoint() -> anyhow::Result<()> {
+        let down = fake_console(hyper::StatusCode::SERVICE_UNAVAILABLE, "")?;
+        let up = fake_console(
+            hyper::StatusCode::OK,
+            r#"{"ready": true, "conn_info": {"host": "localhost", "port": 5432, "dbname": "postgres", "user": "john_doe"}}"#,
+        )?;
+        let creds = ClientCredentials {
+            user: "john_doe@zenith".to_owned(),
+            dbname: "postgres".to_owned(),
+            sni_data: None,
+        };
+        let md5_response = "md5";
+        let salt = [1, 2, 3, 4];
+
+        let http_client = reqwest::Client::new();
+        let db_info = authenticate_proxy_client(
+            &http_client,
+            &[&down, &up],
+            &creds,
+            md5_response,
+            &salt,
+            &super::super::link::new_psql_session_id(16),
+        )
+        .await?;
+        assert_eq!(db_info.host, "localhost");
+        assert_eq!(db_info.port, 5432);
+
+        // If all endpoints are down, the error mentions each of them.
+        let err = authenticate_proxy_client(
+            &http_client,
+            &[&down, &down],
+            &creds,
+            md5_response,
+            &salt,
+            &super::super::link::new_psql_session_id(16),
+        )
+        .await
+        .expect_err("all endpoints are down");
+        assert!(matches!(*err.0, AuthErrorImpl::ConsoleUnavailable(_)));
+        assert_eq!(err.to_string().matches(down.as_str()).count(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_malformed_database_info() -> anyhow::Result<()> {
+        let console = fake_console(
+            hyper::StatusCode::OK,
+            r#"{"ready": true, "conn_info": {"host": "", "port": 5432, "dbname": "postgres", "user": "john_doe"}}"#,
+        )?;
+        let creds = ClientCredentials {
+            user: "john_doe@zenith".to_owned(),
+            dbname: "postgres".to_owned(),
+            sni_data: None,
+        };
+
+        let err = authenticate_proxy_client(
+            &reqwest::Client::new(),
+            &[&console],
+            &creds,
+            "md5",
+            &[1, 2, 3, 4],
+            &super::super::link::new_psql_session_id(16),
+        )
+        .await
+        .expect_err("host is empty");
+        assert!(matches!(
+            *err.0,
+            AuthErrorImpl::MalformedDatabaseInfo(MalformedDatabaseInfo::EmptyHost)
+        ));
+
+        Ok(())
+    }
 }
@@ -1,4 +1,5 @@
 use crate::{auth, compute, stream::PqStream};
+use rand::{rngs::OsRng, RngCore};
 use tokio::io::{AsyncRead, AsyncWrite};
 use utils::pq_proto::{BeMessage as Be, BeParameterStatusMessage};
 
@@ -14,15 +15,22 @@ fn hello_message(redirect_uri: &str, session_id: &str) -> String {
     )
 }
 
-pub fn new_psql_session_id() -> String {
-    hex::encode(rand::random::<[u8; 8]>())
+/// Generate a new session id of 'len' random bytes, hex-encoded.
+///
+/// Whoever knows the session id can complete the authentication, as it's
+/// part of the link we hand out, so it comes straight from the OS CSPRNG.
+pub fn new_psql_session_id(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
 }
 
 pub async fn handle_user(
     redirect_uri: &reqwest::Url,
+    session_id_len: usize,
     client: &mut PqStream<impl AsyncRead + AsyncWrite + Unpin>,
 ) -> auth::Result<compute::NodeInfo> {
-    let psql_session_id = new_psql_session_id();
+    let psql_session_id = new_psql_session_id(session_id_len);
     let greeting = hello_message(redirect_uri.as_str(), &psql_session_id);
 
     let db_info = super::with_waiter(psql_session_id, |waiter| async {
@@ -34,7 +42,12 @@ pub async fn handle_user(
             .await?;
 
         // Wait for web console response (see `mgmt`)
-        waiter.await?.map_err(auth::AuthErrorImpl::auth_failed)
+        let _timer = super::CPLANE_AUTH_SECONDS
+            .with_label_values(&[super::METHOD_LINK])
+            .start_timer();
+        let result = waiter.await?;
+        super::observe_cplane_auth_result(super::METHOD_LINK, &result);
+        result.map_err(auth::AuthErrorImpl::auth_failed)
     })
     .await?;
 
@@ -45,3 +58,60 @@ pub async fn handle_user(
         scram_keys: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::{notify, CPLANE_AUTH_RESPONSES, METHOD_LINK};
+    use super::*;
+    use crate::auth::DatabaseInfo;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn session_id_format() {
+        for len in [8, 16, 32] {
+            let id = new_psql_session_id(len);
+            assert_eq!(id.len(), len * 2);
+            assert_eq!(hex::decode(&id).unwrap().len(), len);
+            assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+            assert_ne!(id, new_psql_session_id(len));
+        }
+    }
+
+    #[tokio::test]
+    async fn successful_auth_is_counted() -> anyhow::Result<()> {
+        const REDIRECT_URI: &str = "http://console.test/psql_session/";
+        let ready = CPLANE_AUTH_RESPONSES.with_label_values(&[METHOD_LINK, "ready"]);
+        let ready_before = ready.get();
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let auth = tokio::spawn(async move {
+            let redirect_uri = reqwest::Url::parse(REDIRECT_URI)?;
+            let mut stream = PqStream::new(server);
+            handle_user(&redirect_uri, 16, &mut stream).await?;
+            anyhow::Ok(())
+        });
+
+        // Fish the session id out of the greeting, and pretend that the
+        // console has authenticated the user.
+        let mut greeting = Vec::new();
+        let psql_session_id = loop {
+            let mut buf = [0u8; 1024];
+            let n = client.read(&mut buf).await?;
+            anyhow::ensure!(n > 0, "connection closed before the greeting");
+            greeting.extend_from_slice(&buf[..n]);
+
+            let text = String::from_utf8_lossy(&greeting);
+            if let Some((_, rest)) = text.split_once(REDIRECT_URI) {
+                if rest.len() >= 32 {
+                    break rest[..32].to_owned();
+                }
+            }
+        };
+        notify(&psql_session_id, Ok(DatabaseInfo::default()))?;
+
+        auth.await??;
+        assert_eq!(ready.get(), ready_before + 1);
+
+        Ok(())
+    }
+}
@@ -8,10 +8,23 @@ use std::collections::HashMap;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-#[derive(Debug, Error)]
+/// Max length of the `user` and `database` startup parameters, in bytes.
+/// They end up in the query string of the console requests.
+const MAX_PARAM_LEN: usize = 256;
+
+#[derive(Debug, Error, PartialEq, Eq)]
 pub enum ClientCredsParseError {
     #[error("Parameter `{0}` is missing in startup packet")]
     MissingKey(&'static str),
+
+    #[error("Parameter `{0}` is empty")]
+    EmptyValue(&'static str),
+
+    #[error("Parameter `{0}` is longer than {MAX_PARAM_LEN} bytes")]
+    ValueTooLong(&'static str),
+
+    #[error("Parameter `{0}` contains control characters")]
+    ControlCharacters(&'static str),
 }
 
 impl UserFacingError for ClientCredsParseError {}
@@ -60,10 +73,12 @@ impl TryFrom<HashMap<String, String>> for ClientCredentials {
     type Error = ClientCredsParseError;
 
     fn try_from(mut value: HashMap<String, String>) -> Result<Self, Self::Error> {
-        let mut get_param = |key| {
-            value
+        let mut get_param = |key| -> Result<String, Self::Error> {
+            let param = value
                 .remove(key)
-                .ok_or(ClientCredsParseError::MissingKey(key))
+                .ok_or(ClientCredsParseError::MissingKey(key))?;
+            validate_param(key, &param)?;
+            Ok(param)
         };
 
         let user = get_param("user")?;
@@ -77,6 +92,21 @@ impl TryFrom<HashMap<String, String>> for ClientCredentials {
     }
 }
 
+/// Check a startup parameter before it's used anywhere, e.g. in the logs or
+/// in the URL of a console request.
+fn validate_param(key: &'static str, param: &str) -> Result<(), ClientCredsParseError> {
+    if param.is_empty() {
+        return Err(ClientCredsParseError::EmptyValue(key));
+    }
+    if param.len() > MAX_PARAM_LEN {
+        return Err(ClientCredsParseError::ValueTooLong(key));
+    }
+    if param.chars().any(char::is_control) {
+        return Err(ClientCredsParseError::ControlCharacters(key));
+    }
+    Ok(())
+}
+
 impl ClientCredentials {
     /// Use credentials to authenticate the user.
     pub async fn authenticate(
@@ -88,3 +118,56 @@ impl ClientCredentials {
         super::backend::handle_user(config, client, self).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn startup_params(user: &str, database: &str) -> HashMap<String, String> {
+        HashMap::from([
+            ("user".to_owned(), user.to_owned()),
+            ("database".to_owned(), database.to_owned()),
+        ])
+    }
+
+    #[test]
+    fn parse_credentials() {
+        let creds = ClientCredentials::try_from(startup_params("john_doe", "postgres")).unwrap();
+        assert_eq!(creds.user, "john_doe");
+        assert_eq!(creds.dbname, "postgres");
+
+        let mut params = startup_params("john_doe", "postgres");
+        params.remove("database");
+        assert_eq!(
+            ClientCredentials::try_from(params),
+            Err(ClientCredsParseError::MissingKey("database"))
+        );
+    }
+
+    #[test]
+    fn reject_bad_credentials() {
+        use ClientCredsParseError::*;
+
+        assert_eq!(
+            ClientCredentials::try_from(startup_params("", "postgres")),
+            Err(EmptyValue("user"))
+        );
+        assert_eq!(
+            ClientCredentials::try_from(startup_params("john_doe", &"x".repeat(MAX_PARAM_LEN + 1))),
+            Err(ValueTooLong("database"))
+        );
+        assert!(ClientCredentials::try_from(startup_params(
+            "john_doe",
+            &"x".repeat(MAX_PARAM_LEN)
+        ))
+        .is_ok());
+        assert_eq!(
+            ClientCredentials::try_from(startup_params("john\ndoe", "postgres")),
+            Err(ControlCharacters("user"))
+        );
+        assert_eq!(
+            ClientCredentials::try_from(startup_params("john_doe", "post\x00gres")),
+            Err(ControlCharacters("database"))
+        );
+    }
+}
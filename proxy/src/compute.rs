@@ -23,8 +23,26 @@ pub enum ConnectionError {
 
 impl UserFacingError for ConnectionError {}
 
-/// PostgreSQL version as [`String`].
-pub type Version = String;
+/// Parameters which postgres reports to clients via `ParameterStatus`
+/// messages, i.e. GUCs marked as `GUC_REPORT`.
+const REPORTED_PARAMETERS: &[&str] = &[
+    "application_name",
+    "client_encoding",
+    "DateStyle",
+    "in_hot_standby",
+    "integer_datetimes",
+    "IntervalStyle",
+    "is_superuser",
+    "server_encoding",
+    "server_version",
+    "session_authorization",
+    "standard_conforming_strings",
+    "TimeZone",
+];
+
+/// Parameters reported by the compute node, to be relayed to the client.
+/// Contains at least `server_version`.
+pub type ServerParams = Vec<(String, String)>;
 
 /// A pair of `ClientKey` & `ServerKey` for `SCRAM-SHA-256`.
 pub type ScramKeys = tokio_postgres::config::ScramKeys<32>;
@@ -46,7 +64,9 @@ impl NodeInfo {
     }
 
     /// Connect to a corresponding compute node.
-    pub async fn connect(self) -> Result<(TcpStream, Version, CancelClosure), ConnectionError> {
+    pub async fn connect(
+        self,
+    ) -> Result<(TcpStream, ServerParams, CancelClosure), ConnectionError> {
         let (socket_addr, mut socket) = self
             .connect_raw()
             .await
@@ -59,13 +79,22 @@ impl NodeInfo {
 
         // TODO: establish a secure connection to the DB
         let (client, conn) = config.connect_raw(&mut socket, NoTls).await?;
-        let version = conn
-            .parameter("server_version")
-            .ok_or(ConnectionError::FailedToFetchPgVersion)?
-            .into();
+        if conn.parameter("server_version").is_none() {
+            return Err(ConnectionError::FailedToFetchPgVersion);
+        }
+
+        let params = REPORTED_PARAMETERS
+            .iter()
+            .filter_map(|name| {
+                let value = conn.parameter(name)?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect();
 
+        // The cancel token carries the compute's `BackendKeyData`, so that
+        // the client's `CancelRequest` can be forwarded to the right backend.
         let cancel_closure = CancelClosure::new(socket_addr, client.cancel_token());
 
-        Ok((socket, version, cancel_closure))
+        Ok((socket, params, cancel_closure))
     }
 }
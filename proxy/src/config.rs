@@ -33,7 +33,25 @@ pub struct ProxyConfig {
     pub tls_config: Option<TlsConfig>,
     pub auth_backend: AuthBackendType,
     pub auth_endpoint: ApiUrl,
+    /// Endpoints to try, in order, if `auth_endpoint` is unavailable.
+    /// Only used by the legacy console backend.
+    pub auth_fallback_endpoints: Vec<ApiUrl>,
     pub auth_link_uri: ApiUrl,
+    /// Length of the session ids handed out for link auth, in bytes.
+    pub session_id_len: usize,
+    /// Client for the requests to the console. It's shared by all the
+    /// connections, so that its pooled connections to the console are
+    /// reused instead of doing a new TCP and TLS handshake on every auth.
+    pub http_client: reqwest::Client,
+}
+
+impl ProxyConfig {
+    /// All the auth endpoints, in the order they should be tried.
+    pub fn auth_endpoints(&self) -> Vec<&ApiUrl> {
+        std::iter::once(&self.auth_endpoint)
+            .chain(&self.auth_fallback_endpoints)
+            .collect()
+    }
 }
 
 pub type TlsConfig = Arc<rustls::ServerConfig>;
@@ -19,7 +19,7 @@ mod stream;
 mod url;
 mod waiters;
 
-use anyhow::{bail, Context};
+use anyhow::{bail, ensure, Context};
 use clap::{App, Arg};
 use config::ProxyConfig;
 use futures::FutureExt;
@@ -87,6 +87,20 @@ async fn main() -> anyhow::Result<()> {
                 .help("cloud API endpoint for authenticating users")
                 .default_value("http://localhost:3000/authenticate_proxy_request/"),
         )
+        .arg(
+            Arg::new("auth-fallback-endpoint")
+                .long("auth-fallback-endpoint")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("cloud API endpoint to use if auth-endpoint is unavailable, can be repeated"),
+        )
+        .arg(
+            Arg::new("session-id-bytes")
+                .long("session-id-bytes")
+                .takes_value(true)
+                .help("length of the session ids used for link auth, in bytes")
+                .default_value("16"),
+        )
         .arg(
             Arg::new("tls-key")
                 .short('k')
@@ -118,11 +132,26 @@ async fn main() -> anyhow::Result<()> {
     let mgmt_address: SocketAddr = arg_matches.value_of("mgmt").unwrap().parse()?;
     let http_address: SocketAddr = arg_matches.value_of("http").unwrap().parse()?;
 
+    let session_id_len: usize = arg_matches.value_of("session-id-bytes").unwrap().parse()?;
+    // The session id in the link is all it takes to log in, it mustn't be guessable.
+    ensure!(
+        session_id_len >= 8,
+        "session-id-bytes must be at least 8, got {session_id_len}"
+    );
+
     let config: &ProxyConfig = Box::leak(Box::new(ProxyConfig {
         tls_config,
         auth_backend: arg_matches.value_of("auth-backend").unwrap().parse()?,
         auth_endpoint: arg_matches.value_of("auth-endpoint").unwrap().parse()?,
+        auth_fallback_endpoints: arg_matches
+            .values_of("auth-fallback-endpoint")
+            .into_iter()
+            .flatten()
+            .map(str::parse)
+            .collect::<anyhow::Result<_>>()?,
         auth_link_uri: arg_matches.value_of("uri").unwrap().parse()?,
+        session_id_len,
+        http_client: reqwest::Client::new(),
     }));
 
     println!("Version: {GIT_VERSION}");
@@ -192,14 +192,19 @@ impl<S: AsyncRead + AsyncWrite + Unpin + Send> Client<S> {
         let auth = creds.authenticate(config, &mut stream).await;
         let node = async { auth }.or_else(|e| stream.throw_error(e)).await?;
 
-        let (db, version, cancel_closure) =
+        let (db, params, cancel_closure) =
             node.connect().or_else(|e| stream.throw_error(e)).await?;
         let cancel_key_data = session.enable_cancellation(cancel_closure);
 
+        // Relay the compute's parameters, so that the client sees e.g. the
+        // actual server_version and TimeZone.
+        for (name, value) in params.iter() {
+            stream.write_message_noflush(&BeMessage::ParameterStatus(
+                BeParameterStatusMessage::Other { name, value },
+            ))?;
+        }
+
         stream
-            .write_message_noflush(&BeMessage::ParameterStatus(
-                BeParameterStatusMessage::ServerVersion(&version),
-            ))?
             .write_message_noflush(&Be::BackendKeyData(cancel_key_data))?
             .write_message(&BeMessage::ReadyForQuery)
             .await?;
@@ -468,6 +473,72 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn cancel_request_is_routed_to_backend() -> anyhow::Result<()> {
+        use crate::cancellation::CancelClosure;
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        // The key which the compute node hands out in its `BackendKeyData`.
+        let backend_key = CancelKeyData {
+            backend_pid: 42,
+            cancel_key: 4242,
+        };
+
+        // A fake compute node which completes the startup and reports its key.
+        let (client, server) = tokio::io::duplex(1024);
+        let compute = tokio::spawn(async move {
+            let mut stream = PqStream::new(Stream::from_raw(client));
+            stream.read_startup_packet().await?;
+            stream
+                .write_message_noflush(&Be::AuthenticationOk)?
+                .write_message_noflush(&Be::BackendKeyData(backend_key))?
+                .write_message(&BeMessage::ReadyForQuery)
+                .await?;
+            anyhow::Ok(stream)
+        });
+
+        let (compute_client, _conn) = tokio_postgres::Config::new()
+            .user("john_doe")
+            .dbname("earth")
+            .ssl_mode(SslMode::Disable)
+            .connect_raw(server, NoTls)
+            .await?;
+        let _compute_stream = compute.await??;
+
+        // Cancel requests for the compute node will arrive here.
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let cancel_closure =
+            CancelClosure::new(listener.local_addr()?, compute_client.cancel_token());
+
+        let cancel_map = CancelMap::default();
+        let cancel_map = &cancel_map;
+        let request = cancel_map
+            .with_session(|session| async move {
+                let key = session.enable_cancellation(cancel_closure);
+                assert_ne!(key, backend_key, "proxy should not leak the backend key");
+
+                let accept = async {
+                    let (mut socket, _) = listener.accept().await?;
+                    let mut buf = [0u8; 16];
+                    socket.read_exact(&mut buf).await?;
+                    anyhow::Ok(buf)
+                };
+                let (cancelled, request) = tokio::join!(cancel_map.cancel_session(key), accept);
+                cancelled?;
+                request
+            })
+            .await?;
+
+        // CancelRequest: length, request code, backend pid, secret key.
+        assert_eq!(request[0..4], 16i32.to_be_bytes());
+        assert_eq!(request[4..8], 80877102i32.to_be_bytes());
+        assert_eq!(request[8..12], backend_key.backend_pid.to_be_bytes());
+        assert_eq!(request[12..16], backend_key.cancel_key.to_be_bytes());
+
+        Ok(())
+    }
+
     #[rstest]
     #[case("password_foo")]
     #[case("pwd-bar")]
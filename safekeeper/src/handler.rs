@@ -18,6 +18,7 @@ use utils::{
     lsn::Lsn,
     postgres_backend::{self, PostgresBackend},
     pq_proto::{BeMessage, FeStartupPacket, RowDescriptor, INT4_OID, TEXT_OID},
+    wal_compression::WalCompression,
     zid::{ZTenantId, ZTenantTimelineId, ZTimelineId},
 };
 
@@ -34,10 +35,17 @@ pub struct SafekeeperPostgresHandler {
 
 /// Parsed Postgres command.
 enum SafekeeperPostgresCommand {
-    StartWalPush { pageserver_connstr: Option<String> },
-    StartReplication { start_lsn: Lsn },
+    StartWalPush {
+        pageserver_connstr: Option<String>,
+    },
+    StartReplication {
+        start_lsn: Lsn,
+        compression: Option<WalCompression>,
+    },
     IdentifySystem,
-    JSONCtrl { cmd: AppendLogicalMessage },
+    JSONCtrl {
+        cmd: AppendLogicalMessage,
+    },
 }
 
 fn parse_cmd(cmd: &str) -> Result<SafekeeperPostgresCommand> {
@@ -48,14 +56,22 @@ fn parse_cmd(cmd: &str) -> Result<SafekeeperPostgresCommand> {
         let pageserver_connstr = caps.get(1).map(|m| m.as_str().to_owned());
         Ok(SafekeeperPostgresCommand::StartWalPush { pageserver_connstr })
     } else if cmd.starts_with("START_REPLICATION") {
-        let re =
-            Regex::new(r"START_REPLICATION(?: PHYSICAL)? ([[:xdigit:]]+/[[:xdigit:]]+)").unwrap();
-        let mut caps = re.captures_iter(cmd);
-        let start_lsn = caps
-            .next()
-            .map(|cap| cap[1].parse::<Lsn>())
-            .context("failed to parse start LSN from START_REPLICATION command")??;
-        Ok(SafekeeperPostgresCommand::StartReplication { start_lsn })
+        let re = Regex::new(
+            r"START_REPLICATION(?: PHYSICAL)? ([[:xdigit:]]+/[[:xdigit:]]+)(?: COMPRESSION (\w+))?",
+        )
+        .unwrap();
+        let caps = re
+            .captures(cmd)
+            .context("failed to parse start LSN from START_REPLICATION command")?;
+        let start_lsn = caps[1].parse::<Lsn>()?;
+        let compression = caps
+            .get(2)
+            .map(|m| m.as_str().parse::<WalCompression>())
+            .transpose()?;
+        Ok(SafekeeperPostgresCommand::StartReplication {
+            start_lsn,
+            compression,
+        })
     } else if cmd.starts_with("IDENTIFY_SYSTEM") {
         Ok(SafekeeperPostgresCommand::IdentifySystem)
     } else if cmd.starts_with("JSON_CTRL") {
@@ -118,9 +134,18 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
                     .run(self)
                     .context("failed to run ReceiveWalConn")?;
             }
-            SafekeeperPostgresCommand::StartReplication { start_lsn } => {
+            SafekeeperPostgresCommand::StartReplication {
+                start_lsn,
+                compression,
+            } => {
                 ReplicationConn::new(pgb)
-                    .run(self, pgb, start_lsn, self.pageserver_connstr.clone())
+                    .run(
+                        self,
+                        pgb,
+                        start_lsn,
+                        compression,
+                        self.pageserver_connstr.clone(),
+                    )
                     .context("failed to run ReplicationConn")?;
             }
             SafekeeperPostgresCommand::IdentifySystem => {
@@ -175,6 +200,10 @@ impl SafekeeperPostgresHandler {
         let tli = PG_TLI.to_string();
         let tli_bytes = tli.as_bytes();
         let sysid_bytes = sysid.as_bytes();
+        // Extra column, not in Postgres, to tell the pageserver what WAL
+        // compression methods it can ask for in START_REPLICATION.
+        let compression = WalCompression::advertise();
+        let compression_bytes = compression.as_bytes();
 
         pgb.write_message_noflush(&BeMessage::RowDescription(&[
             RowDescriptor {
@@ -201,12 +230,19 @@ impl SafekeeperPostgresHandler {
                 typlen: -1,
                 ..Default::default()
             },
+            RowDescriptor {
+                name: b"wal_compression",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
         ]))?
         .write_message_noflush(&BeMessage::DataRow(&[
             Some(sysid_bytes),
             Some(tli_bytes),
             Some(lsn_bytes),
             None,
+            Some(compression_bytes),
         ]))?
         .write_message(&BeMessage::CommandComplete(b"IDENTIFY_SYSTEM"))?;
         Ok(())
@@ -224,6 +224,19 @@ pub struct SafekeeperMemState {
     pub proposer_uuid: PgUuid,
 }
 
+/// Consensus term and WAL position of a safekeeper, for cluster status
+/// reporting. Cheap to get, unlike the whole state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TermInfo {
+    /// The last term this safekeeper voted for
+    pub term: Term,
+    /// Term of the last record in the WAL, aka epoch
+    pub last_log_term: Term,
+    pub flush_lsn: Lsn,
+    /// Note: this value is not flushed to control file yet and can be lost
+    pub commit_lsn: Lsn,
+}
+
 impl SafeKeeperState {
     pub fn new(zttid: &ZTenantTimelineId, peers: Vec<NodeId>) -> SafeKeeperState {
         SafeKeeperState {
@@ -352,6 +365,11 @@ pub struct AppendResponse {
     pub commit_lsn: Lsn,
     pub hs_feedback: HotStandbyFeedback,
     pub zenith_feedback: ZenithFeedback,
+    // Backpressure hint: amount of committed WAL, in bytes, not yet made
+    // durable remotely by pageserver and thus not removable here. Proposer
+    // should throttle when it grows too large, otherwise safekeeper disk
+    // fills up. Sent as part of zenith_feedback.
+    pub backpressure_lag: u64,
 }
 
 impl AppendResponse {
@@ -362,6 +380,7 @@ impl AppendResponse {
             commit_lsn: Lsn(0),
             hs_feedback: HotStandbyFeedback::empty(),
             zenith_feedback: ZenithFeedback::empty(),
+            backpressure_lag: 0,
         }
     }
 }
@@ -479,7 +498,13 @@ impl AcceptorProposerMessage {
                 buf.put_u64_le(msg.hs_feedback.xmin);
                 buf.put_u64_le(msg.hs_feedback.catalog_xmin);
 
-                msg.zenith_feedback.serialize(buf)?
+                // zenith_feedback is a key-value list proposers skip unknown
+                // keys of, so the hint goes there to keep the layout intact.
+                let zenith_feedback = ZenithFeedback {
+                    backpressure_lag: msg.backpressure_lag,
+                    ..msg.zenith_feedback
+                };
+                zenith_feedback.serialize(buf)?
             }
         }
 
@@ -513,6 +538,20 @@ impl SafeKeeperMetrics {
     }
 }
 
+/// Outcome of [`SafeKeeper::validate_msg`]: whether the message would be
+/// accepted by [`SafeKeeper::process_msg`], and if not, why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MsgValidation {
+    Accept,
+    Reject(String),
+}
+
+impl MsgValidation {
+    pub fn is_accept(&self) -> bool {
+        matches!(self, MsgValidation::Accept)
+    }
+}
+
 /// SafeKeeper which consumes events (messages from compute) and provides
 /// replies.
 pub struct SafeKeeper<CTRL: control_file::Storage, WAL: wal_storage::Storage> {
@@ -583,6 +622,15 @@ where
         self.state.acceptor_state.get_epoch(self.flush_lsn())
     }
 
+    pub fn get_term_info(&self) -> TermInfo {
+        TermInfo {
+            term: self.state.acceptor_state.term,
+            last_log_term: self.get_epoch(),
+            flush_lsn: self.flush_lsn(),
+            commit_lsn: self.inmem.commit_lsn,
+        }
+    }
+
     /// wal_store wrapper avoiding commit_lsn <= flush_lsn violation when we don't have WAL yet.
     fn flush_lsn(&self) -> Lsn {
         max(self.wal_store.flush_lsn(), self.state.timeline_start_lsn)
@@ -606,12 +654,31 @@ where
         }
     }
 
-    /// Handle initial message from proposer: check its sanity and send my
-    /// current term.
-    fn handle_greeting(
-        &mut self,
-        msg: &ProposerGreeting,
-    ) -> Result<Option<AcceptorProposerMessage>> {
+    /// Check whether the message would be accepted by process_msg(), without
+    /// persisting or advancing any state. Uses the same checks as process_msg(),
+    /// so the two can't diverge.
+    pub fn validate_msg(&self, msg: &ProposerAcceptorMessage) -> Result<MsgValidation> {
+        let validation = match msg {
+            ProposerAcceptorMessage::Greeting(msg) => match self.check_greeting(msg) {
+                Ok(()) => MsgValidation::Accept,
+                Err(e) => MsgValidation::Reject(e.to_string()),
+            },
+            ProposerAcceptorMessage::VoteRequest(msg) => self.check_vote_request(msg),
+            ProposerAcceptorMessage::Elected(msg) => self.check_elected(msg),
+            ProposerAcceptorMessage::AppendRequest(msg)
+            | ProposerAcceptorMessage::NoFlushAppendRequest(msg) => {
+                match self.check_append_request(msg) {
+                    Ok(validation) => validation,
+                    Err(e) => MsgValidation::Reject(e.to_string()),
+                }
+            }
+            ProposerAcceptorMessage::FlushWAL => MsgValidation::Accept,
+        };
+        Ok(validation)
+    }
+
+    /// Check that the greeting comes from a compatible proposer of our timeline.
+    fn check_greeting(&self, msg: &ProposerGreeting) -> Result<()> {
         /* Check protocol compatibility */
         if msg.protocol_version != SK_PROTOCOL_VERSION {
             bail!(
@@ -620,15 +687,6 @@ where
                 SK_PROTOCOL_VERSION
             );
         }
-        /* Postgres upgrade is not treated as fatal error */
-        if msg.pg_version != self.state.server.pg_version
-            && self.state.server.pg_version != UNKNOWN_SERVER_VERSION
-        {
-            info!(
-                "incompatible server version {}, expected {}",
-                msg.pg_version, self.state.server.pg_version
-            );
-        }
         if msg.tenant_id != self.state.tenant_id {
             bail!(
                 "invalid tenant ID, got {}, expected {}",
@@ -643,6 +701,68 @@ where
                 self.state.timeline_id
             );
         }
+        Ok(())
+    }
+
+    /// The vote is given only for a term higher than ours.
+    fn check_vote_request(&self, msg: &VoteRequest) -> MsgValidation {
+        if self.state.acceptor_state.term < msg.term {
+            MsgValidation::Accept
+        } else {
+            MsgValidation::Reject(format!(
+                "vote requested for term {}, but acceptor term is already {}",
+                msg.term, self.state.acceptor_state.term
+            ))
+        }
+    }
+
+    /// ProposerElected is ignored if our term is higher.
+    fn check_elected(&self, msg: &ProposerElected) -> MsgValidation {
+        if self.state.acceptor_state.term > msg.term {
+            MsgValidation::Reject(format!(
+                "proposer elected in term {}, but acceptor term is {}",
+                msg.term, self.state.acceptor_state.term
+            ))
+        } else {
+            MsgValidation::Accept
+        }
+    }
+
+    /// AppendRequest is refused if our term is higher, and must continue the
+    /// WAL written so far. Returns an error for messages that are invalid in
+    /// any term.
+    fn check_append_request(&self, msg: &AppendRequest) -> Result<MsgValidation> {
+        if self.state.acceptor_state.term < msg.h.term {
+            bail!("got AppendRequest before ProposerElected");
+        }
+        if self.state.acceptor_state.term > msg.h.term {
+            return Ok(MsgValidation::Reject(format!(
+                "AppendRequest in term {}, but acceptor term is {}",
+                msg.h.term, self.state.acceptor_state.term
+            )));
+        }
+        if !msg.wal_data.is_empty() {
+            wal_storage::check_wal_continuity(self.wal_store.write_lsn(), msg.h.begin_lsn)?;
+        }
+        Ok(MsgValidation::Accept)
+    }
+
+    /// Handle initial message from proposer: check its sanity and send my
+    /// current term.
+    fn handle_greeting(
+        &mut self,
+        msg: &ProposerGreeting,
+    ) -> Result<Option<AcceptorProposerMessage>> {
+        self.check_greeting(msg)?;
+        /* Postgres upgrade is not treated as fatal error */
+        if msg.pg_version != self.state.server.pg_version
+            && self.state.server.pg_version != UNKNOWN_SERVER_VERSION
+        {
+            info!(
+                "incompatible server version {}, expected {}",
+                msg.pg_version, self.state.server.pg_version
+            );
+        }
 
         // set basic info about server, if not yet
         // TODO: verify that is doesn't change after
@@ -679,7 +799,7 @@ where
             term_history: self.get_term_history(),
             timeline_start_lsn: self.state.timeline_start_lsn,
         };
-        if self.state.acceptor_state.term < msg.term {
+        if self.check_vote_request(msg).is_accept() {
             let mut state = self.state.clone();
             state.acceptor_state.term = msg.term;
             // persist vote before sending it out
@@ -702,6 +822,14 @@ where
         Ok(())
     }
 
+    /// How much committed WAL we hold only because pageserver hasn't yet
+    /// persisted it remotely (see get_horizon_segno). WAL before
+    /// local_start_lsn is not stored here, so it doesn't count.
+    fn backpressure_lag(&self) -> u64 {
+        let horizon = max(self.inmem.remote_consistent_lsn, self.state.local_start_lsn);
+        self.inmem.commit_lsn.0.saturating_sub(horizon.0)
+    }
+
     /// Form AppendResponse from current state.
     fn append_response(&self) -> AppendResponse {
         let ar = AppendResponse {
@@ -711,6 +839,7 @@ where
             // will be filled by the upper code to avoid bothering safekeeper
             hs_feedback: HotStandbyFeedback::empty(),
             zenith_feedback: ZenithFeedback::empty(),
+            backpressure_lag: self.backpressure_lag(),
         };
         trace!("formed AppendResponse {:?}", ar);
         ar
@@ -720,7 +849,7 @@ where
         info!("received ProposerElected {:?}", msg);
         self.bump_if_higher(msg.term)?;
         // If our term is higher, ignore the message (next feedback will inform the compute)
-        if self.state.acceptor_state.term > msg.term {
+        if !self.check_elected(msg).is_accept() {
             return Ok(None);
         }
 
@@ -793,6 +922,67 @@ where
         Ok(())
     }
 
+    /// Change the set of safekeepers this timeline is replicated to, and
+    /// persist it. The change is refused unless every quorum of the current
+    /// membership intersects every quorum of the new one; otherwise two
+    /// proposers could get elected in the same term. For instance, replacing
+    /// one of three members must be done as an addition followed by a removal.
+    pub fn change_membership(&mut self, new_peer_ids: Vec<NodeId>) -> Result<()> {
+        if new_peer_ids.is_empty() {
+            bail!("new membership must not be empty");
+        }
+        let mut sorted_ids = new_peer_ids.clone();
+        sorted_ids.sort();
+        sorted_ids.dedup();
+        if sorted_ids.len() != new_peer_ids.len() {
+            bail!(
+                "duplicate safekeeper ids in new membership {:?}",
+                new_peer_ids
+            );
+        }
+
+        let old_peers = &self.state.peers.0;
+        // Membership might be not known yet, then anything goes.
+        if !old_peers.is_empty() {
+            let majority = |n: usize| n / 2 + 1;
+            let common = old_peers
+                .iter()
+                .filter(|(id, _)| new_peer_ids.contains(id))
+                .count();
+            // Minimal number of common members in any quorum of either config.
+            let old_needs =
+                majority(old_peers.len()) as isize - (old_peers.len() - common) as isize;
+            let new_needs =
+                majority(new_peer_ids.len()) as isize - (new_peer_ids.len() - common) as isize;
+            if old_needs <= 0 || new_needs <= 0 || old_needs + new_needs <= common as isize {
+                bail!(
+                    "unsafe membership change from {:?} to {:?}, quorums might not intersect",
+                    old_peers.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+                    new_peer_ids
+                );
+            }
+        }
+
+        let peers = new_peer_ids
+            .iter()
+            .map(|id| {
+                let info = old_peers
+                    .iter()
+                    .find(|(old_id, _)| old_id == id)
+                    .map(|(_, info)| info.clone())
+                    .unwrap_or_else(PeerInfo::new);
+                (*id, info)
+            })
+            .collect();
+
+        let mut state = self.state.clone();
+        state.peers = Peers(peers);
+        self.persist_control_file(state)?;
+
+        info!("changed membership to {:?}", new_peer_ids);
+        Ok(())
+    }
+
     /// Persist in-memory state to the disk, taking other data from state.
     fn persist_control_file(&mut self, mut state: SafeKeeperState) -> Result<()> {
         state.commit_lsn = self.inmem.commit_lsn;
@@ -810,12 +1000,8 @@ where
         msg: &AppendRequest,
         require_flush: bool,
     ) -> Result<Option<AcceptorProposerMessage>> {
-        if self.state.acceptor_state.term < msg.h.term {
-            bail!("got AppendRequest before ProposerElected");
-        }
-
         // If our term is higher, immediately refuse the message.
-        if self.state.acceptor_state.term > msg.h.term {
+        if !self.check_append_request(msg)?.is_accept() {
             let resp = AppendResponse::term_only(self.state.acceptor_state.term);
             return Ok(Some(AcceptorProposerMessage::AppendResponse(resp)));
         }
@@ -976,6 +1162,10 @@ mod tests {
             self.lsn
         }
 
+        fn write_lsn(&self) -> Lsn {
+            self.lsn
+        }
+
         fn init_storage(&mut self, _state: &SafeKeeperState) -> Result<()> {
             Ok(())
         }
@@ -1086,4 +1276,205 @@ mod tests {
         sk.wal_store.truncate_wal(Lsn(3)).unwrap(); // imitate the complete record at 3 %)
         assert_eq!(sk.get_epoch(), 1);
     }
+
+    #[test]
+    fn test_term_info() {
+        let storage = InMemoryState {
+            persisted_state: SafeKeeperState::empty(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let ztli = ZTimelineId::from([0u8; 16]);
+
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
+        assert_eq!(
+            sk.get_term_info(),
+            TermInfo {
+                term: 0,
+                last_log_term: 0,
+                flush_lsn: Lsn(0),
+                commit_lsn: Lsn(0),
+            }
+        );
+
+        let vote_request = ProposerAcceptorMessage::VoteRequest(VoteRequest { term: 3 });
+        sk.process_msg(&vote_request).unwrap();
+        let info = sk.get_term_info();
+        assert_eq!(info.term, 3);
+        // No WAL written in the new term yet
+        assert_eq!(info.last_log_term, 0);
+
+        let pem = ProposerElected {
+            term: 3,
+            start_streaming_at: Lsn(1),
+            term_history: TermHistory(vec![TermSwitchEntry {
+                term: 3,
+                lsn: Lsn(1),
+            }]),
+            timeline_start_lsn: Lsn(0),
+        };
+        sk.process_msg(&ProposerAcceptorMessage::Elected(pem))
+            .unwrap();
+        let info = sk.get_term_info();
+        assert_eq!(info.term, 3);
+        assert_eq!(info.last_log_term, sk.get_epoch());
+        assert_eq!(info.flush_lsn, sk.wal_store.flush_lsn());
+    }
+
+    #[test]
+    fn test_validate_msg() {
+        let storage = InMemoryState {
+            persisted_state: SafeKeeperState::empty(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let ztli = ZTimelineId::from([0u8; 16]);
+
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
+
+        let vote_request = ProposerAcceptorMessage::VoteRequest(VoteRequest { term: 2 });
+        assert!(sk.validate_msg(&vote_request).unwrap().is_accept());
+        sk.process_msg(&vote_request).unwrap();
+        // the vote is already given
+        assert!(!sk.validate_msg(&vote_request).unwrap().is_accept());
+
+        let pem = ProposerElected {
+            term: 2,
+            start_streaming_at: Lsn(1),
+            term_history: TermHistory(vec![TermSwitchEntry {
+                term: 2,
+                lsn: Lsn(1),
+            }]),
+            timeline_start_lsn: Lsn(0),
+        };
+        sk.process_msg(&ProposerAcceptorMessage::Elected(pem))
+            .unwrap();
+
+        let append_request = |term, begin_lsn: Lsn| {
+            ProposerAcceptorMessage::AppendRequest(AppendRequest {
+                h: AppendRequestHeader {
+                    term,
+                    epoch_start_lsn: Lsn(1),
+                    begin_lsn,
+                    end_lsn: begin_lsn + 1,
+                    commit_lsn: Lsn(0),
+                    truncate_lsn: Lsn(0),
+                    proposer_uuid: [0; 16],
+                },
+                wal_data: Bytes::from_static(b"b"),
+            })
+        };
+
+        // stale term is refused
+        let stale = append_request(1, Lsn(1));
+        match sk.validate_msg(&stale).unwrap() {
+            MsgValidation::Reject(reason) => assert!(reason.contains("term"), "{}", reason),
+            MsgValidation::Accept => panic!("stale term append validated as accept"),
+        }
+        // so is WAL that doesn't continue what's written
+        assert!(!sk
+            .validate_msg(&append_request(2, Lsn(5)))
+            .unwrap()
+            .is_accept());
+
+        // current one is accepted, and validation doesn't change anything
+        let current = append_request(2, Lsn(1));
+        assert!(sk.validate_msg(&current).unwrap().is_accept());
+        assert_eq!(sk.wal_store.flush_lsn(), Lsn(1));
+        assert_eq!(sk.state.acceptor_state.term, 2);
+
+        sk.process_msg(&current).unwrap();
+        assert_eq!(sk.wal_store.flush_lsn(), Lsn(2));
+        // having been processed, it doesn't continue the WAL anymore
+        assert!(!sk.validate_msg(&current).unwrap().is_accept());
+    }
+
+    #[test]
+    fn test_backpressure_lag() {
+        let storage = InMemoryState {
+            persisted_state: SafeKeeperState::empty(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let ztli = ZTimelineId::from([0u8; 16]);
+
+        let mut sk = SafeKeeper::new(ztli, storage, wal_store, NodeId(0)).unwrap();
+
+        let pem = ProposerElected {
+            term: 1,
+            start_streaming_at: Lsn(1),
+            term_history: TermHistory(vec![TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(1),
+            }]),
+            timeline_start_lsn: Lsn(0),
+        };
+        sk.process_msg(&ProposerAcceptorMessage::Elected(pem))
+            .unwrap();
+
+        // append and commit 1MB of WAL, pageserver hasn't seen any of it
+        let len = 1024 * 1024;
+        let append_request = AppendRequest {
+            h: AppendRequestHeader {
+                term: 1,
+                epoch_start_lsn: Lsn(1),
+                begin_lsn: Lsn(1),
+                end_lsn: Lsn(1 + len),
+                commit_lsn: Lsn(1 + len),
+                truncate_lsn: Lsn(0),
+                proposer_uuid: [0; 16],
+            },
+            wal_data: Bytes::from(vec![0u8; len as usize]),
+        };
+        let resp = sk.process_msg(&ProposerAcceptorMessage::AppendRequest(append_request));
+        match resp.unwrap() {
+            Some(AcceptorProposerMessage::AppendResponse(resp)) => {
+                assert_eq!(resp.commit_lsn, Lsn(1 + len));
+                assert_eq!(resp.backpressure_lag, len);
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+
+        // once pageserver catches up, the hint drops
+        sk.record_safekeeper_info(&SkTimelineInfo {
+            last_log_term: None,
+            flush_lsn: None,
+            commit_lsn: None,
+            backup_lsn: None,
+            remote_consistent_lsn: Some(Lsn(1 + len - 100)),
+            peer_horizon_lsn: None,
+            safekeeper_connection_string: None,
+        })
+        .unwrap();
+        match sk.process_msg(&ProposerAcceptorMessage::FlushWAL).unwrap() {
+            Some(AcceptorProposerMessage::AppendResponse(resp)) => {
+                assert_eq!(resp.backpressure_lag, 100)
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_append_response_layout() {
+        let mut resp = AppendResponse::term_only(1);
+        resp.flush_lsn = Lsn(2);
+        resp.commit_lsn = Lsn(3);
+        resp.backpressure_lag = 100;
+        let mut buf = BytesMut::new();
+        AcceptorProposerMessage::AppendResponse(resp)
+            .serialize(&mut buf)
+            .unwrap();
+
+        // Decode it the way proposers do: fixed part, then zenith feedback
+        // taking the rest of the message, with nothing after it.
+        let mut buf = buf.freeze();
+        assert_eq!(buf.get_u64_le(), 'a' as u64);
+        assert_eq!(buf.get_u64_le(), 1);
+        assert_eq!(buf.get_u64_le(), 2);
+        assert_eq!(buf.get_u64_le(), 3);
+        buf.advance(3 * 8); // hs_feedback
+        let mut expected = BytesMut::new();
+        ZenithFeedback::parse(buf.clone())
+            .serialize(&mut expected)
+            .unwrap();
+        assert_eq!(buf, expected.freeze());
+        assert_eq!(ZenithFeedback::parse(buf).backpressure_lag, 100);
+    }
 }
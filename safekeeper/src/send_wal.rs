@@ -25,6 +25,7 @@ use utils::{
     postgres_backend::PostgresBackend,
     pq_proto::{BeMessage, FeMessage, WalSndKeepAlive, XLogDataBody, ZenithFeedback},
     sock_split::ReadStream,
+    wal_compression::WalCompression,
     zid::{ZTenantId, ZTimelineId},
 };
 
@@ -199,6 +200,7 @@ impl ReplicationConn {
         spg: &mut SafekeeperPostgresHandler,
         pgb: &mut PostgresBackend,
         mut start_pos: Lsn,
+        compression: Option<WalCompression>,
         pageserver_connstr: Option<String>,
     ) -> Result<()> {
         let _enter = info_span!("WAL sender", timeline = %spg.ztimelineid.unwrap(), pageserver_connstr = %pageserver_connstr.as_deref().unwrap_or_default()).entered();
@@ -254,7 +256,10 @@ impl ReplicationConn {
         } else {
             None
         };
-        info!("Start replication from {:?} till {:?}", start_pos, stop_pos);
+        info!(
+            "Start replication from {:?} till {:?}, compression {:?}",
+            start_pos, stop_pos, compression
+        );
 
         // Don't spam pageserver with callmemaybe queries
         // when replication connection with pageserver is already established.
@@ -340,13 +345,21 @@ impl ReplicationConn {
             // read wal into buffer
             let send_size = wal_reader.read(send_buf)?;
             let send_buf = &send_buf[..send_size];
+            let compressed_buf;
+            let data = match compression {
+                Some(compression) => {
+                    compressed_buf = compression.compress(send_buf)?;
+                    &compressed_buf[..]
+                }
+                None => send_buf,
+            };
 
             // Write some data to the network socket.
             pgb.write_message(&BeMessage::XLogData(XLogDataBody {
                 wal_start: start_pos.0,
                 wal_end: end_pos.0,
                 timestamp: get_current_timestamp(),
-                data: send_buf,
+                data,
             }))
             .context("Failed to send XLogData")?;
 
@@ -2,6 +2,7 @@
 //! persistence and support for interaction between sending and receiving wal.
 
 use anyhow::{bail, Context, Result};
+use bytes::Bytes;
 
 use etcd_broker::SkTimelineInfo;
 use lazy_static::lazy_static;
@@ -29,12 +30,13 @@ use crate::callmemaybe::{CallmeEvent, SubscriptionStateKey};
 use crate::control_file;
 use crate::safekeeper::{
     AcceptorProposerMessage, ProposerAcceptorMessage, SafeKeeper, SafeKeeperState,
-    SafekeeperMemState,
+    SafekeeperMemState, TermInfo,
 };
 use crate::send_wal::HotStandbyFeedback;
 
 use crate::wal_storage;
 use crate::wal_storage::Storage as wal_storage_iface;
+use crate::wal_storage::WalReader;
 use crate::SafeKeeperConf;
 
 const POLL_STATE_TIMEOUT: Duration = Duration::from_secs(1);
@@ -524,6 +526,11 @@ impl Timeline {
         (shared_state.sk.inmem.clone(), shared_state.sk.state.clone())
     }
 
+    /// Current consensus term and WAL position, without cloning the state.
+    pub fn get_term_info(&self) -> TermInfo {
+        self.mutex.lock().unwrap().sk.get_term_info()
+    }
+
     pub fn get_wal_backup_lsn(&self) -> Lsn {
         self.mutex.lock().unwrap().sk.inmem.backup_lsn
     }
@@ -602,6 +609,44 @@ impl Timeline {
         shared_state.sk.wal_store.flush_lsn()
     }
 
+    /// Read committed WAL starting at `start`, e.g. to serve a lagging peer.
+    /// Returns at most `max_len` bytes and the LSN right after the last
+    /// returned byte. Never reads past commit_lsn, as WAL after it might
+    /// still be truncated.
+    pub fn read_wal(&self, start: Lsn, max_len: usize) -> Result<(Bytes, Lsn)> {
+        let (timeline_dir, wal_seg_size, commit_lsn) = {
+            let shared_state = self.mutex.lock().unwrap();
+            (
+                shared_state.sk.wal_store.timeline_dir().to_owned(),
+                shared_state.get_wal_seg_size(),
+                shared_state.sk.inmem.commit_lsn,
+            )
+        };
+        // WAL seg size not initialized yet, no WAL exists.
+        if wal_seg_size == 0 {
+            bail!("cannot read WAL of timeline {}: no WAL yet", self.zttid);
+        }
+        if start > commit_lsn {
+            bail!(
+                "cannot read WAL at {}, it is beyond commit_lsn {}",
+                start,
+                commit_lsn
+            );
+        }
+
+        let len = min(max_len as u64, commit_lsn.0 - start.0) as usize;
+        let mut buf = vec![0u8; len];
+        WalReader::new(timeline_dir, wal_seg_size, start).read_exact(&mut buf)?;
+        Ok((Bytes::from(buf), start + len as u64))
+    }
+
+    /// Change the set of safekeepers the timeline is replicated to, e.g.
+    /// to replace a failed node. See [`SafeKeeper::change_membership`].
+    pub fn change_membership(&self, new_peer_ids: Vec<NodeId>) -> Result<()> {
+        let mut shared_state = self.mutex.lock().unwrap();
+        shared_state.sk.change_membership(new_peer_ids)
+    }
+
     pub fn remove_old_wal(&self, wal_backup_enabled: bool) -> Result<()> {
         let horizon_segno: XLogSegNo;
         let remover: Box<dyn Fn(u64) -> Result<(), anyhow::Error>>;
@@ -856,3 +901,101 @@ impl GlobalTimelines {
         Ok(deleted)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_file::Storage as _;
+    use postgres_ffi::xlog_utils::{XLogFileName, PG_TLI, XLOG_BLCKSZ};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_change_membership() -> Result<()> {
+        let conf = SafeKeeperConf {
+            workdir: tempfile::tempdir()?.into_path(),
+            ..Default::default()
+        };
+        let zttid = ZTenantTimelineId::generate();
+        fs::create_dir_all(conf.timeline_dir(&zttid))?;
+
+        let shared_state =
+            SharedState::create(&conf, &zttid, vec![NodeId(1), NodeId(2), NodeId(3)])?;
+        let (callmemaybe_tx, _callmemaybe_rx) = mpsc::unbounded_channel();
+        let (wal_backup_launcher_tx, _wal_backup_launcher_rx) = mpsc::channel(1);
+        let tli = Timeline::new(zttid, callmemaybe_tx, wal_backup_launcher_tx, shared_state);
+
+        // Replacing a member in one step is unsafe
+        assert!(tli
+            .change_membership(vec![NodeId(1), NodeId(2), NodeId(4)])
+            .is_err());
+        assert!(tli.change_membership(vec![NodeId(4), NodeId(5)]).is_err());
+
+        tli.change_membership(vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4)])?;
+
+        // The new membership must survive a restart
+        let restored = SharedState::restore(&conf, &zttid)?;
+        let peers: Vec<NodeId> = restored
+            .sk
+            .state
+            .peers
+            .0
+            .iter()
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(peers, vec![NodeId(1), NodeId(2), NodeId(3), NodeId(4)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_wal_up_to_commit_lsn() -> Result<()> {
+        let conf = SafeKeeperConf {
+            workdir: tempfile::tempdir()?.into_path(),
+            ..Default::default()
+        };
+        let zttid = ZTenantTimelineId::generate();
+        fs::create_dir_all(conf.timeline_dir(&zttid))?;
+
+        // Tiny segments, so that the WAL spans several of them.
+        let wal_seg_size = 2 * XLOG_BLCKSZ;
+        let mut shared_state = SharedState::create(&conf, &zttid, vec![])?;
+        let mut state = shared_state.sk.state.clone();
+        state.server.wal_seg_size = wal_seg_size as u32;
+        shared_state.sk.state.persist(&state)?;
+
+        // Segments 1 and 2, the second one still being written.
+        let wal: Vec<u8> = (0..2 * wal_seg_size).map(|i| (i % 251) as u8).collect();
+        for (i, segment) in wal.chunks(wal_seg_size).enumerate() {
+            let mut name = XLogFileName(PG_TLI, i as u64 + 1, wal_seg_size);
+            if i == 1 {
+                name += ".partial";
+            }
+            fs::write(conf.timeline_dir(&zttid).join(name), segment)?;
+        }
+        let start = Lsn(wal_seg_size as u64);
+        let commit_lsn = start + (wal_seg_size + 1000) as u64;
+        shared_state.sk.inmem.commit_lsn = commit_lsn;
+
+        let (callmemaybe_tx, _callmemaybe_rx) = mpsc::unbounded_channel();
+        let (wal_backup_launcher_tx, _wal_backup_launcher_rx) = mpsc::channel(1);
+        let tli = Timeline::new(zttid, callmemaybe_tx, wal_backup_launcher_tx, shared_state);
+
+        // A sub-range that crosses a segment boundary comes back byte-for-byte
+        let (bytes, end_lsn) = tli.read_wal(start + 100, wal_seg_size)?;
+        assert_eq!(bytes, &wal[100..100 + wal_seg_size]);
+        assert_eq!(end_lsn, start + (100 + wal_seg_size) as u64);
+
+        // A read past commit_lsn is truncated at it, although more WAL is on disk
+        let (bytes, end_lsn) = tli.read_wal(start + 500, 2 * wal_seg_size)?;
+        assert_eq!(bytes, &wal[500..wal_seg_size + 1000]);
+        assert_eq!(end_lsn, commit_lsn);
+
+        // Nothing is left to read at commit_lsn, and reading beyond it fails
+        let (bytes, end_lsn) = tli.read_wal(commit_lsn, 100)?;
+        assert!(bytes.is_empty());
+        assert_eq!(end_lsn, commit_lsn);
+        assert!(tli.read_wal(commit_lsn + 1, 100).is_err());
+
+        Ok(())
+    }
+}
@@ -88,10 +88,34 @@ impl WalStorageMetrics {
     }
 }
 
+/// Check that a write starting at `startpos` continues the WAL written up to
+/// `write_lsn`. Disallows any non-sequential writes, which can result in gaps
+/// or overwrites. If we need to move the pointer, use truncate_wal() instead.
+pub fn check_wal_continuity(write_lsn: Lsn, startpos: Lsn) -> Result<()> {
+    if write_lsn > startpos {
+        bail!(
+            "write_wal rewrites WAL written before, write_lsn={}, startpos={}",
+            write_lsn,
+            startpos
+        );
+    }
+    if write_lsn < startpos && write_lsn != Lsn(0) {
+        bail!(
+            "write_wal creates gap in written WAL, write_lsn={}, startpos={}",
+            write_lsn,
+            startpos
+        );
+    }
+    Ok(())
+}
+
 pub trait Storage {
     /// LSN of last durably stored WAL record.
     fn flush_lsn(&self) -> Lsn;
 
+    /// End of the written WAL, possibly not flushed yet. The next write must start here.
+    fn write_lsn(&self) -> Lsn;
+
     /// Init storage with wal_seg_size and read WAL from disk to get latest LSN.
     fn init_storage(&mut self, state: &SafeKeeperState) -> Result<()>;
 
@@ -168,6 +192,11 @@ impl PhysicalStorage {
         }
     }
 
+    /// Directory holding WAL segments of this timeline.
+    pub fn timeline_dir(&self) -> &Path {
+        &self.timeline_dir
+    }
+
     /// Wrapper for flush_lsn updates that also updates metrics.
     fn update_flush_lsn(&mut self) {
         self.flush_record_lsn = self.write_record_lsn;
@@ -301,6 +330,10 @@ impl Storage for PhysicalStorage {
         self.flush_record_lsn
     }
 
+    fn write_lsn(&self) -> Lsn {
+        self.write_lsn
+    }
+
     /// Storage needs to know wal_seg_size to know which segment to read/write, but
     /// wal_seg_size is not always known at the moment of storage creation. This method
     /// allows to postpone its initialization.
@@ -350,22 +383,7 @@ impl Storage for PhysicalStorage {
 
     /// Write WAL to disk.
     fn write_wal(&mut self, startpos: Lsn, buf: &[u8]) -> Result<()> {
-        // Disallow any non-sequential writes, which can result in gaps or overwrites.
-        // If we need to move the pointer, use truncate_wal() instead.
-        if self.write_lsn > startpos {
-            bail!(
-                "write_wal rewrites WAL written before, write_lsn={}, startpos={}",
-                self.write_lsn,
-                startpos
-            );
-        }
-        if self.write_lsn < startpos && self.write_lsn != Lsn(0) {
-            bail!(
-                "write_wal creates gap in written WAL, write_lsn={}, startpos={}",
-                self.write_lsn,
-                startpos
-            );
-        }
+        check_wal_continuity(self.write_lsn, startpos)?;
 
         {
             let _timer = self.metrics.write_wal_seconds.start_timer();
@@ -565,6 +583,15 @@ impl WalReader {
         Ok(send_size)
     }
 
+    /// Fill the whole `buf`, crossing WAL segment boundaries as needed.
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        let mut nread = 0;
+        while nread < buf.len() {
+            nread += self.read(&mut buf[nread..])?;
+        }
+        Ok(())
+    }
+
     /// Helper function for opening a wal file.
     fn open_wal_file(wal_file_path: &Path) -> Result<File> {
         // First try to open the .partial file.
@@ -608,3 +635,42 @@ fn wal_file_paths(
     let wal_file_partial_path = timeline_dir.join(wal_file_name + ".partial");
     Ok((wal_file_path, wal_file_partial_path))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SafeKeeperConf;
+
+    #[test]
+    fn test_read_wal_across_segments() -> Result<()> {
+        let conf = SafeKeeperConf {
+            workdir: tempfile::tempdir()?.into_path(),
+            ..Default::default()
+        };
+        let zttid = ZTenantTimelineId::generate();
+        fs::create_dir_all(conf.timeline_dir(&zttid))?;
+
+        // Tiny segments, so that the written WAL spans several of them.
+        let wal_seg_size = 2 * XLOG_BLCKSZ;
+        let mut storage = PhysicalStorage::new(&zttid, &conf);
+        storage.wal_seg_size = Some(wal_seg_size);
+
+        let start = Lsn(wal_seg_size as u64);
+        let wal: Vec<u8> = (0..3 * wal_seg_size).map(|i| (i % 251) as u8).collect();
+        storage.write_exact(start, &wal)?;
+
+        // Read back a sub-range which starts and ends in the middle of segments.
+        let from = 1000;
+        let len = 2 * wal_seg_size;
+        let mut buf = vec![0u8; len];
+        let mut reader = WalReader::new(
+            storage.timeline_dir().to_owned(),
+            wal_seg_size,
+            start + from as u64,
+        );
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, &wal[from..from + len]);
+
+        Ok(())
+    }
+}
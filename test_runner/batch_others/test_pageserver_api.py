@@ -85,6 +85,8 @@ def test_pageserver_http_get_wal_receiver_success(zenith_simple_env: ZenithEnv):
             "wal_producer_connstr",
             "last_received_msg_lsn",
             "last_received_msg_ts",
+            "end_of_wal",
+            "caught_up",
         ]
 
         assert res["last_received_msg_lsn"] is not None, "the last received message's LSN is empty"